@@ -0,0 +1,494 @@
+// src/cgroups.rs
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sysinfo::Pid;
+
+const PROC_DIRECTORY: &str = "/proc";
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Deepest process tree searched for a tool's descendants
+const MAX_TREE_DEPTH: usize = 64;
+
+lazy_static! {
+    /// Cgroups shared by everything a user or the system runs, accounting for far more than a tool
+    static ref SHARED_CGROUP: Regex =
+        Regex::new(r"^(session-[^/]+\.scope|user@[^/]+\.service|init\.scope)$").unwrap();
+}
+
+/// Accounting of the cgroup v2 a tool runs in, covering the tool and its
+/// children. Counters of CPU and IO are deltas since the tool started.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CgroupUsage {
+    pub cgroup: String,
+    pub memory_current: Option<u64>,
+    /// Since the cgroup was created, needs Linux 5.19 or later
+    pub memory_peak: Option<u64>,
+    pub memory_anon: Option<u64>,
+    pub memory_file: Option<u64>,
+    pub cpu_usage_usec: Option<u64>,
+    pub cpu_user_usec: Option<u64>,
+    pub cpu_system_usec: Option<u64>,
+    pub cpu_nr_periods: Option<u64>,
+    pub cpu_nr_throttled: Option<u64>,
+    pub cpu_throttled_usec: Option<u64>,
+    /// Summed over devices, only there when the io controller is enabled
+    pub io_read_bytes: Option<u64>,
+    pub io_write_bytes: Option<u64>,
+    pub io_read_operations: Option<u64>,
+    pub io_write_operations: Option<u64>,
+}
+
+impl CgroupUsage {
+    /// The counters accumulated since `baseline` was read
+    pub fn since(&self, baseline: &CgroupUsage) -> CgroupUsage {
+        let delta = |value: Option<u64>, base: Option<u64>| {
+            value.map(|value| value.saturating_sub(base.unwrap_or_default()))
+        };
+        CgroupUsage {
+            cpu_usage_usec: delta(self.cpu_usage_usec, baseline.cpu_usage_usec),
+            cpu_user_usec: delta(self.cpu_user_usec, baseline.cpu_user_usec),
+            cpu_system_usec: delta(self.cpu_system_usec, baseline.cpu_system_usec),
+            cpu_nr_periods: delta(self.cpu_nr_periods, baseline.cpu_nr_periods),
+            cpu_nr_throttled: delta(self.cpu_nr_throttled, baseline.cpu_nr_throttled),
+            cpu_throttled_usec: delta(self.cpu_throttled_usec, baseline.cpu_throttled_usec),
+            io_read_bytes: delta(self.io_read_bytes, baseline.io_read_bytes),
+            io_write_bytes: delta(self.io_write_bytes, baseline.io_write_bytes),
+            io_read_operations: delta(self.io_read_operations, baseline.io_read_operations),
+            io_write_operations: delta(self.io_write_operations, baseline.io_write_operations),
+            ..self.clone()
+        }
+    }
+}
+
+/// The parent pid in `/proc/<pid>/stat`, the field after the state. The
+/// command name before it is in parentheses and may hold spaces.
+fn read_parent_pid(proc_directory: &Path, pid: &str) -> Option<String> {
+    let stat = std::fs::read_to_string(proc_directory.join(pid).join("stat")).ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1).map(String::from)
+}
+
+/// The cgroup v2 path of `/proc/<pid>/cgroup`, the `0::` line
+pub fn parse_cgroup_path(cgroup: &str) -> Option<String> {
+    cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(String::from)
+}
+
+/// Parses files of `key value` lines like `memory.stat` and `cpu.stat`
+fn parse_flat_keyed(contents: &str) -> HashMap<&str, u64> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter_map(|(key, value)| Some((key, value.trim().parse().ok()?)))
+        .collect()
+}
+
+/// Parses `io.stat`, lines of `<major>:<minor> rbytes=1 wbytes=2 ...`, summed over devices
+fn parse_io_stat(contents: &str) -> HashMap<&str, u64> {
+    let mut totals = HashMap::new();
+    for field in contents
+        .lines()
+        .flat_map(|line| line.split_whitespace().skip(1))
+    {
+        if let Some((key, value)) = field.split_once('=') {
+            if let Ok(value) = value.parse::<u64>() {
+                *totals.entry(key).or_insert(0) += value;
+            }
+        }
+    }
+    totals
+}
+
+/// Reads the accounting of the cgroup at `path` of the hierarchy mounted at `cgroup_root`
+pub fn read_cgroup_usage(cgroup_root: &Path, path: &str) -> Option<CgroupUsage> {
+    let directory = cgroup_root.join(path.trim_start_matches('/'));
+    if !directory.is_dir() {
+        return None;
+    }
+    let read = |name| std::fs::read_to_string(directory.join(name)).ok();
+    let read_number = |name| read(name).and_then(|value| value.trim().parse().ok());
+
+    let memory_stat = read("memory.stat").unwrap_or_default();
+    let memory_stat = parse_flat_keyed(&memory_stat);
+    let cpu_stat = read("cpu.stat").unwrap_or_default();
+    let cpu_stat = parse_flat_keyed(&cpu_stat);
+    let io_stat = read("io.stat");
+    let io_stat = io_stat.as_deref().map(parse_io_stat);
+    let io = |key| {
+        io_stat
+            .as_ref()
+            .map(|io_stat| io_stat.get(key).copied().unwrap_or(0))
+    };
+
+    Some(CgroupUsage {
+        cgroup: path.to_string(),
+        memory_current: read_number("memory.current"),
+        memory_peak: read_number("memory.peak"),
+        memory_anon: memory_stat.get("anon").copied(),
+        memory_file: memory_stat.get("file").copied(),
+        cpu_usage_usec: cpu_stat.get("usage_usec").copied(),
+        cpu_user_usec: cpu_stat.get("user_usec").copied(),
+        cpu_system_usec: cpu_stat.get("system_usec").copied(),
+        cpu_nr_periods: cpu_stat.get("nr_periods").copied(),
+        cpu_nr_throttled: cpu_stat.get("nr_throttled").copied(),
+        cpu_throttled_usec: cpu_stat.get("throttled_usec").copied(),
+        io_read_bytes: io("rbytes"),
+        io_write_bytes: io("wbytes"),
+        io_read_operations: io("rios"),
+        io_write_operations: io("wios"),
+    })
+}
+
+struct TrackedCgroup {
+    path: String,
+    /// Reading taken when the tool started, the cgroup may be older than it
+    baseline: Option<CgroupUsage>,
+    last_usage: Option<CgroupUsage>,
+}
+
+/// Reads the cgroup accounting of tracked tools that run in a cgroup of their
+/// own, like containers, systemd scopes and SLURM steps. A cgroup is only
+/// accounted to a tool while it holds nothing but the tool and its children,
+/// the numbers would cover everything else running there otherwise.
+pub struct CgroupCollector {
+    proc_directory: PathBuf,
+    cgroup_root: PathBuf,
+    daemon_cgroup: Option<String>,
+    tracked: HashMap<Pid, TrackedCgroup>,
+}
+
+impl CgroupCollector {
+    pub fn new() -> Self {
+        let daemon_cgroup = std::fs::read_to_string(Path::new(PROC_DIRECTORY).join("self/cgroup"))
+            .ok()
+            .and_then(|cgroup| parse_cgroup_path(&cgroup));
+        CgroupCollector {
+            proc_directory: PathBuf::from(PROC_DIRECTORY),
+            cgroup_root: PathBuf::from(CGROUP_ROOT),
+            daemon_cgroup,
+            tracked: HashMap::new(),
+        }
+    }
+
+    fn is_own_cgroup(&self, path: &str) -> bool {
+        let last_component = path.rsplit('/').next().unwrap_or_default();
+        path != "/"
+            && self.daemon_cgroup.as_deref() != Some(path)
+            && !SHARED_CGROUP.is_match(last_component)
+    }
+
+    /// Processes in the cgroup, `None` when it's gone
+    fn read_cgroup_procs(&self, path: &str) -> Option<Vec<String>> {
+        let procs = self
+            .cgroup_root
+            .join(path.trim_start_matches('/'))
+            .join("cgroup.procs");
+        let procs = std::fs::read_to_string(procs).ok()?;
+        Some(procs.lines().map(String::from).collect())
+    }
+
+    fn is_in_tree(&self, pid: &str, root: &str) -> bool {
+        let mut current = pid.to_string();
+        for _ in 0..MAX_TREE_DEPTH {
+            if current == root {
+                return true;
+            }
+            match read_parent_pid(&self.proc_directory, &current) {
+                Some(parent) if parent != "0" && parent != current => current = parent,
+                _ => return false,
+            }
+        }
+        false
+    }
+
+    /// Whether the cgroup holds the tool and its children, and nothing else
+    fn holds_only_tree(&self, path: &str, pid: Pid) -> bool {
+        let root = pid.to_string();
+        self.read_cgroup_procs(path)
+            .is_some_and(|procs| procs.iter().all(|proc| self.is_in_tree(proc, &root)))
+    }
+
+    /// Starts accounting for a tool, if it runs in a cgroup of its own. A
+    /// cgroup is accounted to a single tool, its children aren't accounted again.
+    pub fn track(&mut self, pid: Pid) {
+        self.tracked.remove(&pid);
+        let path =
+            std::fs::read_to_string(self.proc_directory.join(pid.to_string()).join("cgroup"))
+                .ok()
+                .and_then(|cgroup| parse_cgroup_path(&cgroup))
+                .filter(|path| self.is_own_cgroup(path))
+                .filter(|path| !self.tracked.values().any(|tracked| tracked.path == *path))
+                .filter(|path| self.holds_only_tree(path, pid));
+        if let Some(path) = path {
+            let baseline = read_cgroup_usage(&self.cgroup_root, &path);
+            self.tracked.insert(
+                pid,
+                TrackedCgroup {
+                    path,
+                    baseline,
+                    last_usage: None,
+                },
+            );
+        }
+    }
+
+    fn read_delta(&self, tracked: &TrackedCgroup) -> Option<CgroupUsage> {
+        let usage = read_cgroup_usage(&self.cgroup_root, &tracked.path)?;
+        Some(match &tracked.baseline {
+            Some(baseline) => usage.since(baseline),
+            None => usage,
+        })
+    }
+
+    /// Accounting of the cgroup of a tracked tool since it started. The cgroup
+    /// is no longer accounted to the tool once other processes join it.
+    pub fn read_usage(&mut self, pid: Pid) -> Option<CgroupUsage> {
+        let tracked = self.tracked.get(&pid)?;
+        if !self.holds_only_tree(&tracked.path, pid) {
+            self.tracked.remove(&pid);
+            return None;
+        }
+        let usage = self.read_delta(tracked)?;
+        self.tracked.get_mut(&pid)?.last_usage = Some(usage.clone());
+        Some(usage)
+    }
+
+    /// Final accounting of the cgroup of a tool that exited. Once the tool is
+    /// gone its children can't be told apart, so the cgroup is only read again
+    /// when it's empty, and the last read is used otherwise.
+    pub fn finish(&mut self, pid: Pid) -> Option<CgroupUsage> {
+        let tracked = self.tracked.remove(&pid)?;
+        let is_empty = self
+            .read_cgroup_procs(&tracked.path)
+            .is_some_and(|procs| procs.is_empty());
+        is_empty
+            .then(|| self.read_delta(&tracked))
+            .flatten()
+            .or(tracked.last_usage)
+    }
+
+    pub fn clear(&mut self) {
+        self.tracked.clear();
+    }
+}
+
+impl Default for CgroupCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const STEP: &str = "/system.slice/slurmstepd.scope/job_42/step_0/user/task_0";
+
+    fn collector(root: &Path) -> CgroupCollector {
+        CgroupCollector {
+            proc_directory: root.join("proc"),
+            cgroup_root: root.join("cgroup"),
+            daemon_cgroup: Some("/system.slice/tracer.service".to_string()),
+            tracked: HashMap::new(),
+        }
+    }
+
+    fn write_process(root: &Path, pid: u32, parent_pid: u32, path: &str) {
+        let directory = root.join("proc").join(pid.to_string());
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("cgroup"), format!("0::{}\n", path)).unwrap();
+        fs::write(
+            directory.join("stat"),
+            format!("{} (my tool) S {} {} 0 0", pid, parent_pid, pid),
+        )
+        .unwrap();
+    }
+
+    fn write_cgroup_procs(root: &Path, path: &str, pids: &[u32]) {
+        let procs: String = pids.iter().map(|pid| format!("{}\n", pid)).collect();
+        write_cgroup(root, path, &[("cgroup.procs", &procs)]);
+    }
+
+    fn write_cgroup(root: &Path, path: &str, files: &[(&str, &str)]) {
+        let directory = root.join("cgroup").join(path.trim_start_matches('/'));
+        fs::create_dir_all(&directory).unwrap();
+        for (name, contents) in files {
+            fs::write(directory.join(name), contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_cgroup_usage() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        write_cgroup(
+            root.path(),
+            STEP,
+            &[
+                ("memory.current", "1048576\n"),
+                ("memory.peak", "8388608\n"),
+                ("memory.stat", "anon 524288\nfile 262144\nkernel 4096\n"),
+                (
+                    "cpu.stat",
+                    "usage_usec 2500000\nuser_usec 2000000\nsystem_usec 500000\nnr_periods 40\nnr_throttled 3\nthrottled_usec 120000\n",
+                ),
+                (
+                    "io.stat",
+                    "8:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n259:0 rbytes=1000 wbytes=0 rios=5 wios=0 dbytes=0 dios=0\n",
+                ),
+            ],
+        );
+
+        assert_eq!(
+            read_cgroup_usage(&root.path().join("cgroup"), STEP),
+            Some(CgroupUsage {
+                cgroup: STEP.to_string(),
+                memory_current: Some(1048576),
+                memory_peak: Some(8388608),
+                memory_anon: Some(524288),
+                memory_file: Some(262144),
+                cpu_usage_usec: Some(2500000),
+                cpu_user_usec: Some(2000000),
+                cpu_system_usec: Some(500000),
+                cpu_nr_periods: Some(40),
+                cpu_nr_throttled: Some(3),
+                cpu_throttled_usec: Some(120000),
+                io_read_bytes: Some(5096),
+                io_write_bytes: Some(8192),
+                io_read_operations: Some(6),
+                io_write_operations: Some(2),
+            })
+        );
+
+        // Older kernels have no memory.peak, and io.stat needs the io controller
+        write_cgroup(
+            root.path(),
+            "/docker-1.scope",
+            &[("memory.current", "10\n")],
+        );
+        let usage = read_cgroup_usage(&root.path().join("cgroup"), "/docker-1.scope").unwrap();
+        assert_eq!(usage.memory_current, Some(10));
+        assert_eq!(usage.memory_peak, None);
+        assert_eq!(usage.io_read_bytes, None);
+
+        assert_eq!(
+            read_cgroup_usage(&root.path().join("cgroup"), "/gone.scope"),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_collector_only_accounts_for_own_cgroups() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let session = "/user.slice/user-1000.slice/session-3.scope";
+        write_process(root.path(), 100, 1, STEP);
+        write_process(root.path(), 200, 1, session);
+        write_process(root.path(), 300, 1, "/system.slice/tracer.service");
+        write_cgroup(root.path(), STEP, &[("memory.peak", "100\n")]);
+        write_cgroup_procs(root.path(), STEP, &[100]);
+        write_cgroup(root.path(), session, &[("memory.peak", "100\n")]);
+        write_cgroup_procs(root.path(), session, &[200]);
+
+        let mut collector = collector(root.path());
+        for pid in [100, 200, 300, 400] {
+            collector.track(Pid::from(pid));
+        }
+
+        assert_eq!(
+            collector
+                .read_usage(Pid::from(100))
+                .and_then(|usage| usage.memory_peak),
+            Some(100)
+        );
+        assert_eq!(collector.read_usage(Pid::from(200)), None);
+        assert_eq!(collector.read_usage(Pid::from(300)), None);
+        assert_eq!(collector.read_usage(Pid::from(400)), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cgroups_shared_with_other_processes_are_not_accounted() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let container = "/system.slice/docker-1.scope";
+        // 100 runs 101, 200 runs next to them in the same cgroup
+        write_process(root.path(), 100, 1, container);
+        write_process(root.path(), 101, 100, container);
+        write_process(root.path(), 200, 1, container);
+        write_cgroup(root.path(), container, &[("cpu.stat", "usage_usec 10\n")]);
+        write_cgroup_procs(root.path(), container, &[100, 101]);
+
+        let mut collector = collector(root.path());
+        collector.track(Pid::from(100));
+        // Its child is covered by the cgroup already accounted to 100
+        collector.track(Pid::from(101));
+        assert!(collector.read_usage(Pid::from(100)).is_some());
+        assert_eq!(collector.read_usage(Pid::from(101)), None);
+
+        write_cgroup_procs(root.path(), container, &[100, 101, 200]);
+        assert_eq!(collector.read_usage(Pid::from(100)), None);
+        collector.track(Pid::from(200));
+        assert_eq!(collector.read_usage(Pid::from(200)), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_usage_is_counted_from_when_the_tool_started() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        write_process(root.path(), 100, 1, STEP);
+        write_cgroup_procs(root.path(), STEP, &[100]);
+        // An earlier tool of the same step already used the CPU for 5 seconds
+        write_cgroup(
+            root.path(),
+            STEP,
+            &[
+                ("cpu.stat", "usage_usec 5000000\n"),
+                ("memory.current", "2048\n"),
+            ],
+        );
+
+        let mut collector = collector(root.path());
+        collector.track(Pid::from(100));
+        write_cgroup(root.path(), STEP, &[("cpu.stat", "usage_usec 7500000\n")]);
+
+        let usage = collector.read_usage(Pid::from(100)).unwrap();
+        assert_eq!(usage.cpu_usage_usec, Some(2500000));
+        assert_eq!(usage.memory_current, Some(2048));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_finish_falls_back_to_the_last_read() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        write_process(root.path(), 100, 1, STEP);
+        write_cgroup(root.path(), STEP, &[("memory.peak", "100\n")]);
+        write_cgroup_procs(root.path(), STEP, &[100]);
+
+        let mut collector = collector(root.path());
+        collector.track(Pid::from(100));
+        write_cgroup(root.path(), STEP, &[("memory.peak", "250\n")]);
+        collector.read_usage(Pid::from(100));
+
+        // The step's cgroup is removed once its last process exits
+        fs::remove_dir_all(root.path().join("cgroup"))?;
+
+        assert_eq!(
+            collector
+                .finish(Pid::from(100))
+                .and_then(|usage| usage.memory_peak),
+            Some(250)
+        );
+        assert_eq!(collector.finish(Pid::from(100)), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+// src/checksum/mod.rs
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileChecksum {
+    pub sha256: String,
+    pub md5: Option<String>,
+}
+
+/// Identity of a file's content: if none of these changed, the digest is still valid
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ChecksumKey {
+    pub path: String,
+    pub size: u64,
+    pub last_update: DateTime<Utc>,
+    pub inode: u64,
+}
+
+impl ChecksumKey {
+    #[allow(dead_code)]
+    pub fn from_path(path: &str) -> Result<ChecksumKey> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(ChecksumKey {
+            path: path.to_string(),
+            size: metadata.len(),
+            last_update: metadata.modified()?.into(),
+            inode: metadata.ino(),
+        })
+    }
+}
+
+#[derive(Default)]
+struct ChecksumCache {
+    entries: HashMap<ChecksumKey, FileChecksum>,
+    pending: HashSet<ChecksumKey>,
+    completed: Vec<(ChecksumKey, FileChecksum)>,
+}
+
+pub fn compute_file_checksum(path: &str, compute_md5: bool) -> Result<FileChecksum> {
+    let mut file = File::open(Path::new(path))?;
+    let mut sha256 = Sha256::new();
+    let mut md5 = compute_md5.then(Md5::new);
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        sha256.update(&buffer[..read]);
+        if let Some(md5) = md5.as_mut() {
+            md5.update(&buffer[..read]);
+        }
+    }
+
+    Ok(FileChecksum {
+        sha256: format!("{:x}", sha256.finalize()),
+        md5: md5.map(|md5| format!("{:x}", md5.finalize())),
+    })
+}
+
+/// Hashes files on a background thread and caches the digests by
+/// (path, size, mtime, inode), so unchanged files are only read once.
+pub struct FileHasher {
+    cache: Arc<RwLock<ChecksumCache>>,
+    queue: Sender<ChecksumKey>,
+}
+
+impl FileHasher {
+    pub fn new(compute_md5: bool) -> FileHasher {
+        let cache: Arc<RwLock<ChecksumCache>> = Arc::new(RwLock::new(ChecksumCache::default()));
+        let (queue, receiver) = channel::<ChecksumKey>();
+
+        let thread_cache = cache.clone();
+        thread::spawn(move || {
+            // Ends once the hasher, and with it the sending half, is dropped
+            while let Ok(key) = receiver.recv() {
+                let result = compute_file_checksum(&key.path, compute_md5);
+                let mut cache = thread_cache.write().unwrap();
+                cache.pending.remove(&key);
+                match result {
+                    Ok(checksum) => {
+                        cache.completed.push((key.clone(), checksum.clone()));
+                        cache.entries.insert(key, checksum);
+                    }
+                    Err(error) => {
+                        eprintln!(
+                            "[{}] Failed to compute checksum of {}: {}",
+                            Utc::now(),
+                            key.path,
+                            error
+                        );
+                    }
+                }
+            }
+        });
+
+        FileHasher { cache, queue }
+    }
+
+    /// Queues the file for hashing unless a digest for its current content is
+    /// already cached or being computed.
+    pub fn request(&self, key: ChecksumKey) {
+        let mut cache = self.cache.write().unwrap();
+        if cache.entries.contains_key(&key) || cache.pending.contains(&key) {
+            return;
+        }
+        if self.queue.send(key.clone()).is_ok() {
+            cache.pending.insert(key);
+        }
+    }
+
+    pub fn get(&self, key: &ChecksumKey) -> Option<FileChecksum> {
+        self.cache.read().unwrap().entries.get(key).cloned()
+    }
+
+    /// Forgets the digests of the files `keep` returns false for
+    pub fn retain(&self, keep: impl Fn(&ChecksumKey) -> bool) {
+        self.cache
+            .write()
+            .unwrap()
+            .entries
+            .retain(|key, _| keep(key));
+    }
+
+    /// Digests computed since the last call
+    pub fn take_completed(&self) -> Vec<(ChecksumKey, FileChecksum)> {
+        std::mem::take(&mut self.cache.write().unwrap().completed)
+    }
+
+    #[allow(dead_code)]
+    pub fn is_idle(&self) -> bool {
+        self.cache.read().unwrap().pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::time::Duration;
+
+    fn wait_until_idle(hasher: &FileHasher) {
+        for _ in 0..500 {
+            if hasher.is_idle() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Hashing did not finish in time");
+    }
+
+    #[test]
+    fn test_compute_file_checksum() -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(b"hello world")?;
+
+        let checksum = compute_file_checksum(file.path().to_str().unwrap(), true)?;
+
+        assert_eq!(
+            checksum.sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        assert_eq!(
+            checksum.md5,
+            Some("5eb63bbbe01eeed093cb22bb8f5acdc3".to_string())
+        );
+
+        let checksum = compute_file_checksum(file.path().to_str().unwrap(), false)?;
+        assert_eq!(checksum.md5, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_hasher_caches_by_file_identity() -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(b"ACGT")?;
+        let path = file.path().to_str().unwrap().to_string();
+
+        let hasher = FileHasher::new(false);
+        let key = ChecksumKey::from_path(&path)?;
+
+        hasher.request(key.clone());
+        wait_until_idle(&hasher);
+
+        assert!(hasher.get(&key).is_some());
+        assert_eq!(hasher.take_completed().len(), 1);
+
+        // Same identity, nothing new to hash
+        hasher.request(key.clone());
+        wait_until_idle(&hasher);
+        assert!(hasher.take_completed().is_empty());
+
+        // A different size is a different identity
+        file.write_all(b"ACGT")?;
+        let changed_key = ChecksumKey::from_path(&path)?;
+        assert_ne!(key, changed_key);
+        assert!(hasher.get(&changed_key).is_none());
+
+        hasher.retain(|cached_key| *cached_key == changed_key);
+        assert!(hasher.get(&key).is_none());
+
+        Ok(())
+    }
+}
@@ -2,19 +2,23 @@
 use crate::{
     config_manager::ConfigManager,
     daemon_communication::client::{
-        send_alert_request, send_end_run_request, send_log_request,
-        send_log_short_lived_process_request, send_start_run_request, send_terminate_request,
-        send_update_tags_request, send_upload_file_request,
+        send_alert_request, send_end_run_request, send_lineage_request, send_log_request,
+        send_log_short_lived_process_request, send_report_request, send_start_run_request,
+        send_terminate_request, send_timeline_request, send_update_tags_request,
+        send_upload_file_request, send_uploads_request,
     },
     process_watcher::ProcessWatcher,
-    run, start_daemon, SOCKET_PATH,
+    run,
+    runtime_paths::{current_uid, get_socket_path},
+    start_daemon,
 };
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use nondaemon_commands::{
-    clean_up_after_daemon, print_config_info_sync, setup_config, update_tracer,
+    clean_up_after_daemon, print_config_info_sync, print_uploads, setup_config, update_tracer,
 };
 
+use std::io::Write;
 use std::{env, fs::canonicalize};
 use sysinfo::System;
 mod nondaemon_commands;
@@ -55,25 +59,45 @@ pub enum Commands {
     Alert { message: String },
 
     /// Start the daemon
-    Init,
+    Init {
+        /// Run a single daemon for all users of the machine, requires root
+        #[clap(long)]
+        system: bool,
+    },
 
     /// Stop the daemon
     Terminate,
 
     /// Remove all the temporary files created by the daemon, in a case of the process being terminated unexpectedly
-    Cleanup,
+    Cleanup {
+        /// Clean up after the system-wide daemon
+        #[clap(long)]
+        system: bool,
+    },
 
-    /// Shows the current configuration and the daemon status
-    Info,
+    /// Shows the current configuration, the daemon status and the active runs
+    Info {
+        /// Only show this run
+        #[clap(long)]
+        run: Option<String>,
+    },
 
     /// Update the daemon to the latest version
     Update,
 
-    /// Start a new pipeline run
-    Start,
+    /// Start a new pipeline run, next to the ones already active
+    Start {
+        /// End this run and start a new one in its place
+        #[clap(long)]
+        run: Option<String>,
+    },
 
     /// End the current pipeline run
-    End,
+    End {
+        /// Run to end, by default the one this shell belongs to or $TRACER_RUN_ID
+        #[clap(long)]
+        run: Option<String>,
+    },
 
     /// Test the configuration by sending a request to the service
     Test,
@@ -85,7 +109,12 @@ pub enum Commands {
     UploadDaemon,
 
     /// Change the tags of the current pipeline run
-    Tag { tags: Vec<String> },
+    Tag {
+        tags: Vec<String>,
+        /// Run to tag, by default the one this shell belongs to or $TRACER_RUN_ID
+        #[clap(long)]
+        run: Option<String>,
+    },
 
     /// Configure .bashrc file to include aliases for short-lived processes commands. To use them, a new terminal session must be started.
     ApplyBashrc,
@@ -95,16 +124,55 @@ pub enum Commands {
 
     /// Shows the current version of the daemon
     Version,
+
+    /// Export the file lineage graph of the current pipeline run, or of the last one ended if no run is active
+    Lineage {
+        /// Run to export, by default the one this shell belongs to or $TRACER_RUN_ID
+        #[clap(long)]
+        run: Option<String>,
+        /// Output format of the graph
+        #[clap(long, short, default_value = "dot", value_parser = ["dot", "json"])]
+        format: String,
+    },
+
+    /// Print a report of the current pipeline run, or of the last one ended if no run is active
+    Report {
+        /// Run to report on, by default the one this shell belongs to or $TRACER_RUN_ID
+        #[clap(long)]
+        run: Option<String>,
+        /// Output format of the report
+        #[clap(long, short, default_value = "md", value_parser = ["md", "html", "json"])]
+        format: String,
+    },
+
+    /// Export the tool executions of the current pipeline run, or of the last one ended if no
+    /// run is active, as a Chrome trace that Perfetto and chrome://tracing open
+    Timeline {
+        /// Run to export, by default the one this shell belongs to or $TRACER_RUN_ID
+        #[clap(long)]
+        run: Option<String>,
+        /// Put the tools of each process tree or of each workflow engine task on a track
+        #[clap(long, default_value = "process", value_parser = ["process", "task"])]
+        group_by: String,
+    },
+
+    /// List the queued, running, done and failed file uploads
+    Uploads,
 }
 
 pub fn process_cli() -> Result<()> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Init => {
+        Commands::Init { system } => {
+            if *system && current_uid() != 0 {
+                return Err(anyhow::anyhow!(
+                    "The system-wide daemon must be started by root"
+                ));
+            }
             let test_result = ConfigManager::test_service_config_sync();
             if test_result.is_err() {
-                print_config_info_sync()?;
+                print_config_info_sync(None)?;
                 return Ok(());
             }
             println!("Starting daemon...");
@@ -114,8 +182,11 @@ pub fn process_cli() -> Result<()> {
                 println!("Failed to start daemon. Maybe the daemon is already running? If it's not, run `tracer cleanup` to clean up the previous daemon files.");
                 return Ok(());
             }
-            run(current_working_directory.to_str().unwrap().to_string())?;
-            clean_up_after_daemon()
+            run(
+                current_working_directory.to_str().unwrap().to_string(),
+                *system,
+            )?;
+            clean_up_after_daemon(*system)
         }
         Commands::Test => {
             let result = ConfigManager::test_service_config_sync();
@@ -124,58 +195,106 @@ pub fn process_cli() -> Result<()> {
             }
             Ok(())
         }
-        Commands::Cleanup => {
-            let result = clean_up_after_daemon();
+        Commands::Cleanup { system } => {
+            let result = clean_up_after_daemon(*system);
             if result.is_ok() {
                 println!("Daemon files cleaned up successfully.");
             }
             result
         }
         Commands::ApplyBashrc => ConfigManager::setup_aliases(),
-        Commands::Info => print_config_info_sync(),
+        Commands::Info { run } => print_config_info_sync(run.clone()),
         _ => run_async_command(cli.command),
     }
 }
 
 #[tokio::main]
 pub async fn run_async_command(commands: Commands) -> Result<()> {
+    run_command(commands, get_socket_path(), &mut std::io::stdout()).await
+}
+
+fn command_error(error: anyhow::Error) -> anyhow::Error {
+    // Couldn't talk to the daemon at all
+    if error.downcast_ref::<std::io::Error>().is_some() {
+        return error.context(
+            "Failed to send command to the daemon. Maybe the daemon is not running? If it's not, run `tracer init` to start the daemon.",
+        );
+    }
+    error.context("The daemon failed to process the command")
+}
+
+/// Runs a command through the daemon listening on `socket_path`. Commands
+/// whose output is meant to be redirected write nothing else to `out`.
+async fn run_command(commands: Commands, socket_path: &str, out: &mut impl Write) -> Result<()> {
     let result = match commands {
-        Commands::Log { message } => send_log_request(SOCKET_PATH, message).await,
-        Commands::Alert { message } => send_alert_request(SOCKET_PATH, message).await,
-        Commands::Terminate => send_terminate_request(SOCKET_PATH).await,
-        Commands::Start => send_start_run_request(SOCKET_PATH).await,
-        Commands::End => send_end_run_request(SOCKET_PATH).await,
-        Commands::Update => update_tracer().await,
-        Commands::Tag { tags } => send_update_tags_request(SOCKET_PATH, &tags).await,
+        Commands::Log { message } => send_log_request(socket_path, message).await,
+        Commands::Alert { message } => send_alert_request(socket_path, message).await,
+        Commands::Terminate => send_terminate_request(socket_path).await,
+        Commands::Start { run } => send_start_run_request(socket_path, run).await,
+        Commands::End { run } => send_end_run_request(socket_path, run).await,
+        Commands::Update => return update_tracer().await,
+        Commands::Tag { tags, run } => send_update_tags_request(socket_path, &tags, run).await,
+        Commands::Lineage { run, format } => {
+            let lineage = send_lineage_request(socket_path, run, &format)
+                .await
+                .map_err(command_error)?;
+            writeln!(out, "{}", lineage)?;
+            return Ok(());
+        }
+        Commands::Report { run, format } => {
+            let report = send_report_request(socket_path, run, &format)
+                .await
+                .map_err(command_error)?;
+            writeln!(out, "{}", report)?;
+            return Ok(());
+        }
+        Commands::Timeline { run, group_by } => {
+            let trace = send_timeline_request(socket_path, run, &group_by)
+                .await
+                .map_err(command_error)?;
+            writeln!(out, "{}", trace)?;
+            return Ok(());
+        }
+        Commands::Uploads => {
+            let uploads = send_uploads_request(socket_path).await;
+            if let Ok(uploads) = &uploads {
+                print_uploads(uploads);
+            }
+            uploads.map(|_| ())
+        }
         Commands::Setup {
             api_key,
             service_url,
             process_polling_interval_ms,
             batch_submission_interval_ms,
         } => {
-            setup_config(
+            return setup_config(
                 &api_key,
                 &service_url,
                 &process_polling_interval_ms,
                 &batch_submission_interval_ms,
             )
-            .await
+            .await;
         }
         Commands::LogShortLivedProcess { command } => {
             let data = ProcessWatcher::gather_short_lived_process_data(&System::new(), &command);
-            send_log_short_lived_process_request(SOCKET_PATH, data).await
+            send_log_short_lived_process_request(socket_path, data).await
         }
         Commands::Upload { file_path } => {
-            let path = canonicalize(&file_path);
-            if let Err(e) = path {
-                println!(
-                    "Failed to find the file. Please provide the full path to the file. Error: {}",
-                    e
-                );
-                return Ok(());
-            }
+            let path = canonicalize(&file_path).with_context(|| {
+                format!(
+                    "Failed to find the file {}. Please provide the full path to the file.",
+                    file_path
+                )
+            })?;
 
-            send_upload_file_request(SOCKET_PATH, &path.unwrap()).await
+            let upload_id = send_upload_file_request(socket_path, &path).await;
+            match &upload_id {
+                Ok(Some(upload_id)) => println!("File uploaded (upload {}).", upload_id),
+                Ok(None) => println!("The same file content was already uploaded."),
+                Err(_) => {}
+            }
+            upload_id.map(|_| ())
         }
         _ => {
             println!("Command not implemented yet");
@@ -183,11 +302,104 @@ pub async fn run_async_command(commands: Commands) -> Result<()> {
         }
     };
 
-    if result.is_err() {
-        println!("Failed to send command to the daemon. Maybe the daemon is not running? If it's not, run `tracer init` to start the daemon.");
-    } else {
-        println!("Command sent successfully.")
+    result.map_err(command_error)?;
+    writeln!(out, "Command completed successfully.")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon_communication::client::tests::respond_to_request;
+    use crate::daemon_communication::protocol::PROTOCOL_VERSION;
+    use crate::daemon_communication::structs::{
+        LineageResponse, ReportResponse, Response, TimelineResponse,
+    };
+    use serial_test::serial;
+    use tokio::net::UnixListener;
+
+    const SOCKET_PATH: &str = "/tmp/tracerd-cli-test.sock";
+
+    fn daemon_answering(response: Response) {
+        let _ = std::fs::remove_file(SOCKET_PATH);
+        let listener = UnixListener::bind(SOCKET_PATH).unwrap();
+        respond_to_request(listener, PROTOCOL_VERSION, response);
     }
 
-    Ok(())
+    #[tokio::test]
+    #[serial]
+    async fn test_timeline_prints_only_the_trace() -> Result<()> {
+        let trace = r#"{"traceEvents":[]}"#;
+        daemon_answering(Response::Timeline(TimelineResponse {
+            group_by: "process".to_string(),
+            trace: trace.to_string(),
+        }));
+
+        let mut out = vec![];
+        run_command(
+            Commands::Timeline {
+                run: None,
+                group_by: "process".to_string(),
+            },
+            SOCKET_PATH,
+            &mut out,
+        )
+        .await?;
+
+        assert_eq!(String::from_utf8(out)?, format!("{}\n", trace));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_lineage_prints_only_the_graph() -> Result<()> {
+        let lineage = "digraph lineage {\n    rankdir=LR;\n}\n";
+        daemon_answering(Response::Lineage(LineageResponse {
+            format: "dot".to_string(),
+            lineage: lineage.to_string(),
+        }));
+
+        let mut out = vec![];
+        run_command(
+            Commands::Lineage {
+                run: None,
+                format: "dot".to_string(),
+            },
+            SOCKET_PATH,
+            &mut out,
+        )
+        .await?;
+
+        assert_eq!(String::from_utf8(out)?, format!("{}\n", lineage));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_report_prints_only_the_report() -> Result<()> {
+        let report = r#"{"run_id":"run-1"}"#;
+        daemon_answering(Response::Report(ReportResponse {
+            format: "json".to_string(),
+            report: report.to_string(),
+        }));
+
+        let mut out = vec![];
+        run_command(
+            Commands::Report {
+                run: None,
+                format: "json".to_string(),
+            },
+            SOCKET_PATH,
+            &mut out,
+        )
+        .await?;
+
+        let out = String::from_utf8(out)?;
+        assert_eq!(out, format!("{}\n", report));
+        assert!(serde_json::from_str::<serde_json::Value>(&out).is_ok());
+
+        Ok(())
+    }
 }
@@ -4,21 +4,25 @@ use anyhow::{Context, Result};
 use std::result::Result::Ok;
 
 use crate::{
-    config_manager::{ConfigManager, INTERCEPTOR_STDOUT_FILE},
+    config_manager::ConfigManager,
     daemon_communication::client::{send_info_request, send_refresh_config_request},
-    FILE_CACHE_DIR, PID_FILE, REPO_NAME, REPO_OWNER, SOCKET_PATH, STDERR_FILE, STDOUT_FILE,
+    runtime_paths::{get_cache_dir, get_socket_path},
+    upload::upload_queue::{prepare_cache_directory, UploadEntry, UploadStatus},
+    INTERCEPTOR_STDOUT_FILE, PID_FILE, REPO_NAME, REPO_OWNER, STDERR_FILE, STDOUT_FILE,
 };
 
-pub fn clean_up_after_daemon() -> Result<()> {
-    std::fs::remove_file(PID_FILE).context("Failed to remove pid file")?;
-    std::fs::remove_file(STDOUT_FILE).context("Failed to remove stdout file")?;
-    std::fs::remove_file(STDERR_FILE).context("Failed to remove stderr file")?;
-    let _ = std::fs::remove_file(INTERCEPTOR_STDOUT_FILE).context("Failed to remove stdout file");
-    std::fs::remove_dir_all(FILE_CACHE_DIR).context("Failed to remove cache directory")?;
+pub fn clean_up_after_daemon(system_mode: bool) -> Result<()> {
+    std::fs::remove_file(&*PID_FILE).context("Failed to remove pid file")?;
+    std::fs::remove_file(&*STDOUT_FILE).context("Failed to remove stdout file")?;
+    std::fs::remove_file(&*STDERR_FILE).context("Failed to remove stderr file")?;
+    let _ = std::fs::remove_file(&*INTERCEPTOR_STDOUT_FILE).context("Failed to remove stdout file");
+    // Pending uploads are kept for the next daemon to resume
+    prepare_cache_directory(get_cache_dir(system_mode).to_str().unwrap())
+        .context("Failed to clean cache directory")?;
     Ok(())
 }
 
-pub async fn print_config_info() -> Result<()> {
+pub async fn print_config_info(run_id: Option<String>) -> Result<()> {
     let config = ConfigManager::load_config();
     println!("Service URL: {}", config.service_url);
     println!("API Key: {}", config.api_key);
@@ -31,26 +35,96 @@ pub async fn print_config_info() -> Result<()> {
         config.batch_submission_interval_ms
     );
     println!("Daemon version: {}", env!("CARGO_PKG_VERSION"));
-    let daemon_status = send_info_request(SOCKET_PATH).await;
-    if let Ok(info) = daemon_status {
-        if !info.run_name.is_empty() {
-            println!("Run name: {}", info.run_name);
-            println!("Run ID: {}", info.run_id);
-            println!("Service name: {}", info.service_name);
+    match send_info_request(get_socket_path(), run_id).await {
+        Ok(runs) => {
+            for info in runs {
+                println!("Run name: {}", info.run_name);
+                println!("Run ID: {}", info.run_id);
+                println!("Service name: {}", info.service_name);
+                if let Some(pipeline) = info.pipeline {
+                    println!(
+                        "Pipeline: {} ({}{}{})",
+                        pipeline.pipeline.as_deref().unwrap_or("unknown"),
+                        pipeline.engine.as_str(),
+                        pipeline
+                            .revision
+                            .map(|revision| format!(", revision {}", revision))
+                            .unwrap_or_default(),
+                        pipeline
+                            .profile
+                            .map(|profile| format!(", profile {}", profile))
+                            .unwrap_or_default(),
+                    );
+                }
+                if let Some(cost) = info.estimated_cost {
+                    println!(
+                        "Estimated cost: ${:.2} (${:.2} by tools, {} at ${:.3}/h)",
+                        cost.instance_cost,
+                        cost.tools_cost,
+                        cost.instance_type.as_deref().unwrap_or("this machine"),
+                        cost.hourly_price,
+                    );
+                }
+            }
+            println!("Daemon status: Running");
+        }
+        Err(error) if error.downcast_ref::<std::io::Error>().is_some() => {
+            println!("Daemon status: Stopped");
+        }
+        Err(error) => {
+            println!("Daemon status: Running");
+            println!("Failed to get the run info: {}", error);
         }
-        println!("Daemon status: Running");
-    } else {
-        println!("Daemon status: Stopped");
     }
     Ok(())
 }
 
-pub fn print_config_info_sync() -> Result<()> {
+pub fn print_config_info_sync(run_id: Option<String>) -> Result<()> {
     let runtime = tokio::runtime::Runtime::new().unwrap();
-    runtime.block_on(print_config_info())?;
+    runtime.block_on(print_config_info(run_id))?;
     Ok(())
 }
 
+pub fn print_uploads(uploads: &[UploadEntry]) {
+    if uploads.is_empty() {
+        println!("No uploads.");
+        return;
+    }
+
+    for status in [
+        UploadStatus::Running,
+        UploadStatus::Queued,
+        UploadStatus::Failed,
+        UploadStatus::Done,
+    ] {
+        let entries: Vec<&UploadEntry> = uploads
+            .iter()
+            .filter(|upload| upload.status == status)
+            .collect();
+        if entries.is_empty() {
+            continue;
+        }
+
+        println!("{} ({}):", status.as_str(), entries.len());
+        for upload in entries {
+            let mut line = format!(
+                "  {}  {}  attempts: {}",
+                upload.id, upload.source_path, upload.attempts
+            );
+            if status == UploadStatus::Queued && upload.attempts > 0 {
+                line.push_str(&format!(", next attempt: {}", upload.next_attempt_at));
+            }
+            if let Some(duplicate_of) = &upload.duplicate_of {
+                line.push_str(&format!(", same content as {}", duplicate_of));
+            }
+            if let Some(error) = &upload.last_error {
+                line.push_str(&format!(", last error: {}", error));
+            }
+            println!("{}", line);
+        }
+    }
+}
+
 pub async fn setup_config(
     api_key: &Option<String>,
     service_url: &Option<String>,
@@ -64,8 +138,8 @@ pub async fn setup_config(
         batch_submission_interval_ms,
     )?;
 
-    let _ = send_refresh_config_request(SOCKET_PATH).await;
-    print_config_info().await?;
+    let _ = send_refresh_config_request(get_socket_path()).await;
+    print_config_info(None).await?;
     Ok(())
 }
 
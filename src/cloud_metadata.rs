@@ -0,0 +1,376 @@
+// src/cloud_metadata.rs
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+
+const AWS_METADATA_URL: &str = "http://169.254.169.254";
+const GCP_METADATA_URL: &str = "http://metadata.google.internal";
+const AZURE_METADATA_URL: &str = "http://169.254.169.254";
+const AZURE_API_VERSION: &str = "2021-02-01";
+/// Metadata services answer in milliseconds, off the cloud nothing answers at all
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const AWS_TOKEN_TTL_SECONDS: &str = "60";
+
+lazy_static! {
+    static ref INSTANCE_METADATA: OnceCell<Option<InstanceMetadata>> = OnceCell::new();
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloudProvider {
+    Aws,
+    Gcp,
+    Azure,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InstanceMetadata {
+    pub provider: CloudProvider,
+    pub instance_id: Option<String>,
+    pub instance_type: Option<String>,
+    pub region: Option<String>,
+    pub availability_zone: Option<String>,
+    pub image_id: Option<String>,
+    /// Spot on AWS and Azure, spot or preemptible on GCP
+    pub spot: Option<bool>,
+}
+
+/// Where each cloud's metadata service is found, overridden in tests
+pub struct MetadataEndpoints {
+    pub aws: String,
+    pub gcp: String,
+    pub azure: String,
+}
+
+impl Default for MetadataEndpoints {
+    fn default() -> Self {
+        MetadataEndpoints {
+            aws: AWS_METADATA_URL.to_string(),
+            gcp: GCP_METADATA_URL.to_string(),
+            azure: AZURE_METADATA_URL.to_string(),
+        }
+    }
+}
+
+fn string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) if !value.is_empty() => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Last component of the resource paths GCP answers with, like
+/// `projects/123/zones/europe-west4-a`
+fn last_component(value: &Value) -> Option<String> {
+    string(value).map(|value| value.rsplit('/').next().unwrap_or_default().to_string())
+}
+
+async fn get_text(request: RequestBuilder) -> Result<String> {
+    let response = request.timeout(PROBE_TIMEOUT).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("Metadata service answered {}", status);
+    }
+    Ok(response.text().await?)
+}
+
+/// IMDSv2: a session token first, then the instance identity document.
+/// Instances that still allow IMDSv1 answer without the token too.
+async fn probe_aws(client: &Client, base_url: &str) -> Result<InstanceMetadata> {
+    let token_response = client
+        .put(format!("{}/latest/api/token", base_url))
+        .header(
+            "X-aws-ec2-metadata-token-ttl-seconds",
+            AWS_TOKEN_TTL_SECONDS,
+        )
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await?;
+    let token = if token_response.status().is_success() {
+        Some(token_response.text().await?)
+    } else {
+        None
+    };
+    let get = |path: &str| {
+        let request = client.get(format!("{}/latest/{}", base_url, path));
+        match &token {
+            Some(token) => request.header("X-aws-ec2-metadata-token", token),
+            None => request,
+        }
+    };
+
+    let document = get_text(get("dynamic/instance-identity/document")).await?;
+    let document: Value = serde_json::from_str(&document)
+        .context("Failed to parse the instance identity document")?;
+    let life_cycle = get_text(get("meta-data/instance-life-cycle")).await.ok();
+
+    Ok(InstanceMetadata {
+        provider: CloudProvider::Aws,
+        instance_id: string(&document["instanceId"]),
+        instance_type: string(&document["instanceType"]),
+        region: string(&document["region"]),
+        availability_zone: string(&document["availabilityZone"]),
+        image_id: string(&document["imageId"]),
+        spot: life_cycle.map(|life_cycle| life_cycle.trim() == "spot"),
+    })
+}
+
+async fn probe_gcp(client: &Client, base_url: &str) -> Result<InstanceMetadata> {
+    let response = client
+        .get(format!(
+            "{}/computeMetadata/v1/instance/?recursive=true",
+            base_url
+        ))
+        .header("Metadata-Flavor", "Google")
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await?;
+    // Anything else listening on the address doesn't flavor its answers
+    let flavor = response.headers().get("Metadata-Flavor");
+    if !response.status().is_success() || flavor.is_none_or(|flavor| flavor != "Google") {
+        bail!("Not a GCP metadata service");
+    }
+    let instance: Value = response.json().await?;
+
+    let availability_zone = last_component(&instance["zone"]);
+    let scheduling = &instance["scheduling"];
+    let spot = scheduling["provisioningModel"].as_str() == Some("SPOT")
+        || scheduling["preemptible"].as_str() == Some("TRUE");
+
+    Ok(InstanceMetadata {
+        provider: CloudProvider::Gcp,
+        instance_id: string(&instance["id"]),
+        instance_type: last_component(&instance["machineType"]),
+        // Zones are their region with a suffix, like `europe-west4-a`
+        region: availability_zone
+            .as_deref()
+            .and_then(|zone| zone.rsplit_once('-'))
+            .map(|(region, _)| region.to_string()),
+        availability_zone,
+        image_id: last_component(&instance["image"]),
+        spot: Some(spot),
+    })
+}
+
+async fn probe_azure(client: &Client, base_url: &str) -> Result<InstanceMetadata> {
+    let instance = get_text(
+        client
+            .get(format!("{}/metadata/instance", base_url))
+            .query(&[("api-version", AZURE_API_VERSION)])
+            .header("Metadata", "true"),
+    )
+    .await?;
+    let instance: Value = serde_json::from_str(&instance)?;
+    let compute = &instance["compute"];
+    if !compute.is_object() {
+        bail!("Not an Azure metadata service");
+    }
+
+    let image = &compute["storageProfile"]["imageReference"];
+    let image_id = string(&image["id"]).or_else(|| {
+        let parts: Option<Vec<String>> = ["publisher", "offer", "sku", "version"]
+            .iter()
+            .map(|part| string(&image[part]))
+            .collect();
+        parts.map(|parts| parts.join(":"))
+    });
+
+    Ok(InstanceMetadata {
+        provider: CloudProvider::Azure,
+        instance_id: string(&compute["vmId"]),
+        instance_type: string(&compute["vmSize"]),
+        region: string(&compute["location"]),
+        availability_zone: string(&compute["zone"]),
+        image_id,
+        spot: string(&compute["priority"]).map(|priority| priority == "Spot"),
+    })
+}
+
+/// Asks every cloud's metadata service at once, the instance is on the cloud that answers
+pub async fn probe_instance_metadata(endpoints: &MetadataEndpoints) -> Option<InstanceMetadata> {
+    let client = Client::new();
+    let (aws, gcp, azure) = tokio::join!(
+        probe_aws(&client, &endpoints.aws),
+        probe_gcp(&client, &endpoints.gcp),
+        probe_azure(&client, &endpoints.azure),
+    );
+    aws.or(gcp).or(azure).ok()
+}
+
+/// Metadata of the cloud instance the daemon runs on, probed once per process
+pub async fn get_instance_metadata() -> Option<InstanceMetadata> {
+    INSTANCE_METADATA
+        .get_or_init(|| async { probe_instance_metadata(&MetadataEndpoints::default()).await })
+        .await
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+    use serde_json::json;
+
+    fn endpoints(server: &mockito::ServerGuard) -> MetadataEndpoints {
+        MetadataEndpoints {
+            aws: server.url(),
+            gcp: server.url(),
+            azure: server.url(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probe_aws_with_imdsv2() {
+        let mut server = mockito::Server::new_async().await;
+        let token = server
+            .mock("PUT", "/latest/api/token")
+            .match_header(
+                "X-aws-ec2-metadata-token-ttl-seconds",
+                AWS_TOKEN_TTL_SECONDS,
+            )
+            .with_body("session-token")
+            .expect(1)
+            .create_async()
+            .await;
+        // IMDSv2-only instances refuse requests without the token
+        server
+            .mock("GET", Matcher::Regex("^/latest/".to_string()))
+            .match_header("X-aws-ec2-metadata-token", Matcher::Missing)
+            .with_status(401)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/latest/dynamic/instance-identity/document")
+            .match_header("X-aws-ec2-metadata-token", "session-token")
+            .with_body(
+                json!({
+                    "instanceId": "i-0abc123",
+                    "instanceType": "r6i.2xlarge",
+                    "region": "eu-west-1",
+                    "availabilityZone": "eu-west-1b",
+                    "imageId": "ami-0123456789",
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/latest/meta-data/instance-life-cycle")
+            .match_header("X-aws-ec2-metadata-token", "session-token")
+            .with_body("spot")
+            .create_async()
+            .await;
+
+        assert_eq!(
+            probe_instance_metadata(&endpoints(&server)).await,
+            Some(InstanceMetadata {
+                provider: CloudProvider::Aws,
+                instance_id: Some("i-0abc123".to_string()),
+                instance_type: Some("r6i.2xlarge".to_string()),
+                region: Some("eu-west-1".to_string()),
+                availability_zone: Some("eu-west-1b".to_string()),
+                image_id: Some("ami-0123456789".to_string()),
+                spot: Some(true),
+            })
+        );
+        token.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_probe_gcp() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/computeMetadata/v1/instance/")
+            .match_query(Matcher::UrlEncoded("recursive".into(), "true".into()))
+            .match_header("Metadata-Flavor", "Google")
+            .with_header("Metadata-Flavor", "Google")
+            .with_body(
+                json!({
+                    "id": 4520031799277581759u64,
+                    "machineType": "projects/123/machineTypes/n2-highmem-8",
+                    "zone": "projects/123/zones/europe-west4-a",
+                    "image": "projects/debian-cloud/global/images/debian-12-bookworm-v20240110",
+                    "scheduling": { "preemptible": "FALSE", "provisioningModel": "STANDARD" },
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        assert_eq!(
+            probe_instance_metadata(&endpoints(&server)).await,
+            Some(InstanceMetadata {
+                provider: CloudProvider::Gcp,
+                instance_id: Some("4520031799277581759".to_string()),
+                instance_type: Some("n2-highmem-8".to_string()),
+                region: Some("europe-west4".to_string()),
+                availability_zone: Some("europe-west4-a".to_string()),
+                image_id: Some("debian-12-bookworm-v20240110".to_string()),
+                spot: Some(false),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_azure() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/metadata/instance")
+            .match_query(Matcher::UrlEncoded(
+                "api-version".into(),
+                AZURE_API_VERSION.into(),
+            ))
+            .match_header("Metadata", "true")
+            .with_body(
+                json!({
+                    "compute": {
+                        "vmId": "02aab8a4-74ef-476e-8182-f6d2ba4166a6",
+                        "vmSize": "Standard_D4s_v5",
+                        "location": "westeurope",
+                        "zone": "2",
+                        "priority": "Spot",
+                        "storageProfile": {
+                            "imageReference": {
+                                "id": "",
+                                "publisher": "Canonical",
+                                "offer": "0001-com-ubuntu-server-jammy",
+                                "sku": "22_04-lts-gen2",
+                                "version": "latest",
+                            },
+                        },
+                    },
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        assert_eq!(
+            probe_instance_metadata(&endpoints(&server)).await,
+            Some(InstanceMetadata {
+                provider: CloudProvider::Azure,
+                instance_id: Some("02aab8a4-74ef-476e-8182-f6d2ba4166a6".to_string()),
+                instance_type: Some("Standard_D4s_v5".to_string()),
+                region: Some("westeurope".to_string()),
+                availability_zone: Some("2".to_string()),
+                image_id: Some(
+                    "Canonical:0001-com-ubuntu-server-jammy:22_04-lts-gen2:latest".to_string()
+                ),
+                spot: Some(true),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_off_the_cloud() {
+        // Nothing but 404s, like any other web server
+        let server = mockito::Server::new_async().await;
+        assert_eq!(probe_instance_metadata(&endpoints(&server)).await, None);
+    }
+}
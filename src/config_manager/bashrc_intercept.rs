@@ -7,13 +7,18 @@ use std::{
 
 use crate::config_manager::target_process::target_matching::TargetMatch;
 use crate::config_manager::target_process::Target;
+use crate::runtime_paths::{INTERCEPTOR_STDERR_FILE, INTERCEPTOR_STDOUT_FILE, PID_FILE};
 
 const INTERCEPTOR_BASHRC_PATH: &str = ".config/tracer/.bashrc";
 const INTERCEPTOR_SOURCE_COMMAND: &str = "source ~/.config/tracer/.bashrc";
-pub const INTERCEPTOR_STDOUT_FILE: &str = "/tmp/tracerd-stdout";
-pub const INTERCEPTOR_STDERR_FILE: &str = "/tmp/tracerd-stderr";
-const INTERCEPTOR_STDOUT_COMMAND: &str = "exec &> >(tee >(awk 'system(\"[ ! -f /tmp/tracerd.pid ]\") == 1' >> \"/tmp/tracerd-stdout\"))\n";
-const INTERCEPTOR_STDERR_COMMAND: &str = "exec 2> >(tee >(awk 'system(\"[ ! -f /tmp/tracerd.pid ]\") == 1' >> \"/tmp/tracerd-stderr\"))\n";
+
+/// Copies the shell's output to `output_file` while the daemon is running
+fn get_output_interceptor(redirection: &str, output_file: &str) -> String {
+    format!(
+        "exec {} >(tee >(awk 'system(\"[ ! -f {} ]\") == 1' >> \"{}\"))\n",
+        redirection, *PID_FILE, output_file
+    )
+}
 
 pub fn get_command_interceptor(
     current_tracer_exe_path: PathBuf,
@@ -60,11 +65,11 @@ pub fn rewrite_interceptor_bashrc_file(
     }
 
     bashrc_file
-        .write_all(INTERCEPTOR_STDOUT_COMMAND.as_bytes())
+        .write_all(get_output_interceptor("&>", &INTERCEPTOR_STDOUT_FILE).as_bytes())
         .unwrap();
 
     bashrc_file
-        .write_all(INTERCEPTOR_STDERR_COMMAND.as_bytes())
+        .write_all(get_output_interceptor("2>", &INTERCEPTOR_STDERR_FILE).as_bytes())
         .unwrap();
 
     Ok(())
@@ -22,8 +22,27 @@ const DEFAULT_CONFIG_FILE_LOCATION_FROM_HOME: &str = ".config/tracer/tracer.toml
 const PROCESS_POLLING_INTERVAL_MS: u64 = 5;
 const BATCH_SUBMISSION_INTERVAL_MS: u64 = 10000;
 const NEW_RUN_PAUSE_MS: u64 = 10 * 60 * 1000;
+const AUTOMATIC_RUNS: bool = false;
+const SLURM_JOB_RUNS: bool = false;
+const RUN_ROOT_PROCESSES: [&str; 5] = ["nextflow", "snakemake", "cromwell", "miniwdl", "cwltool"];
+const ENVIRONMENT_VARIABLES: [&str; 9] = [
+    "SLURM_JOB_ID",
+    "SLURM_ARRAY_JOB_ID",
+    "SLURM_ARRAY_TASK_ID",
+    "NXF_TASK_WORKDIR",
+    "SNAKEMAKE_*",
+    "AWS_BATCH_JOB_ID",
+    "AWS_BATCH_JOB_ARRAY_INDEX",
+    "PBS_JOBID",
+    "LSB_JOBID",
+];
 const PROCESS_METRICS_SEND_INTERVAL_MS: u64 = 10000;
 const FILE_SIZE_NOT_CHANGING_PERIOD_MS: u64 = 1000 * 60;
+const COMPUTE_MD5_CHECKSUMS: bool = false;
+const UPLOAD_MAX_FILE_SIZE_BYTES: u64 = 5 * 1024 * 1024 * 1024;
+const UPLOAD_WORKERS: usize = 2;
+const FILE_CACHE_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+const RUN_REPORT_FORMAT: &str = "html";
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ConfigFile {
@@ -32,8 +51,18 @@ pub struct ConfigFile {
     pub process_polling_interval_ms: Option<u64>,
     pub batch_submission_interval_ms: Option<u64>,
     pub new_run_pause_ms: Option<u64>,
+    pub automatic_runs: Option<bool>,
+    pub slurm_job_runs: Option<bool>,
+    pub run_root_processes: Option<Vec<String>>,
+    pub environment_variables: Option<Vec<String>>,
     pub file_size_not_changing_period_ms: Option<u64>,
     pub process_metrics_send_interval_ms: Option<u64>,
+    pub compute_md5_checksums: Option<bool>,
+    pub upload_max_file_size_bytes: Option<u64>,
+    pub upload_workers: Option<usize>,
+    pub file_cache_max_bytes: Option<u64>,
+    pub price_catalog: Option<String>,
+    pub run_report_format: Option<String>,
     pub targets: Option<Vec<Target>>,
 }
 
@@ -45,10 +74,41 @@ pub struct Config {
     pub process_metrics_send_interval_ms: u64,
     pub file_size_not_changing_period_ms: u64,
     pub service_url: String,
+    /// Ends an automatically detected run after this long without tool activity
     pub new_run_pause_ms: u64,
+    /// Start and end runs with the pipeline's root process instead of `tracer start`/`tracer end`
+    pub automatic_runs: bool,
+    /// Start a run for each SLURM job with processes on the node, ended with the job
+    pub slurm_job_runs: bool,
+    /// Process names that start a run when `automatic_runs` is set
+    pub run_root_processes: Vec<String>,
+    /// Variables read from the environment of tracked processes, `PREFIX_*` matches a prefix
+    pub environment_variables: Vec<String>,
+    pub compute_md5_checksums: bool,
+    pub upload_max_file_size_bytes: u64,
+    pub upload_workers: usize,
+    pub file_cache_max_bytes: u64,
+    /// Price catalog used over the bundled one to estimate costs
+    pub price_catalog: Option<String>,
+    /// Format of the report written next to the pipeline when a run ends: md, html or json
+    pub run_report_format: String,
     pub targets: Vec<Target>,
 }
 
+fn default_run_root_processes() -> Vec<String> {
+    RUN_ROOT_PROCESSES
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn default_environment_variables() -> Vec<String> {
+    ENVIRONMENT_VARIABLES
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
+}
+
 pub struct ConfigManager;
 
 impl ConfigManager {
@@ -79,12 +139,32 @@ impl ConfigManager {
                 .service_url
                 .unwrap_or(DEFAULT_SERVICE_URL.to_string()),
             new_run_pause_ms: config.new_run_pause_ms.unwrap_or(NEW_RUN_PAUSE_MS),
+            automatic_runs: config.automatic_runs.unwrap_or(AUTOMATIC_RUNS),
+            slurm_job_runs: config.slurm_job_runs.unwrap_or(SLURM_JOB_RUNS),
+            run_root_processes: config
+                .run_root_processes
+                .unwrap_or_else(default_run_root_processes),
+            environment_variables: config
+                .environment_variables
+                .unwrap_or_else(default_environment_variables),
             process_metrics_send_interval_ms: config
                 .process_metrics_send_interval_ms
                 .unwrap_or(PROCESS_METRICS_SEND_INTERVAL_MS),
             file_size_not_changing_period_ms: config
                 .file_size_not_changing_period_ms
                 .unwrap_or(FILE_SIZE_NOT_CHANGING_PERIOD_MS),
+            compute_md5_checksums: config
+                .compute_md5_checksums
+                .unwrap_or(COMPUTE_MD5_CHECKSUMS),
+            upload_max_file_size_bytes: config
+                .upload_max_file_size_bytes
+                .unwrap_or(UPLOAD_MAX_FILE_SIZE_BYTES),
+            upload_workers: config.upload_workers.unwrap_or(UPLOAD_WORKERS),
+            file_cache_max_bytes: config.file_cache_max_bytes.unwrap_or(FILE_CACHE_MAX_BYTES),
+            price_catalog: config.price_catalog,
+            run_report_format: config
+                .run_report_format
+                .unwrap_or_else(|| RUN_REPORT_FORMAT.to_string()),
             targets: config
                 .targets
                 .unwrap_or_else(|| targets_list::TARGETS.to_vec()),
@@ -97,10 +177,20 @@ impl ConfigManager {
             process_polling_interval_ms: PROCESS_POLLING_INTERVAL_MS,
             batch_submission_interval_ms: BATCH_SUBMISSION_INTERVAL_MS,
             new_run_pause_ms: NEW_RUN_PAUSE_MS,
+            automatic_runs: AUTOMATIC_RUNS,
+            slurm_job_runs: SLURM_JOB_RUNS,
+            run_root_processes: default_run_root_processes(),
+            environment_variables: default_environment_variables(),
             file_size_not_changing_period_ms: FILE_SIZE_NOT_CHANGING_PERIOD_MS,
             service_url: DEFAULT_SERVICE_URL.to_string(),
             targets: targets_list::TARGETS.to_vec(),
             process_metrics_send_interval_ms: PROCESS_METRICS_SEND_INTERVAL_MS,
+            compute_md5_checksums: COMPUTE_MD5_CHECKSUMS,
+            upload_max_file_size_bytes: UPLOAD_MAX_FILE_SIZE_BYTES,
+            upload_workers: UPLOAD_WORKERS,
+            file_cache_max_bytes: FILE_CACHE_MAX_BYTES,
+            price_catalog: None,
+            run_report_format: RUN_REPORT_FORMAT.to_string(),
         }
     }
 
@@ -161,11 +251,21 @@ impl ConfigManager {
             api_key: config.api_key.clone(),
             service_url: Some(config.service_url.clone()),
             new_run_pause_ms: Some(config.new_run_pause_ms),
+            automatic_runs: Some(config.automatic_runs),
+            slurm_job_runs: Some(config.slurm_job_runs),
+            run_root_processes: Some(config.run_root_processes.clone()),
+            environment_variables: Some(config.environment_variables.clone()),
             file_size_not_changing_period_ms: Some(config.file_size_not_changing_period_ms),
             process_polling_interval_ms: Some(config.process_polling_interval_ms),
             batch_submission_interval_ms: Some(config.batch_submission_interval_ms),
             targets: Some(config.targets.clone()),
             process_metrics_send_interval_ms: Some(config.process_metrics_send_interval_ms),
+            compute_md5_checksums: Some(config.compute_md5_checksums),
+            upload_max_file_size_bytes: Some(config.upload_max_file_size_bytes),
+            upload_workers: Some(config.upload_workers),
+            file_cache_max_bytes: Some(config.file_cache_max_bytes),
+            price_catalog: config.price_catalog.clone(),
+            run_report_format: Some(config.run_report_format.clone()),
         };
         let config = toml::to_string(&config_out)?;
         std::fs::write(config_file_location, config)?;
@@ -239,6 +339,9 @@ mod tests {
             config.process_metrics_send_interval_ms,
             PROCESS_METRICS_SEND_INTERVAL_MS
         );
+        assert!(!config.automatic_runs);
+        assert!(!config.slurm_job_runs);
+        assert!(config.run_root_processes.contains(&"nextflow".to_string()));
         assert!(!config.targets.is_empty());
     }
 }
@@ -4,12 +4,6 @@ pub mod targets_list;
 use serde::{Deserialize, Serialize};
 use target_matching::{matches_target, TargetMatch};
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct CommandContainsStruct {
-    pub process_name: Option<String>,
-    pub command_content: String,
-}
-
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum DisplayName {
     Name(String),
@@ -58,7 +52,14 @@ pub struct Target {
 }
 
 pub trait TargetMatchable {
-    fn matches(&self, process_name: &str, command: &str, bin_path: &str) -> bool;
+    /// `container_image` is the image of the container the process runs in, if any
+    fn matches(
+        &self,
+        process_name: &str,
+        command: &str,
+        bin_path: &str,
+        container_image: Option<&str>,
+    ) -> bool;
 }
 
 impl Target {
@@ -108,23 +109,48 @@ impl Target {
     pub fn get_display_name_object(&self) -> DisplayName {
         self.display_name.clone()
     }
+
+    /// Whether matching needs the container image of processes
+    pub fn uses_container_image(&self) -> bool {
+        let is_image_match =
+            |match_type: &TargetMatch| matches!(match_type, TargetMatch::ContainerImage(_));
+        is_image_match(&self.match_type) || self.filter_out.iter().flatten().any(is_image_match)
+    }
 }
 
 impl TargetMatchable for Target {
-    fn matches(&self, process_name: &str, command: &str, bin_path: &str) -> bool {
-        matches_target(&self.match_type, process_name, command, bin_path)
-            && (self.filter_out.is_none()
-                || !self
-                    .filter_out
-                    .as_ref()
-                    .unwrap()
-                    .matches(process_name, command, bin_path))
+    fn matches(
+        &self,
+        process_name: &str,
+        command: &str,
+        bin_path: &str,
+        container_image: Option<&str>,
+    ) -> bool {
+        matches_target(
+            &self.match_type,
+            process_name,
+            command,
+            bin_path,
+            container_image,
+        ) && (self.filter_out.is_none()
+            || !self.filter_out.as_ref().unwrap().matches(
+                process_name,
+                command,
+                bin_path,
+                container_image,
+            ))
     }
 }
 
 impl TargetMatchable for Vec<TargetMatch> {
-    fn matches(&self, process_name: &str, command: &str, bin_path: &str) -> bool {
+    fn matches(
+        &self,
+        process_name: &str,
+        command: &str,
+        bin_path: &str,
+        container_image: Option<&str>,
+    ) -> bool {
         self.iter()
-            .any(|target| matches_target(target, process_name, command, bin_path))
+            .any(|target| matches_target(target, process_name, command, bin_path, container_image))
     }
 }
@@ -15,9 +15,11 @@ pub enum TargetMatch {
     CommandContains(CommandContainsStruct),
     BinPathStartsWith(String),
     BinPathLastComponent(String),
+    /// The image of the container the process runs in contains this, e.g. `biocontainers/samtools`
+    ContainerImage(String),
 }
 
-pub fn to_lowercase(s: &str) -> Cow<str> {
+pub fn to_lowercase(s: &str) -> Cow<'_, str> {
     if s.chars().any(|c| c.is_uppercase()) {
         Cow::Owned(s.to_lowercase())
     } else {
@@ -55,6 +57,7 @@ pub fn matches_target(
     process_name: &str,
     command: &str,
     bin_path: &str,
+    container_image: Option<&str>,
 ) -> bool {
     match target {
         TargetMatch::ProcessName(name) => process_name_matches(name, process_name),
@@ -68,6 +71,9 @@ pub fn matches_target(
         TargetMatch::BinPathLastComponent(expected_name) => {
             bin_path_last_component_matches(expected_name, bin_path)
         }
+        TargetMatch::ContainerImage(image) => {
+            container_image.is_some_and(|container_image| command_contains(container_image, image))
+        }
     }
 }
 
@@ -87,27 +93,29 @@ mod tests {
             "/opt/conda/bin/python3.12 /opt/conda/bin/plotPCA -in rnaseq.npz -o PCA_rnaseq_2.png";
         let bin_path = "/opt/conda/bin/python3.12";
 
-        assert!(target.matches(process_name, command, bin_path));
+        assert!(target.matches(process_name, command, bin_path, None));
 
         // Test with incorrect process name
-        assert!(!target.matches("python3", command, bin_path));
+        assert!(!target.matches("python3", command, bin_path, None));
 
         // Test with incorrect command content
         assert!(!target.matches(
             process_name,
             "/opt/conda/bin/python3.12 /opt/conda/bin/differentCommand",
-            bin_path
+            bin_path,
+            None
         ));
 
         // Test conda bin with BinPathStarts
         let conda_target =
             Target::new(TargetMatch::BinPathStartsWith("/opt/conda/bin".to_string()));
 
-        assert!(conda_target.matches("any_process", "/opt/conda/bin/somecommand", bin_path));
+        assert!(conda_target.matches("any_process", "/opt/conda/bin/somecommand", bin_path, None));
         assert!(!conda_target.matches(
             "any_process",
             "/usr/bin/somecommand",
-            "/usr/bin/somecommand"
+            "/usr/bin/somecommand",
+            None
         ));
     }
 
@@ -120,16 +128,17 @@ mod tests {
         let process_name = "python3.12";
         let command = "/opt/conda/bin/python3.12 /opt/conda/bin/plotFingerprint -b control.sorted.bam test.sorted.bam --labels Control Test";
         let bin_path = "/opt/conda/bin/python3.12";
-        assert!(target.matches(process_name, command, bin_path));
+        assert!(target.matches(process_name, command, bin_path, None));
 
         // Test with incorrect process name
-        assert!(!target.matches("python3", command, bin_path));
+        assert!(!target.matches("python3", command, bin_path, None));
 
         // Test with incorrect command content
         assert!(!target.matches(
             process_name,
             "/opt/conda/bin/python3.12 /opt/conda/bin/differentCommand",
-            bin_path
+            bin_path,
+            None
         ));
     }
 
@@ -144,16 +153,17 @@ mod tests {
             "kallisto quant -t 4 -i control_index -o ./control_quant_9 control1_1.fq control1_2.fq";
         let bin_path = "/usr/bin/kallisto";
 
-        assert!(target.matches(process_name, command, bin_path));
+        assert!(target.matches(process_name, command, bin_path, None));
 
         // Test with incorrect process name
-        assert!(!target.matches("different_process", command, bin_path));
+        assert!(!target.matches("different_process", command, bin_path, None));
 
         // Test with incorrect command content
         assert!(!target.matches(
             process_name,
             "kallisto index -i transcripts.idx transcripts.fa.gz",
-            bin_path
+            bin_path,
+            None
         ));
     }
 
@@ -164,13 +174,14 @@ mod tests {
         let command = "/opt/conda/bin/somecommand";
         let bin_path = "/opt/conda/bin/somecommand";
 
-        assert!(target.matches(process_name, command, bin_path));
+        assert!(target.matches(process_name, command, bin_path, None));
 
         // Even with a non-matching process name and different command, it should match due to "/opt/conda/bin"
         assert!(target.matches(
             "different_process",
             "/opt/conda/bin/different_command",
-            bin_path
+            bin_path,
+            None
         ));
     }
 
@@ -180,8 +191,8 @@ mod tests {
         let command = "/opt/conda/bin/somecommand";
         let bin_path = "/opt/conda/bin/somecommand";
 
-        assert!(target.matches("specific_process", command, bin_path));
-        assert!(!target.matches("different_process", command, bin_path));
+        assert!(target.matches("specific_process", command, bin_path, None));
+        assert!(!target.matches("different_process", command, bin_path, None));
     }
 
     #[test]
@@ -197,12 +208,12 @@ mod tests {
         let command = "/opt/conda/bin/somecommand";
         let bin_path = "/bin/specific_process";
 
-        assert!(target.matches("specific_process", command, bin_path));
+        assert!(target.matches("specific_process", command, bin_path, None));
 
         let command = "/opt/conda/bin/somecommand filter_me";
         let bin_path = "/bin/specific_process";
 
-        assert!(!target.matches("specific_process", command, bin_path));
+        assert!(!target.matches("specific_process", command, bin_path, None));
     }
 
     #[test]
@@ -221,25 +232,56 @@ mod tests {
 
         let bin_path = "/bin/specific_process";
 
-        assert!(target.matches("specific_process", "/opt/conda/bin/somecommand", bin_path));
+        assert!(target.matches(
+            "specific_process",
+            "/opt/conda/bin/somecommand",
+            bin_path,
+            None
+        ));
 
         assert!(!target.matches(
             "specific_process",
             "/opt/conda/bin/somecommand filter_me_one",
-            bin_path
+            bin_path,
+            None
         ));
 
         assert!(!target.matches(
             "specific_process",
             "/opt/conda/bin/somecommand filter_me_too",
-            bin_path
+            bin_path,
+            None
         ));
 
         assert!(target.matches(
             "specific_process",
             "/opt/conda/bin/somecommand filter_me_three",
-            bin_path
+            bin_path,
+            None
+        ));
+    }
+
+    #[test]
+    fn test_container_image() {
+        let target = Target::new(TargetMatch::ContainerImage(
+            "biocontainers/samtools".to_string(),
+        ));
+        let command = "samtools sort -o sorted.bam input.bam";
+        let bin_path = "/usr/local/bin/samtools";
+
+        assert!(target.matches(
+            "samtools",
+            command,
+            bin_path,
+            Some("quay.io/biocontainers/samtools:1.17--h00cdaf9_0")
+        ));
+        assert!(!target.matches(
+            "samtools",
+            command,
+            bin_path,
+            Some("docker.io/library/python:3.12")
         ));
+        assert!(!target.matches("samtools", command, bin_path, None));
     }
 
     #[test]
@@ -249,17 +291,20 @@ mod tests {
         assert!(target.matches(
             "specific_process",
             "/opt/conda/bin/somecommand",
-            "/bin/specific_process"
+            "/bin/specific_process",
+            None
         ));
         assert!(target.matches(
             "Specific_Process",
             "/opt/conda/bin/somecommand",
-            "/bin/specific_process"
+            "/bin/specific_process",
+            None
         ));
         assert!(target.matches(
             "SPECIFIC_PROCESS",
             "/opt/conda/bin/somecommand",
-            "/bin/specific_process"
+            "/bin/specific_process",
+            None
         ));
     }
 
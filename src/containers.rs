@@ -0,0 +1,507 @@
+// src/containers.rs
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sysinfo::Pid;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use crate::process_environment::read_process_environment;
+
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+const PODMAN_SOCKET: &str = "/run/podman/podman.sock";
+const CONTAINERD_TASK_DIRECTORY: &str = "/run/containerd/io.containerd.runtime.v2.task";
+const CRI_IMAGE_ANNOTATION: &str = "io.kubernetes.cri.image-name";
+/// Set by Singularity and Apptainer to the image a container runs
+const SINGULARITY_VARIABLE: &str = "SINGULARITY_CONTAINER";
+const APPTAINER_VARIABLE: &str = "APPTAINER_CONTAINER";
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref CGROUP_PATTERNS: Vec<(ContainerRuntime, Regex)> = vec![
+        // `/docker/<id>` with cgroup v1, `/system.slice/docker-<id>.scope` with v2
+        (ContainerRuntime::Docker, Regex::new(r"/docker[/-]([0-9a-f]{64})").unwrap()),
+        (ContainerRuntime::Podman, Regex::new(r"/libpod-([0-9a-f]{64})").unwrap()),
+        (ContainerRuntime::Containerd, Regex::new(r"/cri-containerd-([0-9a-f]{64})").unwrap()),
+        // Kubernetes pods with cgroup v1 don't name the runtime, it's containerd nowadays
+        (ContainerRuntime::Containerd, Regex::new(r"/kubepods[^:]*/([0-9a-f]{64})").unwrap()),
+    ];
+    /// Host paths of the `/etc/hostname` runtimes bind mount into containers
+    static ref HOSTNAME_SOURCE_PATTERNS: Vec<(ContainerRuntime, Regex)> = vec![
+        (ContainerRuntime::Docker, Regex::new(r"/docker/containers/([0-9a-f]{64})/hostname$").unwrap()),
+        (ContainerRuntime::Podman, Regex::new(r"/overlay-containers/([0-9a-f]{64})/userdata/hostname$").unwrap()),
+    ];
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerRuntime {
+    Docker,
+    Podman,
+    Containerd,
+    Singularity,
+    Apptainer,
+}
+
+/// The container a process runs in
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ContainerInfo {
+    pub runtime: ContainerRuntime,
+    /// Singularity containers have no id
+    pub container_id: Option<String>,
+    pub image: Option<String>,
+}
+
+fn find_container(
+    patterns: &[(ContainerRuntime, Regex)],
+    path: &str,
+) -> Option<(ContainerRuntime, String)> {
+    patterns.iter().find_map(|(runtime, pattern)| {
+        let captures = pattern.captures(path)?;
+        Some((*runtime, captures[1].to_string()))
+    })
+}
+
+pub fn parse_cgroup(cgroup: &str) -> Option<(ContainerRuntime, String)> {
+    cgroup
+        .lines()
+        .find_map(|line| find_container(&CGROUP_PATTERNS, line))
+}
+
+/// Containers with a cgroup namespace of their own are told apart by the
+/// mounts of their namespace. The host has mounts below the runtime's
+/// directories too, so only what's mounted on `/etc/hostname` counts.
+pub fn parse_mountinfo(mountinfo: &str) -> Option<(ContainerRuntime, String)> {
+    mountinfo.lines().find_map(|line| {
+        // `<id> <parent> <major:minor> <root> <mount point> ...`
+        let mut fields = line.split(' ').skip(3);
+        match (fields.next(), fields.next()) {
+            (Some(root), Some("/etc/hostname")) => find_container(&HOSTNAME_SOURCE_PATTERNS, root),
+            _ => None,
+        }
+    })
+}
+
+/// Image of a container, from the Docker compatible API on a runtime's socket
+pub async fn query_socket_image(socket: &Path, container_id: &str) -> Option<String> {
+    // HTTP/1.0 gets a plain body rather than a chunked one
+    let request = format!(
+        "GET /containers/{}/json HTTP/1.0\r\nHost: localhost\r\n\r\n",
+        container_id
+    );
+    let exchange = async {
+        let mut stream = UnixStream::connect(socket).await.ok()?;
+        stream.write_all(request.as_bytes()).await.ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.ok()?;
+        Some(response)
+    };
+    let response = tokio::time::timeout(SOCKET_TIMEOUT, exchange)
+        .await
+        .ok()??;
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    if !head.starts_with("HTTP/1.") || !head.split_whitespace().nth(1)?.starts_with('2') {
+        return None;
+    }
+    let container: Value = serde_json::from_str(body).ok()?;
+    container["Config"]["Image"].as_str().map(String::from)
+}
+
+/// Image of a Kubernetes container, from the annotations of its containerd task
+pub fn read_containerd_image(task_directory: &Path, container_id: &str) -> Option<String> {
+    std::fs::read_dir(task_directory)
+        .ok()?
+        .filter_map(|namespace| namespace.ok())
+        .find_map(|namespace| {
+            let config =
+                std::fs::read_to_string(namespace.path().join(container_id).join("config.json"))
+                    .ok()?;
+            let config: Value = serde_json::from_str(&config).ok()?;
+            config["annotations"][CRI_IMAGE_ANNOTATION]
+                .as_str()
+                .map(String::from)
+        })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum ImageLookup {
+    /// The runtime's socket hasn't answered yet
+    Pending,
+    Resolved(Option<String>),
+}
+
+/// Finds the container of processes, caching what it finds per process and
+/// the images per container. Runtimes are asked over a socket on a background
+/// task, so the image of their containers is only known on a later call.
+pub struct ContainerResolver {
+    proc_directory: PathBuf,
+    docker_sockets: Vec<PathBuf>,
+    podman_sockets: Vec<PathBuf>,
+    containerd_task_directory: PathBuf,
+    /// By pid and start time, as pids get reused
+    processes: HashMap<(Pid, u64), Option<ContainerInfo>>,
+    images: Arc<Mutex<HashMap<String, ImageLookup>>>,
+}
+
+impl ContainerResolver {
+    pub fn new() -> Self {
+        ContainerResolver {
+            proc_directory: PathBuf::from("/proc"),
+            docker_sockets: vec![PathBuf::from(DOCKER_SOCKET)],
+            podman_sockets: vec![PathBuf::from(PODMAN_SOCKET)],
+            containerd_task_directory: PathBuf::from(CONTAINERD_TASK_DIRECTORY),
+            processes: HashMap::new(),
+            images: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn get_container(
+        &mut self,
+        pid: Pid,
+        start_time: u64,
+        uid: Option<u32>,
+    ) -> Option<ContainerInfo> {
+        let container = match self.processes.get(&(pid, start_time)) {
+            Some(container) => container.clone(),
+            None => {
+                let container = self.detect(pid);
+                self.processes.insert((pid, start_time), container.clone());
+                container
+            }
+        };
+        container.map(|mut container| {
+            if let (None, Some(container_id)) = (&container.image, &container.container_id) {
+                if let ImageLookup::Resolved(image) =
+                    self.get_image(container.runtime, container_id, uid)
+                {
+                    container.image = image;
+                }
+            }
+            container
+        })
+    }
+
+    /// Whether the container's runtime was asked for its image and didn't answer yet
+    pub fn is_resolving(&self, container: &ContainerInfo) -> bool {
+        container.image.is_none()
+            && container.container_id.as_ref().is_some_and(|container_id| {
+                self.images.lock().unwrap().get(container_id) == Some(&ImageLookup::Pending)
+            })
+    }
+
+    /// Drops what's cached about processes that exited
+    pub fn retain_processes(&mut self, is_alive: impl Fn(Pid, u64) -> bool) {
+        self.processes
+            .retain(|(pid, start_time), _| is_alive(*pid, *start_time));
+    }
+
+    fn detect(&self, pid: Pid) -> Option<ContainerInfo> {
+        let process_directory = self.proc_directory.join(pid.to_string());
+        let read = |name| std::fs::read_to_string(process_directory.join(name)).ok();
+        let container = read("cgroup")
+            .and_then(|cgroup| parse_cgroup(&cgroup))
+            .or_else(|| read("mountinfo").and_then(|mountinfo| parse_mountinfo(&mountinfo)));
+        if let Some((runtime, container_id)) = container {
+            return Some(ContainerInfo {
+                runtime,
+                container_id: Some(container_id),
+                image: None,
+            });
+        }
+
+        // Singularity shares the host's cgroups and leaves the image in the environment
+        let allow_list = vec![
+            SINGULARITY_VARIABLE.to_string(),
+            APPTAINER_VARIABLE.to_string(),
+        ];
+        let environment = read_process_environment(&self.proc_directory, pid.as_u32(), &allow_list);
+        let (variable, image) = environment.into_iter().next()?;
+        Some(ContainerInfo {
+            runtime: match variable.as_str() {
+                APPTAINER_VARIABLE => ContainerRuntime::Apptainer,
+                _ => ContainerRuntime::Singularity,
+            },
+            container_id: None,
+            image: Some(image),
+        })
+    }
+
+    fn get_image(
+        &self,
+        runtime: ContainerRuntime,
+        container_id: &str,
+        uid: Option<u32>,
+    ) -> ImageLookup {
+        let mut images = self.images.lock().unwrap();
+        if let Some(lookup) = images.get(container_id) {
+            return lookup.clone();
+        }
+
+        let sockets = match runtime {
+            ContainerRuntime::Docker => self.docker_sockets.clone(),
+            ContainerRuntime::Podman => {
+                // Rootless containers are served by their user's socket
+                let user_socket =
+                    uid.map(|uid| PathBuf::from(format!("/run/user/{}/podman/podman.sock", uid)));
+                self.podman_sockets
+                    .iter()
+                    .cloned()
+                    .chain(user_socket)
+                    .collect()
+            }
+            ContainerRuntime::Containerd => {
+                let lookup = ImageLookup::Resolved(read_containerd_image(
+                    &self.containerd_task_directory,
+                    container_id,
+                ));
+                images.insert(container_id.to_string(), lookup.clone());
+                return lookup;
+            }
+            ContainerRuntime::Singularity | ContainerRuntime::Apptainer => {
+                return ImageLookup::Resolved(None)
+            }
+        };
+        images.insert(container_id.to_string(), ImageLookup::Pending);
+
+        let images = self.images.clone();
+        let container_id = container_id.to_string();
+        tokio::spawn(async move {
+            let mut image = None;
+            for socket in sockets {
+                image = query_socket_image(&socket, &container_id).await;
+                if image.is_some() {
+                    break;
+                }
+            }
+            images
+                .lock()
+                .unwrap()
+                .insert(container_id, ImageLookup::Resolved(image));
+        });
+        ImageLookup::Pending
+    }
+}
+
+impl Default for ContainerResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+
+    const ID: &str = "4f1c3a0e9b8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1e0d9c8b7a6f5e4d3c2b";
+
+    fn resolver(root: &Path) -> ContainerResolver {
+        ContainerResolver {
+            proc_directory: root.join("proc"),
+            docker_sockets: vec![root.join("docker.sock")],
+            podman_sockets: vec![],
+            containerd_task_directory: root.join("tasks"),
+            processes: HashMap::new(),
+            images: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn write_proc_file(root: &Path, pid: u32, name: &str, contents: &str) {
+        let directory = root.join("proc").join(pid.to_string());
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join(name), contents).unwrap();
+    }
+
+    /// Answers one request on a socket like the Docker API would
+    fn serve_container(socket: &Path, image: &str) -> std::thread::JoinHandle<String> {
+        let listener = UnixListener::bind(socket).unwrap();
+        let body = serde_json::json!({ "Id": ID, "Config": { "Image": image } }).to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Reads the whole request, closing with some of it unread resets the connection
+            let mut request = vec![];
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let length = stream.read(&mut buffer).unwrap();
+                assert!(length > 0);
+                request.extend_from_slice(&buffer[..length]);
+            }
+            write!(
+                stream,
+                "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        })
+    }
+
+    #[test]
+    fn test_parse_cgroup() {
+        assert_eq!(
+            parse_cgroup(&format!("12:pids:/docker/{}\n", ID)),
+            Some((ContainerRuntime::Docker, ID.to_string()))
+        );
+        assert_eq!(
+            parse_cgroup(&format!("0::/system.slice/docker-{}.scope\n", ID)),
+            Some((ContainerRuntime::Docker, ID.to_string()))
+        );
+        assert_eq!(
+            parse_cgroup(&format!("0::/user.slice/user-1000.slice/user@1000.service/user.slice/libpod-{}.scope/container\n", ID)),
+            Some((ContainerRuntime::Podman, ID.to_string()))
+        );
+        assert_eq!(
+            parse_cgroup(&format!("0::/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1a2b.slice/cri-containerd-{}.scope\n", ID)),
+            Some((ContainerRuntime::Containerd, ID.to_string()))
+        );
+        assert_eq!(
+            parse_cgroup("0::/user.slice/user-1000.slice/session-3.scope\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        assert_eq!(
+            parse_mountinfo(&format!("1119 1101 0:45 / / rw - overlay overlay rw\n1120 1101 259:2 /var/lib/docker/containers/{}/hostname /etc/hostname rw,relatime - ext4 /dev/root rw\n", ID)),
+            Some((ContainerRuntime::Docker, ID.to_string()))
+        );
+        // The host sees the shared memory mounts of every container
+        assert_eq!(
+            parse_mountinfo(&format!(
+                "540 29 0:60 / /var/lib/docker/containers/{}/mounts/shm rw - tmpfs shm rw\n",
+                ID
+            )),
+            None
+        );
+        assert_eq!(
+            parse_mountinfo("22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/root rw\n"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_docker_image_from_socket() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        write_proc_file(
+            root.path(),
+            100,
+            "cgroup",
+            &format!("0::/system.slice/docker-{}.scope\n", ID),
+        );
+        let server = serve_container(
+            &root.path().join("docker.sock"),
+            "quay.io/biocontainers/samtools:1.17--h00cdaf9_0",
+        );
+
+        let mut resolver = resolver(root.path());
+
+        // The socket is asked in the background, the image comes on a later call
+        let container = resolver.get_container(Pid::from(100), 1, None).unwrap();
+        assert_eq!(container.image, None);
+        assert!(resolver.is_resolving(&container));
+        for _ in 0..100 {
+            if !resolver.is_resolving(&container) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let container = resolver.get_container(Pid::from(100), 1, None);
+        assert_eq!(
+            container,
+            Some(ContainerInfo {
+                runtime: ContainerRuntime::Docker,
+                container_id: Some(ID.to_string()),
+                image: Some("quay.io/biocontainers/samtools:1.17--h00cdaf9_0".to_string()),
+            })
+        );
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with(&format!("GET /containers/{}/json HTTP/1.0", ID)));
+
+        // Both the process and the image are cached, the socket served once
+        assert_eq!(resolver.get_container(Pid::from(100), 1, None), container);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_containerd_image_from_task_config() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        write_proc_file(
+            root.path(),
+            100,
+            "cgroup",
+            &format!("0::/kubepods/besteffort/pod1a2b/{}\n", ID),
+        );
+        let task = root.path().join("tasks").join("k8s.io").join(ID);
+        fs::create_dir_all(&task)?;
+        fs::write(
+            task.join("config.json"),
+            serde_json::json!({ "annotations": { CRI_IMAGE_ANNOTATION: "docker.io/library/python:3.12" } }).to_string(),
+        )?;
+
+        let container = resolver(root.path())
+            .get_container(Pid::from(100), 1, None)
+            .unwrap();
+
+        assert_eq!(container.runtime, ContainerRuntime::Containerd);
+        assert_eq!(
+            container.image.as_deref(),
+            Some("docker.io/library/python:3.12")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_singularity_image_from_environment() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        write_proc_file(
+            root.path(),
+            100,
+            "cgroup",
+            "0::/user.slice/user-1000.slice/session-3.scope\n",
+        );
+        write_proc_file(
+            root.path(),
+            100,
+            "environ",
+            "HOME=/home/alice\0APPTAINER_CONTAINER=/images/fastqc_0.12.1.sif\0",
+        );
+        write_proc_file(
+            root.path(),
+            200,
+            "cgroup",
+            "0::/user.slice/user-1000.slice/session-3.scope\n",
+        );
+
+        let mut resolver = resolver(root.path());
+
+        assert_eq!(
+            resolver.get_container(Pid::from(100), 1, None),
+            Some(ContainerInfo {
+                runtime: ContainerRuntime::Apptainer,
+                container_id: None,
+                image: Some("/images/fastqc_0.12.1.sif".to_string()),
+            })
+        );
+        assert_eq!(resolver.get_container(Pid::from(200), 1, None), None);
+
+        resolver.retain_processes(|pid, _| pid == Pid::from(200));
+        assert_eq!(resolver.processes.len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+// src/cost/mod.rs
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::cloud_metadata::InstanceMetadata;
+
+const BUNDLED_CATALOG: &str = include_str!("prices.toml");
+const BYTES_PER_GB: f64 = 1024.0 * 1024.0 * 1024.0;
+const SECONDS_PER_HOUR: f64 = 3600.0;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InstancePrice {
+    pub instance_type: String,
+    /// Applies to every region when not set
+    pub region: Option<String>,
+    pub on_demand: f64,
+    pub spot: Option<f64>,
+}
+
+/// Prices machines the catalog doesn't list, like on-prem ones
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FallbackPrice {
+    pub core_hour: f64,
+    pub gb_hour: f64,
+}
+
+/// Hourly prices, in USD
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PriceCatalog {
+    #[serde(default)]
+    pub instances: Vec<InstancePrice>,
+    pub fallback: Option<FallbackPrice>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Pricing {
+    OnDemand,
+    Spot,
+    /// Per core and GB of memory, for machines the catalog doesn't list
+    PerResource,
+}
+
+/// What the machine the daemon runs on costs per hour, split between its CPUs
+/// and memory so tools are charged for their share of each
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MachineRate {
+    pub pricing: Pricing,
+    pub instance_type: Option<String>,
+    pub hourly_price: f64,
+    cpu_hourly_price: f64,
+    memory_hourly_price: f64,
+    cpus: usize,
+    memory_bytes: u64,
+}
+
+/// Estimated cost of a run, in USD
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RunCost {
+    pub pricing: Pricing,
+    pub instance_type: Option<String>,
+    pub hourly_price: f64,
+    pub duration_seconds: f64,
+    /// The whole machine over the run's wall time
+    pub instance_cost: f64,
+    /// The share of the machine the run's tools used, for the tools that finished
+    pub tools_cost: f64,
+}
+
+impl PriceCatalog {
+    pub fn bundled() -> PriceCatalog {
+        toml::from_str(BUNDLED_CATALOG).expect("The bundled price catalog is valid")
+    }
+
+    /// The bundled catalog, with the entries of the user's catalog at `path` taking precedence
+    pub fn load(path: Option<&Path>) -> Result<PriceCatalog> {
+        let mut catalog = PriceCatalog::bundled();
+        if let Some(path) = path {
+            let user_catalog = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the price catalog {:?}", path))?;
+            let user_catalog: PriceCatalog = toml::from_str(&user_catalog)
+                .with_context(|| format!("Failed to parse the price catalog {:?}", path))?;
+            catalog.instances.splice(0..0, user_catalog.instances);
+            catalog.fallback = user_catalog.fallback.or(catalog.fallback);
+        }
+        Ok(catalog)
+    }
+
+    fn find_instance(&self, instance_type: &str, region: Option<&str>) -> Option<&InstancePrice> {
+        let matching = || {
+            self.instances
+                .iter()
+                .filter(move |price| price.instance_type == instance_type)
+        };
+        matching()
+            .find(|price| price.region.is_some() && price.region.as_deref() == region)
+            .or_else(|| matching().find(|price| price.region.is_none()))
+    }
+
+    /// Prices the machine from its instance type when the catalog lists it,
+    /// per core and GB of memory otherwise
+    pub fn get_rate(
+        &self,
+        instance: Option<&InstanceMetadata>,
+        cpus: usize,
+        memory_bytes: u64,
+    ) -> Option<MachineRate> {
+        let instance_type = instance.and_then(|instance| instance.instance_type.clone());
+        let price = instance_type.as_deref().and_then(|instance_type| {
+            self.find_instance(
+                instance_type,
+                instance.and_then(|instance| instance.region.as_deref()),
+            )
+        });
+
+        let (pricing, cpu_hourly_price, memory_hourly_price) = match price {
+            Some(price) => {
+                let spot_price = price
+                    .spot
+                    .filter(|_| instance.and_then(|instance| instance.spot) == Some(true));
+                let (pricing, hourly_price) = match spot_price {
+                    Some(spot_price) => (Pricing::Spot, spot_price),
+                    None => (Pricing::OnDemand, price.on_demand),
+                };
+                // Instance prices don't say what's paid for CPUs and memory, split evenly
+                (pricing, hourly_price / 2.0, hourly_price / 2.0)
+            }
+            None => {
+                let fallback = self.fallback.as_ref()?;
+                (
+                    Pricing::PerResource,
+                    fallback.core_hour * cpus as f64,
+                    fallback.gb_hour * memory_bytes as f64 / BYTES_PER_GB,
+                )
+            }
+        };
+
+        Some(MachineRate {
+            pricing,
+            instance_type,
+            hourly_price: cpu_hourly_price + memory_hourly_price,
+            cpu_hourly_price,
+            memory_hourly_price,
+            cpus,
+            memory_bytes,
+        })
+    }
+}
+
+impl MachineRate {
+    /// The whole machine over `duration`
+    pub fn cost(&self, duration: Duration) -> f64 {
+        self.hourly_price * duration.as_secs_f64() / SECONDS_PER_HOUR
+    }
+
+    /// A tool's share of the machine over its wall time, from its average CPU
+    /// usage (in percent of a CPU) and memory
+    pub fn tool_cost(&self, duration: Duration, cpu_usage: f64, memory_bytes: f64) -> f64 {
+        let share = |used: f64, total: f64| {
+            if total > 0.0 {
+                (used / total).clamp(0.0, 1.0)
+            } else {
+                0.0
+            }
+        };
+        let cpu_share = share(cpu_usage / 100.0, self.cpus as f64);
+        let memory_share = share(memory_bytes, self.memory_bytes as f64);
+        (cpu_share * self.cpu_hourly_price + memory_share * self.memory_hourly_price)
+            * duration.as_secs_f64()
+            / SECONDS_PER_HOUR
+    }
+
+    pub fn run_cost(&self, duration: Duration, tools_cost: f64) -> RunCost {
+        RunCost {
+            pricing: self.pricing,
+            instance_type: self.instance_type.clone(),
+            hourly_price: self.hourly_price,
+            duration_seconds: duration.as_secs_f64(),
+            instance_cost: self.cost(duration),
+            tools_cost,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud_metadata::CloudProvider;
+
+    const GB: u64 = 1024 * 1024 * 1024;
+
+    fn instance(instance_type: &str, region: &str, spot: bool) -> InstanceMetadata {
+        InstanceMetadata {
+            provider: CloudProvider::Aws,
+            instance_id: Some("i-0abc123".to_string()),
+            instance_type: Some(instance_type.to_string()),
+            region: Some(region.to_string()),
+            availability_zone: None,
+            image_id: None,
+            spot: Some(spot),
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_bundled_catalog() {
+        let catalog = PriceCatalog::bundled();
+        assert!(catalog.fallback.is_some());
+        assert!(catalog
+            .instances
+            .iter()
+            .all(|price| price.on_demand > 0.0 && price.spot.is_none_or(|spot| spot > 0.0)));
+    }
+
+    #[test]
+    fn test_get_rate() -> Result<()> {
+        let mut user_catalog = tempfile::NamedTempFile::new()?;
+        std::io::Write::write_all(
+            &mut user_catalog,
+            br#"
+[[instances]]
+instance_type = "r6i.2xlarge"
+region = "eu-west-1"
+on_demand = 0.6
+spot = 0.2
+
+[fallback]
+core_hour = 0.05
+gb_hour = 0.01
+"#,
+        )?;
+        let catalog = PriceCatalog::load(Some(user_catalog.path()))?;
+
+        // The user's regional price wins over the bundled one
+        let rate = catalog
+            .get_rate(
+                Some(&instance("r6i.2xlarge", "eu-west-1", false)),
+                8,
+                64 * GB,
+            )
+            .unwrap();
+        assert_eq!(rate.pricing, Pricing::OnDemand);
+        assert_close(rate.hourly_price, 0.6);
+        let rate = catalog
+            .get_rate(
+                Some(&instance("r6i.2xlarge", "eu-west-1", true)),
+                8,
+                64 * GB,
+            )
+            .unwrap();
+        assert_eq!(rate.pricing, Pricing::Spot);
+        assert_close(rate.hourly_price, 0.2);
+        let rate = catalog
+            .get_rate(
+                Some(&instance("r6i.2xlarge", "us-east-1", false)),
+                8,
+                64 * GB,
+            )
+            .unwrap();
+        assert_close(rate.hourly_price, 0.504);
+
+        // Machines off the cloud are priced per resource
+        let rate = catalog.get_rate(None, 16, 128 * GB).unwrap();
+        assert_eq!(rate.pricing, Pricing::PerResource);
+        assert_eq!(rate.instance_type, None);
+        assert_close(rate.hourly_price, 16.0 * 0.05 + 128.0 * 0.01);
+
+        assert!(PriceCatalog::load(Some(Path::new("/nonexistent/prices.toml"))).is_err());
+        assert_eq!(PriceCatalog::default().get_rate(None, 16, 128 * GB), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tool_cost() {
+        let catalog = PriceCatalog {
+            instances: vec![],
+            fallback: Some(FallbackPrice {
+                core_hour: 0.04,
+                gb_hour: 0.01,
+            }),
+        };
+        let rate = catalog.get_rate(None, 4, 16 * GB).unwrap();
+        let two_hours = Duration::from_secs(2 * 3600);
+
+        assert_close(rate.cost(two_hours), 2.0 * (4.0 * 0.04 + 16.0 * 0.01));
+        // Two of four CPUs and a quarter of the memory
+        assert_close(
+            rate.tool_cost(two_hours, 200.0, (4 * GB) as f64),
+            2.0 * (0.5 * 0.16 + 0.25 * 0.16),
+        );
+        // Usage over the machine's size is capped to it
+        assert_close(
+            rate.tool_cost(two_hours, 800.0, (32 * GB) as f64),
+            rate.cost(two_hours),
+        );
+
+        let run_cost = rate.run_cost(two_hours, 0.1);
+        assert_eq!(run_cost.pricing, Pricing::PerResource);
+        assert_close(run_cost.duration_seconds, 7200.0);
+        assert_close(run_cost.instance_cost, rate.cost(two_hours));
+    }
+}
@@ -1,179 +1,243 @@
-use std::path::PathBuf;
+use std::path::Path;
 
 // src/cli.rs
-use anyhow::Result;
-use serde::Deserialize;
-use serde_json::{from_str, json};
+use anyhow::{bail, Context, Result};
+use serde_json::json;
 
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::UnixStream,
-};
+use tokio::{io::BufReader, net::UnixStream, time::timeout};
 
 use crate::debug_log::Logger;
 use crate::process_watcher::ShortLivedProcessLog;
+use crate::run_assignment::RUN_ID_ENV_VAR;
+use crate::upload::upload_queue::UploadEntry;
+
+use super::protocol::{read_message, read_response, write_message, Handshake, PROTOCOL_VERSION};
+use super::structs::{InfoResponse, Request, Response, RunSelector};
+
+/// How long to wait for the daemon to answer the handshake. Daemons from
+/// before the handshake existed wait for the connection to close instead.
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Sends the request and waits for the daemon's response, turning a failed
+/// command into an error carrying the daemon's message.
+async fn send_request(socket_path: &str, request: &Request) -> Result<Response> {
+    let mut stream = BufReader::new(UnixStream::connect(socket_path).await?);
+
+    write_message(&mut stream, &Handshake::current()).await?;
+    let handshake: Handshake = timeout(HANDSHAKE_TIMEOUT, read_message(&mut stream))
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "The daemon didn't answer the protocol handshake, it is likely older than this CLI. Restart it with `tracer terminate` and `tracer init`."
+            )
+        })??
+        .context("The daemon closed the connection during the handshake")?;
+
+    if handshake.protocol_version != PROTOCOL_VERSION {
+        bail!(
+            "The daemon speaks protocol version {} but this CLI speaks version {}. Restart the daemon with `tracer terminate` and `tracer init`, or update tracer.",
+            handshake.protocol_version,
+            PROTOCOL_VERSION
+        );
+    }
 
-use super::structs::InfoResponse;
-
-pub async fn send_log_request(socket_path: &str, message: String) -> Result<()> {
-    let mut socket = UnixStream::connect(socket_path).await?;
-
-    let log_request = json!({
-            "command": "log",
-            "message": message
-    });
-    let log_request_json =
-        serde_json::to_string(&log_request).expect("Failed to serialize log request");
-    socket.write_all(log_request_json.as_bytes()).await?;
+    write_message(&mut stream, request).await?;
+    let response = read_response(&mut stream)
+        .await?
+        .context("The daemon closed the connection without responding")?;
 
-    Ok(())
+    match response {
+        Response::Error { message } => Err(anyhow::anyhow!(message)),
+        response => Ok(response),
+    }
 }
 
-pub async fn send_alert_request(socket_path: &str, message: String) -> Result<()> {
-    let mut socket = UnixStream::connect(socket_path).await?;
-    let alert_request: serde_json::Value = json!({
-            "command": "alert",
-            "message": message
-    });
-    let alert_request_json =
-        serde_json::to_string(&alert_request).expect("Failed to serialize alrt request");
-    socket.write_all(alert_request_json.as_bytes()).await?;
-
-    Ok(())
+fn unexpected_response(request: &Request, response: Response) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Unexpected response to {} command: {:?}",
+        request.name(),
+        response
+    )
 }
 
-pub async fn send_terminate_request(socket_path: &str) -> Result<()> {
-    let mut socket = UnixStream::connect(socket_path).await?;
-
-    let terminate_request = json!({
-            "command": "terminate"
-    });
-
-    let terminate_request_json =
-        serde_json::to_string(&terminate_request).expect("Failed to serialize terminate request");
-
-    socket.write_all(terminate_request_json.as_bytes()).await?;
-
-    Ok(())
+/// Sends a request that doesn't return anything besides success
+async fn send_command(socket_path: &str, request: Request) -> Result<()> {
+    match send_request(socket_path, &request).await? {
+        Response::Ok => Ok(()),
+        response => Err(unexpected_response(&request, response)),
+    }
 }
 
-pub async fn send_start_run_request(socket_path: &str) -> Result<()> {
-    let mut socket = UnixStream::connect(socket_path).await?;
-
-    let start_request = json!({
-            "command": "start"
-    });
-
-    let start_request_json =
-        serde_json::to_string(&start_request).expect("Failed to serialize start request");
-
-    socket.write_all(start_request_json.as_bytes()).await?;
-
-    socket.shutdown().await?;
-
-    #[derive(Deserialize)]
-    struct StartRunResponse {
-        run_name: String,
+/// The run given with `--run`, or through the environment. The CLI's parent
+/// and working directory let the daemon find the run otherwise.
+pub fn get_run_selector(run_id: Option<String>) -> RunSelector {
+    RunSelector {
+        run_id: run_id.or_else(|| {
+            std::env::var(RUN_ID_ENV_VAR)
+                .ok()
+                .filter(|run_id| !run_id.is_empty())
+        }),
+        parent_pid: Some(std::os::unix::process::parent_id()),
+        working_directory: std::env::current_dir()
+            .ok()
+            .map(|directory| directory.to_string_lossy().to_string()),
     }
-
-    let mut buffer = [0; 1024];
-    let n = socket.read(&mut buffer).await?;
-    let response = std::str::from_utf8(&buffer[..n])?;
-    let response: StartRunResponse = from_str(response)?;
-
-    println!("Started a new run with name: {}", response.run_name);
-
-    Ok(())
 }
 
-pub async fn send_end_run_request(socket_path: &str) -> Result<()> {
-    let mut socket = UnixStream::connect(socket_path).await?;
+pub async fn send_log_request(socket_path: &str, message: String) -> Result<()> {
+    send_command(socket_path, Request::Log { message }).await
+}
 
-    let end_request = json!({
-            "command": "end"
-    });
+pub async fn send_alert_request(socket_path: &str, message: String) -> Result<()> {
+    send_command(socket_path, Request::Alert { message }).await
+}
 
-    let end_request_json =
-        serde_json::to_string(&end_request).expect("Failed to serialize start request");
+pub async fn send_terminate_request(socket_path: &str) -> Result<()> {
+    send_command(socket_path, Request::Terminate).await
+}
 
-    socket.write_all(end_request_json.as_bytes()).await?;
+pub async fn send_start_run_request(socket_path: &str, run_id: Option<String>) -> Result<()> {
+    let request = Request::Start {
+        selector: get_run_selector(run_id),
+    };
+
+    let info = match send_request(socket_path, &request).await? {
+        Response::Info(info) => info,
+        response => return Err(unexpected_response(&request, response)),
+    };
+
+    println!(
+        "Started a new run with name: {} (id: {})",
+        info.run_name, info.run_id
+    );
+    println!(
+        "To attribute other commands to this run, run: export {}={}",
+        RUN_ID_ENV_VAR, info.run_id
+    );
 
     Ok(())
 }
 
-pub async fn send_info_request(socket_path: &str) -> Result<InfoResponse> {
-    let mut socket = UnixStream::connect(socket_path).await?;
+pub async fn send_end_run_request(socket_path: &str, run_id: Option<String>) -> Result<()> {
+    send_command(
+        socket_path,
+        Request::End {
+            selector: get_run_selector(run_id),
+        },
+    )
+    .await
+}
 
-    let ping_request = json!({
-            "command": "info"
-    });
+/// The active runs visible to the caller, or only the one given
+pub async fn send_info_request(
+    socket_path: &str,
+    run_id: Option<String>,
+) -> Result<Vec<InfoResponse>> {
+    let request = Request::Info {
+        selector: RunSelector {
+            run_id,
+            ..Default::default()
+        },
+    };
+
+    match send_request(socket_path, &request).await? {
+        Response::Runs(runs) => Ok(runs.runs),
+        response => Err(unexpected_response(&request, response)),
+    }
+}
 
-    let info_request_json =
-        serde_json::to_string(&ping_request).expect("Failed to serialize info request");
+pub async fn send_lineage_request(
+    socket_path: &str,
+    run_id: Option<String>,
+    format: &str,
+) -> Result<String> {
+    let request = Request::Lineage {
+        selector: get_run_selector(run_id),
+        format: Some(format.to_string()),
+    };
+
+    match send_request(socket_path, &request).await? {
+        Response::Lineage(lineage) => Ok(lineage.lineage),
+        response => Err(unexpected_response(&request, response)),
+    }
+}
 
-    socket.write_all(info_request_json.as_bytes()).await?;
+pub async fn send_report_request(
+    socket_path: &str,
+    run_id: Option<String>,
+    format: &str,
+) -> Result<String> {
+    let request = Request::Report {
+        selector: get_run_selector(run_id),
+        format: Some(format.to_string()),
+    };
+
+    match send_request(socket_path, &request).await? {
+        Response::Report(report) => Ok(report.report),
+        response => Err(unexpected_response(&request, response)),
+    }
+}
 
-    socket.shutdown().await?;
+pub async fn send_timeline_request(
+    socket_path: &str,
+    run_id: Option<String>,
+    group_by: &str,
+) -> Result<String> {
+    let request = Request::Timeline {
+        selector: get_run_selector(run_id),
+        group_by: Some(group_by.to_string()),
+    };
+
+    match send_request(socket_path, &request).await? {
+        Response::Timeline(timeline) => Ok(timeline.trace),
+        response => Err(unexpected_response(&request, response)),
+    }
+}
 
-    let mut buffer = [0; 1024];
-    let n = socket.read(&mut buffer).await?;
-    let response = std::str::from_utf8(&buffer[..n])?;
-    let response: InfoResponse = from_str(response)?;
+pub async fn send_uploads_request(socket_path: &str) -> Result<Vec<UploadEntry>> {
+    let request = Request::Uploads;
 
-    Ok(response)
+    match send_request(socket_path, &request).await? {
+        Response::Uploads(uploads) => Ok(uploads.uploads),
+        response => Err(unexpected_response(&request, response)),
+    }
 }
 
 pub async fn send_refresh_config_request(socket_path: &str) -> Result<()> {
-    let mut socket = UnixStream::connect(socket_path).await?;
-
-    let setup_request = json!({
-            "command": "refresh_config"
-    });
-
-    let setup_request_json =
-        serde_json::to_string(&setup_request).expect("Failed to serialize setup request");
-
-    socket.write_all(setup_request_json.as_bytes()).await?;
-
-    Ok(())
+    send_command(socket_path, Request::RefreshConfig).await
 }
 
-pub async fn send_update_tags_request(socket_path: &str, tags: &Vec<String>) -> Result<()> {
-    let mut socket = UnixStream::connect(socket_path).await?;
-
-    let tag_request = json!({
-            "command": "tag",
-            "tags": tags
-    });
-
-    let tag_request_json =
-        serde_json::to_string(&tag_request).expect("Failed to serialize tag request");
-
-    socket.write_all(tag_request_json.as_bytes()).await?;
-
-    Ok(())
+pub async fn send_update_tags_request(
+    socket_path: &str,
+    tags: &[String],
+    run_id: Option<String>,
+) -> Result<()> {
+    send_command(
+        socket_path,
+        Request::Tag {
+            tags: tags.to_vec(),
+            selector: get_run_selector(run_id),
+        },
+    )
+    .await
 }
 
 pub async fn send_log_short_lived_process_request(
     socket_path: &str,
     log: ShortLivedProcessLog,
 ) -> Result<()> {
-    let mut socket = UnixStream::connect(socket_path).await?;
-
-    let log_request = json!({
-            "command": "log_short_lived_process",
-            "log": log
-    });
-
-    let log_request_json =
-        serde_json::to_string(&log_request).expect("Failed to serialize log request");
-
-    socket.write_all(log_request_json.as_bytes()).await?;
-
-    Ok(())
+    send_command(
+        socket_path,
+        Request::LogShortLivedProcess { log: Box::new(log) },
+    )
+    .await
 }
 
-pub async fn send_upload_file_request(socket_path: &str, file_path: &PathBuf) -> Result<()> {
+/// Returns the id of the upload, or `None` if the same content was uploaded before
+pub async fn send_upload_file_request(
+    socket_path: &str,
+    file_path: &Path,
+) -> Result<Option<String>> {
     let logger = Logger::new();
     logger
         .log(
@@ -186,34 +250,36 @@ pub async fn send_upload_file_request(socket_path: &str, file_path: &PathBuf) ->
         )
         .await;
 
-    let mut socket = UnixStream::connect(socket_path).await?;
-
-    let upload_request = json!({
-        "command": "upload",
-        "file_path": file_path
-    });
+    let request = Request::Upload {
+        file_path: file_path.to_string_lossy().to_string(),
+        selector: get_run_selector(None),
+    };
 
-    let upload_request_json =
-        serde_json::to_string(&upload_request).expect("Failed to serialize upload request");
-
-    socket.write_all(upload_request_json.as_bytes()).await?;
+    let upload = match send_request(socket_path, &request).await? {
+        Response::Upload(upload) => upload,
+        response => return Err(unexpected_response(&request, response)),
+    };
 
     logger
         .log(
-            "send_upload_file_request//socket.write_all",
-            Some(&upload_request),
+            "send_upload_file_request//response",
+            Some(&json!({ "upload_id": upload.upload_id })),
         )
         .await;
 
-    Ok(())
+    Ok(upload.upload_id)
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use crate::SOCKET_PATH;
+    use crate::daemon_communication::structs::{RunsResponse, UploadResponse};
+    use serde_json::Value;
     use serial_test::serial;
-    use tokio::{io::AsyncReadExt, net::UnixListener};
+    use std::path::PathBuf;
+    use tokio::{net::UnixListener, task::JoinHandle};
+
+    const SOCKET_PATH: &str = "/tmp/tracerd-client-test.sock";
 
     fn setup_test_unix_listener() -> UnixListener {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -224,12 +290,27 @@ mod tests {
         UnixListener::bind(SOCKET_PATH).expect("Failed to bind to unix socket")
     }
 
-    async fn check_listener_value(listener: &UnixListener, expected_value: &str) {
-        let (mut stream, _) = listener.accept().await.unwrap();
-        let mut buffer = [0; 1024];
-        let n = stream.read(&mut buffer).await.unwrap();
-        let received = std::str::from_utf8(&buffer[..n]).unwrap();
-        assert_eq!(received, expected_value);
+    /// Answers a single request the way the daemon would, returning what it received
+    pub(crate) fn respond_to_request(
+        listener: UnixListener,
+        protocol_version: u32,
+        response: Response,
+    ) -> JoinHandle<Option<Value>> {
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+
+            let _: Handshake = read_message(&mut stream).await.unwrap().unwrap();
+            write_message(&mut stream, &Handshake { protocol_version })
+                .await
+                .unwrap();
+
+            let received: Option<Value> = read_message(&mut stream).await.unwrap();
+            if received.is_some() {
+                write_message(&mut stream, &response).await.unwrap();
+            }
+            received
+        })
     }
 
     #[tokio::test]
@@ -237,19 +318,17 @@ mod tests {
     async fn test_send_log_request() -> Result<()> {
         let listener = setup_test_unix_listener();
         let message = "Test Message".to_string();
+        let received = respond_to_request(listener, PROTOCOL_VERSION, Response::Ok);
 
         send_log_request(SOCKET_PATH, message.clone()).await?;
 
-        check_listener_value(
-            &listener,
-            json!({
+        assert_eq!(
+            received.await?,
+            Some(json!({
                 "command": "log",
                 "message": message
-            })
-            .to_string()
-            .as_str(),
-        )
-        .await;
+            }))
+        );
 
         Ok(())
     }
@@ -259,19 +338,17 @@ mod tests {
     async fn test_send_alert_request() -> Result<()> {
         let listener = setup_test_unix_listener();
         let message = "Test Message".to_string();
+        let received = respond_to_request(listener, PROTOCOL_VERSION, Response::Ok);
 
         send_alert_request(SOCKET_PATH, message.clone()).await?;
 
-        check_listener_value(
-            &listener,
-            json!({
+        assert_eq!(
+            received.await?,
+            Some(json!({
                 "command": "alert",
                 "message": message
-            })
-            .to_string()
-            .as_str(),
-        )
-        .await;
+            }))
+        );
 
         Ok(())
     }
@@ -280,18 +357,16 @@ mod tests {
     #[serial]
     async fn test_send_terminate_request() -> Result<()> {
         let listener = setup_test_unix_listener();
+        let received = respond_to_request(listener, PROTOCOL_VERSION, Response::Ok);
 
         send_terminate_request(SOCKET_PATH).await?;
 
-        check_listener_value(
-            &listener,
-            json!({
+        assert_eq!(
+            received.await?,
+            Some(json!({
                 "command": "terminate"
-            })
-            .to_string()
-            .as_str(),
-        )
-        .await;
+            }))
+        );
 
         Ok(())
     }
@@ -300,18 +375,17 @@ mod tests {
     #[serial]
     async fn test_send_end_run_request() -> Result<()> {
         let listener = setup_test_unix_listener();
+        let received = respond_to_request(listener, PROTOCOL_VERSION, Response::Ok);
 
-        send_end_run_request(SOCKET_PATH).await?;
+        send_end_run_request(SOCKET_PATH, Some("run-1".to_string())).await?;
 
-        check_listener_value(
-            &listener,
-            json!({
-                "command": "end"
-            })
-            .to_string()
-            .as_str(),
-        )
-        .await;
+        assert_eq!(
+            received.await?,
+            Some(json!({
+                "command": "end",
+                "selector": get_run_selector(Some("run-1".to_string()))
+            }))
+        );
 
         Ok(())
     }
@@ -320,18 +394,16 @@ mod tests {
     #[serial]
     async fn test_send_refresh_config_request() -> Result<()> {
         let listener = setup_test_unix_listener();
+        let received = respond_to_request(listener, PROTOCOL_VERSION, Response::Ok);
 
         send_refresh_config_request(SOCKET_PATH).await?;
 
-        check_listener_value(
-            &listener,
-            json!({
+        assert_eq!(
+            received.await?,
+            Some(json!({
                 "command": "refresh_config"
-            })
-            .to_string()
-            .as_str(),
-        )
-        .await;
+            }))
+        );
 
         Ok(())
     }
@@ -341,19 +413,18 @@ mod tests {
     async fn test_send_update_tags_request() -> Result<()> {
         let listener = setup_test_unix_listener();
         let tags = vec!["tag1".to_string(), "tag2".to_string(), "tag3".to_string()];
+        let received = respond_to_request(listener, PROTOCOL_VERSION, Response::Ok);
 
-        send_update_tags_request(SOCKET_PATH, &tags).await?;
+        send_update_tags_request(SOCKET_PATH, &tags, None).await?;
 
-        check_listener_value(
-            &listener,
-            json!({
+        assert_eq!(
+            received.await?,
+            Some(json!({
                 "command": "tag",
-                "tags": tags
-            })
-            .to_string()
-            .as_str(),
-        )
-        .await;
+                "tags": tags,
+                "selector": get_run_selector(None)
+            }))
+        );
 
         Ok(())
     }
@@ -363,19 +434,104 @@ mod tests {
     async fn test_send_upload_file_request() -> Result<()> {
         let listener = setup_test_unix_listener();
         let file_path = PathBuf::from("log_outgoing_http_calls.txt".to_string());
+        let received = respond_to_request(
+            listener,
+            PROTOCOL_VERSION,
+            Response::Upload(UploadResponse {
+                upload_id: Some("upload-1".to_string()),
+            }),
+        );
+
+        let upload_id = send_upload_file_request(SOCKET_PATH, &file_path).await?;
+        assert_eq!(upload_id, Some("upload-1".to_string()));
+
+        assert_eq!(
+            received.await?,
+            Some(json!({
+                "command": "upload",
+                "file_path": file_path.clone(),
+                "selector": get_run_selector(None)
+            }))
+        );
 
-        send_upload_file_request(SOCKET_PATH, &file_path).await?;
+        Ok(())
+    }
 
-        check_listener_value(
-            &listener,
-            json!({
-                "command": "upload",
-                "file_path": file_path.clone()
-            })
+    #[tokio::test]
+    #[serial]
+    async fn test_send_info_request_reads_payload() -> Result<()> {
+        let listener = setup_test_unix_listener();
+        let received = respond_to_request(
+            listener,
+            PROTOCOL_VERSION,
+            Response::Runs(RunsResponse {
+                runs: vec![InfoResponse {
+                    run_name: "brave-ant".to_string(),
+                    run_id: "run-1".to_string(),
+                    service_name: "tracer".to_string(),
+                    pipeline: None,
+                    estimated_cost: None,
+                }],
+            }),
+        );
+
+        let runs = send_info_request(SOCKET_PATH, Some("run-1".to_string())).await?;
+
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].run_name, "brave-ant");
+        assert_eq!(runs[0].run_id, "run-1");
+        assert_eq!(
+            received.await?,
+            Some(json!({
+                "command": "info",
+                "selector": {
+                    "run_id": "run-1",
+                    "parent_pid": null,
+                    "working_directory": null
+                }
+            }))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_failed_command_returns_daemon_error() -> Result<()> {
+        let listener = setup_test_unix_listener();
+        let received = respond_to_request(
+            listener,
+            PROTOCOL_VERSION,
+            Response::Error {
+                message: "Error while sending send_http_event: 500".to_string(),
+            },
+        );
+
+        let result = send_update_tags_request(SOCKET_PATH, &["tag".to_string()], None).await;
+
+        received.await?;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Error while sending send_http_event: 500"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_protocol_version_mismatch_is_reported() -> Result<()> {
+        let listener = setup_test_unix_listener();
+        let received = respond_to_request(listener, PROTOCOL_VERSION + 1, Response::Ok);
+
+        let result = send_end_run_request(SOCKET_PATH, None).await;
+
+        // The request is never sent to a daemon speaking another version
+        assert_eq!(received.await?, None);
+        assert!(result
+            .unwrap_err()
             .to_string()
-            .as_str(),
-        )
-        .await;
+            .contains(&format!("protocol version {}", PROTOCOL_VERSION + 1)));
 
         Ok(())
     }
@@ -0,0 +1,147 @@
+// src/daemon_communication/protocol.rs
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Bumped whenever `Request` or `Response` change incompatibly
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Longest message the daemon accepts, so a bad client can't exhaust its
+/// memory. Responses aren't capped: reports, timelines and lineage graphs of
+/// long runs are larger, and the client trusts its daemon.
+pub const MAX_MESSAGE_BYTES: u64 = 1024 * 1024;
+
+/// First message in each direction on every connection. The daemon answers
+/// with its own version and drops the connection if the two don't match.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+}
+
+impl Handshake {
+    pub fn current() -> Handshake {
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// Messages are JSON documents, one per line
+pub async fn write_message<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads the next message of at most `MAX_MESSAGE_BYTES`, or `None` if the
+/// peer closed the connection first
+pub async fn read_message<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: AsyncBufRead + Unpin,
+    T: DeserializeOwned,
+{
+    read_message_up_to(reader, MAX_MESSAGE_BYTES).await
+}
+
+/// Reads the next message whatever its size, for responses of the daemon
+pub async fn read_response<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: AsyncBufRead + Unpin,
+    T: DeserializeOwned,
+{
+    read_message_up_to(reader, u64::MAX).await
+}
+
+async fn read_message_up_to<R, T>(reader: &mut R, max_bytes: u64) -> Result<Option<T>>
+where
+    R: AsyncBufRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut line = String::new();
+    let read = (&mut *reader)
+        .take(max_bytes.saturating_add(1))
+        .read_line(&mut line)
+        .await?;
+
+    if read == 0 {
+        return Ok(None);
+    }
+    if read as u64 > max_bytes {
+        bail!("Message is longer than {} bytes", max_bytes);
+    }
+
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_messages_are_newline_delimited() -> Result<()> {
+        let mut buffer = vec![];
+        write_message(&mut buffer, &Handshake::current()).await?;
+        write_message(
+            &mut buffer,
+            &Handshake {
+                protocol_version: 7,
+            },
+        )
+        .await?;
+
+        assert_eq!(
+            String::from_utf8(buffer.clone())?,
+            format!(
+                "{{\"protocol_version\":{}}}\n{{\"protocol_version\":7}}\n",
+                PROTOCOL_VERSION
+            )
+        );
+
+        let mut reader = BufReader::new(buffer.as_slice());
+        let first: Option<Handshake> = read_message(&mut reader).await?;
+        let second: Option<Handshake> = read_message(&mut reader).await?;
+        let end: Option<Handshake> = read_message(&mut reader).await?;
+
+        assert_eq!(first, Some(Handshake::current()));
+        assert_eq!(
+            second,
+            Some(Handshake {
+                protocol_version: 7
+            })
+        );
+        assert_eq!(end, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_rejected() {
+        let message = vec![b'a'; MAX_MESSAGE_BYTES as usize + 10];
+        let mut reader = BufReader::new(message.as_slice());
+
+        let result: Result<Option<Handshake>> = read_message(&mut reader).await;
+
+        assert!(result.unwrap_err().to_string().contains("longer than"));
+    }
+
+    #[tokio::test]
+    async fn test_responses_are_not_capped() -> Result<()> {
+        let report = "a".repeat(MAX_MESSAGE_BYTES as usize * 2);
+        let mut buffer = vec![];
+        write_message(&mut buffer, &report).await?;
+        let mut reader = BufReader::new(buffer.as_slice());
+
+        let response: Option<String> = read_response(&mut reader).await?;
+
+        assert_eq!(response, Some(report));
+
+        Ok(())
+    }
+}
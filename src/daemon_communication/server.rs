@@ -1,11 +1,23 @@
-use anyhow::{Ok, Result};
-use core::panic;
-use serde_json::{json, Value};
-use std::{future::Future, pin::Pin, sync::Arc};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use std::{
+    fs::OpenOptions,
+    future::Future,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+use sysinfo::Pid;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncWrite, BufReader},
     net::{UnixListener, UnixStream},
-    sync::{Mutex, RwLock},
+    sync::{mpsc, oneshot, Mutex, RwLock, Semaphore},
+    time::timeout,
 };
 use tokio_util::sync::CancellationToken;
 
@@ -14,119 +26,300 @@ use crate::{
     debug_log::Logger,
     events::{send_alert_event, send_log_event, send_update_tags_event},
     process_watcher::ShortLivedProcessLog,
-    tracer_client::TracerClient,
-    upload::upload_from_file_path,
+    runtime_paths::current_uid,
+    tracer_client::{RunMetadata, RunOrigin, TracerClient},
+    upload::upload_queue::{UploadOrigin, UploadStatus},
+    users::get_username,
+};
+
+use super::protocol::{read_message, write_message, Handshake, PROTOCOL_VERSION};
+use super::structs::{
+    InfoResponse, LineageResponse, ReportResponse, Request, Response, RunSelector, RunsResponse,
+    TimelineResponse, UploadResponse, UploadsResponse,
 };
 
-type ProcessOutput<'a> =
-    Option<Pin<Box<dyn Future<Output = Result<String, anyhow::Error>> + 'a + Send>>>;
+/// Connections handled at the same time; further clients wait to be accepted
+const MAX_CONNECTIONS: usize = 64;
+/// How long a client has to send the handshake and its request
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+/// Uploads wait for the first attempt, which takes a while for large files
+const UPLOAD_COMMAND_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Who sent a request. Users other than root and the user running the daemon
+/// only reach a system-wide daemon, and only act on their own run there.
+#[derive(Clone, Copy, Debug)]
+pub struct Caller {
+    pub uid: u32,
+    pub is_admin: bool,
+    /// Login session the CLI runs in, whose processes the caller's runs track
+    pub session_id: Option<Pid>,
+}
+
+impl Caller {
+    pub fn new(uid: u32, daemon_uid: u32) -> Caller {
+        Caller {
+            uid,
+            is_admin: uid == daemon_uid || uid == 0,
+            session_id: None,
+        }
+    }
+
+    pub fn with_session_of(mut self, pid: Option<i32>) -> Caller {
+        self.session_id = pid.and_then(get_session_id);
+        self
+    }
+
+    /// Owner of the runs this caller starts and controls, `None` for all runs
+    fn run_owner(&self) -> Option<u32> {
+        if self.is_admin {
+            None
+        } else {
+            Some(self.uid)
+        }
+    }
+}
+
+fn get_session_id(pid: i32) -> Option<Pid> {
+    let session_id = unsafe { libc::getsid(pid) };
+    (session_id > 0).then(|| Pid::from_u32(session_id as u32))
+}
+
+fn check_run_access(tracer_client: &TracerClient, run_id: &str, caller: Caller) -> Result<()> {
+    if !tracer_client.can_control_run(run_id, caller.run_owner()) {
+        return Err(anyhow::anyhow!(
+            "Permission denied: run {} belongs to another user",
+            run_id
+        ));
+    }
+    Ok(())
+}
+
+/// The run the command refers to, if the caller may control it
+fn resolve_run(
+    tracer_client: &TracerClient,
+    selector: &RunSelector,
+    caller: Caller,
+) -> Result<Option<String>> {
+    let run_id = tracer_client.resolve_run(
+        selector.run_id.as_deref(),
+        selector.parent_pid.map(Pid::from_u32),
+        selector.working_directory.as_deref(),
+        caller.run_owner(),
+    )?;
+    if let Some(run_id) = &run_id {
+        check_run_access(tracer_client, run_id, caller)?;
+    }
+    Ok(run_id)
+}
+
+type ProcessOutput<'a> = Pin<Box<dyn Future<Output = Result<Response, anyhow::Error>> + 'a + Send>>;
 
 pub fn process_log_command<'a>(
     service_url: &'a str,
     api_key: &'a str,
-    object: &serde_json::Map<String, serde_json::Value>,
+    message: String,
 ) -> ProcessOutput<'a> {
-    if !object.contains_key("message") {
-        return None;
-    };
-
-    let message = object.get("message").unwrap().as_str().unwrap().to_string();
-    Some(Box::pin(send_log_event(service_url, api_key, message)))
+    Box::pin(async move {
+        send_log_event(service_url, api_key, message).await?;
+        Ok(Response::Ok)
+    })
 }
 
 pub fn process_alert_command<'a>(
     service_url: &'a str,
     api_key: &'a str,
-    object: &serde_json::Map<String, serde_json::Value>,
+    message: String,
 ) -> ProcessOutput<'a> {
-    if !object.contains_key("message") {
-        return None;
-    };
+    Box::pin(async move {
+        send_alert_event(service_url, api_key, message).await?;
+        Ok(Response::Ok)
+    })
+}
 
-    let message = object.get("message").unwrap().as_str().unwrap().to_string();
-    Some(Box::pin(send_alert_event(service_url, api_key, message)))
+fn get_run_info(tracer_client: &TracerClient, run: RunMetadata) -> InfoResponse {
+    InfoResponse {
+        estimated_cost: tracer_client.get_run_cost(&run.id),
+        run_name: run.name,
+        run_id: run.id,
+        service_name: run.service_name,
+        pipeline: run.pipeline,
+    }
 }
 
-pub fn process_start_run_command<'a>(
-    tracer_client: &'a Arc<Mutex<TracerClient>>,
-    stream: &'a mut UnixStream,
-) -> ProcessOutput<'a> {
-    async fn fun<'a>(
-        tracer_client: &'a Arc<Mutex<TracerClient>>,
-        stream: &'a mut UnixStream,
-    ) -> Result<String, anyhow::Error> {
-        tracer_client.lock().await.start_new_run(None).await?;
-
-        let info = tracer_client.lock().await.get_run_metadata();
-
-        let output = if let Some(info) = info {
-            json!({
-                "run_name": info.name,
-                "run_id": info.id,
-                "service_name": info.service_name,
-            })
-        } else {
-            json!({
-                "run_name": "",
-                "run_id": "",
-                "service_name": "",
-            })
+/// Starts a run next to the active ones. A run given explicitly is replaced.
+pub fn process_start_run_command(
+    tracer_client: &Arc<Mutex<TracerClient>>,
+    selector: RunSelector,
+    caller: Caller,
+) -> ProcessOutput<'_> {
+    Box::pin(async move {
+        let mut tracer_client = tracer_client.lock().await;
+
+        if let Some(run_id) = &selector.run_id {
+            check_run_access(&tracer_client, run_id, caller)?;
+            tracer_client
+                .stop_run(run_id, "Run replaced by a new one")
+                .await?;
+        }
+
+        let origin = RunOrigin {
+            owner_uid: caller.run_owner(),
+            parent_pid: selector.parent_pid.map(Pid::from_u32),
+            working_directory: selector.working_directory,
+            session_id: caller.session_id,
+            ..Default::default()
         };
+        let run_id = tracer_client.start_new_run(None, origin).await?;
 
-        stream
-            .write_all(serde_json::to_string(&output)?.as_bytes())
-            .await?;
+        let run = tracer_client
+            .get_run_metadata(&run_id)
+            .ok_or_else(|| anyhow::anyhow!("Run {} disappeared", run_id))?;
+        Ok(Response::Info(Box::new(get_run_info(&tracer_client, run))))
+    })
+}
 
-        stream.flush().await?;
+/// The run given explicitly, or every active run the caller may see
+pub fn process_info_command(
+    tracer_client: &Arc<Mutex<TracerClient>>,
+    selector: RunSelector,
+    caller: Caller,
+) -> ProcessOutput<'_> {
+    Box::pin(async move {
+        let tracer_client = tracer_client.lock().await;
+
+        let runs = match &selector.run_id {
+            Some(run_id) => {
+                check_run_access(&tracer_client, run_id, caller)?;
+                vec![tracer_client
+                    .get_run_metadata(run_id)
+                    .ok_or_else(|| anyhow::anyhow!("No active run with id {}", run_id))?]
+            }
+            None => tracer_client
+                .get_runs()
+                .into_iter()
+                .filter(|run| caller.is_admin || run.owner_uid == Some(caller.uid))
+                .collect(),
+        };
 
-        Ok("".to_string())
+        Ok(Response::Runs(RunsResponse {
+            runs: runs
+                .into_iter()
+                .map(|run| get_run_info(&tracer_client, run))
+                .collect(),
+        }))
+    })
+}
+
+/// The active run the caller refers to. `None` for a run that ended, or when
+/// no run is active, which commands on ended runs look up with `get_ended_run`.
+fn resolve_active_run(
+    tracer_client: &TracerClient,
+    selector: &RunSelector,
+    caller: Caller,
+) -> Result<Option<String>> {
+    match &selector.run_id {
+        Some(run_id) if tracer_client.get_run_metadata(run_id).is_none() => Ok(None),
+        _ => resolve_run(tracer_client, selector, caller),
     }
+}
 
-    Some(Box::pin(fun(tracer_client, stream)))
+fn no_run_found(selector: &RunSelector) -> anyhow::Error {
+    match &selector.run_id {
+        Some(run_id) => anyhow::anyhow!("No active or recently ended run with id {}", run_id),
+        None => anyhow::anyhow!("No active or recently ended run"),
+    }
 }
 
-pub fn process_info_command<'a>(
-    tracer_client: &'a Arc<Mutex<TracerClient>>,
-    stream: &'a mut UnixStream,
-) -> ProcessOutput<'a> {
-    async fn fun<'a>(
-        tracer_client: &'a Arc<Mutex<TracerClient>>,
-        stream: &'a mut UnixStream,
-    ) -> Result<String, anyhow::Error> {
-        let out = tracer_client.lock().await.get_run_metadata();
-
-        let output = if let Some(out) = out {
-            json!({
-                "run_name": out.name,
-                "run_id": out.id,
-                "service_name": out.service_name,
-            })
-        } else {
-            json!({
-                "run_name": "",
-                "run_id": "",
-                "service_name": "",
-            })
-        };
+/// Report of the run the caller refers to, or of the last one they ended if none is active
+pub fn process_report_command(
+    tracer_client: &Arc<Mutex<TracerClient>>,
+    selector: RunSelector,
+    format: Option<String>,
+    caller: Caller,
+) -> ProcessOutput<'_> {
+    let format = format.unwrap_or_else(|| "md".to_string());
+
+    Box::pin(async move {
+        let tracer_client = tracer_client.lock().await;
+        let report = match resolve_active_run(&tracer_client, &selector, caller)? {
+            Some(run_id) => tracer_client.build_run_report(&run_id, None),
+            None => tracer_client
+                .get_ended_run(selector.run_id.as_deref(), caller.run_owner())
+                .map(|ended_run| ended_run.report.clone()),
+        }
+        .ok_or_else(|| no_run_found(&selector))?;
 
-        stream
-            .write_all(serde_json::to_string(&output)?.as_bytes())
-            .await?;
+        Ok(Response::Report(ReportResponse {
+            report: report.export(&format)?,
+            format,
+        }))
+    })
+}
 
-        stream.flush().await?;
+/// Lineage of the run the caller refers to, or of the last one they ended if none is active
+pub fn process_lineage_command(
+    tracer_client: &Arc<Mutex<TracerClient>>,
+    selector: RunSelector,
+    format: Option<String>,
+    caller: Caller,
+) -> ProcessOutput<'_> {
+    let format = format.unwrap_or_else(|| "dot".to_string());
+
+    Box::pin(async move {
+        let tracer_client = tracer_client.lock().await;
+        let lineage = match resolve_active_run(&tracer_client, &selector, caller)? {
+            Some(run_id) => tracer_client.export_lineage(&run_id, &format),
+            None => tracer_client
+                .get_ended_run(selector.run_id.as_deref(), caller.run_owner())
+                .ok_or_else(|| no_run_found(&selector))?
+                .lineage
+                .export(&format),
+        }?;
+
+        Ok(Response::Lineage(LineageResponse { format, lineage }))
+    })
+}
 
-        Ok("".to_string())
-    }
+/// Timeline of the run the caller refers to, or of the last one they ended if none is active
+pub fn process_timeline_command(
+    tracer_client: &Arc<Mutex<TracerClient>>,
+    selector: RunSelector,
+    group_by: Option<String>,
+    caller: Caller,
+) -> ProcessOutput<'_> {
+    let group_by = group_by.unwrap_or_else(|| "process".to_string());
+
+    Box::pin(async move {
+        let tracer_client = tracer_client.lock().await;
+        let trace = match resolve_active_run(&tracer_client, &selector, caller)? {
+            Some(run_id) => tracer_client
+                .build_run_timeline(&run_id, Utc::now())
+                .map(|timeline| timeline.to_chrome_trace(&group_by)),
+            None => tracer_client
+                .get_ended_run(selector.run_id.as_deref(), caller.run_owner())
+                .map(|ended_run| ended_run.timeline.to_chrome_trace(&group_by)),
+        }
+        .ok_or_else(|| no_run_found(&selector))??;
 
-    Some(Box::pin(fun(tracer_client, stream)))
+        Ok(Response::Timeline(TimelineResponse { group_by, trace }))
+    })
 }
 
-pub fn process_end_run_command(tracer_client: &Arc<Mutex<TracerClient>>) -> ProcessOutput<'_> {
-    Some(Box::pin(async move {
+pub fn process_end_run_command(
+    tracer_client: &Arc<Mutex<TracerClient>>,
+    selector: RunSelector,
+    caller: Caller,
+) -> ProcessOutput<'_> {
+    Box::pin(async move {
         let mut tracer_client = tracer_client.lock().await;
-        tracer_client.stop_run().await?;
-        Ok("".to_string())
-    }))
+        let run_id = resolve_run(&tracer_client, &selector, caller)?
+            .ok_or_else(|| anyhow::anyhow!("No active run to end"))?;
+        tracer_client
+            .stop_run(&run_id, "Run ended by the user")
+            .await?;
+        Ok(Response::Ok)
+    })
 }
 
 pub fn process_refresh_config_command<'a>(
@@ -135,166 +328,601 @@ pub fn process_refresh_config_command<'a>(
 ) -> ProcessOutput<'a> {
     let config_file = ConfigManager::load_config();
 
-    async fn fun<'a>(
-        tracer_client: &'a Arc<Mutex<TracerClient>>,
-        config: &'a Arc<RwLock<Config>>,
-        config_file: crate::config_manager::Config,
-    ) -> Result<String, anyhow::Error> {
+    Box::pin(async move {
         tracer_client.lock().await.reload_config_file(&config_file);
         config.write().await.clone_from(&config_file);
-        Ok("".to_string())
-    }
-
-    Some(Box::pin(fun(tracer_client, config, config_file)))
+        Ok(Response::Ok)
+    })
 }
 
 pub fn process_tag_command<'a>(
     service_url: &'a str,
     api_key: &'a str,
-    object: &serde_json::Map<String, serde_json::Value>,
+    run_id: Option<String>,
+    tags: Vec<String>,
 ) -> ProcessOutput<'a> {
-    if !object.contains_key("tags") {
-        return None;
-    };
+    Box::pin(async move {
+        send_update_tags_event(service_url, api_key, run_id.as_deref(), tags).await?;
+        Ok(Response::Ok)
+    })
+}
+
+pub fn process_log_short_lived_process_command(
+    tracer_client: &Arc<Mutex<TracerClient>>,
+    mut log: ShortLivedProcessLog,
+    caller: Caller,
+) -> ProcessOutput<'_> {
+    // Users can't attribute their processes to someone else
+    if !caller.is_admin {
+        log.properties.process_uid = Some(caller.uid);
+        log.properties.process_username = get_username(caller.uid);
+    }
+
+    Box::pin(async move {
+        let mut tracer_client = tracer_client.lock().await;
+        tracer_client.fill_logs_with_short_lived_process(log)?;
+        Ok(Response::Ok)
+    })
+}
+
+/// Queues the file and waits for the first attempt at uploading it, so the
+/// caller learns whether it went through.
+pub fn process_upload_command(
+    tracer_client: &Arc<Mutex<TracerClient>>,
+    file_path: String,
+    selector: RunSelector,
+    caller: Caller,
+) -> ProcessOutput<'_> {
+    Box::pin(async move {
+        let logger = Logger::new();
+
+        logger.log("server.rs//process_upload_command", None).await;
 
-    let tags_json = object.get("tags").unwrap().as_array().unwrap();
+        // The daemon may be able to read files the caller can't. The file is
+        // opened once, without following a final symlink, and the snapshot is
+        // taken through that descriptor, so the file checked is the one uploaded.
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+            .open(&file_path)
+            .with_context(|| format!("Failed to open {}", file_path))?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(anyhow::anyhow!("{} is not a regular file", file_path));
+        }
+        if !caller.is_admin && metadata.uid() != caller.uid {
+            return Err(anyhow::anyhow!(
+                "Permission denied: {} doesn't belong to uid {}",
+                file_path,
+                caller.uid
+            ));
+        }
+        let upload_from = format!("/proc/self/fd/{}", file.as_raw_fd());
+        let file_name = Path::new(&file_path)
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid file path: {}", file_path))?;
 
-    let tags: Vec<String> = tags_json
-        .iter()
-        .map(|tag| tag.as_str().unwrap().to_string())
-        .collect();
+        let (upload_queue, origin) = {
+            let tracer_client = tracer_client.lock().await;
+            // An upload the run can't be told for is still made, just not reported with a run
+            let origin = UploadOrigin {
+                owner_uid: caller.run_owner(),
+                run_id: resolve_active_run(&tracer_client, &selector, caller).unwrap_or_default(),
+            };
+            (tracer_client.get_upload_queue(), origin)
+        };
+        // Taking the snapshot may take a while, the daemon keeps polling meanwhile
+        let upload_id = upload_queue
+            .enqueue(&file_path, &upload_from, file_name, None, None, origin)
+            .await?;
+        drop(file);
+        tracer_client.lock().await.process_uploads()?;
+
+        if let Some(upload_id) = &upload_id {
+            let entry = upload_queue
+                .wait_for_attempt(upload_id)
+                .await
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Upload {} disappeared from the queue", upload_id)
+                })?;
+
+            if entry.status != UploadStatus::Done {
+                return Err(anyhow::anyhow!(
+                    "Upload {} failed: {}",
+                    upload_id,
+                    entry.last_error.unwrap_or_default()
+                ));
+            }
+        }
 
-    Some(Box::pin(send_update_tags_event(service_url, api_key, tags)))
+        logger.log("process_upload_command completed", None).await;
+        Ok(Response::Upload(UploadResponse { upload_id }))
+    })
 }
 
-pub fn process_log_short_lived_process_command<'a>(
-    tracer_client: &'a Arc<Mutex<TracerClient>>,
-    object: &serde_json::Map<String, serde_json::Value>,
-) -> ProcessOutput<'a> {
-    if !object.contains_key("log") {
-        return None;
+/// Uploads the caller queued, or every upload for administrators
+pub fn process_uploads_command(
+    tracer_client: &Arc<Mutex<TracerClient>>,
+    caller: Caller,
+) -> ProcessOutput<'_> {
+    Box::pin(async move {
+        let uploads = tracer_client.lock().await.get_uploads(caller.run_owner());
+        Ok(Response::Uploads(UploadsResponse { uploads }))
+    })
+}
+
+async fn write_error<S: AsyncWrite + Unpin>(stream: &mut S, message: String) {
+    eprintln!("{}", message);
+    let _ = write_message(stream, &Response::Error { message }).await;
+}
+
+/// Runs the handshake and reads the request. Malformed input is answered with
+/// an error response; `None` means there is nothing left to process.
+pub async fn read_request<S>(stream: &mut BufReader<S>) -> Option<Request>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake: Handshake = match read_message(stream).await {
+        Ok(Some(handshake)) => handshake,
+        Ok(None) => return None,
+        Err(error) => {
+            write_error(
+                stream,
+                format!(
+                    "Expected a protocol handshake ({}). The tracer CLI is likely older than the daemon, which speaks protocol version {}.",
+                    error, PROTOCOL_VERSION
+                ),
+            )
+            .await;
+            return None;
+        }
     };
 
-    let log: ShortLivedProcessLog =
-        serde_json::from_value(object.get("log").unwrap().clone()).unwrap();
+    if write_message(stream, &Handshake::current()).await.is_err() {
+        return None;
+    }
+    // The client reports the mismatch, it knows both versions now
+    if handshake.protocol_version != PROTOCOL_VERSION {
+        return None;
+    }
 
-    Some(Box::pin(async move {
-        let mut tracer_client = tracer_client.lock().await;
-        tracer_client.fill_logs_with_short_lived_process(log)?;
-        Ok("".to_string())
-    }))
+    match read_message(stream).await {
+        Ok(request) => request,
+        Err(error) => {
+            write_error(stream, format!("Invalid request: {:#}", error)).await;
+            None
+        }
+    }
 }
 
-pub fn process_upload_command<'a>(
-    service_url: &'a str,
-    api_key: &'a str,
-    object: &'a serde_json::Map<String, serde_json::Value>,
-) -> ProcessOutput<'a> {
-    if !object.contains_key("file_path") {
-        return None;
+type StateCommand = (Request, Caller, oneshot::Sender<Result<Response>>);
+
+/// Applies the commands that change the run state one at a time, in the order
+/// they were received. A command whose client timed out is still applied in
+/// full, so the state is never left halfway through a change.
+async fn apply_state_commands(
+    tracer_client: Arc<Mutex<TracerClient>>,
+    config: Arc<RwLock<Config>>,
+    mut state_commands: mpsc::UnboundedReceiver<StateCommand>,
+) {
+    while let Some((request, caller, reply)) = state_commands.recv().await {
+        let result = match request {
+            Request::Start { selector } => {
+                process_start_run_command(&tracer_client, selector, caller).await
+            }
+            Request::End { selector } => {
+                process_end_run_command(&tracer_client, selector, caller).await
+            }
+            Request::RefreshConfig => process_refresh_config_command(&tracer_client, &config).await,
+            Request::LogShortLivedProcess { log } => {
+                process_log_short_lived_process_command(&tracer_client, *log, caller).await
+            }
+            request => Err(anyhow::anyhow!(
+                "{} does not change the run state",
+                request.name()
+            )),
+        };
+        let _ = reply.send(result);
+    }
+}
+
+async fn process_request(
+    request: Request,
+    caller: Caller,
+    tracer_client: &Arc<Mutex<TracerClient>>,
+    state_commands: &mpsc::UnboundedSender<StateCommand>,
+) -> Result<Response> {
+    if request.mutates_state() {
+        let (reply, response) = oneshot::channel();
+        state_commands
+            .send((request, caller, reply))
+            .map_err(|_| anyhow::anyhow!("The daemon is shutting down"))?;
+        return response.await.context("The daemon is shutting down")?;
+    }
+
+    let (service_url, api_key, run_id) = {
+        let tracer_client = tracer_client.lock().await;
+        let run_id = match &request {
+            Request::Tag { selector, .. } => resolve_run(&tracer_client, selector, caller)?,
+            _ => None,
+        };
+        let service_url = tracer_client.get_service_url().to_owned();
+        let api_key = tracer_client.get_api_key().to_owned();
+        (service_url, api_key, run_id)
     };
 
-    Some(Box::pin(async move {
-        let logger = Logger::new();
+    match request {
+        Request::Log { message } => process_log_command(&service_url, &api_key, message).await,
+        Request::Alert { message } => process_alert_command(&service_url, &api_key, message).await,
+        Request::Tag { tags, .. } => {
+            process_tag_command(&service_url, &api_key, run_id, tags).await
+        }
+        Request::Info { selector } => process_info_command(tracer_client, selector, caller).await,
+        Request::Upload {
+            file_path,
+            selector,
+        } => process_upload_command(tracer_client, file_path, selector, caller).await,
+        Request::Uploads => process_uploads_command(tracer_client, caller).await,
+        Request::Lineage { selector, format } => {
+            process_lineage_command(tracer_client, selector, format, caller).await
+        }
+        Request::Report { selector, format } => {
+            process_report_command(tracer_client, selector, format, caller).await
+        }
+        Request::Timeline { selector, group_by } => {
+            process_timeline_command(tracer_client, selector, group_by, caller).await
+        }
+        request => Err(anyhow::anyhow!(
+            "{} can't be processed here",
+            request.name()
+        )),
+    }
+}
 
-        logger.log("server.rs//process_upload_command", None).await;
+/// A daemon of a single user only serves its administrators. A system-wide
+/// daemon serves every user, but only administrators may stop or reconfigure it.
+pub fn is_authorized(request: &Request, caller: Caller, system_mode: bool) -> bool {
+    caller.is_admin || (system_mode && !request.requires_admin())
+}
 
-        upload_from_file_path(
-            service_url,
-            api_key,
-            object.get("file_path").unwrap().as_str().unwrap(),
+fn command_timeout(request: &Request) -> Duration {
+    match request {
+        Request::Upload { .. } => UPLOAD_COMMAND_TIMEOUT,
+        _ => COMMAND_TIMEOUT,
+    }
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    tracer_client: Arc<Mutex<TracerClient>>,
+    state_commands: mpsc::UnboundedSender<StateCommand>,
+    cancellation_token: CancellationToken,
+    system_mode: bool,
+) {
+    let peer_credentials = stream.peer_cred();
+    let mut stream = BufReader::new(stream);
+
+    let logger = Logger::new();
+
+    let request = match timeout(REQUEST_READ_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Some(request)) => request,
+        Ok(None) => return,
+        Err(_) => {
+            write_error(
+                &mut stream,
+                format!(
+                    "Timed out after {} seconds waiting for the request",
+                    REQUEST_READ_TIMEOUT.as_secs()
+                ),
+            )
+            .await;
+            return;
+        }
+    };
+    let command = request.name();
+
+    let daemon_uid = current_uid();
+    let caller = match peer_credentials.map(|credentials| (credentials.uid(), credentials.pid())) {
+        Ok((peer_uid, peer_pid))
+            if is_authorized(&request, Caller::new(peer_uid, daemon_uid), system_mode) =>
+        {
+            Caller::new(peer_uid, daemon_uid).with_session_of(peer_pid)
+        }
+        Ok((peer_uid, _)) => {
+            write_error(
+                &mut stream,
+                format!(
+                    "Permission denied: uid {} can't send {} to the daemon of uid {}",
+                    peer_uid, command, daemon_uid
+                ),
+            )
+            .await;
+            return;
+        }
+        Err(error) => {
+            write_error(
+                &mut stream,
+                format!("Failed to read the credentials of the client: {}", error),
+            )
+            .await;
+            return;
+        }
+    };
+
+    logger
+        .log(
+            &format!("Received command: {}, {:?}", command, request),
             None,
         )
-        .await?;
+        .await;
 
-        logger.log("process_upload_command completed", None).await;
-        Ok("Upload command processed".to_string())
-    }))
+    if let Request::Terminate = request {
+        let _ = write_message(&mut stream, &Response::Ok).await;
+        cancellation_token.cancel();
+        return;
+    }
+
+    let limit = command_timeout(&request);
+    let response = match timeout(
+        limit,
+        process_request(request, caller, &tracer_client, &state_commands),
+    )
+    .await
+    {
+        Ok(Ok(response)) => response,
+        Ok(Err(error)) => {
+            eprintln!("Command {} failed: {:?}", command, error);
+            Response::Error {
+                message: format!("{:#}", error),
+            }
+        }
+        Err(_) => {
+            eprintln!("Command {} timed out", command);
+            Response::Error {
+                message: format!(
+                    "Command {} timed out after {} seconds",
+                    command,
+                    limit.as_secs()
+                ),
+            }
+        }
+    };
+
+    if let Err(error) = write_message(&mut stream, &response).await {
+        eprintln!("Failed to write response: {}", error);
+    }
 }
 
+/// Serves each connection in its own task, up to MAX_CONNECTIONS at a time,
+/// until a terminate command cancels the token. In system mode every user may
+/// connect to the socket.
 pub async fn run_server(
     tracer_client: Arc<Mutex<TracerClient>>,
     socket_path: &str,
     cancellation_token: CancellationToken,
     config: Arc<RwLock<Config>>,
+    system_mode: bool,
 ) -> Result<(), anyhow::Error> {
     if std::fs::metadata(socket_path).is_ok() {
-        std::fs::remove_file(socket_path)
-            .unwrap_or_else(|_| panic!("Failed to remove existing socket file"));
+        std::fs::remove_file(socket_path).context("Failed to remove existing socket file")?;
     }
-    let listener = UnixListener::bind(socket_path).expect("Failed to bind to unix socket");
+    let listener = UnixListener::bind(socket_path).context("Failed to bind to unix socket")?;
+    if system_mode {
+        std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o666))?;
+    }
+
+    let (state_commands, state_commands_receiver) = mpsc::unbounded_channel();
+    tokio::spawn(apply_state_commands(
+        tracer_client.clone(),
+        config,
+        state_commands_receiver,
+    ));
+
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+
     loop {
-        let (mut stream, _) = listener.accept().await.unwrap();
+        let permit = connections.clone().acquire_owned().await?;
+
+        let stream = tokio::select! {
+            _ = cancellation_token.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    eprintln!("Error accepting connection: {}", error);
+                    continue;
+                }
+            },
+        };
 
-        let mut message = String::new();
+        let tracer_client = tracer_client.clone();
+        let state_commands = state_commands.clone();
+        let cancellation_token = cancellation_token.clone();
+        tokio::spawn(async move {
+            handle_connection(
+                stream,
+                tracer_client,
+                state_commands,
+                cancellation_token,
+                system_mode,
+            )
+            .await;
+            drop(permit);
+        });
+    }
 
-        let logger = Logger::new();
+    Ok(())
+}
 
-        let result = stream.read_to_string(&mut message).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon_communication::client::send_info_request;
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncWriteExt, BufReader},
+        net::UnixStream,
+    };
 
-        if result.is_err() {
-            eprintln!("Error reading from socket: {}", result.err().unwrap());
-            continue;
-        }
+    /// Feeds `input` to `read_request` and returns what the daemon side wrote back
+    async fn read_request_from(input: &[u8]) -> (Option<Request>, Vec<Value>) {
+        let (daemon, client) = UnixStream::pair().unwrap();
+        let mut client = BufReader::new(client);
+
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
 
-        let json_parse_result = serde_json::from_str(&message);
+        let request = read_request(&mut BufReader::new(daemon)).await;
 
-        if json_parse_result.is_err() {
-            eprintln!("Error parsing JSON: {}", json_parse_result.err().unwrap());
-            continue;
+        let mut replies = vec![];
+        while let Some(reply) = read_message(&mut client).await.unwrap() {
+            replies.push(reply);
         }
+        (request, replies)
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        let owner = Caller::new(1000, 1000);
+        let root = Caller::new(0, 1000);
+        let other_user = Caller::new(1001, 1000);
+
+        assert!(is_authorized(&Request::Terminate, owner, false));
+        assert!(is_authorized(&Request::Terminate, root, false));
+        assert!(!is_authorized(
+            &Request::Info {
+                selector: RunSelector::default()
+            },
+            other_user,
+            false
+        ));
+
+        // A system-wide daemon serves everyone, but only admins may stop it
+        let system = Caller::new(0, 0);
+        let user = Caller::new(1001, 0);
+        assert!(is_authorized(&Request::Terminate, system, true));
+        assert!(is_authorized(
+            &Request::Start {
+                selector: RunSelector::default()
+            },
+            user,
+            true
+        ));
+        assert!(!is_authorized(&Request::Terminate, user, true));
+        assert!(!is_authorized(&Request::RefreshConfig, user, true));
+    }
 
-        let parsed: Value = json_parse_result.unwrap();
+    fn handshake_line(protocol_version: u32) -> String {
+        format!("{}\n", json!({ "protocol_version": protocol_version }))
+    }
 
-        if !parsed.is_object() {
-            eprintln!("Invalid JSON received: {}", message);
-            continue;
-        }
+    #[tokio::test]
+    async fn test_read_request_parses_typed_request() {
+        let input = handshake_line(PROTOCOL_VERSION) + "{\"command\":\"tag\",\"tags\":[\"a\"]}\n";
 
-        let object = parsed.as_object().unwrap();
+        let (request, replies) = read_request_from(input.as_bytes()).await;
 
-        if !object.contains_key("command") {
-            eprintln!("Invalid JSON, no command field, received: {}", message);
-            continue;
+        assert!(matches!(request, Some(Request::Tag { tags, .. }) if tags == vec!["a"]));
+        assert_eq!(
+            replies,
+            vec![json!({ "protocol_version": PROTOCOL_VERSION })]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_requests_get_error_responses() {
+        let malformed = [
+            "not json\n",
+            "{\"command\":\"tag\",\"tags\":[1, 2]}\n",
+            "{\"command\":\"tag\"}\n",
+            "{\"command\":\"unknown\"}\n",
+            "{\"message\":\"no command\"}\n",
+        ];
+
+        for request in malformed {
+            let input = handshake_line(PROTOCOL_VERSION) + request;
+            let (parsed, replies) = read_request_from(input.as_bytes()).await;
+
+            assert!(parsed.is_none(), "{} was accepted", request);
+            assert_eq!(replies.len(), 2);
+            assert_eq!(replies[1]["response"], "error", "{}", request);
         }
+    }
 
-        let command = object.get("command").unwrap().as_str().unwrap();
+    #[tokio::test]
+    async fn test_request_without_handshake_is_rejected() {
+        // What a CLI from before the handshake sends
+        let (request, replies) = read_request_from(b"{\"command\":\"info\"}").await;
+
+        assert!(request.is_none());
+        assert_eq!(replies[0]["response"], "error");
+        assert!(replies[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("older than the daemon"));
+    }
 
-        let (service_url, api_key) = {
-            let tracer_client = tracer_client.lock().await;
-            let service_url = tracer_client.get_service_url().to_owned();
-            let api_key = tracer_client.get_api_key().to_owned();
-            (service_url, api_key)
-        };
+    #[tokio::test]
+    async fn test_protocol_version_mismatch_drops_the_request() {
+        let input = handshake_line(PROTOCOL_VERSION + 1) + "{\"command\":\"info\"}\n";
 
-        logger
-            .log(&format!("Received command: {}, {}", command, message), None)
-            .await;
+        let (request, replies) = read_request_from(input.as_bytes()).await;
 
-        let result = match command {
-            "terminate" => {
-                cancellation_token.cancel();
-                return Ok(());
-            }
-            "log" => process_log_command(&service_url, &api_key, object),
-            "alert" => process_alert_command(&service_url, &api_key, object),
-            "start" => process_start_run_command(&tracer_client, &mut stream),
-            "end" => process_end_run_command(&tracer_client),
-            "refresh_config" => process_refresh_config_command(&tracer_client, &config),
-            "tag" => process_tag_command(&service_url, &api_key, object),
-            "log_short_lived_process" => {
-                process_log_short_lived_process_command(&tracer_client, object)
-            }
-            "info" => process_info_command(&tracer_client, &mut stream),
-            "upload" => process_upload_command(&service_url, &api_key, object),
-            _ => {
-                eprintln!("Invalid command: {}", command);
-                None
-            }
-        };
+        assert!(request.is_none());
+        assert_eq!(
+            replies,
+            vec![json!({ "protocol_version": PROTOCOL_VERSION })]
+        );
+    }
 
-        if let Some(future) = result {
-            future.await?;
+    #[tokio::test]
+    async fn test_slow_client_does_not_block_other_connections() -> Result<()> {
+        let socket_dir = tempfile::tempdir()?;
+        let socket_path = socket_dir.path().join("tracerd.sock");
+        let socket_path = socket_path.to_str().unwrap().to_string();
+
+        let config = ConfigManager::load_default_config();
+        let pwd = std::env::current_dir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let tracer_client = TracerClient::new(
+            config.clone(),
+            pwd.to_str().unwrap().to_string(),
+            cache_dir.path(),
+        )
+        .await?;
+        let cancellation_token = CancellationToken::new();
+
+        let server = tokio::spawn({
+            let socket_path = socket_path.clone();
+            let cancellation_token = cancellation_token.clone();
+            async move {
+                run_server(
+                    Arc::new(Mutex::new(tracer_client)),
+                    &socket_path,
+                    cancellation_token,
+                    Arc::new(RwLock::new(config)),
+                    false,
+                )
+                .await
+            }
+        });
+        while UnixStream::connect(&socket_path).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+
+        // Connects but never sends its request
+        let _slow_client = UnixStream::connect(&socket_path).await?;
+
+        let runs = timeout(
+            Duration::from_secs(2),
+            send_info_request(&socket_path, None),
+        )
+        .await??;
+        assert!(runs.is_empty());
+
+        cancellation_token.cancel();
+        server.await??;
+
+        Ok(())
     }
 }
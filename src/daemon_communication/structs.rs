@@ -1,8 +1,172 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize)]
+use crate::cost::RunCost;
+use crate::process_watcher::ShortLivedProcessLog;
+use crate::upload::upload_queue::UploadEntry;
+use crate::workflow_engines::PipelineMetadata;
+
+/// Which run a command refers to. Without an explicit id the daemon picks the
+/// run started by an ancestor of the CLI, or in one of its parent directories.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RunSelector {
+    pub run_id: Option<String>,
+    /// Process that invoked the CLI, usually the pipeline's shell
+    pub parent_pid: Option<u32>,
+    pub working_directory: Option<String>,
+}
+
+/// Commands the CLI sends to the daemon, one per connection
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Log {
+        message: String,
+    },
+    Alert {
+        message: String,
+    },
+    Terminate,
+    Start {
+        #[serde(default)]
+        selector: RunSelector,
+    },
+    End {
+        #[serde(default)]
+        selector: RunSelector,
+    },
+    RefreshConfig,
+    Tag {
+        tags: Vec<String>,
+        #[serde(default)]
+        selector: RunSelector,
+    },
+    LogShortLivedProcess {
+        log: Box<ShortLivedProcessLog>,
+    },
+    Info {
+        #[serde(default)]
+        selector: RunSelector,
+    },
+    Upload {
+        file_path: String,
+        #[serde(default)]
+        selector: RunSelector,
+    },
+    Uploads,
+    Lineage {
+        #[serde(default)]
+        selector: RunSelector,
+        format: Option<String>,
+    },
+    Report {
+        #[serde(default)]
+        selector: RunSelector,
+        format: Option<String>,
+    },
+    Timeline {
+        #[serde(default)]
+        selector: RunSelector,
+        group_by: Option<String>,
+    },
+}
+
+impl Request {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Log { .. } => "log",
+            Request::Alert { .. } => "alert",
+            Request::Terminate => "terminate",
+            Request::Start { .. } => "start",
+            Request::End { .. } => "end",
+            Request::RefreshConfig => "refresh_config",
+            Request::Tag { .. } => "tag",
+            Request::LogShortLivedProcess { .. } => "log_short_lived_process",
+            Request::Info { .. } => "info",
+            Request::Upload { .. } => "upload",
+            Request::Uploads => "uploads",
+            Request::Lineage { .. } => "lineage",
+            Request::Report { .. } => "report",
+            Request::Timeline { .. } => "timeline",
+        }
+    }
+
+    /// Commands only root or the user running the daemon may send, even to a
+    /// system-wide daemon
+    pub fn requires_admin(&self) -> bool {
+        matches!(self, Request::Terminate | Request::RefreshConfig)
+    }
+
+    /// Commands that change the run state of `TracerClient`. These are applied
+    /// one at a time, in the order the daemon received them.
+    pub fn mutates_state(&self) -> bool {
+        matches!(
+            self,
+            Request::Start { .. }
+                | Request::End { .. }
+                | Request::RefreshConfig
+                | Request::LogShortLivedProcess { .. }
+        )
+    }
+}
+
+/// Reply the daemon writes back for every request
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Info(Box<InfoResponse>),
+    Runs(RunsResponse),
+    Lineage(LineageResponse),
+    Report(ReportResponse),
+    Timeline(TimelineResponse),
+    Upload(UploadResponse),
+    Uploads(UploadsResponse),
+    Error { message: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct InfoResponse {
     pub run_name: String,
     pub run_id: String,
     pub service_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<PipelineMetadata>,
+    /// Estimated so far, for active runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_cost: Option<RunCost>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunsResponse {
+    pub runs: Vec<InfoResponse>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LineageResponse {
+    pub format: String,
+    pub lineage: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReportResponse {
+    pub format: String,
+    pub report: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TimelineResponse {
+    pub group_by: String,
+    /// Chrome Trace Event JSON
+    pub trace: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadResponse {
+    /// `None` when the same content was uploaded before
+    pub upload_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadsResponse {
+    pub uploads: Vec<UploadEntry>,
 }
@@ -1,21 +1,35 @@
+use std::collections::{HashMap, VecDeque};
+
 use chrono::serde::ts_seconds;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::run_report::RunEvents;
+
+/// Ended runs remembered so their late events aren't summarized again
+const MAX_ENDED_RUNS: usize = 64;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Event {
     #[serde(with = "ts_seconds")]
-    timestamp: DateTime<Utc>,
-    message: String,
+    pub timestamp: DateTime<Utc>,
+    pub message: String,
     pub event_type: String,
     process_type: String,
-    process_status: String,
+    pub process_status: String,
     pub attributes: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
 }
 
 pub struct EventRecorder {
     events: Vec<Event>,
+    /// Run of events that aren't tied to a process of a specific run
+    default_run_id: Option<String>,
+    /// Summaries of the events of each run, kept past the batch the events are sent in
+    run_events: HashMap<String, RunEvents>,
+    ended_runs: VecDeque<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
@@ -27,6 +41,14 @@ pub enum EventType {
     ToolMetricEvent,
     MetricEvent,
     SyslogEvent,
+    FileLineage,
+    FileChecksum,
+    ToolInputChecksum,
+    QcMetricEvent,
+    PipelineMetadata,
+    TaskEvent,
+    SlurmJob,
+    SlurmResourceUsage,
     TestEvent, // Added TestEvent variant
 }
 
@@ -40,6 +62,14 @@ impl EventType {
             EventType::MetricEvent => "metric_event",
             EventType::SyslogEvent => "syslog_event",
             EventType::ToolMetricEvent => "tool_metric_event",
+            EventType::FileLineage => "file_lineage",
+            EventType::FileChecksum => "file_checksum",
+            EventType::ToolInputChecksum => "tool_input_checksum",
+            EventType::QcMetricEvent => "qc_metric_event",
+            EventType::PipelineMetadata => "pipeline_metadata",
+            EventType::TaskEvent => "task_event",
+            EventType::SlurmJob => "slurm_job",
+            EventType::SlurmResourceUsage => "slurm_resource_usage",
             EventType::TestEvent => "test_event", // Handle TestEvent
         }
     }
@@ -47,7 +77,16 @@ impl EventType {
 
 impl EventRecorder {
     pub fn new() -> Self {
-        EventRecorder { events: Vec::new() }
+        EventRecorder {
+            events: Vec::new(),
+            default_run_id: None,
+            run_events: HashMap::new(),
+            ended_runs: VecDeque::new(),
+        }
+    }
+
+    pub fn set_default_run_id(&mut self, run_id: Option<String>) {
+        self.default_run_id = run_id;
     }
 
     pub fn record_event(
@@ -56,6 +95,18 @@ impl EventRecorder {
         message: String,
         attributes: Option<Value>,
         timestamp: Option<DateTime<Utc>>,
+    ) {
+        self.record_run_event(None, event_type, message, attributes, timestamp);
+    }
+
+    /// Records an event of the given run, or of the default run if `None`
+    pub fn record_run_event(
+        &mut self,
+        run_id: Option<&str>,
+        event_type: EventType,
+        message: String,
+        attributes: Option<Value>,
+        timestamp: Option<DateTime<Utc>>,
     ) {
         let event = Event {
             timestamp: timestamp.unwrap_or_else(Utc::now),
@@ -64,10 +115,36 @@ impl EventRecorder {
             process_type: "pipeline".to_owned(),
             process_status: event_type.as_str().to_owned(),
             attributes,
+            run_id: run_id
+                .map(String::from)
+                .or_else(|| self.default_run_id.clone()),
         };
+        if let Some(run_id) = event
+            .run_id
+            .as_ref()
+            .filter(|run_id| !self.ended_runs.contains(run_id))
+        {
+            self.run_events
+                .entry(run_id.clone())
+                .or_default()
+                .add(&event);
+        }
         self.events.push(event);
     }
 
+    pub fn get_run_events(&self, run_id: &str) -> Option<&RunEvents> {
+        self.run_events.get(run_id)
+    }
+
+    /// Stops summarizing the events of a run that ended
+    pub fn take_run_events(&mut self, run_id: &str) -> Option<RunEvents> {
+        if self.ended_runs.len() == MAX_ENDED_RUNS {
+            self.ended_runs.pop_front();
+        }
+        self.ended_runs.push_back(run_id.to_string());
+        self.run_events.remove(run_id)
+    }
+
     pub fn get_events(&self) -> &[Event] {
         &self.events
     }
@@ -136,6 +213,57 @@ mod tests {
         assert!(recorder.is_empty());
     }
 
+    #[test]
+    fn test_events_carry_their_run_id() {
+        let mut recorder = EventRecorder::new();
+        recorder.record_event(EventType::MetricEvent, "No run".to_string(), None, None);
+
+        recorder.set_default_run_id(Some("default".to_string()));
+        recorder.record_event(EventType::MetricEvent, "Default".to_string(), None, None);
+        recorder.record_run_event(
+            Some("other"),
+            EventType::ToolExecution,
+            "Other".to_string(),
+            None,
+            None,
+        );
+
+        let run_ids: Vec<Option<&str>> = recorder
+            .get_events()
+            .iter()
+            .map(|event| event.run_id.as_deref())
+            .collect();
+        assert_eq!(run_ids, vec![None, Some("default"), Some("other")]);
+        assert!(serde_json::to_value(&recorder.get_events()[0])
+            .unwrap()
+            .get("run_id")
+            .is_none());
+    }
+
+    #[test]
+    fn test_late_events_of_ended_runs_are_not_summarized() {
+        let mut recorder = EventRecorder::new();
+        let metric = json!({ "system_cpu_utilization": 12.5 });
+        recorder.record_run_event(
+            Some("ended"),
+            EventType::MetricEvent,
+            "Metric".to_string(),
+            Some(metric.clone()),
+            None,
+        );
+        assert!(recorder.take_run_events("ended").is_some());
+
+        recorder.record_run_event(
+            Some("ended"),
+            EventType::MetricEvent,
+            "Late metric".to_string(),
+            Some(metric),
+            None,
+        );
+        assert!(recorder.get_run_events("ended").is_none());
+        assert_eq!(recorder.len(), 2);
+    }
+
     #[test]
     fn test_event_type_as_str() {
         assert_eq!(EventType::FinishedRun.as_str(), "finished_run");
@@ -1,9 +1,9 @@
-use std::time::Duration;
-
 // src/events/mod.rs
 use crate::{
+    cloud_metadata::{get_instance_metadata, CloudProvider},
     debug_log::Logger,
-    http_client::{send_http_event, send_http_get},
+    http_client::send_http_event,
+    kubernetes::detect_pod,
     metrics::SystemMetricsCollector,
 };
 use anyhow::{Context, Result};
@@ -14,6 +14,7 @@ use sysinfo::System;
 use tracing::info;
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub enum EventStatus {
     #[allow(dead_code)]
     NewRun,
@@ -65,20 +66,13 @@ pub struct RunEventOut {
     pub service_name: String,
 }
 
-const AWS_METADATA_URL: &str = "http://169.254.169.254/latest/meta-data/";
-
-async fn get_aws_instance_metadata() -> Result<Value> {
-    let (status, response_text) =
-        send_http_get(AWS_METADATA_URL, None, Some(Duration::from_secs(2))).await?;
-
-    serde_json::from_str(&response_text).context(format!(
-        "Failed to get AWS instance metadata. Status: {}, Response: {}",
-        status, response_text
-    ))
-}
-
 async fn gather_system_properties(system: &System) -> Value {
-    let aws_metadata = get_aws_instance_metadata().await.unwrap_or(json!(null));
+    let cloud_metadata = get_instance_metadata().await;
+    let aws_metadata = cloud_metadata
+        .as_ref()
+        .filter(|metadata| metadata.provider == CloudProvider::Aws);
+
+    let kubernetes_metadata = detect_pod();
 
     let disk_metadata = SystemMetricsCollector::gather_disk_data();
 
@@ -94,7 +88,11 @@ async fn gather_system_properties(system: &System) -> Value {
             "total_swap": system.total_swap(),
             "uptime": System::uptime(),
             "aws_metadata": aws_metadata,
-            "is_aws_instance": !aws_metadata.is_null(),
+            "is_aws_instance": aws_metadata.is_some(),
+            "cloud_provider": cloud_metadata.as_ref().map(|metadata| metadata.provider),
+            "cloud_metadata": cloud_metadata,
+            "is_kubernetes_pod": kubernetes_metadata.is_some(),
+            "kubernetes_metadata": kubernetes_metadata,
             "system_disk_io": disk_metadata,
         }
     )
@@ -178,10 +176,11 @@ pub async fn send_start_run_event(
     })
 }
 
-pub async fn send_end_run_event(service_url: &str, api_key: &str) -> Result<String> {
+pub async fn send_end_run_event(service_url: &str, api_key: &str, run_id: &str) -> Result<String> {
     info!("Finishing pipeline run...");
 
     let end_entry = json!({
+        "run_id": run_id,
         "message": "[CLI] Finishing pipeline run",
         "process_type": "pipeline",
         "process_status": "finished_run",
@@ -210,9 +209,11 @@ pub async fn send_daemon_start_event(service_url: &str, api_key: &str) -> Result
 pub async fn send_update_tags_event(
     service_url: &str,
     api_key: &str,
+    run_id: Option<&str>,
     tags: Vec<String>,
 ) -> Result<String> {
     let tags_entry = json!({
+        "run_id": run_id,
         "tags": tags,
         "message": "[CLI] Updating tags",
         "process_type": "pipeline",
@@ -0,0 +1,454 @@
+// src/file_cache.rs
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+
+use crate::upload::upload_queue::{UPLOAD_QUEUE_FILE, UPLOAD_QUEUE_TEMP_FILE};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CopyMethod {
+    Reflink,
+    Hardlink,
+    Copy,
+}
+
+struct CacheEntry {
+    size: u64,
+    modified: SystemTime,
+    last_used: SystemTime,
+    pinned: bool,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    total_bytes: u64,
+}
+
+/// Copies of workflow files kept in the cache directory, within a byte budget.
+/// When a new copy doesn't fit, the least recently used unpinned copies are
+/// evicted; copies pinned by a pending upload are never evicted.
+#[derive(Clone)]
+pub struct FileCache {
+    directory: PathBuf,
+    max_bytes: u64,
+    state: Arc<Mutex<CacheState>>,
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, destination: &Path) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let source = fs::File::open(source)?;
+    let destination_file = fs::File::create(destination)?;
+    // SAFETY: both descriptors are open for the duration of the call
+    let result = unsafe {
+        libc::ioctl(
+            destination_file.as_raw_fd(),
+            libc::FICLONE as _,
+            source.as_raw_fd(),
+        )
+    };
+    if result != 0 {
+        let error = std::io::Error::last_os_error();
+        drop(destination_file);
+        let _ = fs::remove_file(destination);
+        return Err(error.into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _destination: &Path) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "Reflinks are not supported on this platform"
+    ))
+}
+
+/// Cheapest way to duplicate the file: a copy-on-write clone, then a hard link
+/// when allowed and both paths are on the same filesystem, then a plain copy.
+pub fn link_or_copy(source: &Path, destination: &Path, allow_hardlink: bool) -> Result<CopyMethod> {
+    if reflink(source, destination).is_ok() {
+        return Ok(CopyMethod::Reflink);
+    }
+    if allow_hardlink && fs::hard_link(source, destination).is_ok() {
+        return Ok(CopyMethod::Hardlink);
+    }
+    fs::copy(source, destination)?;
+    Ok(CopyMethod::Copy)
+}
+
+/// Copies the file, returning how and the modification time of the copy
+fn copy_into_cache(
+    source: &Path,
+    destination: &Path,
+    allow_hardlink: bool,
+) -> Result<(CopyMethod, SystemTime)> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let method = link_or_copy(source, destination, allow_hardlink)?;
+    Ok((method, fs::metadata(destination)?.modified()?))
+}
+
+fn walk_files(directory: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            walk_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+impl FileCache {
+    /// Opens the cache, accounting for the copies already in the directory.
+    /// The upload queue kept next to them isn't a copy, so it's never evicted.
+    pub fn new(directory: &str, max_bytes: u64) -> Result<FileCache> {
+        fs::create_dir_all(directory)?;
+
+        let mut files = vec![];
+        walk_files(Path::new(directory), &mut files);
+        files.retain(|file| {
+            file.parent() != Some(Path::new(directory))
+                || file
+                    .file_name()
+                    .is_none_or(|name| name != UPLOAD_QUEUE_FILE && name != UPLOAD_QUEUE_TEMP_FILE)
+        });
+
+        let mut state = CacheState::default();
+        for file in files {
+            let metadata = fs::metadata(&file)?;
+            let modified = metadata.modified()?;
+            state.total_bytes += metadata.len();
+            state.entries.insert(
+                file.to_str().unwrap().to_string(),
+                CacheEntry {
+                    size: metadata.len(),
+                    modified,
+                    last_used: modified,
+                    pinned: false,
+                },
+            );
+        }
+
+        Ok(FileCache {
+            directory: PathBuf::from(directory),
+            max_bytes,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    pub fn get_directory(&self) -> &Path {
+        &self.directory
+    }
+
+    #[allow(dead_code)]
+    pub fn get_total_bytes(&self) -> u64 {
+        self.state.lock().unwrap().total_bytes
+    }
+
+    fn remove_entry(state: &mut CacheState, path: &str) {
+        if let Some(entry) = state.entries.remove(path) {
+            state.total_bytes -= entry.size;
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Stores a copy of `source` at `destination`, which must be inside the
+    /// cache directory, replacing whatever was cached there before. Pinned
+    /// copies are snapshots of pending uploads, so they're never hard links,
+    /// which would change along with the source.
+    pub fn store(&self, source: &str, destination: &str, pinned: bool) -> Result<CopyMethod> {
+        let size = fs::metadata(source)?.len();
+        self.reserve(source, destination, size)?;
+
+        // The copy may take a while, so it's made without holding the lock
+        let copied = copy_into_cache(Path::new(source), Path::new(destination), !pinned)
+            .with_context(|| format!("Failed to cache {}", source));
+
+        let mut state = self.state.lock().unwrap();
+        let (method, modified) = match copied {
+            Ok(copied) => copied,
+            Err(error) => {
+                state.total_bytes -= size;
+                return Err(error);
+            }
+        };
+        let replaced = state.entries.insert(
+            destination.to_string(),
+            CacheEntry {
+                size,
+                modified,
+                last_used: SystemTime::now(),
+                pinned,
+            },
+        );
+        // Stored again in the meantime, that copy was just overwritten
+        if let Some(replaced) = replaced {
+            state.total_bytes -= replaced.size;
+        }
+
+        Ok(method)
+    }
+
+    /// Makes room for `size` bytes and counts them as used until the copy is made
+    fn reserve(&self, source: &str, destination: &str, size: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        Self::remove_entry(&mut state, destination);
+
+        // Copying a cached file counts as using it
+        if let Some(entry) = state.entries.get_mut(source) {
+            entry.last_used = SystemTime::now();
+        }
+
+        if size > self.max_bytes {
+            return Err(anyhow::anyhow!(
+                "File {} ({} bytes) is larger than the file cache ({} bytes)",
+                source,
+                size,
+                self.max_bytes
+            ));
+        }
+
+        while state.total_bytes + size > self.max_bytes {
+            let evicted = state
+                .entries
+                .iter()
+                .filter(|(_, entry)| !entry.pinned)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+
+            let Some(evicted) = evicted else {
+                return Err(anyhow::anyhow!(
+                    "File cache is full of pending uploads, cannot cache {}",
+                    source
+                ));
+            };
+            Self::remove_entry(&mut state, &evicted);
+        }
+
+        state.total_bytes += size;
+        Ok(())
+    }
+
+    /// Whether the copy is still cached and unchanged. A hard-linked copy
+    /// changes along with its source if that is written in place; such a copy
+    /// no longer holds the cached content and is dropped.
+    pub fn contains(&self, path: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let unchanged = match (state.entries.get(path), fs::metadata(path)) {
+            (Some(entry), Ok(metadata)) => {
+                metadata.len() == entry.size && metadata.modified().ok() == Some(entry.modified)
+            }
+            (Some(_), Err(_)) => false,
+            (None, _) => return false,
+        };
+
+        if !unchanged {
+            eprintln!(
+                "[{}] Cached copy {} changed or disappeared, dropping it",
+                Utc::now(),
+                path
+            );
+            Self::remove_entry(&mut state, path);
+        }
+        unchanged
+    }
+
+    pub fn pin(&self, path: &str) {
+        if let Some(entry) = self.state.lock().unwrap().entries.get_mut(path) {
+            entry.pinned = true;
+        }
+    }
+
+    pub fn remove(&self, path: &str) {
+        Self::remove_entry(&mut self.state.lock().unwrap(), path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+
+    fn create_file(directory: &Path, name: &str, size: usize) -> String {
+        let path = directory.join(name);
+        fs::File::create(&path)
+            .unwrap()
+            .write_all(&vec![b'A'; size])
+            .unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn cache_path(cache: &FileCache, name: &str) -> String {
+        cache
+            .get_directory()
+            .join(name)
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_store_evicts_least_recently_used() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let cache = FileCache::new(cache_dir.path().to_str().unwrap(), 350)?;
+
+        let first = create_file(source_dir.path(), "first", 100);
+        let second = create_file(source_dir.path(), "second", 100);
+        let fourth = create_file(source_dir.path(), "fourth", 100);
+
+        cache.store(&first, &cache_path(&cache, "first"), false)?;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        cache.store(&second, &cache_path(&cache, "second"), false)?;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        // Copying the first cached file makes the second one the least recently used
+        cache.store(
+            &cache_path(&cache, "first"),
+            &cache_path(&cache, "third"),
+            false,
+        )?;
+        cache.store(&fourth, &cache_path(&cache, "fourth"), false)?;
+
+        assert!(cache.contains(&cache_path(&cache, "first")));
+        assert!(!cache.contains(&cache_path(&cache, "second")));
+        assert!(!Path::new(&cache_path(&cache, "second")).exists());
+        assert!(cache.contains(&cache_path(&cache, "third")));
+        assert!(cache.contains(&cache_path(&cache, "fourth")));
+        assert_eq!(cache.get_total_bytes(), 300);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filling_the_cache_keeps_the_upload_queue() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let directory = cache_dir.path().to_str().unwrap();
+        let queue_file = cache_dir.path().join(UPLOAD_QUEUE_FILE);
+        let temp_file = cache_dir.path().join(UPLOAD_QUEUE_TEMP_FILE);
+        fs::write(&queue_file, "[]")?;
+        fs::write(&temp_file, "[]")?;
+
+        // As after a restart, with the queue older than any copy
+        let cache = FileCache::new(directory, 250)?;
+        assert_eq!(cache.get_total_bytes(), 0);
+        for name in ["first", "second", "third"] {
+            let source = create_file(source_dir.path(), name, 100);
+            cache.store(&source, &cache_path(&cache, name), false)?;
+        }
+
+        assert!(queue_file.exists());
+        assert!(temp_file.exists());
+        assert_eq!(cache.get_total_bytes(), 200);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_copy_gives_back_its_room() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let cache = FileCache::new(cache_dir.path().to_str().unwrap(), 150)?;
+        let source = create_file(source_dir.path(), "source", 100);
+        // A file where the copy's directory would go
+        fs::write(cache_path(&cache, "blocked"), "")?;
+
+        assert!(cache
+            .store(&source, &cache_path(&cache, "blocked/copy"), false)
+            .is_err());
+        assert_eq!(cache.get_total_bytes(), 0);
+        cache.store(&source, &cache_path(&cache, "copy"), false)?;
+        assert_eq!(cache.get_total_bytes(), 100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_copies_are_not_evicted() -> Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let cache = FileCache::new(cache_dir.path().to_str().unwrap(), 150)?;
+
+        let pending = create_file(source_dir.path(), "pending", 100);
+        let other = create_file(source_dir.path(), "other", 100);
+        let too_large = create_file(source_dir.path(), "too_large", 200);
+
+        cache.store(&pending, &cache_path(&cache, "pending"), true)?;
+        assert!(cache
+            .store(&other, &cache_path(&cache, "other"), false)
+            .is_err());
+        assert!(cache
+            .store(&too_large, &cache_path(&cache, "too_large"), false)
+            .is_err());
+        assert!(cache.contains(&cache_path(&cache, "pending")));
+
+        cache.remove(&cache_path(&cache, "pending"));
+        assert_eq!(cache.get_total_bytes(), 0);
+        cache.store(&other, &cache_path(&cache, "other"), false)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hardlinked_copy_is_dropped_when_source_changes() -> Result<()> {
+        let cache_dir = tempfile::tempdir()?;
+        // Same filesystem, so the copy can be a link
+        let source = create_file(cache_dir.path(), "source", 10);
+        let cache = FileCache::new(cache_dir.path().join("cache").to_str().unwrap(), 1024)?;
+        let copy = cache_path(&cache, "copy");
+
+        let method = cache.store(&source, &copy, false)?;
+        assert_ne!(method, CopyMethod::Copy);
+        assert!(cache.contains(&copy));
+
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&source)?
+            .write_all(b"more")?;
+
+        if method == CopyMethod::Hardlink {
+            assert_eq!(fs::metadata(&copy)?.ino(), fs::metadata(&source)?.ino());
+            assert!(!cache.contains(&copy));
+        } else {
+            assert!(cache.contains(&copy));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_copy_is_not_changed_by_its_source() -> Result<()> {
+        let cache_dir = tempfile::tempdir()?;
+        let source = create_file(cache_dir.path(), "source", 10);
+        let cache = FileCache::new(cache_dir.path().join("cache").to_str().unwrap(), 1024)?;
+        let snapshot = cache_path(&cache, "snapshot");
+
+        assert_ne!(cache.store(&source, &snapshot, true)?, CopyMethod::Hardlink);
+        assert_ne!(fs::metadata(&snapshot)?.ino(), fs::metadata(&source)?.ino());
+
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&source)?
+            .write_all(b"BBBB")?;
+
+        assert!(cache.contains(&snapshot));
+        assert_eq!(fs::read(&snapshot)?, vec![b'A'; 10]);
+
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use std::collections::HashSet;
-use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::{collections::HashMap, path::Path};
 
 use anyhow::Result;
@@ -9,13 +9,16 @@ use predicates::prelude::predicate;
 use predicates::str::RegexPredicate;
 use predicates::Predicate;
 
+use crate::checksum::{ChecksumKey, FileChecksum, FileHasher};
 use crate::debug_log::Logger;
-use crate::upload::upload_from_file_path;
+use crate::file_cache::FileCache;
+use crate::upload::upload_queue::{prepare_cache_directory, UploadOrigin, UploadQueue};
 
 #[derive(Debug, Clone)]
 pub struct WatchedFileInfo {
     pub path: String,
     pub size: u64,
+    pub inode: u64,
     pub last_update: DateTime<Utc>,
     pub last_upload: Option<DateTime<Utc>>,
     pub cached_path: Option<String>,
@@ -27,12 +30,14 @@ pub struct FileInfo {
     pub name: String,
     pub directory: String,
     pub size: u64,
+    pub inode: u64,
     pub last_update: DateTime<Utc>,
 }
 
 pub struct FileWatcher {
     watched_files: HashMap<String, WatchedFileInfo>,
     all_files: HashMap<String, FileInfo>,
+    file_hasher: FileHasher,
 }
 
 pub enum FilePattern {
@@ -95,10 +100,11 @@ lazy_static! {
 }
 
 impl FileWatcher {
-    pub fn new() -> Self {
+    pub fn new(compute_md5_checksums: bool) -> Self {
         Self {
             watched_files: HashMap::new(),
             all_files: HashMap::new(),
+            file_hasher: FileHasher::new(compute_md5_checksums),
         }
     }
 
@@ -136,6 +142,7 @@ impl FileWatcher {
                     name: file_path.file_name().unwrap().to_str().unwrap().to_string(),
                     directory: directory.to_string(),
                     size,
+                    inode: metadata.ino(),
                     last_update: last_update.into(),
                 },
             );
@@ -161,6 +168,7 @@ impl FileWatcher {
                     WatchedFileInfo {
                         path: file_path.to_string(),
                         size: file_info.size,
+                        inode: file_info.inode,
                         last_update: file_info.last_update,
                         cached_path: None,
                         action: action.clone(),
@@ -222,58 +230,114 @@ impl FileWatcher {
         }
     }
 
-    pub fn cache_file(&self, file_cache_dir: &str, file_info: &mut WatchedFileInfo) -> Result<()> {
-        if file_info.cached_path.is_none() {
-            let file_name =
-                random_string::generate(CACHED_FILE_NAME_LENGTH, CACHED_FILE_NAME_CHARSET);
-            file_info.cached_path = Some(
-                Path::new(file_cache_dir)
-                    .join(file_name)
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
-            );
+    /// Keeps a copy of the file's current version, replacing the copy of the
+    /// previous one, so it can still be uploaded if the file is later removed.
+    pub fn cache_file(
+        &self,
+        file_cache: &FileCache,
+        previous_cached_path: Option<&String>,
+        file_info: &mut WatchedFileInfo,
+    ) -> Result<()> {
+        if let Some(previous_cached_path) = previous_cached_path {
+            file_cache.remove(previous_cached_path);
         }
-        fs::copy(&file_info.path, file_info.cached_path.as_ref().unwrap())?;
+
+        let file_name = random_string::generate(CACHED_FILE_NAME_LENGTH, CACHED_FILE_NAME_CHARSET);
+        let cached_path = file_cache
+            .get_directory()
+            .join(file_name)
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        file_info.cached_path = None;
+        file_cache.store(&file_info.path, &cached_path, false)?;
+        file_info.cached_path = Some(cached_path);
         Ok(())
     }
 
     pub fn prepare_cache_directory(&self, file_cache_dir: &str) -> Result<()> {
-        let path = Path::new(file_cache_dir);
-        if path.exists() {
-            fs::remove_dir_all(path)?;
-        }
-        fs::create_dir_all(path)?;
-        Ok(())
+        prepare_cache_directory(file_cache_dir)
     }
 
-    pub async fn upload_file(
+    pub async fn queue_upload(
         &self,
-        service_url: &str,
-        api_key: &str,
+        file_cache: &FileCache,
+        upload_queue: &UploadQueue,
         file_info: &WatchedFileInfo,
+        run_id: Option<&str>,
     ) -> Result<()> {
         let logger = Logger::new();
-        logger
-            .log(&format!("Uploading file: {}", file_info.path), None)
-            .await;
 
-        let file_path = file_info.cached_path.as_ref().unwrap_or(&file_info.path);
+        let cached_path = file_info
+            .cached_path
+            .as_ref()
+            .filter(|cached_path| file_cache.contains(cached_path));
+        let file_path = cached_path.unwrap_or(&file_info.path);
+
+        let checksum = self.file_hasher.get(&ChecksumKey {
+            path: file_info.path.clone(),
+            size: file_info.size,
+            last_update: file_info.last_update,
+            inode: file_info.inode,
+        });
 
-        upload_from_file_path(
-            service_url,
-            api_key,
-            file_path,
-            Path::new(&file_info.path).file_name().unwrap().to_str(),
-        )
-        .await?;
+        let queued = upload_queue
+            .enqueue(
+                &file_info.path,
+                file_path,
+                Path::new(&file_info.path)
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap(),
+                checksum,
+                cached_path.map(|cached_path| cached_path.as_str()),
+                UploadOrigin {
+                    owner_uid: None,
+                    run_id: run_id.map(|run_id| run_id.to_string()),
+                },
+            )
+            .await?;
+
+        let message = match queued {
+            Some(_) => format!("Queued upload of file: {}", file_info.path),
+            None => format!("Skipped upload of unchanged file: {}", file_info.path),
+        };
+        logger.log(&message, None).await;
 
         Ok(())
     }
 
+    /// Returns the cached digest of the file's current content, queueing it
+    /// for hashing in the background if it isn't known yet.
+    pub fn get_checksum(&self, path: &str, file_info: &FileInfo) -> Option<FileChecksum> {
+        let key = ChecksumKey {
+            path: path.to_string(),
+            size: file_info.size,
+            last_update: file_info.last_update,
+            inode: file_info.inode,
+        };
+        let checksum = self.file_hasher.get(&key);
+        if checksum.is_none() {
+            self.file_hasher.request(key);
+        }
+        checksum
+    }
+
+    /// Digests that finished computing since the last call
+    pub fn take_new_checksums(&self) -> Vec<(ChecksumKey, FileChecksum)> {
+        self.file_hasher.take_completed()
+    }
+
+    pub fn get_all_files(&self) -> &HashMap<String, FileInfo> {
+        &self.all_files
+    }
+
     pub fn get_file_by_path_suffix(&self, path_suffix: &str) -> Option<(&String, &FileInfo)> {
         let path = self.all_files.keys().find(|path| {
-            path.ends_with(path_suffix) && path_suffix.contains(path.split('/').last().unwrap())
+            path.ends_with(path_suffix)
+                && path_suffix.contains(path.split('/').next_back().unwrap())
         });
 
         if let Some(path) = path {
@@ -284,11 +348,11 @@ impl FileWatcher {
 
     pub async fn poll_files(
         &mut self,
-        service_url: &str,
-        api_key: &str,
         workflow_directory: &str,
-        file_cache_dir: &str,
+        file_cache: &FileCache,
         new_size_duration: TimeDelta,
+        upload_queue: &UploadQueue,
+        run_id: Option<&str>,
     ) -> Result<()> {
         let logger = Logger::new();
         let mut to_upload: Vec<WatchedFileInfo> = Vec::new();
@@ -312,6 +376,17 @@ impl FileWatcher {
             Self::gather_pattern_from_directory(&found_files, &mut watched_files, pattern, action)?;
         }
 
+        for file_info in watched_files.values() {
+            if found_files.contains_key(&file_info.path) {
+                self.file_hasher.request(ChecksumKey {
+                    path: file_info.path.clone(),
+                    size: file_info.size,
+                    last_update: file_info.last_update,
+                    inode: file_info.inode,
+                });
+            }
+        }
+
         let paths = found_files.keys().cloned().collect::<Vec<String>>();
 
         logger.log(&format!("Found files: {:?}", paths), None).await;
@@ -349,14 +424,32 @@ impl FileWatcher {
         }
 
         for file_info in to_upload {
-            self.upload_file(service_url, api_key, &file_info).await?;
+            if let Err(error) = self
+                .queue_upload(file_cache, upload_queue, &file_info, run_id)
+                .await
+            {
+                logger
+                    .log(
+                        &format!("Failed to queue upload of {}: {}", file_info.path, error),
+                        None,
+                    )
+                    .await;
+            }
         }
 
         for file_info in watched_files.values_mut() {
             let old_file_info = self.watched_files.get(&file_info.path);
             let update = self.check_if_file_to_update(old_file_info, Some(file_info));
             if update {
-                self.cache_file(file_cache_dir, file_info)?;
+                let previous_cached_path = old_file_info.and_then(|old| old.cached_path.as_ref());
+                if let Err(error) = self.cache_file(file_cache, previous_cached_path, file_info) {
+                    logger
+                        .log(
+                            &format!("Failed to cache {}: {}", file_info.path, error),
+                            None,
+                        )
+                        .await;
+                }
             } else if let Some(old_file_info) = old_file_info {
                 file_info.cached_path = old_file_info.cached_path.clone();
                 file_info.last_upload = if let Some(last_upload) = old_file_info.last_upload {
@@ -367,6 +460,15 @@ impl FileWatcher {
             }
         }
 
+        // Digests of files that are gone or changed since won't be asked for again
+        self.file_hasher.retain(|key| {
+            found_files.get(&key.path).is_some_and(|file_info| {
+                key.size == file_info.size
+                    && key.last_update == file_info.last_update
+                    && key.inode == file_info.inode
+            })
+        });
+
         self.watched_files = watched_files;
         self.all_files = found_files;
 
@@ -383,12 +485,13 @@ mod tests {
     #[test]
     fn test_check_if_file_to_update_no_changes() {
         let now: DateTime<Utc> = Utc::now();
-        let file_watcher = FileWatcher::new();
+        let file_watcher = FileWatcher::new(false);
         let old_file_info = WatchedFileInfo {
             path: "/tmp/test.txt".to_string(),
             size: 50,
-            last_update: now.clone(),
-            last_upload: Some(now.clone()),
+            inode: 1,
+            last_update: now,
+            last_upload: Some(now),
             cached_path: None,
             action: FileAction::None,
         };
@@ -396,8 +499,9 @@ mod tests {
         let new_file_info = WatchedFileInfo {
             path: "/tmp/test.txt".to_string(),
             size: 50,
-            last_update: now.clone(),
-            last_upload: Some(now.clone()),
+            inode: 1,
+            last_update: now,
+            last_upload: Some(now),
             cached_path: None,
             action: FileAction::None,
         };
@@ -408,12 +512,13 @@ mod tests {
     #[test]
     fn test_check_if_file_to_update_new_file() {
         let now: DateTime<Utc> = Utc::now();
-        let file_watcher = FileWatcher::new();
+        let file_watcher = FileWatcher::new(false);
         let old_file_info = WatchedFileInfo {
             path: "/tmp/test.txt".to_string(),
             size: 50,
-            last_update: now.clone(),
-            last_upload: Some(now.clone()),
+            inode: 1,
+            last_update: now,
+            last_upload: Some(now),
             cached_path: None,
             action: FileAction::None,
         };
@@ -422,8 +527,9 @@ mod tests {
         let new_file_info = WatchedFileInfo {
             path: "/tmp/test.txt".to_string(),
             size: 50,
-            last_update: newer.clone(),
-            last_upload: Some(now.clone()),
+            inode: 1,
+            last_update: newer,
+            last_upload: Some(now),
             cached_path: None,
             action: FileAction::None,
         };
@@ -0,0 +1,233 @@
+// src/kubernetes.rs
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::containers::{parse_cgroup, parse_mountinfo, ContainerRuntime};
+
+const DOWNWARD_API_DIRECTORY: &str = "etc/podinfo";
+/// Mounted in every pod with a service account, unless opted out of
+const SERVICE_ACCOUNT_NAMESPACE: &str = "var/run/secrets/kubernetes.io/serviceaccount/namespace";
+/// Set in every container by the kubelet
+const SERVICE_HOST_VARIABLE: &str = "KUBERNETES_SERVICE_HOST";
+
+lazy_static! {
+    /// `/kubepods/burstable/pod<uid>/` with cgroup v1, `kubepods-burstable-pod<uid>.slice` with
+    /// v2 and the systemd driver, which writes the dashes of the uid as underscores
+    static ref CGROUP_POD_UID: Regex =
+        Regex::new(r"kubepods[^\n]*?[/-]pod([0-9a-f]{8}[-_][0-9a-f]{4}[-_][0-9a-f]{4}[-_][0-9a-f]{4}[-_][0-9a-f]{12})")
+            .unwrap();
+}
+
+/// Resources of the daemon's container, as the Downward API puts them: cores
+/// and bytes unless the pod spec sets a divisor
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PodResources {
+    pub cpu_request: Option<String>,
+    pub cpu_limit: Option<String>,
+    pub memory_request: Option<String>,
+    pub memory_limit: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PodMetadata {
+    pub pod_name: Option<String>,
+    pub namespace: Option<String>,
+    pub pod_uid: Option<String>,
+    pub pod_ip: Option<String>,
+    pub node_name: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub resources: PodResources,
+    pub container_runtime: Option<ContainerRuntime>,
+    pub container_id: Option<String>,
+}
+
+/// Parses the `labels` file of a Downward API volume, lines of `key="value"`
+pub fn parse_labels(labels: &str) -> BTreeMap<String, String> {
+    labels
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            // Values are quoted the way Go does, which reads as JSON for label values
+            let value =
+                serde_json::from_str(value).unwrap_or_else(|_| value.trim_matches('"').to_string());
+            (key.to_string(), value)
+        })
+        .collect()
+}
+
+pub fn get_pod_uid_from_cgroup(cgroup: &str) -> Option<String> {
+    CGROUP_POD_UID
+        .captures(cgroup)
+        .map(|captures| captures[1].replace('_', "-"))
+}
+
+/// Reads the metadata of the pod the daemon runs in, from the environment and
+/// the files under `root`, the API server isn't asked. `None` outside of
+/// Kubernetes. The pod spec exposes what it wants through the Downward API,
+/// as the variables `POD_NAME`, `POD_NAMESPACE`, `POD_UID`, `POD_IP`,
+/// `NODE_NAME`, `CPU_REQUEST`, `CPU_LIMIT`, `MEMORY_REQUEST` and
+/// `MEMORY_LIMIT`, or as the files `name`, `namespace`, `uid`, `labels`,
+/// `cpu_request`, `cpu_limit`, `mem_request` and `mem_limit` of a volume
+/// mounted at `/etc/podinfo`.
+pub fn read_pod_metadata(get: impl Fn(&str) -> Option<String>, root: &Path) -> Option<PodMetadata> {
+    let read = |path: &Path| {
+        std::fs::read_to_string(root.join(path))
+            .ok()
+            .map(|contents| contents.trim().to_string())
+            .filter(|contents| !contents.is_empty())
+    };
+    let downward_api = Path::new(DOWNWARD_API_DIRECTORY);
+    let field =
+        |variable: &str, file: &str| get(variable).or_else(|| read(&downward_api.join(file)));
+
+    let namespace =
+        field("POD_NAMESPACE", "namespace").or_else(|| read(Path::new(SERVICE_ACCOUNT_NAMESPACE)));
+    if get(SERVICE_HOST_VARIABLE).is_none() && namespace.is_none() {
+        return None;
+    }
+
+    let cgroup = read(Path::new("proc/self/cgroup"));
+    // A container with a cgroup namespace of its own only sees `0::/`
+    let container = cgroup.as_deref().and_then(parse_cgroup).or_else(|| {
+        read(Path::new("proc/self/mountinfo")).and_then(|mountinfo| parse_mountinfo(&mountinfo))
+    });
+
+    Some(PodMetadata {
+        // The hostname of a pod is its name, unless the spec sets another one
+        pod_name: field("POD_NAME", "name").or_else(|| get("HOSTNAME")),
+        namespace,
+        pod_uid: field("POD_UID", "uid")
+            .or_else(|| cgroup.as_deref().and_then(get_pod_uid_from_cgroup)),
+        pod_ip: get("POD_IP"),
+        node_name: get("NODE_NAME"),
+        labels: read(&downward_api.join("labels"))
+            .map(|labels| parse_labels(&labels))
+            .unwrap_or_default(),
+        resources: PodResources {
+            cpu_request: field("CPU_REQUEST", "cpu_request"),
+            cpu_limit: field("CPU_LIMIT", "cpu_limit"),
+            memory_request: field("MEMORY_REQUEST", "mem_request"),
+            memory_limit: field("MEMORY_LIMIT", "mem_limit"),
+        },
+        container_runtime: container.as_ref().map(|(runtime, _)| *runtime),
+        container_id: container.map(|(_, container_id)| container_id),
+    })
+}
+
+/// Metadata of the pod the daemon runs in, `None` outside of Kubernetes
+pub fn detect_pod() -> Option<PodMetadata> {
+    read_pod_metadata(|name| std::env::var(name).ok(), Path::new("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+
+    const ID: &str = "4f1c3a0e9b8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1e0d9c8b7a6f5e4d3c2b";
+
+    fn write_file(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_parse_labels() {
+        assert_eq!(
+            parse_labels("app=\"rnaseq\"\napp.kubernetes.io/part-of=\"nf-core\"\nempty=\"\"\n"),
+            BTreeMap::from([
+                ("app".to_string(), "rnaseq".to_string()),
+                (
+                    "app.kubernetes.io/part-of".to_string(),
+                    "nf-core".to_string()
+                ),
+                ("empty".to_string(), "".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_get_pod_uid_from_cgroup() {
+        assert_eq!(
+            get_pod_uid_from_cgroup(&format!("0::/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod1b4e28ba_2fa1_11d2_883f_0016d3cca427.slice/cri-containerd-{}.scope\n", ID)),
+            Some("1b4e28ba-2fa1-11d2-883f-0016d3cca427".to_string())
+        );
+        assert_eq!(
+            get_pod_uid_from_cgroup(&format!(
+                "4:memory:/kubepods/besteffort/pod1b4e28ba-2fa1-11d2-883f-0016d3cca427/{}\n",
+                ID
+            )),
+            Some("1b4e28ba-2fa1-11d2-883f-0016d3cca427".to_string())
+        );
+        assert_eq!(
+            get_pod_uid_from_cgroup("0::/user.slice/user-1000.slice/session-3.scope\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_read_pod_metadata() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        write_file(
+            root.path(),
+            "etc/podinfo/labels",
+            "app=\"tracer\"\ntier=\"monitoring\"\n",
+        );
+        write_file(root.path(), "etc/podinfo/cpu_limit", "2\n");
+        write_file(root.path(), "etc/podinfo/mem_limit", "4294967296\n");
+        write_file(
+            root.path(),
+            "var/run/secrets/kubernetes.io/serviceaccount/namespace",
+            "genomics",
+        );
+        write_file(
+            root.path(),
+            "proc/self/cgroup",
+            &format!("0::/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1b4e28ba_2fa1_11d2_883f_0016d3cca427.slice/cri-containerd-{}.scope\n", ID),
+        );
+        let environment = HashMap::from([
+            ("KUBERNETES_SERVICE_HOST", "10.96.0.1"),
+            ("HOSTNAME", "tracer-x7k2p"),
+            ("NODE_NAME", "node-3"),
+            ("CPU_REQUEST", "1"),
+        ]);
+
+        assert_eq!(
+            read_pod_metadata(
+                |name| environment.get(name).map(|value| value.to_string()),
+                root.path()
+            ),
+            Some(PodMetadata {
+                pod_name: Some("tracer-x7k2p".to_string()),
+                namespace: Some("genomics".to_string()),
+                pod_uid: Some("1b4e28ba-2fa1-11d2-883f-0016d3cca427".to_string()),
+                pod_ip: None,
+                node_name: Some("node-3".to_string()),
+                labels: BTreeMap::from([
+                    ("app".to_string(), "tracer".to_string()),
+                    ("tier".to_string(), "monitoring".to_string()),
+                ]),
+                resources: PodResources {
+                    cpu_request: Some("1".to_string()),
+                    cpu_limit: Some("2".to_string()),
+                    memory_request: None,
+                    memory_limit: Some("4294967296".to_string()),
+                },
+                container_runtime: Some(ContainerRuntime::Containerd),
+                container_id: Some(ID.to_string()),
+            })
+        );
+
+        // Outside of Kubernetes
+        let empty = tempfile::tempdir()?;
+        assert_eq!(read_pod_metadata(|_| None, empty.path()), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,624 @@
+// src/lineage.rs
+use std::collections::hash_map::Entry::Vacant;
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::event_recorder::{EventRecorder, EventType};
+use crate::file_watcher::FileInfo;
+use crate::process_watcher::InputFile;
+
+/// How long after a tool finished files it wrote may still be found by the file watcher
+const OUTPUT_GRACE_PERIOD: TimeDelta = TimeDelta::minutes(2);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "node_type", rename_all = "snake_case")]
+pub enum LineageNode {
+    Tool {
+        id: String,
+        tool_name: String,
+        tool_pid: String,
+        start_timestamp: String,
+        end_timestamp: Option<String>,
+    },
+    File {
+        id: String,
+        file_name: String,
+        file_path: String,
+    },
+}
+
+impl LineageNode {
+    pub fn id(&self) -> &str {
+        match self {
+            LineageNode::Tool { id, .. } => id,
+            LineageNode::File { id, .. } => id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LineageEdgeType {
+    /// File -> tool: the file was an input of the tool
+    ConsumedBy,
+    /// Tool -> file: the file was written by the tool
+    ProducedBy,
+}
+
+impl LineageEdgeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineageEdgeType::ConsumedBy => "consumed_by",
+            LineageEdgeType::ProducedBy => "produced_by",
+        }
+    }
+}
+
+/// Edges always point in the direction the data flows
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LineageEdge {
+    pub from: String,
+    pub to: String,
+    pub edge_type: LineageEdgeType,
+}
+
+struct TrackedTool {
+    tool_pid: String,
+    tool_name: String,
+    start_time: DateTime<Utc>,
+    end_time: Option<DateTime<Utc>>,
+    output_candidates: Vec<String>,
+}
+
+/// Whether a tool running over the given times may have written a file last modified at `last_update`
+fn may_have_written(
+    start_time: DateTime<Utc>,
+    end_time: Option<DateTime<Utc>>,
+    last_update: DateTime<Utc>,
+) -> bool {
+    last_update >= start_time
+        && end_time.is_none_or(|end_time| last_update <= end_time + OUTPUT_GRACE_PERIOD)
+}
+
+#[derive(Default)]
+pub struct LineageGraph {
+    /// The run the lineage events of this graph are recorded for
+    run_id: Option<String>,
+    nodes: Vec<LineageNode>,
+    /// Position of each node in `nodes`, by node id
+    node_indices: HashMap<String, usize>,
+    edges: Vec<LineageEdge>,
+    /// Tools whose outputs may still show up, by node id since pids get reused
+    tools: HashMap<String, TrackedTool>,
+}
+
+/// One lineage graph per active run, so concurrent runs don't share tools and files
+#[derive(Default)]
+pub struct RunLineages {
+    graphs: HashMap<String, LineageGraph>,
+}
+
+impl RunLineages {
+    pub fn new() -> Self {
+        RunLineages::default()
+    }
+
+    pub fn get(&self, run_id: &str) -> Option<&LineageGraph> {
+        self.graphs.get(run_id)
+    }
+
+    pub fn get_mut(&mut self, run_id: &str) -> &mut LineageGraph {
+        self.graphs
+            .entry(run_id.to_string())
+            .or_insert_with(|| LineageGraph::for_run(run_id))
+    }
+
+    pub fn remove(&mut self, run_id: &str) -> Option<LineageGraph> {
+        self.graphs.remove(run_id)
+    }
+
+    pub fn link_outputs(
+        &mut self,
+        files: &HashMap<String, FileInfo>,
+        event_logger: &mut EventRecorder,
+    ) -> Result<()> {
+        for graph in self.graphs.values_mut() {
+            graph.link_outputs(files, event_logger)?;
+        }
+        Ok(())
+    }
+}
+
+fn file_node_id(file_path: &str) -> String {
+    format!("file:{}", file_path)
+}
+
+fn tool_node_id(tool_pid: &str, start_time: &DateTime<Utc>) -> String {
+    format!("tool:{}:{}", tool_pid, start_time.timestamp_millis())
+}
+
+/// Same matching rule as `FileWatcher::get_file_by_path_suffix`
+fn argument_matches_path(argument: &str, path: &str) -> bool {
+    path.ends_with(argument) && argument.contains(path.split('/').next_back().unwrap())
+}
+
+impl LineageGraph {
+    pub fn new() -> Self {
+        LineageGraph::default()
+    }
+
+    pub fn for_run(run_id: &str) -> Self {
+        LineageGraph {
+            run_id: Some(run_id.to_string()),
+            ..LineageGraph::default()
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get_nodes(&self) -> &[LineageNode] {
+        &self.nodes
+    }
+
+    #[allow(dead_code)]
+    pub fn get_edges(&self) -> &[LineageEdge] {
+        &self.edges
+    }
+
+    /// Adds the node unless one with the same id is already in the graph
+    fn add_node(&mut self, node: LineageNode) {
+        if let Vacant(entry) = self.node_indices.entry(node.id().to_string()) {
+            entry.insert(self.nodes.len());
+            self.nodes.push(node);
+        }
+    }
+
+    fn add_file_node(&mut self, file_name: &str, file_path: &str) -> String {
+        let id = file_node_id(file_path);
+        self.add_node(LineageNode::File {
+            id: id.clone(),
+            file_name: file_name.to_string(),
+            file_path: file_path.to_string(),
+        });
+        id
+    }
+
+    fn add_edge(
+        &mut self,
+        edge: LineageEdge,
+        tool_pid: &str,
+        tool_name: &str,
+        file_path: &str,
+        event_logger: &mut EventRecorder,
+    ) {
+        if self.edges.contains(&edge) {
+            return;
+        }
+
+        event_logger.record_run_event(
+            self.run_id.as_deref(),
+            EventType::FileLineage,
+            format!(
+                "[{}] {} {} {}",
+                Utc::now(),
+                file_path,
+                edge.edge_type.as_str(),
+                tool_name
+            ),
+            Some(json!({
+                "edge_type": edge.edge_type.as_str(),
+                "file_path": file_path,
+                "tool_name": tool_name,
+                "tool_pid": tool_pid,
+            })),
+            None,
+        );
+
+        self.edges.push(edge);
+    }
+
+    /// Registers a tool execution together with the files it read. Arguments
+    /// that didn't resolve to an existing file are kept as candidates for the
+    /// tool's outputs and resolved later by `link_outputs`.
+    pub fn record_tool_start(
+        &mut self,
+        tool_name: &str,
+        tool_pid: &str,
+        start_time: DateTime<Utc>,
+        input_files: &[InputFile],
+        output_candidates: Vec<String>,
+        event_logger: &mut EventRecorder,
+    ) {
+        let node_id = tool_node_id(tool_pid, &start_time);
+
+        self.add_node(LineageNode::Tool {
+            id: node_id.clone(),
+            tool_name: tool_name.to_string(),
+            tool_pid: tool_pid.to_string(),
+            start_timestamp: start_time.to_rfc3339(),
+            end_timestamp: None,
+        });
+
+        self.tools.insert(
+            node_id.clone(),
+            TrackedTool {
+                tool_pid: tool_pid.to_string(),
+                tool_name: tool_name.to_string(),
+                start_time,
+                end_time: None,
+                output_candidates,
+            },
+        );
+
+        for input_file in input_files {
+            let file_id = self.add_file_node(&input_file.file_name, &input_file.file_path);
+            self.add_edge(
+                LineageEdge {
+                    from: file_id,
+                    to: node_id.clone(),
+                    edge_type: LineageEdgeType::ConsumedBy,
+                },
+                tool_pid,
+                tool_name,
+                &input_file.file_path,
+                event_logger,
+            );
+        }
+    }
+
+    /// Marks the tool as finished; its outputs are only looked for a while longer
+    pub fn record_tool_finish(
+        &mut self,
+        tool_pid: &str,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) {
+        let node_id = tool_node_id(tool_pid, &start_time);
+        if let Some(tool) = self.tools.get_mut(&node_id) {
+            tool.end_time = Some(end_time);
+        }
+
+        if let Some(&index) = self.node_indices.get(&node_id) {
+            if let LineageNode::Tool { end_timestamp, .. } = &mut self.nodes[index] {
+                *end_timestamp = Some(end_time.to_rfc3339());
+            }
+        }
+    }
+
+    /// Connects files found in the workflow directory to the tools that wrote
+    /// them: a file is an output of a tool when one of the tool's unresolved
+    /// arguments points at it and it was modified after the tool started.
+    pub fn link_outputs(
+        &mut self,
+        files: &HashMap<String, FileInfo>,
+        event_logger: &mut EventRecorder,
+    ) -> Result<()> {
+        let mut produced = vec![];
+
+        for (node_id, tool) in self.tools.iter_mut() {
+            let TrackedTool {
+                tool_pid,
+                tool_name,
+                start_time,
+                end_time,
+                output_candidates,
+            } = tool;
+            output_candidates.retain(|argument| {
+                let found = files.iter().find(|(path, file_info)| {
+                    argument_matches_path(argument, path)
+                        && may_have_written(*start_time, *end_time, file_info.last_update)
+                });
+
+                if let Some((path, file_info)) = found {
+                    produced.push((
+                        tool_pid.clone(),
+                        tool_name.clone(),
+                        node_id.clone(),
+                        path.clone(),
+                        file_info.name.clone(),
+                    ));
+                    return false;
+                }
+                true
+            });
+        }
+
+        // Outputs of tools that finished a while ago would have been found by now
+        let now = Utc::now();
+        self.tools.retain(|_, tool| {
+            !tool.output_candidates.is_empty()
+                && tool
+                    .end_time
+                    .is_none_or(|end_time| now <= end_time + OUTPUT_GRACE_PERIOD)
+        });
+
+        for (tool_pid, tool_name, tool_node_id, file_path, file_name) in produced {
+            let file_id = self.add_file_node(&file_name, &file_path);
+            self.add_edge(
+                LineageEdge {
+                    from: tool_node_id,
+                    to: file_id,
+                    edge_type: LineageEdgeType::ProducedBy,
+                },
+                &tool_pid,
+                &tool_name,
+                &file_path,
+                event_logger,
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&json!({
+            "nodes": self.nodes,
+            "edges": self.edges,
+        }))?)
+    }
+
+    pub fn to_dot(&self) -> String {
+        let escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut out = String::from("digraph lineage {\n    rankdir=LR;\n");
+
+        for node in &self.nodes {
+            match node {
+                LineageNode::Tool {
+                    id,
+                    tool_name,
+                    tool_pid,
+                    ..
+                } => out.push_str(&format!(
+                    "    \"{}\" [shape=box, label=\"{} ({})\"];\n",
+                    escape(id),
+                    escape(tool_name),
+                    escape(tool_pid)
+                )),
+                LineageNode::File { id, file_name, .. } => out.push_str(&format!(
+                    "    \"{}\" [shape=note, label=\"{}\"];\n",
+                    escape(id),
+                    escape(file_name)
+                )),
+            }
+        }
+
+        for edge in &self.edges {
+            out.push_str(&format!(
+                "    \"{}\" -> \"{}\" [label=\"{}\"];\n",
+                escape(&edge.from),
+                escape(&edge.to),
+                edge.edge_type.as_str()
+            ));
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn export(&self, format: &str) -> Result<String> {
+        match format {
+            "dot" => Ok(self.to_dot()),
+            "json" => self.to_json(),
+            _ => Err(anyhow::anyhow!("Unsupported lineage format: {}", format)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    fn input_file(path: &str) -> InputFile {
+        let file_name = path.split('/').next_back().unwrap().to_string();
+        InputFile {
+            file_name,
+            file_size: 10,
+            file_path: path.to_string(),
+            file_directory: "/data".to_string(),
+            file_updated_at_timestamp: Utc::now().to_rfc3339(),
+            file_sha256: None,
+            file_md5: None,
+        }
+    }
+
+    fn file_info(name: &str, last_update: DateTime<Utc>) -> FileInfo {
+        FileInfo {
+            name: name.to_string(),
+            directory: "/data".to_string(),
+            size: 10,
+            inode: 1,
+            last_update,
+        }
+    }
+
+    #[test]
+    fn test_lineage_connects_outputs_to_downstream_inputs() -> Result<()> {
+        let mut graph = LineageGraph::new();
+        let mut logs = EventRecorder::new();
+        let start = Utc::now();
+
+        graph.record_tool_start(
+            "STAR",
+            "100",
+            start,
+            &[input_file("/data/sample.fastq")],
+            vec!["sample.bam".to_string()],
+            &mut logs,
+        );
+
+        let mut files = HashMap::new();
+        files.insert(
+            "/data/sample.bam".to_string(),
+            file_info("sample.bam", start + TimeDelta::seconds(5)),
+        );
+        graph.link_outputs(&files, &mut logs)?;
+        graph.record_tool_finish("100", start, start + TimeDelta::seconds(10));
+
+        graph.record_tool_start(
+            "samtools",
+            "200",
+            start + TimeDelta::seconds(20),
+            &[input_file("/data/sample.bam")],
+            vec![],
+            &mut logs,
+        );
+
+        let edges = graph.get_edges();
+        assert_eq!(edges.len(), 3);
+        assert_eq!(edges[1].edge_type, LineageEdgeType::ProducedBy);
+        assert_eq!(edges[1].to, "file:/data/sample.bam");
+        assert_eq!(edges[2].from, "file:/data/sample.bam");
+        assert_eq!(edges[2].edge_type, LineageEdgeType::ConsumedBy);
+
+        // One node per file, even though the BAM is both an output and an input
+        assert_eq!(graph.get_nodes().len(), 4);
+        assert_eq!(logs.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lineage_ignores_files_older_than_the_tool() -> Result<()> {
+        let mut graph = LineageGraph::new();
+        let mut logs = EventRecorder::new();
+        let start = Utc::now();
+
+        graph.record_tool_start(
+            "samtools",
+            "100",
+            start,
+            &[],
+            vec!["out.bam".to_string()],
+            &mut logs,
+        );
+
+        let mut files = HashMap::new();
+        files.insert(
+            "/data/out.bam".to_string(),
+            file_info("out.bam", start - TimeDelta::seconds(60)),
+        );
+        graph.link_outputs(&files, &mut logs)?;
+
+        assert!(graph.get_edges().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lineage_stops_matching_outputs_of_long_finished_tools() -> Result<()> {
+        let mut graph = LineageGraph::new();
+        let mut logs = EventRecorder::new();
+        let start = Utc::now() - TimeDelta::hours(1);
+
+        graph.record_tool_start(
+            "samtools",
+            "100",
+            start,
+            &[],
+            vec!["out.bam".to_string(), "out.bai".to_string()],
+            &mut logs,
+        );
+        graph.record_tool_finish("100", start, start + TimeDelta::seconds(10));
+        // The pid is reused by a later tool
+        let restart = start + TimeDelta::minutes(30);
+        graph.record_tool_start(
+            "bcftools",
+            "100",
+            restart,
+            &[],
+            vec!["calls.vcf".to_string()],
+            &mut logs,
+        );
+
+        let mut files = HashMap::new();
+        files.insert(
+            "/data/out.bam".to_string(),
+            file_info("out.bam", start + TimeDelta::minutes(20)),
+        );
+        files.insert(
+            "/data/calls.vcf".to_string(),
+            file_info("calls.vcf", restart + TimeDelta::seconds(5)),
+        );
+        graph.link_outputs(&files, &mut logs)?;
+
+        let edges = graph.get_edges();
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0].from, tool_node_id("100", &restart));
+        assert_eq!(edges[0].to, "file:/data/calls.vcf");
+        // The finished tool is forgotten, the one running has nothing left to look for
+        assert!(graph.tools.is_empty());
+
+        graph.record_tool_finish("100", restart, restart + TimeDelta::seconds(10));
+        assert!(graph.get_nodes().iter().all(|node| match node {
+            LineageNode::Tool { end_timestamp, .. } => end_timestamp.is_some(),
+            LineageNode::File { .. } => true,
+        }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_lineages_are_kept_apart() -> Result<()> {
+        let mut lineages = RunLineages::new();
+        let mut logs = EventRecorder::new();
+        let start = Utc::now();
+
+        for (run_id, tool_pid) in [("run-1", "100"), ("run-2", "200")] {
+            lineages.get_mut(run_id).record_tool_start(
+                "samtools",
+                tool_pid,
+                start,
+                &[],
+                vec!["out.bam".to_string()],
+                &mut logs,
+            );
+        }
+
+        let mut files = HashMap::new();
+        files.insert(
+            "/data/out.bam".to_string(),
+            file_info("out.bam", start + TimeDelta::seconds(5)),
+        );
+        lineages.link_outputs(&files, &mut logs)?;
+
+        let graph = lineages.remove("run-1").unwrap();
+        assert_eq!(graph.get_nodes().len(), 2);
+        assert_eq!(graph.get_edges().len(), 1);
+        assert_eq!(lineages.get("run-2").unwrap().get_edges().len(), 1);
+        assert!(lineages.get("run-1").is_none());
+        assert!(logs.get_run_events("run-1").is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lineage_export_formats() -> Result<()> {
+        let mut graph = LineageGraph::new();
+        let mut logs = EventRecorder::new();
+
+        graph.record_tool_start(
+            "fastqc",
+            "42",
+            Utc::now(),
+            &[input_file("/data/reads.fastq")],
+            vec![],
+            &mut logs,
+        );
+
+        let dot = graph.export("dot")?;
+        assert!(dot.starts_with("digraph lineage {"));
+        assert!(dot.contains("[label=\"consumed_by\"]"));
+
+        let json: serde_json::Value = serde_json::from_str(&graph.export("json")?)?;
+        assert_eq!(json["nodes"].as_array().unwrap().len(), 2);
+        assert_eq!(json["edges"][0]["edge_type"], "consumed_by");
+
+        assert!(graph.export("xml").is_err());
+
+        Ok(())
+    }
+}
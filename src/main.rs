@@ -1,23 +1,45 @@
+mod cgroups;
+mod checksum;
 mod cli;
+mod cloud_metadata;
 mod config_manager;
+mod containers;
+mod cost;
 mod daemon_communication;
 mod debug_log;
 mod event_recorder;
 mod events;
+mod file_cache;
 mod file_watcher;
 mod http_client;
+mod kubernetes;
+mod lineage;
 mod metrics;
+mod process_environment;
 mod process_watcher;
+mod qc_metrics;
+mod run_assignment;
+mod run_lifecycle;
+mod run_report;
+mod runtime_paths;
+mod slurm;
 mod stdout;
 mod submit_batched_data;
 mod syslog;
+mod timeline;
 mod tracer_client;
 mod upload;
+mod users;
+mod workflow_engines;
 use anyhow::{Context, Ok, Result};
 use cli::process_cli;
-use config_manager::{INTERCEPTOR_STDERR_FILE, INTERCEPTOR_STDOUT_FILE};
 use daemon_communication::server::run_server;
 use daemonize::Daemonize;
+use runtime_paths::{
+    ensure_private_dir, ensure_system_runtime_dir, get_cache_dir, INTERCEPTOR_STDERR_FILE,
+    INTERCEPTOR_STDOUT_FILE, PID_FILE, RUNTIME_DIR, SOCKET_PATH, STDERR_FILE, STDOUT_FILE,
+    SYSTEM_SOCKET_PATH,
+};
 use std::borrow::BorrowMut;
 use syslog::run_syslog_lines_read_thread;
 
@@ -28,14 +50,9 @@ use tokio::time::{sleep, Duration, Instant};
 use tokio_util::sync::CancellationToken;
 
 use crate::config_manager::ConfigManager;
-use crate::tracer_client::TracerClient;
+use crate::tracer_client::{RunOrigin, TracerClient};
 
-const PID_FILE: &str = "/tmp/tracerd.pid";
 const WORKING_DIR: &str = "/tmp";
-const STDOUT_FILE: &str = "/tmp/tracerd.out";
-const STDERR_FILE: &str = "/tmp/tracerd.err";
-const SOCKET_PATH: &str = "/tmp/tracerd.sock";
-const FILE_CACHE_DIR: &str = "/tmp/tracerd_cache";
 
 const SYSLOG_FILE: &str = "/var/log/syslog";
 
@@ -47,15 +64,15 @@ pub fn start_daemon() -> Result<()> {
 
     let daemon = Daemonize::new();
     daemon
-        .pid_file(PID_FILE)
+        .pid_file(&*PID_FILE)
         .working_directory(WORKING_DIR)
         .stdout(
-            File::create(STDOUT_FILE)
+            File::create(&*STDOUT_FILE)
                 .context("Failed to create stdout file")
                 .unwrap(),
         )
         .stderr(
-            File::create(STDERR_FILE)
+            File::create(&*STDERR_FILE)
                 .context("Failed to create stderr file")
                 .unwrap(),
         )
@@ -64,25 +81,37 @@ pub fn start_daemon() -> Result<()> {
 }
 
 pub fn main() -> Result<()> {
+    ensure_private_dir(&RUNTIME_DIR)?;
     process_cli()
 }
 
 #[tokio::main]
-pub async fn run(workflow_directory_path: String) -> Result<()> {
+pub async fn run(workflow_directory_path: String, system_mode: bool) -> Result<()> {
     let raw_config = ConfigManager::load_config();
-    let client = TracerClient::new(raw_config.clone(), workflow_directory_path)
+    let cache_dir = get_cache_dir(system_mode);
+    ensure_private_dir(&cache_dir)?;
+    let client = TracerClient::new(raw_config.clone(), workflow_directory_path, &cache_dir)
         .await
         .context("Failed to create TracerClient")?;
     let tracer_client = Arc::new(Mutex::new(client));
+    let automatic_runs = raw_config.automatic_runs || raw_config.slurm_job_runs;
     let config: Arc<RwLock<config_manager::Config>> = Arc::new(RwLock::new(raw_config));
 
     let cancellation_token = CancellationToken::new();
 
+    let socket_path: &str = if system_mode {
+        ensure_system_runtime_dir()?;
+        SYSTEM_SOCKET_PATH
+    } else {
+        &SOCKET_PATH
+    };
+
     tokio::spawn(run_server(
         tracer_client.clone(),
-        SOCKET_PATH,
+        socket_path,
         cancellation_token.clone(),
         config.clone(),
+        system_mode,
     ));
 
     let syslog_lines_task = tokio::spawn(run_syslog_lines_read_thread(
@@ -91,17 +120,20 @@ pub async fn run(workflow_directory_path: String) -> Result<()> {
     ));
 
     let stdout_lines_task = tokio::spawn(stdout::run_stdout_lines_read_thread(
-        INTERCEPTOR_STDOUT_FILE,
-        INTERCEPTOR_STDERR_FILE,
+        &INTERCEPTOR_STDOUT_FILE,
+        &INTERCEPTOR_STDERR_FILE,
         tracer_client.lock().await.get_stdout_stderr_lines_buffer(),
     ));
 
-    tracer_client
-        .lock()
-        .await
-        .borrow_mut()
-        .start_new_run(None)
-        .await?;
+    // With automatic runs, the first one starts with the pipeline's root process or SLURM job
+    if !automatic_runs {
+        tracer_client
+            .lock()
+            .await
+            .borrow_mut()
+            .start_new_run(None, RunOrigin::default())
+            .await?;
+    }
 
     while !cancellation_token.is_cancelled() {
         let start_time = Instant::now();
@@ -126,6 +158,11 @@ pub async fn run(workflow_directory_path: String) -> Result<()> {
             .await?;
 
         tracer_client.lock().await.borrow_mut().poll_files().await?;
+        tracer_client
+            .lock()
+            .await
+            .borrow_mut()
+            .poll_workflow_engines()?;
     }
 
     syslog_lines_task.abort();
@@ -136,8 +173,9 @@ pub async fn run(workflow_directory_path: String) -> Result<()> {
 
 pub async fn monitor_processes_with_tracer_client(tracer_client: &mut TracerClient) -> Result<()> {
     tracer_client.remove_completed_processes().await?;
+    // Runs start before polling, so the root's first tools are attributed to them
+    tracer_client.run_cleanup().await?;
     tracer_client.poll_processes()?;
-    // tracer_client.run_cleanup().await?;
     tracer_client.poll_process_metrics().await?;
     tracer_client.poll_syslog().await?;
     tracer_client.poll_stdout_stderr().await?;
@@ -160,9 +198,11 @@ mod tests {
     async fn test_monitor_processes_with_tracer_client() {
         let config = load_test_config();
         let pwd = std::env::current_dir().unwrap();
-        let mut tracer_client = TracerClient::new(config, pwd.to_str().unwrap().to_string())
-            .await
-            .unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let mut tracer_client =
+            TracerClient::new(config, pwd.to_str().unwrap().to_string(), cache_dir.path())
+                .await
+                .unwrap();
         let result = monitor_processes_with_tracer_client(&mut tracer_client).await;
         assert!(result.is_ok());
     }
@@ -0,0 +1,106 @@
+// src/process_environment.rs
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Parts of variable names that hint at a credential, never read whatever the allow-list says
+const SECRET_NAME_PARTS: [&str; 5] = ["TOKEN", "KEY", "PASSWORD", "SECRET", "CREDENTIAL"];
+
+fn looks_secret(name: &str) -> bool {
+    let name = name.to_uppercase();
+    SECRET_NAME_PARTS.iter().any(|part| name.contains(part))
+}
+
+/// Allow-list entries are variable names, or prefixes ending with `*` like `SNAKEMAKE_*`
+fn is_allowed(name: &str, allow_list: &[String]) -> bool {
+    allow_list
+        .iter()
+        .any(|allowed| match allowed.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == allowed,
+        })
+}
+
+/// The allowed variables of `NAME=value` entries, without the secret looking ones
+pub fn filter_environment<'a>(
+    entries: impl IntoIterator<Item = &'a str>,
+    allow_list: &[String],
+) -> BTreeMap<String, String> {
+    entries
+        .into_iter()
+        .filter_map(|entry| entry.split_once('='))
+        .filter(|(name, _)| is_allowed(name, allow_list) && !looks_secret(name))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Reads the allowed variables of the environment a process started with, from
+/// `<proc>/<pid>/environ`. Processes of other users can't be read without
+/// privileges, and have no variables then.
+pub fn read_process_environment(
+    proc_directory: &Path,
+    pid: u32,
+    allow_list: &[String],
+) -> BTreeMap<String, String> {
+    if allow_list.is_empty() {
+        return BTreeMap::new();
+    }
+    let Ok(environ) = std::fs::read(proc_directory.join(pid.to_string()).join("environ")) else {
+        return BTreeMap::new();
+    };
+    let environ = String::from_utf8_lossy(&environ);
+    filter_environment(environ.split('\0'), allow_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allow_list() -> Vec<String> {
+        ["SLURM_JOB_ID", "SNAKEMAKE_*", "GITHUB_TOKEN"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_filter_environment() {
+        let environment = filter_environment(
+            [
+                "SLURM_JOB_ID=4242",
+                "SLURM_JOB_NAME=align",
+                "SNAKEMAKE_PROFILE=slurm",
+                "SNAKEMAKE_STORAGE_S3_SECRET_KEY=hunter2",
+                "GITHUB_TOKEN=ghp_abc",
+                "PATH=/usr/bin",
+                "MALFORMED",
+            ],
+            &allow_list(),
+        );
+
+        assert_eq!(
+            environment,
+            BTreeMap::from([
+                ("SLURM_JOB_ID".to_string(), "4242".to_string()),
+                ("SNAKEMAKE_PROFILE".to_string(), "slurm".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_read_process_environment() -> anyhow::Result<()> {
+        let proc_directory = tempfile::tempdir()?;
+        std::fs::create_dir(proc_directory.path().join("100"))?;
+        std::fs::write(
+            proc_directory.path().join("100").join("environ"),
+            "HOME=/root\0SLURM_JOB_ID=7\0API_KEY=abc\0",
+        )?;
+
+        assert_eq!(
+            read_process_environment(proc_directory.path(), 100, &allow_list()),
+            BTreeMap::from([("SLURM_JOB_ID".to_string(), "7".to_string())])
+        );
+        assert!(read_process_environment(proc_directory.path(), 101, &allow_list()).is_empty());
+
+        Ok(())
+    }
+}
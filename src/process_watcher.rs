@@ -1,16 +1,27 @@
 // src/process_watcher.rs
+use crate::cgroups::{CgroupCollector, CgroupUsage};
+use crate::checksum::{ChecksumKey, FileChecksum};
 use crate::config_manager::target_process::Target;
 use crate::config_manager::target_process::TargetMatchable;
+use crate::containers::{ContainerInfo, ContainerResolver};
+use crate::cost::MachineRate;
 use crate::event_recorder::EventRecorder;
 use crate::event_recorder::EventType;
-use crate::file_watcher::FileWatcher;
+use crate::file_watcher::{FileInfo, FileWatcher};
+use crate::lineage::RunLineages;
+use crate::process_environment::read_process_environment;
+use crate::run_assignment::{assign_run, ProcessOrigin, RunScope};
+use crate::users::get_username;
+use crate::workflow_engines::nextflow::{find_task_directory, read_task, NextflowTask};
+use crate::workflow_engines::snakemake::ToolProcess;
+use crate::workflow_engines::{find_engine_task, PipelineMetadata};
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::json;
 use std::collections::hash_map::Entry::Vacant;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
 use std::time::Duration;
 use sysinfo::ProcessStatus;
@@ -18,8 +29,27 @@ use sysinfo::{Pid, Process, System};
 
 pub struct ProcessWatcher {
     targets: Vec<Target>,
+    /// Environment variables read from each new process
+    environment_variables: Vec<String>,
+    containers: ContainerResolver,
+    cgroups: CgroupCollector,
+    /// What the machine costs, to estimate what each tool costs
+    cost_rate: Option<MachineRate>,
+    /// Estimated cost of the finished tools of each run
+    tool_costs: HashMap<String, f64>,
     seen: HashMap<Pid, Proc>,
     process_tree: HashMap<Pid, ProcessTreeNode>,
+    detected_pipelines: Vec<(String, PipelineMetadata)>,
+    nextflow_tasks: Vec<(Option<String>, NextflowTask)>,
+    new_tools: Vec<ToolProcess>,
+    /// Tools that started before the digest of one of their input files was known
+    pending_input_checksums: HashMap<ChecksumKey, Vec<PendingInputChecksum>>,
+}
+
+struct PendingInputChecksum {
+    run_id: Option<String>,
+    tool_pid: String,
+    tool_name: String,
 }
 
 enum ProcLastUpdate {
@@ -29,9 +59,39 @@ enum ProcLastUpdate {
 
 pub struct Proc {
     name: String,
+    uid: Option<u32>,
+    run_id: Option<String>,
     start_time: DateTime<Utc>,
     last_update: ProcLastUpdate,
     just_started: bool,
+    usage: ToolUsage,
+}
+
+/// CPU and memory usage of a tool summed over the polls it was seen in
+#[derive(Default)]
+struct ToolUsage {
+    samples: u32,
+    cpu_usage: f64,
+    memory: f64,
+}
+
+impl ToolUsage {
+    fn record(&mut self, cpu_usage: f32, memory: u64) {
+        self.samples += 1;
+        self.cpu_usage += cpu_usage as f64;
+        self.memory += memory as f64;
+    }
+
+    /// Average CPU usage, in percent of a CPU, and memory
+    fn average(&self) -> (f64, f64) {
+        if self.samples == 0 {
+            return (0.0, 0.0);
+        }
+        (
+            self.cpu_usage / self.samples as f64,
+            self.memory / self.samples as f64,
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -41,6 +101,10 @@ pub struct InputFile {
     pub file_path: String,
     pub file_directory: String,
     pub file_updated_at_timestamp: String,
+    /// Only known here if the file was hashed before the tool started, the
+    /// digests computed later come as `tool_input_checksum` events
+    pub file_sha256: Option<String>,
+    pub file_md5: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -60,9 +124,18 @@ pub struct ProcessProperties {
     pub process_disk_usage_read_total: u64,
     pub process_disk_usage_write_total: u64,
     pub process_status: String,
+    #[serde(default)]
+    pub process_uid: Option<u32>,
+    #[serde(default)]
+    pub process_username: Option<String>,
+    /// Allow-listed variables of the environment the process started with
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub process_environment: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<ContainerInfo>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ShortLivedProcessLog {
     pub command: String,
     pub timestamp: String,
@@ -74,7 +147,6 @@ pub struct ProcessTreeNode {
     pub properties: ProcessProperties,
     pub children: Vec<ProcessTreeNode>,
     pub parent_id: Option<Pid>,
-    pub start_time: DateTime<Utc>,
 }
 
 fn process_status_to_string(status: &ProcessStatus) -> String {
@@ -94,12 +166,43 @@ fn process_status_to_string(status: &ProcessStatus) -> String {
     }
 }
 
+fn get_process_uid(proc: &Process) -> Option<u32> {
+    proc.user_id().map(|uid| **uid)
+}
+
+/// Whether a run owned by `owner_uid` tracks a process of `process_uid`.
+/// Runs without an owner track every user's processes.
+pub fn is_claimed_by(owner_uid: Option<u32>, process_uid: Option<u32>) -> bool {
+    owner_uid.is_none() || owner_uid == process_uid
+}
+
+/// Run the process is attributed to: `None` when no active run may track it,
+/// `Some(None)` when there are no runs at all
+fn get_process_run(system: &System, pid: Pid, runs: &[RunScope]) -> Option<Option<String>> {
+    if runs.is_empty() {
+        return Some(None);
+    }
+    assign_run(runs, &ProcessOrigin::of_process(system, pid), |pid| {
+        system.process(pid).and_then(|process| process.parent())
+    })
+    .map(|run| Some(run.run_id.clone()))
+}
+
 impl ProcessWatcher {
     pub fn new(targets: Vec<Target>) -> Self {
         ProcessWatcher {
             targets,
+            environment_variables: vec![],
+            containers: ContainerResolver::new(),
+            cgroups: CgroupCollector::new(),
+            cost_rate: None,
+            tool_costs: HashMap::new(),
             seen: HashMap::new(),
             process_tree: HashMap::new(),
+            detected_pipelines: vec![],
+            nextflow_tasks: vec![],
+            new_tools: vec![],
+            pending_input_checksums: HashMap::new(),
         }
     }
 
@@ -108,9 +211,28 @@ impl ProcessWatcher {
         system: &mut System,
         event_logger: &mut EventRecorder,
         file_watcher: &FileWatcher,
+        lineage: &mut RunLineages,
+        runs: &[RunScope],
     ) -> Result<()> {
+        self.containers.retain_processes(|pid, start_time| {
+            system
+                .process(pid)
+                .is_some_and(|process| process.start_time() == start_time)
+        });
+        // Looking up containers takes reading /proc, only done when a target needs it
+        let uses_container_images = self.targets.iter().any(Target::uses_container_image);
+
         for (pid, proc) in system.processes().iter() {
             if !self.seen.contains_key(pid) {
+                let container_image = if uses_container_images {
+                    match self.get_container(*pid, proc) {
+                        // Matched on a later poll, once the runtime told its image
+                        Some(container) if self.containers.is_resolving(&container) => continue,
+                        container => container.and_then(|container| container.image),
+                    }
+                } else {
+                    None
+                };
                 let target = self.targets.iter().find(|target| {
                     target.matches(
                         proc.name(),
@@ -119,18 +241,25 @@ impl ProcessWatcher {
                             .unwrap_or_else(|| Path::new(""))
                             .to_str()
                             .unwrap(),
+                        container_image.as_deref(),
                     )
                 });
-                if let Some(target) = target {
-                    self.add_new_process(
-                        *pid,
-                        proc,
-                        system,
-                        event_logger,
-                        Some(&target.clone()),
-                        file_watcher,
-                    )?;
-                }
+                let Some(target) = target else {
+                    continue;
+                };
+                let Some(run_id) = get_process_run(system, *pid, runs) else {
+                    continue;
+                };
+                self.add_new_process(
+                    *pid,
+                    proc,
+                    system,
+                    event_logger,
+                    Some(&target.clone()),
+                    file_watcher,
+                    lineage,
+                    run_id,
+                )?;
             }
         }
 
@@ -143,6 +272,8 @@ impl ProcessWatcher {
                 .collect(),
             event_logger,
             file_watcher,
+            lineage,
+            runs,
         )?;
 
         Ok(())
@@ -154,15 +285,22 @@ impl ProcessWatcher {
         event_logger: &mut EventRecorder,
         process_metrics_send_interval: Duration,
     ) -> Result<()> {
+        for (pid, proc) in self.seen.iter_mut() {
+            if let Some(process) = system.process(*pid) {
+                proc.usage.record(process.cpu_usage(), process.memory());
+            }
+        }
+
         for (pid, proc) in system.processes().iter() {
             if let Some(p) = self.seen.get(pid) {
+                let run_id = p.run_id.clone();
                 if !p.just_started {
                     if let ProcLastUpdate::RefreshesRemaining(refresh_count) = p.last_update {
                         if refresh_count != 0 {
                             self.seen.get_mut(pid).unwrap().last_update =
                                 ProcLastUpdate::RefreshesRemaining(refresh_count - 1);
                         } else {
-                            self.add_process_metrics(proc, event_logger, None)?;
+                            self.add_process_metrics(proc, event_logger, None, run_id.as_deref())?;
                             self.seen.get_mut(pid).unwrap().last_update =
                                 ProcLastUpdate::Some(Utc::now());
                         }
@@ -170,7 +308,7 @@ impl ProcessWatcher {
                     }
                     if let ProcLastUpdate::Some(last_update) = p.last_update {
                         if last_update + process_metrics_send_interval < Utc::now() {
-                            self.add_process_metrics(proc, event_logger, None)?;
+                            self.add_process_metrics(proc, event_logger, None, run_id.as_deref())?;
                             self.seen.get_mut(pid).unwrap().last_update =
                                 ProcLastUpdate::Some(Utc::now());
                         }
@@ -192,19 +330,28 @@ impl ProcessWatcher {
         &mut self,
         system: &mut System,
         event_logger: &mut EventRecorder,
+        lineage: &mut RunLineages,
     ) -> Result<()> {
-        let mut to_remove = vec![];
-        for (pid, proc) in self.seen.iter() {
-            if !system.processes().contains_key(pid) {
-                self.log_completed_process(pid, proc, event_logger)?;
-                to_remove.push(*pid);
+        let completed: Vec<Pid> = self
+            .seen
+            .keys()
+            .filter(|pid| !system.processes().contains_key(pid))
+            .copied()
+            .collect();
+
+        for pid in completed {
+            let proc = self.seen.remove(&pid).unwrap();
+            let cgroup_usage = self.cgroups.finish(pid);
+            self.log_completed_process(&pid, &proc, cgroup_usage, event_logger)?;
+            if let Some(run_id) = &proc.run_id {
+                lineage.get_mut(run_id).record_tool_finish(
+                    &pid.to_string(),
+                    proc.start_time,
+                    Utc::now(),
+                );
             }
         }
 
-        for pid in to_remove {
-            self.seen.remove(&pid);
-        }
-
         Ok(())
     }
 
@@ -217,7 +364,6 @@ impl ProcessWatcher {
                 properties,
                 children: vec![],
                 parent_id: proc.parent(),
-                start_time: DateTime::from_timestamp(proc.start_time() as i64, 0).unwrap(),
             };
 
             nodes.insert(*pid, node);
@@ -273,6 +419,8 @@ impl ProcessWatcher {
         targets: Vec<Target>,
         event_logger: &mut EventRecorder,
         file_watcher: &FileWatcher,
+        lineage: &mut RunLineages,
+        runs: &[RunScope],
     ) -> Result<()> {
         self.build_process_trees(system.processes());
         let nodes: &HashMap<Pid, ProcessTreeNode> = &self.process_tree;
@@ -283,10 +431,20 @@ impl ProcessWatcher {
             let mut valid_processes = vec![];
 
             for (pid, node) in nodes {
+                let container_image = if target.uses_container_image() {
+                    system.process(*pid).and_then(|process| {
+                        self.containers
+                            .get_container(*pid, process.start_time(), get_process_uid(process))
+                            .and_then(|container| container.image)
+                    })
+                } else {
+                    None
+                };
                 if target.matches(
                     &node.properties.tool_name,
                     &node.properties.tool_cmd,
                     &node.properties.tool_binary_path,
+                    container_image.as_deref(),
                 ) {
                     valid_processes.push(*pid);
                 }
@@ -313,7 +471,19 @@ impl ProcessWatcher {
                     continue;
                 }
                 let proc = process.unwrap();
-                self.add_new_process(pid, proc, system, event_logger, Some(target), file_watcher)?;
+                let Some(run_id) = get_process_run(system, pid, runs) else {
+                    continue;
+                };
+                self.add_new_process(
+                    pid,
+                    proc,
+                    system,
+                    event_logger,
+                    Some(target),
+                    file_watcher,
+                    lineage,
+                    run_id,
+                )?;
             }
         }
         Ok(())
@@ -325,6 +495,7 @@ impl ProcessWatcher {
         display_name: Option<String>,
     ) -> ProcessProperties {
         let start_time = Utc::now();
+        let process_uid = get_process_uid(proc);
 
         ProcessProperties {
             tool_name: display_name.unwrap_or(proc.name().to_owned()),
@@ -348,6 +519,10 @@ impl ProcessWatcher {
             process_memory_usage: proc.memory(),
             process_memory_virtual: proc.virtual_memory(),
             process_status: process_status_to_string(&proc.status()),
+            process_uid,
+            process_username: process_uid.and_then(get_username),
+            process_environment: BTreeMap::new(),
+            container: None,
         }
     }
 
@@ -355,9 +530,11 @@ impl ProcessWatcher {
         &mut self,
         short_lived_process: ShortLivedProcessLog,
         event_logger: &mut EventRecorder,
+        run_id: Option<String>,
     ) -> Result<()> {
         let properties = json!(short_lived_process.properties);
-        event_logger.record_event(
+        event_logger.record_run_event(
+            run_id.as_deref(),
             EventType::ToolExecution,
             format!(
                 "[{}] Short lived process: {}",
@@ -373,9 +550,12 @@ impl ProcessWatcher {
         {
             v.insert(Proc {
                 name: short_lived_process.command,
+                uid: short_lived_process.properties.process_uid,
+                run_id,
                 start_time: Utc::now(),
                 last_update: ProcLastUpdate::RefreshesRemaining(2),
                 just_started: true,
+                usage: ToolUsage::default(),
             });
         }
 
@@ -410,11 +590,16 @@ impl ProcessWatcher {
                     process_disk_usage_read_total: 0,
                     process_disk_usage_write_total: 0,
                     process_status: "Unknown".to_string(),
+                    process_uid: None,
+                    process_username: None,
+                    process_environment: BTreeMap::new(),
+                    container: None,
                 },
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn add_new_process(
         &mut self,
         pid: Pid,
@@ -423,24 +608,31 @@ impl ProcessWatcher {
         event_logger: &mut EventRecorder,
         target: Option<&Target>,
         file_watcher: &FileWatcher,
+        lineage: &mut RunLineages,
+        run_id: Option<String>,
     ) -> Result<()> {
+        // The process may have run for a while before this poll noticed it
+        let start_time =
+            DateTime::from_timestamp(proc.start_time() as i64, 0).unwrap_or_else(Utc::now);
         self.seen.insert(
             pid,
             Proc {
                 name: proc.name().to_string(),
-                start_time: Utc::now(),
+                uid: get_process_uid(proc),
+                run_id: run_id.clone(),
+                start_time,
                 last_update: ProcLastUpdate::RefreshesRemaining(2),
                 just_started: true,
+                usage: ToolUsage::default(),
             },
         );
+        self.cgroups.track(pid);
 
         let Some(p) = system.process(pid) else {
             eprintln!("[{}] Process({}) wasn't found", Utc::now(), proc.name());
             return Ok(());
         };
 
-        let start_time = Utc::now();
-
         let display_name = if let Some(target) = target {
             let name = target
                 .get_display_name_object()
@@ -451,14 +643,18 @@ impl ProcessWatcher {
             proc.name().to_owned()
         };
 
-        let mut properties = json!(Self::gather_process_data(
-            &pid,
-            p,
-            Some(display_name.clone())
-        ));
+        let mut process_properties = Self::gather_process_data(&pid, p, Some(display_name.clone()));
+        process_properties.process_environment = read_process_environment(
+            Path::new("/proc"),
+            pid.as_u32(),
+            &self.environment_variables,
+        );
+        process_properties.container = self.get_container(pid, p);
+        let mut properties = json!(process_properties);
 
         let cmd_arguments = p.cmd();
         let mut input_files = vec![];
+        let mut output_candidates = vec![];
 
         let mut arguments_to_check = vec![];
 
@@ -480,19 +676,72 @@ impl ProcessWatcher {
         for arg in arguments_to_check {
             let file = file_watcher.get_file_by_path_suffix(arg);
             if let Some((path, file_info)) = file {
+                let checksum = file_watcher.get_checksum(path, file_info);
+                if checksum.is_none() {
+                    let key = ChecksumKey {
+                        path: path.clone(),
+                        size: file_info.size,
+                        last_update: file_info.last_update,
+                        inode: file_info.inode,
+                    };
+                    self.pending_input_checksums.entry(key).or_default().push(
+                        PendingInputChecksum {
+                            run_id: run_id.clone(),
+                            tool_pid: pid.to_string(),
+                            tool_name: display_name.clone(),
+                        },
+                    );
+                }
                 input_files.push(InputFile {
                     file_name: file_info.name.clone(),
                     file_size: file_info.size,
                     file_path: path.clone(),
                     file_directory: file_info.directory.clone(),
                     file_updated_at_timestamp: file_info.last_update.to_rfc3339(),
+                    file_sha256: checksum.as_ref().map(|checksum| checksum.sha256.clone()),
+                    file_md5: checksum.and_then(|checksum| checksum.md5),
                 });
+            } else if cmd_arguments.first().map(String::as_str) != Some(arg)
+                && (arg.contains('.') || arg.contains('/'))
+            {
+                output_candidates.push(arg.to_string());
             }
         }
 
+        if let Some(run_id) = &run_id {
+            lineage.get_mut(run_id).record_tool_start(
+                &display_name,
+                &pid.to_string(),
+                start_time,
+                &input_files,
+                output_candidates,
+                event_logger,
+            );
+        }
+
         properties["input_files"] = serde_json::to_value(input_files)?;
 
-        event_logger.record_event(
+        if let Some((engine_task, pipeline)) = find_engine_task(system, pid) {
+            properties["engine_task"] = serde_json::to_value(engine_task)?;
+            if let Some(run_id) = &run_id {
+                self.detected_pipelines.push((run_id.clone(), pipeline));
+            }
+        }
+
+        if let Some(task) = p.cwd().and_then(find_task_directory).and_then(read_task) {
+            properties["nextflow_task"] = serde_json::to_value(&task)?;
+            self.nextflow_tasks.push((run_id.clone(), task));
+        }
+
+        self.new_tools.push(ToolProcess {
+            run_id: run_id.clone(),
+            pid: pid.to_string(),
+            command: p.cmd().to_vec(),
+            start_time,
+        });
+
+        event_logger.record_run_event(
+            run_id.as_deref(),
             EventType::ToolExecution,
             format!("[{}] Tool process: {}", start_time, &display_name),
             Some(properties),
@@ -507,6 +756,7 @@ impl ProcessWatcher {
         proc: &Process,
         event_logger: &mut EventRecorder,
         target: Option<&Target>,
+        run_id: Option<&str>,
     ) -> Result<()> {
         let pid = proc.pid();
         let start_time = Utc::now();
@@ -519,13 +769,17 @@ impl ProcessWatcher {
             proc.name().to_owned()
         };
 
-        let properties = json!(Self::gather_process_data(
+        let mut properties = json!(Self::gather_process_data(
             &pid,
             proc,
             Some(display_name.clone())
         ));
+        if let Some(cgroup_usage) = self.cgroups.read_usage(pid) {
+            properties["cgroup"] = serde_json::to_value(cgroup_usage)?;
+        }
 
-        event_logger.record_event(
+        event_logger.record_run_event(
+            run_id,
             EventType::ToolMetricEvent,
             format!("[{}] Tool metric event: {}", start_time, &display_name),
             Some(properties),
@@ -535,90 +789,37 @@ impl ProcessWatcher {
         Ok(())
     }
 
-    pub fn get_earliest_process_time(&self) -> DateTime<Utc> {
-        let mut earliest = Utc::now();
-
-        for proc in self.seen.values() {
-            if proc.start_time < earliest {
-                earliest = proc.start_time;
-            }
-        }
-
-        earliest
-    }
-
-    pub fn get_parent_pid(&self, run_start: Option<DateTime<Utc>>) -> Option<Pid> {
-        let mut possible_parents = vec![];
-
-        let parent = self
-            .seen
-            .iter()
-            .find(|(_, proc)| run_start.is_none() || proc.start_time > run_start.unwrap())?;
-
-        let mut pid = parent.0.to_owned();
-        loop {
-            let process = self.process_tree.get(&pid);
-
-            if process.is_none() {
-                break;
-            }
-
-            pid = process.unwrap().parent_id?;
-
-            possible_parents.push(pid);
-        }
-
-        for process in self.seen.iter() {
-            let mut pid = process.0.to_owned();
-            loop {
-                let process = self.process_tree.get(&pid);
-
-                if process.is_none() {
-                    break;
-                }
-
-                pid = process.unwrap().parent_id?;
-
-                if possible_parents.contains(&pid) {
-                    let index = possible_parents.iter().position(|&x| x == pid).unwrap();
-                    if index > 0 {
-                        possible_parents.drain(0..index - 1);
-                    }
-                    break;
-                }
-            }
-        }
-
-        possible_parents.retain(|x| {
-            run_start.is_none() || self.process_tree[x].start_time > run_start.unwrap()
-        });
-
-        if possible_parents.is_empty() {
-            None
-        } else {
-            Some(*possible_parents.last().unwrap())
-        }
-    }
-
-    pub fn is_process_alive(&self, system: &System, pid: Pid) -> bool {
-        system.process(pid).is_some()
-    }
-
     fn log_completed_process(
-        &self,
+        &mut self,
         pid: &Pid,
         proc: &Proc,
+        cgroup_usage: Option<CgroupUsage>,
         event_logger: &mut EventRecorder,
     ) -> Result<()> {
-        let duration = (Utc::now() - proc.start_time).to_std()?.as_millis();
+        let wall_time = (Utc::now() - proc.start_time).to_std()?;
+        let duration = wall_time.as_millis();
+        let estimated_cost = self.cost_rate.as_ref().map(|cost_rate| {
+            let (cpu_usage, memory) = proc.usage.average();
+            cost_rate.tool_cost(wall_time, cpu_usage, memory)
+        });
+        if let (Some(run_id), Some(estimated_cost)) = (&proc.run_id, estimated_cost) {
+            *self.tool_costs.entry(run_id.clone()).or_default() += estimated_cost;
+        }
 
-        let properties = json!({
+        let mut properties = json!({
             "tool_name": proc.name,
             "tool_pid": pid.to_string(),
-            "duration": duration
+            "duration": duration,
+            "process_uid": proc.uid,
+            "process_username": proc.uid.and_then(get_username),
+            "estimated_cost": estimated_cost,
         });
+        if let Some(cgroup_usage) = cgroup_usage {
+            properties["cgroup"] = serde_json::to_value(cgroup_usage)?;
+        }
 
-        event_logger.record_event(
+        event_logger.record_run_event(
+            proc.run_id.as_deref(),
             EventType::FinishedToolExecution,
             format!("[{}] {} exited", Utc::now(), &proc.name),
             Some(properties),
@@ -635,10 +836,118 @@ impl ProcessWatcher {
 
         self.targets = targets;
         self.seen.clear();
+        self.cgroups.clear();
+    }
+
+    fn get_container(&mut self, pid: Pid, process: &Process) -> Option<ContainerInfo> {
+        self.containers
+            .get_container(pid, process.start_time(), get_process_uid(process))
+    }
+
+    pub fn set_cost_rate(&mut self, cost_rate: Option<MachineRate>) {
+        self.cost_rate = cost_rate;
+    }
+
+    /// Estimated cost of the run's tools that finished so far
+    pub fn get_tools_cost(&self, run_id: &str) -> f64 {
+        self.tool_costs.get(run_id).copied().unwrap_or_default()
+    }
+
+    pub fn forget_run(&mut self, run_id: &str) {
+        self.tool_costs.remove(run_id);
+        for tools in self.pending_input_checksums.values_mut() {
+            tools.retain(|tool| tool.run_id.as_deref() != Some(run_id));
+        }
+        self.pending_input_checksums
+            .retain(|_, tools| !tools.is_empty());
+    }
+
+    /// Records the digests of input files that were still being computed when
+    /// the tools reading them started, and stops waiting for files that changed
+    /// or disappeared since
+    pub fn record_input_checksums(
+        &mut self,
+        checksums: &[(ChecksumKey, FileChecksum)],
+        files: &HashMap<String, FileInfo>,
+        event_logger: &mut EventRecorder,
+    ) {
+        for (key, checksum) in checksums {
+            let Some(tools) = self.pending_input_checksums.remove(key) else {
+                continue;
+            };
+            for tool in tools {
+                event_logger.record_run_event(
+                    tool.run_id.as_deref(),
+                    EventType::ToolInputChecksum,
+                    format!(
+                        "[{}] Input checksum of {}: {}",
+                        Utc::now(),
+                        tool.tool_name,
+                        key.path
+                    ),
+                    Some(json!({
+                        "tool_pid": tool.tool_pid,
+                        "tool_name": tool.tool_name,
+                        "file_path": key.path,
+                        "file_sha256": checksum.sha256,
+                        "file_md5": checksum.md5,
+                    })),
+                    None,
+                );
+            }
+        }
+
+        self.pending_input_checksums.retain(|key, _| {
+            files.get(&key.path).is_some_and(|file| {
+                file.size == key.size
+                    && file.last_update == key.last_update
+                    && file.inode == key.inode
+            })
+        });
+    }
+
+    pub fn reload_environment_variables(&mut self, environment_variables: Vec<String>) {
+        self.environment_variables = environment_variables;
+    }
+
+    /// Pipelines whose launchers spawned the tools seen since the last call,
+    /// with the run the tools belong to
+    pub fn take_detected_pipelines(&mut self) -> Vec<(String, PipelineMetadata)> {
+        std::mem::take(&mut self.detected_pipelines)
+    }
+
+    /// Nextflow tasks the tools seen since the last call run in, with their run
+    pub fn take_nextflow_tasks(&mut self) -> Vec<(Option<String>, NextflowTask)> {
+        std::mem::take(&mut self.nextflow_tasks)
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.seen.is_empty()
+    /// Tools seen since the last call, for engines that attribute them from their logs
+    pub fn take_new_tools(&mut self) -> Vec<ToolProcess> {
+        std::mem::take(&mut self.new_tools)
+    }
+
+    /// Current CPU (in percent of a CPU) and memory usage of each tracked tool, with its run
+    pub fn get_tool_usage(&self, system: &System) -> Vec<(Option<String>, String, f32, u64)> {
+        self.seen
+            .iter()
+            .filter_map(|(pid, proc)| {
+                let process = system.process(*pid)?;
+                Some((
+                    proc.run_id.clone(),
+                    proc.name.clone(),
+                    process.cpu_usage(),
+                    process.memory(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Runs with at least one tracked process alive
+    pub fn get_active_run_ids(&self) -> HashSet<String> {
+        self.seen
+            .values()
+            .filter_map(|proc| proc.run_id.clone())
+            .collect()
     }
 }
 
@@ -679,13 +988,16 @@ mod tests {
                 process_disk_usage_read_total: 0,
                 process_disk_usage_write_total: 0,
                 process_status: "test".to_string(),
+                process_uid: None,
+                process_username: None,
+                process_environment: BTreeMap::new(),
+                container: None,
             };
 
             let node = ProcessTreeNode {
                 properties,
                 children: vec![],
                 parent_id: Some(parent.into()),
-                start_time: Utc::now(),
             };
 
             nodes.insert(child.into(), node);
@@ -709,6 +1021,28 @@ mod tests {
         assert_eq!(result2, vec![2.into(), 1.into()]);
     }
 
+    #[test]
+    fn test_processes_are_claimed_by_their_owner() {
+        assert!(is_claimed_by(None, Some(1000)));
+        assert!(is_claimed_by(None, None));
+        assert!(is_claimed_by(Some(1000), Some(1000)));
+        assert!(!is_claimed_by(Some(1000), Some(1001)));
+        assert!(!is_claimed_by(Some(1000), None));
+    }
+
+    #[test]
+    fn test_process_data_carries_the_user() {
+        let system = System::new_all();
+        let pid = sysinfo::get_current_pid().unwrap();
+
+        let properties =
+            ProcessWatcher::gather_process_data(&pid, system.process(pid).unwrap(), None);
+
+        let uid = crate::runtime_paths::current_uid();
+        assert_eq!(properties.process_uid, Some(uid));
+        assert_eq!(properties.process_username, get_username(uid));
+    }
+
     #[test]
     fn test_create_process_tree() -> Result<()> {
         let mut process_watcher = ProcessWatcher::new(vec![]);
@@ -718,4 +1052,59 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_input_checksums_computed_later_are_recorded_for_the_tool() {
+        let mut process_watcher = ProcessWatcher::new(vec![]);
+        let mut logs = EventRecorder::new();
+        let last_update = Utc::now();
+        let key = |path: &str| ChecksumKey {
+            path: path.to_string(),
+            size: 10,
+            last_update,
+            inode: 1,
+        };
+        for path in ["/data/reads.fq", "/data/removed.fq"] {
+            process_watcher.pending_input_checksums.insert(
+                key(path),
+                vec![PendingInputChecksum {
+                    run_id: Some("run-1".to_string()),
+                    tool_pid: "42".to_string(),
+                    tool_name: "bwa".to_string(),
+                }],
+            );
+        }
+        let files = HashMap::from([(
+            "/data/reads.fq".to_string(),
+            FileInfo {
+                name: "reads.fq".to_string(),
+                directory: "/data".to_string(),
+                size: 10,
+                inode: 1,
+                last_update,
+            },
+        )]);
+        let checksum = FileChecksum {
+            sha256: "abc".to_string(),
+            md5: None,
+        };
+
+        process_watcher.record_input_checksums(&[], &files, &mut logs);
+        assert!(logs.is_empty());
+        assert_eq!(process_watcher.pending_input_checksums.len(), 1);
+
+        process_watcher.record_input_checksums(
+            &[(key("/data/reads.fq"), checksum)],
+            &files,
+            &mut logs,
+        );
+        let event = &logs.get_events()[0];
+        assert_eq!(event.run_id.as_deref(), Some("run-1"));
+        assert_eq!(event.process_status, "tool_input_checksum");
+        let attributes = event.attributes.as_ref().unwrap();
+        assert_eq!(attributes["tool_pid"], "42");
+        assert_eq!(attributes["file_path"], "/data/reads.fq");
+        assert_eq!(attributes["file_sha256"], "abc");
+        assert!(process_watcher.pending_input_checksums.is_empty());
+    }
 }
@@ -0,0 +1,207 @@
+mod parsers;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use predicates::Predicate;
+use serde_json::json;
+
+use crate::{
+    event_recorder::{EventRecorder, EventType},
+    file_watcher::FileInfo,
+};
+use parsers::QcMetrics;
+
+/// QC reports larger than this aren't read, so a poll never holds a huge file in memory
+const MAX_QC_FILE_BYTES: u64 = 64 * 1024 * 1024;
+
+fn read_qc_file(path: &str, parse: fn(&str) -> Result<QcMetrics>) -> Result<QcMetrics> {
+    let mut content = String::new();
+    File::open(path)?
+        .take(MAX_QC_FILE_BYTES + 1)
+        .read_to_string(&mut content)?;
+    if content.len() as u64 > MAX_QC_FILE_BYTES {
+        bail!("larger than {} bytes", MAX_QC_FILE_BYTES);
+    }
+    parse(&content)
+}
+
+/// Turns known QC reports found in the workflow directory into structured metric events
+pub struct QcMetricsCollector {
+    parsed_files: HashMap<String, DateTime<Utc>>,
+}
+
+impl QcMetricsCollector {
+    pub fn new() -> QcMetricsCollector {
+        QcMetricsCollector {
+            parsed_files: HashMap::new(),
+        }
+    }
+
+    /// Parses every matching file once per modification, after it stopped
+    /// changing for `new_size_duration`, the same rule used for uploads.
+    /// Files are read on a blocking thread, and skipped past `MAX_QC_FILE_BYTES`.
+    pub async fn poll_files(
+        &mut self,
+        files: &HashMap<String, FileInfo>,
+        new_size_duration: TimeDelta,
+        logs: &mut EventRecorder,
+    ) -> Result<()> {
+        self.parsed_files.retain(|path, _| files.contains_key(path));
+
+        for (path, file_info) in files {
+            if Utc::now() - file_info.last_update <= new_size_duration
+                || self.parsed_files.get(path) == Some(&file_info.last_update)
+            {
+                continue;
+            }
+
+            let Some(parser) = parsers::QC_PARSERS
+                .iter()
+                .find(|parser| parser.file_pattern.eval(&file_info.name))
+            else {
+                continue;
+            };
+
+            self.parsed_files
+                .insert(path.clone(), file_info.last_update);
+
+            let metrics = if file_info.size > MAX_QC_FILE_BYTES {
+                Err(anyhow::anyhow!("larger than {} bytes", MAX_QC_FILE_BYTES))
+            } else {
+                let (path, parse) = (path.clone(), parser.parse);
+                tokio::task::spawn_blocking(move || read_qc_file(&path, parse)).await?
+            };
+
+            let metrics = match metrics {
+                Ok(metrics) if !metrics.is_empty() => metrics,
+                Ok(_) => continue,
+                Err(error) => {
+                    eprintln!(
+                        "[{}] Failed to parse {} as {}: {}",
+                        Utc::now(),
+                        path,
+                        parser.id,
+                        error
+                    );
+                    continue;
+                }
+            };
+
+            logs.record_event(
+                EventType::QcMetricEvent,
+                format!("[{}] QC metrics ({}): {}", Utc::now(), parser.id, path),
+                Some(json!({
+                    "qc_parser": parser.id,
+                    "file_name": file_info.name,
+                    "file_path": path,
+                    "file_updated_at_timestamp": file_info.last_update.to_rfc3339(),
+                    "qc_metrics": metrics,
+                })),
+                None,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str, last_update: DateTime<Utc>) -> (String, FileInfo) {
+        (
+            format!("test-files/qc/{}", name),
+            FileInfo {
+                name: name.to_string(),
+                directory: "test-files/qc".to_string(),
+                size: 0,
+                inode: 0,
+                last_update,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_poll_files_records_each_modification_once() -> Result<()> {
+        let mut collector = QcMetricsCollector::new();
+        let mut logs = EventRecorder::new();
+        let stable = Utc::now() - TimeDelta::minutes(5);
+
+        let mut files = HashMap::from([
+            fixture("Log.final.out", stable),
+            fixture("sample.flagstat", stable),
+            fixture("chipseq.sh", stable),
+        ]);
+
+        collector
+            .poll_files(&files, TimeDelta::minutes(1), &mut logs)
+            .await?;
+        assert_eq!(logs.len(), 2);
+
+        let event = logs
+            .get_events()
+            .iter()
+            .find(|event| event.attributes.as_ref().unwrap()["qc_parser"] == "star_log_final")
+            .unwrap();
+        assert_eq!(
+            event.attributes.as_ref().unwrap()["qc_metrics"]["uniquely_mapped_reads_percent"],
+            json!(85.32)
+        );
+
+        collector
+            .poll_files(&files, TimeDelta::minutes(1), &mut logs)
+            .await?;
+        assert_eq!(logs.len(), 2);
+
+        // Modified, but still being written
+        files.extend([fixture("sample.flagstat", Utc::now())]);
+        collector
+            .poll_files(&files, TimeDelta::minutes(1), &mut logs)
+            .await?;
+        assert_eq!(logs.len(), 2);
+
+        files.extend([fixture("sample.flagstat", stable + TimeDelta::seconds(1))]);
+        collector
+            .poll_files(&files, TimeDelta::minutes(1), &mut logs)
+            .await?;
+        assert_eq!(logs.len(), 3);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_poll_files_skips_large_files_and_forgets_removed_ones() -> Result<()> {
+        let mut collector = QcMetricsCollector::new();
+        let mut logs = EventRecorder::new();
+        let stable = Utc::now() - TimeDelta::minutes(5);
+
+        let (path, mut file_info) = fixture("test_peaks.narrowPeak", stable);
+        file_info.size = MAX_QC_FILE_BYTES + 1;
+        let mut files = HashMap::from([
+            (path.clone(), file_info),
+            fixture("sample.flagstat", stable),
+        ]);
+
+        collector
+            .poll_files(&files, TimeDelta::minutes(1), &mut logs)
+            .await?;
+        assert_eq!(logs.len(), 1);
+        assert_eq!(
+            logs.get_events()[0].attributes.as_ref().unwrap()["qc_parser"],
+            "samtools_flagstat"
+        );
+
+        files.remove(&path);
+        collector
+            .poll_files(&files, TimeDelta::minutes(1), &mut logs)
+            .await?;
+        assert!(!collector.parsed_files.contains_key(&path));
+        assert_eq!(collector.parsed_files.len(), 1);
+
+        Ok(())
+    }
+}
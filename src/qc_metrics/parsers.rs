@@ -0,0 +1,400 @@
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use predicates::{prelude::predicate, str::RegexPredicate};
+use serde_json::{json, Map, Value};
+
+pub type QcMetrics = Map<String, Value>;
+
+pub struct QcParser {
+    pub id: String,
+    pub file_pattern: RegexPredicate,
+    pub parse: fn(&str) -> Result<QcMetrics>,
+}
+
+impl QcParser {
+    pub fn new(id: &str, file_pattern: &str, parse: fn(&str) -> Result<QcMetrics>) -> QcParser {
+        QcParser {
+            id: id.to_string(),
+            file_pattern: predicate::str::is_match(file_pattern).unwrap(),
+            parse,
+        }
+    }
+}
+
+lazy_static! {
+    /// Parsers are matched against the file name, first match wins
+    pub static ref QC_PARSERS: Vec<QcParser> = vec![
+        QcParser::new("star_log_final", r"Log\.final\.out$", parse_star_log_final),
+        QcParser::new("featurecounts_summary", r"_counts\.summary$", parse_featurecounts_summary),
+        QcParser::new("narrow_peak", r"\.narrowPeak$", parse_narrow_peak),
+        QcParser::new("samtools_flagstat", r"\.flagstat$", parse_samtools_flagstat),
+        QcParser::new("samtools_stats", r"\.stats$", parse_samtools_stats),
+        QcParser::new("fastp", r"fastp\.json$", parse_fastp_json),
+        QcParser::new("picard_markduplicates", r"(dup|duplicate)[a-z_.]*metrics(\.txt)?$", parse_picard_markduplicates),
+        QcParser::new("multiqc", r"^multiqc_data\.json$", parse_multiqc_data),
+    ];
+}
+
+/// "Uniquely mapped reads %" -> "uniquely_mapped_reads_percent"
+fn metric_key(name: &str) -> String {
+    let name = name.replace('%', " percent ");
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| part.to_lowercase())
+        .collect::<Vec<String>>()
+        .join("_")
+}
+
+/// Numbers become JSON numbers, with a trailing '%' stripped; anything else stays a string
+fn metric_value(value: &str) -> Value {
+    let value = value.trim();
+    let number = value.trim_end_matches('%');
+    if let Ok(integer) = number.parse::<i64>() {
+        return json!(integer);
+    }
+    if let Ok(float) = number.parse::<f64>() {
+        return json!(float);
+    }
+    json!(value)
+}
+
+pub fn parse_star_log_final(content: &str) -> Result<QcMetrics> {
+    let mut metrics = Map::new();
+
+    for line in content.lines() {
+        let Some((name, value)) = line.split_once('|') else {
+            continue;
+        };
+        let key = metric_key(name);
+        if key.is_empty() || key.starts_with("started") || key.starts_with("finished") {
+            continue;
+        }
+        metrics.insert(key, metric_value(value));
+    }
+
+    Ok(metrics)
+}
+
+pub fn parse_featurecounts_summary(content: &str) -> Result<QcMetrics> {
+    let mut metrics = Map::new();
+    let mut total = 0;
+
+    for line in content.lines().skip(1) {
+        let columns: Vec<&str> = line.split('\t').collect();
+        if columns.len() < 2 {
+            continue;
+        }
+        // Summaries of several BAM files have one column per file; sum them up
+        let count: i64 = columns[1..]
+            .iter()
+            .filter_map(|value| value.trim().parse::<i64>().ok())
+            .sum();
+        total += count;
+        metrics.insert(metric_key(columns[0]), json!(count));
+    }
+
+    if total > 0 {
+        if let Some(assigned) = metrics.get("assigned").and_then(|value| value.as_i64()) {
+            metrics.insert(
+                "assigned_percent".to_string(),
+                json!(assigned as f64 / total as f64 * 100.0),
+            );
+        }
+    }
+    metrics.insert("total_reads".to_string(), json!(total));
+
+    Ok(metrics)
+}
+
+pub fn parse_narrow_peak(content: &str) -> Result<QcMetrics> {
+    let mut metrics = Map::new();
+
+    let peaks: Vec<Vec<&str>> = content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with("track"))
+        .map(|line| line.split('\t').collect())
+        .collect();
+
+    let widths: Vec<i64> = peaks
+        .iter()
+        .filter_map(|columns| {
+            let start = columns.get(1)?.parse::<i64>().ok()?;
+            let end = columns.get(2)?.parse::<i64>().ok()?;
+            Some(end - start)
+        })
+        .collect();
+
+    metrics.insert("peak_count".to_string(), json!(peaks.len()));
+    if !widths.is_empty() {
+        metrics.insert(
+            "mean_peak_width".to_string(),
+            json!(widths.iter().sum::<i64>() as f64 / widths.len() as f64),
+        );
+    }
+
+    Ok(metrics)
+}
+
+pub fn parse_samtools_flagstat(content: &str) -> Result<QcMetrics> {
+    let mut metrics = Map::new();
+
+    // "1900000 + 0 mapped (95.00% : N/A)"
+    for line in content.lines() {
+        let Some((passed, rest)) = line.split_once(" + ") else {
+            continue;
+        };
+        let Some((failed, description)) = rest.split_once(' ') else {
+            continue;
+        };
+        let mut key = metric_key(description.split(" (").next().unwrap_or(description));
+        if metrics.contains_key(&key) {
+            // e.g. "with mate mapped to a different chr (mapQ>=5)"
+            key = metric_key(description);
+        }
+
+        metrics.insert(key.clone(), metric_value(passed));
+        metrics.insert(format!("{}_qc_failed", key), metric_value(failed));
+
+        if let Some(percent) = rest
+            .split_once('(')
+            .and_then(|(_, detail)| detail.split_once('%'))
+            .and_then(|(percent, _)| percent.parse::<f64>().ok())
+        {
+            metrics.insert(format!("{}_percent", key), json!(percent));
+        }
+    }
+
+    Ok(metrics)
+}
+
+pub fn parse_samtools_stats(content: &str) -> Result<QcMetrics> {
+    let mut metrics = Map::new();
+
+    // "SN\treads mapped:\t1900000\t# comment"
+    for line in content.lines().filter(|line| line.starts_with("SN\t")) {
+        let columns: Vec<&str> = line.split('\t').collect();
+        if columns.len() < 3 {
+            continue;
+        }
+        metrics.insert(metric_key(columns[1]), metric_value(columns[2]));
+    }
+
+    Ok(metrics)
+}
+
+pub fn parse_fastp_json(content: &str) -> Result<QcMetrics> {
+    let report: Value = serde_json::from_str(content).context("Invalid fastp JSON")?;
+    let mut metrics = Map::new();
+
+    for stage in ["before_filtering", "after_filtering"] {
+        if let Some(summary) = report["summary"][stage].as_object() {
+            for (name, value) in summary {
+                metrics.insert(format!("{}_{}", stage, name), value.clone());
+            }
+        }
+    }
+
+    if let Some(filtering_result) = report["filtering_result"].as_object() {
+        for (name, value) in filtering_result {
+            metrics.insert(metric_key(name), value.clone());
+        }
+    }
+
+    if !report["duplication"]["rate"].is_null() {
+        metrics.insert(
+            "duplication_rate".to_string(),
+            report["duplication"]["rate"].clone(),
+        );
+    }
+
+    if let Some(adapter_cutting) = report["adapter_cutting"].as_object() {
+        for (name, value) in adapter_cutting {
+            metrics.insert(metric_key(name), value.clone());
+        }
+    }
+
+    Ok(metrics)
+}
+
+pub fn parse_picard_markduplicates(content: &str) -> Result<QcMetrics> {
+    let mut metrics = Map::new();
+    let mut lines = content.lines();
+
+    // The metrics table follows the "## METRICS CLASS" line: a header row and one row per library
+    lines
+        .by_ref()
+        .find(|line| line.starts_with("## METRICS CLASS"))
+        .context("No METRICS CLASS section found")?;
+
+    let header: Vec<&str> = lines
+        .next()
+        .context("Missing metrics header")?
+        .split('\t')
+        .collect();
+
+    let mut libraries = vec![];
+    for line in lines.take_while(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+        let mut library = Map::new();
+        for (name, value) in header.iter().zip(line.split('\t')) {
+            library.insert(metric_key(name), metric_value(value));
+        }
+        libraries.push(Value::Object(library));
+    }
+
+    if libraries.len() == 1 {
+        if let Value::Object(library) = libraries.remove(0) {
+            metrics = library;
+        }
+    } else {
+        metrics.insert("libraries".to_string(), json!(libraries));
+    }
+
+    Ok(metrics)
+}
+
+pub fn parse_multiqc_data(content: &str) -> Result<QcMetrics> {
+    let report: Value = serde_json::from_str(content).context("Invalid MultiQC JSON")?;
+    let mut samples: Map<String, Value> = Map::new();
+
+    // One object per MultiQC module, each keyed by sample name
+    for module in report["report_general_stats_data"]
+        .as_array()
+        .context("No general stats in MultiQC data")?
+    {
+        let Some(module) = module.as_object() else {
+            continue;
+        };
+        for (sample, sample_metrics) in module {
+            let entry = samples
+                .entry(sample.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if let (Some(entry), Some(sample_metrics)) =
+                (entry.as_object_mut(), sample_metrics.as_object())
+            {
+                for (name, value) in sample_metrics {
+                    entry.insert(name.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    let mut metrics = Map::new();
+    metrics.insert("sample_count".to_string(), json!(samples.len()));
+    metrics.insert("samples".to_string(), Value::Object(samples));
+
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use predicates::Predicate;
+
+    const FIXTURES_DIR: &str = "test-files/qc";
+
+    fn parse_fixture(file_name: &str) -> (String, QcMetrics) {
+        let content = std::fs::read_to_string(format!("{}/{}", FIXTURES_DIR, file_name)).unwrap();
+        let parser = QC_PARSERS
+            .iter()
+            .find(|parser| parser.file_pattern.eval(file_name))
+            .unwrap_or_else(|| panic!("No parser registered for {}", file_name));
+        (parser.id.clone(), (parser.parse)(&content).unwrap())
+    }
+
+    #[test]
+    fn test_metric_key() {
+        assert_eq!(
+            metric_key("Uniquely mapped reads %"),
+            "uniquely_mapped_reads_percent"
+        );
+        assert_eq!(
+            metric_key("Number of splices: GT/AG"),
+            "number_of_splices_gt_ag"
+        );
+    }
+
+    #[test]
+    fn test_parse_star_log_final() {
+        let (id, metrics) = parse_fixture("Log.final.out");
+        assert_eq!(id, "star_log_final");
+        assert_eq!(metrics["uniquely_mapped_reads_percent"], json!(85.32));
+        assert_eq!(metrics["uniquely_mapped_reads_number"], json!(5117615));
+        assert_eq!(metrics["number_of_input_reads"], json!(5998210));
+        assert!(!metrics.contains_key("started_job_on"));
+    }
+
+    #[test]
+    fn test_parse_featurecounts_summary() {
+        let (id, metrics) = parse_fixture("control_counts.summary");
+        assert_eq!(id, "featurecounts_summary");
+        assert_eq!(metrics["assigned"], json!(4210577));
+        assert_eq!(metrics["unassigned_nofeatures"], json!(612044));
+        assert_eq!(metrics["total_reads"], json!(5329708));
+        let assigned_percent = metrics["assigned_percent"].as_f64().unwrap();
+        assert!((assigned_percent - 79.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_parse_narrow_peak() {
+        let (id, metrics) = parse_fixture("test_peaks.narrowPeak");
+        assert_eq!(id, "narrow_peak");
+        assert_eq!(metrics["peak_count"], json!(3));
+        assert_eq!(metrics["mean_peak_width"], json!(460.0));
+    }
+
+    #[test]
+    fn test_parse_samtools_flagstat() {
+        let (id, metrics) = parse_fixture("sample.flagstat");
+        assert_eq!(id, "samtools_flagstat");
+        assert_eq!(metrics["in_total"], json!(2000000));
+        assert_eq!(metrics["mapped"], json!(1900000));
+        assert_eq!(metrics["mapped_percent"], json!(95.0));
+        assert_eq!(metrics["properly_paired_percent"], json!(92.96));
+        assert_eq!(metrics["duplicates_qc_failed"], json!(0));
+    }
+
+    #[test]
+    fn test_parse_samtools_stats() {
+        let (id, metrics) = parse_fixture("sample.stats");
+        assert_eq!(id, "samtools_stats");
+        assert_eq!(metrics["raw_total_sequences"], json!(2000000));
+        assert_eq!(metrics["reads_mapped"], json!(1900000));
+        assert_eq!(metrics["error_rate"], json!(0.00312));
+        assert_eq!(metrics.len(), 12);
+    }
+
+    #[test]
+    fn test_parse_fastp_json() {
+        let (id, metrics) = parse_fixture("fastp.json");
+        assert_eq!(id, "fastp");
+        assert_eq!(metrics["before_filtering_total_reads"], json!(6000000));
+        assert_eq!(metrics["after_filtering_q30_rate"], json!(0.93374));
+        assert_eq!(metrics["passed_filter_reads"], json!(5880000));
+        assert_eq!(metrics["duplication_rate"], json!(0.081234));
+    }
+
+    #[test]
+    fn test_parse_picard_markduplicates() {
+        let (id, metrics) = parse_fixture("sample.marked_dup_metrics.txt");
+        assert_eq!(id, "picard_markduplicates");
+        assert_eq!(metrics["library"], json!("lib1"));
+        assert_eq!(metrics["read_pairs_examined"], json!(945000));
+        assert_eq!(metrics["percent_duplication"], json!(0.079311));
+        assert_eq!(metrics["estimated_library_size"], json!(5730211));
+    }
+
+    #[test]
+    fn test_parse_multiqc_data() {
+        let (id, metrics) = parse_fixture("multiqc_data.json");
+        assert_eq!(id, "multiqc");
+        assert_eq!(metrics["sample_count"], json!(2));
+        assert_eq!(
+            metrics["samples"]["control"]["uniquely_mapped_percent"],
+            json!(85.32)
+        );
+        assert_eq!(
+            metrics["samples"]["treatment"]["percent_duplicates"],
+            json!(21.7)
+        );
+    }
+}
@@ -0,0 +1,289 @@
+// src/run_assignment.rs
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use sysinfo::{Pid, System};
+
+use crate::process_watcher::is_claimed_by;
+use crate::slurm::{read_process_job_id, PROC_DIRECTORY};
+
+/// Set in a pipeline's environment to attribute its processes to a run explicitly
+pub const RUN_ID_ENV_VAR: &str = "TRACER_RUN_ID";
+
+/// What decides which processes belong to an active run
+#[derive(Clone, Debug)]
+pub struct RunScope {
+    pub run_id: String,
+    pub owner_uid: Option<u32>,
+    /// Process that started the run; its descendants belong to the run
+    pub parent_pid: Option<Pid>,
+    /// Processes running below this directory belong to the run
+    pub working_directory: Option<String>,
+    /// Processes of this SLURM job belong to the run
+    pub slurm_job_id: Option<String>,
+    /// Login session of the user who started the run; its processes belong
+    /// to the owner, even those running as another user
+    pub session_id: Option<Pid>,
+    pub start_time: DateTime<Utc>,
+}
+
+impl RunScope {
+    /// Whether the run may track the process at all: it belongs to the run's
+    /// owner or runs in the owner's session
+    pub fn claims(&self, process: &ProcessOrigin) -> bool {
+        is_claimed_by(self.owner_uid, process.uid)
+            || (self.session_id.is_some() && self.session_id == process.session_id)
+    }
+}
+
+/// The facts about a process used to find its run
+pub struct ProcessOrigin<'a> {
+    pub pid: Option<Pid>,
+    pub uid: Option<u32>,
+    pub run_id: Option<&'a str>,
+    pub working_directory: Option<&'a Path>,
+    pub slurm_job_id: Option<String>,
+    pub session_id: Option<Pid>,
+}
+
+impl<'a> ProcessOrigin<'a> {
+    pub fn of_process(system: &'a System, pid: Pid) -> ProcessOrigin<'a> {
+        let process = system.process(pid);
+        ProcessOrigin {
+            pid: Some(pid),
+            uid: process.and_then(|process| process.user_id().map(|uid| **uid)),
+            run_id: process.and_then(|process| get_run_id_from_environ(process.environ())),
+            working_directory: process.and_then(|process| process.cwd()),
+            slurm_job_id: read_process_job_id(Path::new(PROC_DIRECTORY), &pid.to_string()),
+            session_id: process.and_then(|process| process.session_id()),
+        }
+    }
+}
+
+pub fn get_run_id_from_environ(environ: &[String]) -> Option<&str> {
+    let prefix = format!("{}=", RUN_ID_ENV_VAR);
+    environ
+        .iter()
+        .find_map(|variable| variable.strip_prefix(&prefix))
+        .filter(|run_id| !run_id.is_empty())
+}
+
+/// Run the process was explicitly tagged with, runs the SLURM job of, was
+/// started from, or runs in the directory of, in that order of precedence
+pub fn match_run<'a>(
+    runs: &'a [RunScope],
+    process: &ProcessOrigin,
+    parent_of: impl Fn(Pid) -> Option<Pid>,
+) -> Option<&'a RunScope> {
+    let runs: Vec<&RunScope> = runs.iter().filter(|run| run.claims(process)).collect();
+
+    if let Some(run_id) = process.run_id {
+        if let Some(run) = runs.iter().find(|run| run.run_id == run_id) {
+            return Some(run);
+        }
+    }
+
+    if let Some(job_id) = &process.slurm_job_id {
+        if let Some(run) = runs
+            .iter()
+            .find(|run| run.slurm_job_id.as_ref() == Some(job_id))
+        {
+            return Some(run);
+        }
+    }
+
+    // The closest ancestor wins, so nested pipelines go to the inner run
+    let mut ancestor = process.pid;
+    while let Some(pid) = ancestor {
+        if let Some(run) = runs.iter().find(|run| run.parent_pid == Some(pid)) {
+            return Some(run);
+        }
+        ancestor = parent_of(pid).filter(|parent| *parent != pid);
+    }
+
+    let working_directory = process.working_directory?;
+    runs.into_iter()
+        .filter(|run| {
+            run.working_directory
+                .as_ref()
+                .is_some_and(|directory| working_directory.starts_with(directory))
+        })
+        .max_by_key(|run| run.working_directory.as_ref().map(String::len))
+}
+
+/// Run a newly seen process is attributed to. Processes that match no run go
+/// to the most recently started one the process owner may use.
+pub fn assign_run<'a>(
+    runs: &'a [RunScope],
+    process: &ProcessOrigin,
+    parent_of: impl Fn(Pid) -> Option<Pid>,
+) -> Option<&'a RunScope> {
+    match_run(runs, process, parent_of).or_else(|| {
+        runs.iter()
+            .filter(|run| run.claims(process))
+            .max_by_key(|run| run.start_time)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use std::collections::HashMap;
+
+    fn run(run_id: &str, parent_pid: Option<usize>, working_directory: Option<&str>) -> RunScope {
+        RunScope {
+            run_id: run_id.to_string(),
+            owner_uid: None,
+            parent_pid: parent_pid.map(Pid::from),
+            working_directory: working_directory.map(String::from),
+            slurm_job_id: None,
+            session_id: None,
+            start_time: Utc::now(),
+        }
+    }
+
+    fn origin(pid: usize) -> ProcessOrigin<'static> {
+        ProcessOrigin {
+            pid: Some(pid.into()),
+            uid: None,
+            run_id: None,
+            working_directory: None,
+            slurm_job_id: None,
+            session_id: None,
+        }
+    }
+
+    /// 1 -> 10 -> 11 -> 12 and 1 -> 20 -> 21
+    fn parent_of(pid: Pid) -> Option<Pid> {
+        let parents: HashMap<usize, usize> =
+            HashMap::from([(10, 1), (11, 10), (12, 11), (20, 1), (21, 20)]);
+        parents
+            .get(&usize::from(pid))
+            .map(|parent| Pid::from(*parent))
+    }
+
+    #[test]
+    fn test_processes_go_to_the_run_of_their_closest_ancestor() {
+        let runs = vec![
+            run("outer", Some(10), None),
+            run("inner", Some(11), None),
+            run("other", Some(20), None),
+        ];
+
+        let run_of = |pid| match_run(&runs, &origin(pid), parent_of).map(|run| &run.run_id[..]);
+
+        assert_eq!(run_of(12), Some("inner"));
+        assert_eq!(run_of(11), Some("inner"));
+        assert_eq!(run_of(10), Some("outer"));
+        assert_eq!(run_of(21), Some("other"));
+        assert_eq!(run_of(1), None);
+    }
+
+    #[test]
+    fn test_environment_variable_takes_precedence() {
+        let runs = vec![
+            run("by_ancestry", Some(11), None),
+            run("by_env", None, None),
+        ];
+        let process = ProcessOrigin {
+            run_id: Some("by_env"),
+            ..origin(12)
+        };
+
+        let run = match_run(&runs, &process, parent_of).unwrap();
+
+        assert_eq!(run.run_id, "by_env");
+    }
+
+    #[test]
+    fn test_processes_of_a_slurm_job_go_to_its_run() {
+        let mut job_run = run("job", None, None);
+        job_run.slurm_job_id = Some("4242".to_string());
+        let runs = vec![run("by_ancestry", Some(11), None), job_run];
+        let process = ProcessOrigin {
+            slurm_job_id: Some("4242".to_string()),
+            ..origin(12)
+        };
+
+        assert_eq!(match_run(&runs, &process, parent_of).unwrap().run_id, "job");
+        assert_eq!(
+            match_run(&runs, &origin(12), parent_of).unwrap().run_id,
+            "by_ancestry"
+        );
+    }
+
+    #[test]
+    fn test_deepest_working_directory_matches() {
+        let runs = vec![
+            run("project", None, Some("/data/project")),
+            run("sample", None, Some("/data/project/sample_1")),
+        ];
+        let working_directory = Path::new("/data/project/sample_1/work");
+        let process = ProcessOrigin {
+            working_directory: Some(working_directory),
+            ..origin(12)
+        };
+
+        let run = match_run(&runs, &process, parent_of).unwrap();
+
+        assert_eq!(run.run_id, "sample");
+    }
+
+    #[test]
+    fn test_unmatched_processes_go_to_the_latest_run_of_their_owner() {
+        let mut older = run("older", Some(20), None);
+        older.start_time = Utc::now() - TimeDelta::seconds(60);
+        let mut other_user = run("other_user", Some(20), None);
+        other_user.owner_uid = Some(1001);
+        let runs = vec![older, run("latest", Some(20), None), other_user];
+
+        let process = ProcessOrigin {
+            uid: Some(1000),
+            ..origin(12)
+        };
+
+        assert_eq!(
+            assign_run(&runs, &process, parent_of).unwrap().run_id,
+            "latest"
+        );
+        assert!(assign_run(&runs[2..], &process, parent_of).is_none());
+    }
+
+    #[test]
+    fn test_runs_claim_processes_of_their_owners_session() {
+        let mut run = run("session", None, None);
+        run.owner_uid = Some(1000);
+        run.session_id = Some(Pid::from(500));
+        let runs = vec![run];
+
+        // Started with sudo from the owner's shell
+        let in_session = ProcessOrigin {
+            uid: Some(0),
+            session_id: Some(Pid::from(500)),
+            ..origin(12)
+        };
+        let elsewhere = ProcessOrigin {
+            uid: Some(0),
+            session_id: Some(Pid::from(600)),
+            ..origin(12)
+        };
+
+        assert_eq!(
+            assign_run(&runs, &in_session, parent_of).unwrap().run_id,
+            "session"
+        );
+        assert!(assign_run(&runs, &elsewhere, parent_of).is_none());
+    }
+
+    #[test]
+    fn test_get_run_id_from_environ() {
+        let environ = vec![
+            "PATH=/usr/bin".to_string(),
+            format!("{}=run-1", RUN_ID_ENV_VAR),
+        ];
+
+        assert_eq!(get_run_id_from_environ(&environ), Some("run-1"));
+        assert_eq!(get_run_id_from_environ(&environ[..1]), None);
+    }
+}
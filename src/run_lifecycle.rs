@@ -0,0 +1,443 @@
+// src/run_lifecycle.rs
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use sysinfo::{Pid, System};
+
+use crate::workflow_engines::{recognize_launcher, PipelineMetadata, ProcessTable};
+
+/// What run detection needs to know about a process
+#[derive(Clone, Debug)]
+pub struct ObservedProcess {
+    pub name: String,
+    pub cmd: Vec<String>,
+    pub parent_pid: Option<Pid>,
+    pub uid: Option<u32>,
+    pub working_directory: Option<String>,
+    /// Seconds since the epoch; tells a process apart from a later one reusing its pid
+    pub start_time: u64,
+}
+
+pub type ProcessSnapshot = HashMap<Pid, ObservedProcess>;
+
+pub fn snapshot_processes(system: &System) -> ProcessSnapshot {
+    system
+        .processes()
+        .iter()
+        .map(|(pid, process)| {
+            (
+                *pid,
+                ObservedProcess {
+                    name: process.name().to_string(),
+                    cmd: process.cmd().to_vec(),
+                    parent_pid: process.parent(),
+                    uid: process.user_id().map(|uid| **uid),
+                    working_directory: process
+                        .cwd()
+                        .map(|directory| directory.to_string_lossy().to_string()),
+                    start_time: process.start_time(),
+                },
+            )
+        })
+        .collect()
+}
+
+impl ProcessTable for ProcessSnapshot {
+    fn parent(&self, pid: Pid) -> Option<Pid> {
+        self.get(&pid)?.parent_pid
+    }
+
+    fn command(&self, pid: Pid) -> Option<&[String]> {
+        self.get(&pid).map(|process| process.cmd.as_slice())
+    }
+
+    fn working_directory(&self, pid: Pid) -> Option<String> {
+        self.get(&pid)?.working_directory.clone()
+    }
+}
+
+/// A run boundary found in the process table, for the client to act on
+#[derive(Debug, PartialEq)]
+pub enum RunBoundary {
+    Start {
+        root_pid: Pid,
+        root_name: String,
+        root_start_time: u64,
+        owner_uid: Option<u32>,
+        working_directory: Option<String>,
+        /// What the root runs, when it's a workflow engine launcher
+        pipeline: Option<PipelineMetadata>,
+    },
+    End {
+        run_id: String,
+        reason: String,
+    },
+}
+
+struct DetectedRun {
+    root_pid: Pid,
+    root_name: String,
+    root_start_time: u64,
+    last_activity: Instant,
+}
+
+/// Starts a run when a pipeline root process appears and ends it when the
+/// root exits or the run sees no tool activity for `inactivity_timeout`
+pub struct RunDetector {
+    root_processes: Vec<String>,
+    inactivity_timeout: Duration,
+    runs: HashMap<String, DetectedRun>,
+    /// Roots that were given a run, so a run ended for inactivity isn't
+    /// started again while its root lives on
+    handled_roots: HashSet<(Pid, u64)>,
+}
+
+impl RunDetector {
+    pub fn new(root_processes: Vec<String>, inactivity_timeout: Duration) -> Self {
+        RunDetector {
+            root_processes,
+            inactivity_timeout,
+            runs: HashMap::new(),
+            handled_roots: HashSet::new(),
+        }
+    }
+
+    pub fn reload_config(&mut self, root_processes: Vec<String>, inactivity_timeout: Duration) {
+        self.root_processes = root_processes;
+        self.inactivity_timeout = inactivity_timeout;
+    }
+
+    fn is_root(&self, process: &ObservedProcess) -> bool {
+        self.root_processes.contains(&process.name) || recognize_launcher(&process.cmd).is_some()
+    }
+
+    /// Whether a process between this one and the init process is a root,
+    /// as with the job processes a workflow engine spawns of itself
+    fn has_root_ancestor(&self, processes: &ProcessSnapshot, process: &ObservedProcess) -> bool {
+        let mut visited = HashSet::new();
+        let mut ancestor = process.parent_pid;
+        while let Some(pid) = ancestor {
+            if !visited.insert(pid) {
+                break;
+            }
+            let Some(parent) = processes.get(&pid) else {
+                break;
+            };
+            if self.is_root(parent) {
+                return true;
+            }
+            ancestor = parent.parent_pid;
+        }
+        false
+    }
+
+    /// Tracks the run the client started for a `RunBoundary::Start`
+    pub fn register_run(&mut self, run_id: String, boundary: &RunBoundary, now: Instant) {
+        if let RunBoundary::Start {
+            root_pid,
+            root_name,
+            root_start_time,
+            ..
+        } = boundary
+        {
+            self.runs.insert(
+                run_id,
+                DetectedRun {
+                    root_pid: *root_pid,
+                    root_name: root_name.clone(),
+                    root_start_time: *root_start_time,
+                    last_activity: now,
+                },
+            );
+        }
+    }
+
+    /// Stops tracking a run that was ended some other way, e.g. `tracer end`
+    pub fn forget_run(&mut self, run_id: &str) {
+        self.runs.remove(run_id);
+    }
+
+    /// Compares the process table with the detected runs. `active_runs` are
+    /// the runs with a tool process alive right now.
+    pub fn detect(
+        &mut self,
+        processes: &ProcessSnapshot,
+        active_runs: &HashSet<String>,
+        now: Instant,
+    ) -> Vec<RunBoundary> {
+        let mut boundaries = vec![];
+
+        let mut ended_runs = vec![];
+        for (run_id, run) in self.runs.iter_mut() {
+            if active_runs.contains(run_id) {
+                run.last_activity = now;
+            }
+
+            let root_alive = processes
+                .get(&run.root_pid)
+                .is_some_and(|root| root.start_time == run.root_start_time);
+            let reason = if !root_alive {
+                format!(
+                    "Run ended because its root process {} ({}) exited",
+                    run.root_name, run.root_pid
+                )
+            } else if now.duration_since(run.last_activity) > self.inactivity_timeout {
+                format!(
+                    "Run ended after {} s without tool activity",
+                    self.inactivity_timeout.as_secs()
+                )
+            } else {
+                continue;
+            };
+            ended_runs.push(run_id.clone());
+            boundaries.push(RunBoundary::End {
+                run_id: run_id.clone(),
+                reason,
+            });
+        }
+        for run_id in ended_runs {
+            self.runs.remove(&run_id);
+        }
+
+        self.handled_roots.retain(|(pid, start_time)| {
+            processes
+                .get(pid)
+                .is_some_and(|process| process.start_time == *start_time)
+        });
+
+        let mut new_roots: Vec<(&Pid, &ObservedProcess)> = processes
+            .iter()
+            .filter(|(pid, process)| {
+                self.is_root(process)
+                    && !self.handled_roots.contains(&(**pid, process.start_time))
+                    && !self.has_root_ancestor(processes, process)
+            })
+            .collect();
+        new_roots.sort_by_key(|(pid, process)| (process.start_time, **pid));
+
+        for (pid, process) in new_roots {
+            self.handled_roots.insert((*pid, process.start_time));
+            boundaries.push(RunBoundary::Start {
+                root_pid: *pid,
+                root_name: process.name.clone(),
+                root_start_time: process.start_time,
+                owner_uid: process.uid,
+                working_directory: process.working_directory.clone(),
+                pipeline: recognize_launcher(&process.cmd).map(|pipeline| PipelineMetadata {
+                    launch_directory: process.working_directory.clone(),
+                    ..pipeline
+                }),
+            });
+        }
+
+        boundaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(600);
+
+    fn process(name: &str, parent_pid: usize) -> ObservedProcess {
+        ObservedProcess {
+            name: name.to_string(),
+            cmd: vec![name.to_string()],
+            parent_pid: Some(Pid::from(parent_pid)),
+            uid: Some(1000),
+            working_directory: Some("/data/project".to_string()),
+            start_time: 100,
+        }
+    }
+
+    /// bash (10) -> nextflow (11) -> java (12) -> bwa (13)
+    fn pipeline() -> ProcessSnapshot {
+        HashMap::from([
+            (Pid::from(1), process("init", 0)),
+            (Pid::from(10), process("bash", 1)),
+            (Pid::from(11), process("nextflow", 10)),
+            (Pid::from(12), process("java", 11)),
+            (Pid::from(13), process("bwa", 12)),
+        ])
+    }
+
+    fn detector() -> RunDetector {
+        RunDetector::new(
+            vec!["nextflow".to_string(), "snakemake".to_string()],
+            TIMEOUT,
+        )
+    }
+
+    /// Detects on the snapshot and registers every started run as `run-<root pid>`
+    fn detect_and_register(
+        detector: &mut RunDetector,
+        processes: &ProcessSnapshot,
+        active_runs: &[&str],
+        now: Instant,
+    ) -> Vec<RunBoundary> {
+        let active_runs = active_runs.iter().map(|run| run.to_string()).collect();
+        let boundaries = detector.detect(processes, &active_runs, now);
+        for boundary in &boundaries {
+            if let RunBoundary::Start { root_pid, .. } = boundary {
+                detector.register_run(format!("run-{}", root_pid), boundary, now);
+            }
+        }
+        boundaries
+    }
+
+    #[test]
+    fn test_run_starts_once_when_a_root_process_appears() {
+        let mut detector = detector();
+        let now = Instant::now();
+
+        let boundaries = detect_and_register(&mut detector, &pipeline(), &[], now);
+
+        assert_eq!(
+            boundaries,
+            vec![RunBoundary::Start {
+                root_pid: Pid::from(11),
+                root_name: "nextflow".to_string(),
+                root_start_time: 100,
+                owner_uid: Some(1000),
+                working_directory: Some("/data/project".to_string()),
+                pipeline: None,
+            }]
+        );
+        assert!(detect_and_register(&mut detector, &pipeline(), &[], now).is_empty());
+    }
+
+    #[test]
+    fn test_workflow_engine_launchers_are_roots() {
+        let mut detector = RunDetector::new(vec![], TIMEOUT);
+        let mut processes = pipeline();
+        processes.get_mut(&Pid::from(12)).unwrap().cmd = vec![
+            "java".to_string(),
+            "nextflow.cli.Launcher".to_string(),
+            "run".to_string(),
+            "main.nf".to_string(),
+        ];
+
+        let boundaries = detect_and_register(&mut detector, &processes, &[], Instant::now());
+
+        assert!(matches!(
+            &boundaries[..],
+            [RunBoundary::Start { root_pid, pipeline: Some(pipeline), .. }]
+                if *root_pid == Pid::from(12) && pipeline.pipeline.as_deref() == Some("main.nf")
+        ));
+    }
+
+    #[test]
+    fn test_processes_without_a_root_start_no_run() {
+        let mut detector = detector();
+        let mut processes = pipeline();
+        processes.remove(&Pid::from(11));
+
+        assert!(detect_and_register(&mut detector, &processes, &[], Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn test_roots_spawned_by_a_root_share_its_run() {
+        let mut detector = detector();
+        let mut processes = HashMap::from([
+            (Pid::from(20), process("snakemake", 1)),
+            (Pid::from(21), process("python3", 20)),
+            (Pid::from(22), process("snakemake", 21)),
+        ]);
+        processes.insert(Pid::from(1), process("init", 0));
+
+        let boundaries = detect_and_register(&mut detector, &processes, &[], Instant::now());
+
+        assert_eq!(boundaries.len(), 1);
+        assert!(matches!(
+            boundaries[0],
+            RunBoundary::Start { root_pid, .. } if root_pid == Pid::from(20)
+        ));
+    }
+
+    #[test]
+    fn test_run_ends_when_its_root_exits() {
+        let mut detector = detector();
+        let now = Instant::now();
+        detect_and_register(&mut detector, &pipeline(), &[], now);
+
+        let mut processes = pipeline();
+        processes.remove(&Pid::from(11));
+        let boundaries = detect_and_register(&mut detector, &processes, &["run-11"], now);
+
+        assert_eq!(
+            boundaries,
+            vec![RunBoundary::End {
+                run_id: "run-11".to_string(),
+                reason: "Run ended because its root process nextflow (11) exited".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_reused_root_pid_ends_the_run() {
+        let mut detector = detector();
+        let now = Instant::now();
+        detect_and_register(&mut detector, &pipeline(), &[], now);
+
+        let mut processes = pipeline();
+        processes.get_mut(&Pid::from(11)).unwrap().start_time = 200;
+        let boundaries = detect_and_register(&mut detector, &processes, &[], now);
+
+        // The old run ends and the new nextflow gets its own
+        assert!(matches!(&boundaries[0], RunBoundary::End { run_id, .. } if run_id == "run-11"));
+        assert!(matches!(
+            &boundaries[1],
+            RunBoundary::Start {
+                root_start_time: 200,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_run_ends_after_inactivity() {
+        let mut detector = detector();
+        let start = Instant::now();
+        detect_and_register(&mut detector, &pipeline(), &[], start);
+
+        // Tool activity keeps the run going
+        let later = start + TIMEOUT;
+        assert!(detect_and_register(&mut detector, &pipeline(), &["run-11"], later).is_empty());
+        assert!(
+            detect_and_register(&mut detector, &pipeline(), &[], later + TIMEOUT / 2).is_empty()
+        );
+
+        let boundaries = detect_and_register(
+            &mut detector,
+            &pipeline(),
+            &[],
+            later + TIMEOUT + Duration::from_secs(1),
+        );
+        assert_eq!(
+            boundaries,
+            vec![RunBoundary::End {
+                run_id: "run-11".to_string(),
+                reason: "Run ended after 600 s without tool activity".to_string(),
+            }]
+        );
+
+        // The root is still alive but doesn't get a new run
+        assert!(
+            detect_and_register(&mut detector, &pipeline(), &[], later + TIMEOUT * 2).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_forgotten_run_is_not_ended_again() {
+        let mut detector = detector();
+        let now = Instant::now();
+        detect_and_register(&mut detector, &pipeline(), &[], now);
+        detector.forget_run("run-11");
+
+        let mut processes = pipeline();
+        processes.remove(&Pid::from(11));
+
+        assert!(detect_and_register(&mut detector, &processes, &[], now).is_empty());
+    }
+}
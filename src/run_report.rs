@@ -0,0 +1,1124 @@
+// src/run_report.rs
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sysinfo::System;
+
+use crate::cloud_metadata::InstanceMetadata;
+use crate::cost::RunCost;
+use crate::event_recorder::{Event, EventType};
+use crate::kubernetes::{detect_pod, PodMetadata};
+use crate::runtime_paths::current_uid;
+use crate::slurm::SlurmJob;
+use crate::timeline::{
+    push_sample, CounterSample, Timeline, TimelineProcess, MAX_PROCESS_COUNTER_POINTS,
+    MAX_SYSTEM_COUNTER_POINTS,
+};
+use crate::upload::upload_queue::UploadStatus;
+use crate::workflow_engines::PipelineMetadata;
+
+/// What the events of a run tell about one of its tool processes
+#[derive(Default)]
+struct ToolProcessStats {
+    tool_name: String,
+    pid: String,
+    /// Index of the tracked process that started this one
+    parent: Option<usize>,
+    /// Work directory or job id of the workflow engine task running the process
+    task_key: Option<String>,
+    start_time: DateTime<Utc>,
+    end_time: Option<DateTime<Utc>>,
+    samples: Vec<CounterSample>,
+    wall_time_ms: Option<u64>,
+    /// CPU time integrated from the CPU usage of the metric events
+    cpu_seconds: f64,
+    last_cpu_sample: Option<(DateTime<Utc>, f64)>,
+    /// Usage of the tool's own cgroup, which is exact and covers its children
+    cgroup_usage: Option<ProcessUsage>,
+    peak_memory_bytes: u64,
+    read_bytes: u64,
+    written_bytes: u64,
+    exit_code: Option<i32>,
+    failed: bool,
+}
+
+impl ToolProcessStats {
+    fn add_cpu_sample(&mut self, timestamp: DateTime<Utc>, cpu_usage: Option<f64>) {
+        if let Some((last_timestamp, last_cpu_usage)) = self.last_cpu_sample {
+            let seconds = (timestamp - last_timestamp).num_milliseconds().max(0) as f64 / 1000.0;
+            self.cpu_seconds += last_cpu_usage / 100.0 * seconds;
+        }
+        if let Some(cpu_usage) = cpu_usage {
+            self.last_cpu_sample = Some((timestamp, cpu_usage));
+        }
+    }
+
+    fn add_usage(&mut self, timestamp: DateTime<Utc>, attributes: &Value) {
+        if let Some(cpu_usage) = attributes["process_cpu_utilization"].as_f64() {
+            push_sample(
+                &mut self.samples,
+                CounterSample {
+                    timestamp,
+                    cpu_usage,
+                    memory_bytes: attributes["process_memory_usage"]
+                        .as_u64()
+                        .unwrap_or_default(),
+                },
+                MAX_PROCESS_COUNTER_POINTS,
+            );
+        }
+        let number = |key: &str| attributes[key].as_u64().unwrap_or_default();
+        self.peak_memory_bytes = self.peak_memory_bytes.max(number("process_memory_usage"));
+        self.read_bytes = self.read_bytes.max(number("process_disk_usage_read_total"));
+        self.written_bytes = self
+            .written_bytes
+            .max(number("process_disk_usage_write_total"));
+    }
+
+    /// A cgroup of the tool's own accounts for its children too
+    fn add_cgroup_usage(&mut self, cgroup: &Value) {
+        let number = |key: &str| cgroup[key].as_u64();
+        if let Some(memory_peak) = number("memory_peak") {
+            self.peak_memory_bytes = self.peak_memory_bytes.max(memory_peak);
+        }
+        if let Some(cpu_usage_usec) = number("cpu_usage_usec") {
+            self.cgroup_usage = Some(ProcessUsage {
+                cpu_seconds: cpu_usage_usec as f64 / 1_000_000.0,
+                read_bytes: number("io_read_bytes").unwrap_or(self.read_bytes),
+                written_bytes: number("io_write_bytes").unwrap_or(self.written_bytes),
+            });
+        }
+    }
+
+    fn get_usage(&self) -> ProcessUsage {
+        ProcessUsage {
+            cpu_seconds: self.cpu_seconds,
+            read_bytes: self.read_bytes,
+            written_bytes: self.written_bytes,
+        }
+    }
+}
+
+/// Usage that adds up over processes
+#[derive(Clone, Copy, Debug, Default)]
+struct ProcessUsage {
+    cpu_seconds: f64,
+    read_bytes: u64,
+    written_bytes: u64,
+}
+
+impl ProcessUsage {
+    fn add(&mut self, other: ProcessUsage) {
+        self.cpu_seconds += other.cpu_seconds;
+        self.read_bytes += other.read_bytes;
+        self.written_bytes += other.written_bytes;
+    }
+
+    fn subtract(self, other: ProcessUsage) -> ProcessUsage {
+        ProcessUsage {
+            cpu_seconds: (self.cpu_seconds - other.cpu_seconds).max(0.0),
+            read_bytes: self.read_bytes.saturating_sub(other.read_bytes),
+            written_bytes: self.written_bytes.saturating_sub(other.written_bytes),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SyslogError {
+    pub timestamp: DateTime<Utc>,
+    pub error: Option<String>,
+    pub line: String,
+}
+
+/// The events of a run, summarized as they're recorded since they're only
+/// kept until the next batch is sent
+#[derive(Default)]
+pub struct RunEvents {
+    processes: Vec<ToolProcessStats>,
+    /// Index in `processes` of the running ones, by pid
+    running: HashMap<String, usize>,
+    /// Processes of each workflow engine task, by work directory or job id
+    tasks: HashMap<String, Vec<usize>>,
+    task_names: HashMap<String, String>,
+    syslog_errors: Vec<SyslogError>,
+    system_samples: Vec<CounterSample>,
+}
+
+fn string(value: &Value) -> Option<String> {
+    value.as_str().map(String::from)
+}
+
+impl RunEvents {
+    pub fn add(&mut self, event: &Event) {
+        let attributes = event.attributes.as_ref().unwrap_or(&Value::Null);
+        let pid = string(&attributes["tool_pid"]);
+        let status = event.process_status.as_str();
+
+        if status == EventType::ToolExecution.as_str() {
+            let Some(pid) = pid else {
+                return;
+            };
+            let index = self.processes.len();
+            let nextflow_task = &attributes["nextflow_task"];
+            let task_key = string(&nextflow_task["work_dir"]);
+            if let Some(task_key) = &task_key {
+                self.tasks.entry(task_key.clone()).or_default().push(index);
+                if let Some(name) = string(&nextflow_task["name"]) {
+                    self.task_names.insert(task_key.clone(), name);
+                }
+            }
+            let parent = string(&attributes["tool_parent_pid"])
+                .and_then(|parent_pid| self.running.get(&parent_pid))
+                .copied();
+            let mut process = ToolProcessStats {
+                tool_name: string(&attributes["tool_name"]).unwrap_or_default(),
+                pid: pid.clone(),
+                parent,
+                // Tools started by the task's script run for the task too
+                task_key: task_key
+                    .or_else(|| parent.and_then(|parent| self.processes[parent].task_key.clone())),
+                start_time: event.timestamp,
+                ..Default::default()
+            };
+            process.add_usage(event.timestamp, attributes);
+            process.add_cpu_sample(
+                event.timestamp,
+                attributes["process_cpu_utilization"].as_f64(),
+            );
+            self.processes.push(process);
+            self.running.insert(pid, index);
+        } else if status == EventType::ToolMetricEvent.as_str() {
+            let Some(process) = pid
+                .and_then(|pid| self.running.get(&pid))
+                .map(|index| &mut self.processes[*index])
+            else {
+                return;
+            };
+            process.add_usage(event.timestamp, attributes);
+            process.add_cpu_sample(
+                event.timestamp,
+                attributes["process_cpu_utilization"].as_f64(),
+            );
+        } else if status == EventType::FinishedToolExecution.as_str() {
+            let Some(process) = pid
+                .and_then(|pid| self.running.remove(&pid))
+                .map(|index| &mut self.processes[index])
+            else {
+                return;
+            };
+            process.wall_time_ms = attributes["duration"].as_u64();
+            process.end_time = Some(event.timestamp);
+            process.add_cpu_sample(event.timestamp, None);
+            process.add_cgroup_usage(&attributes["cgroup"]);
+        } else if status == EventType::TaskEvent.as_str() {
+            self.add_task_event(attributes);
+        } else if status == EventType::MetricEvent.as_str() {
+            if let Some(cpu_usage) = attributes["system_cpu_utilization"].as_f64() {
+                push_sample(
+                    &mut self.system_samples,
+                    CounterSample {
+                        timestamp: event.timestamp,
+                        cpu_usage,
+                        memory_bytes: attributes["system_memory_used"]
+                            .as_u64()
+                            .unwrap_or_default(),
+                    },
+                    MAX_SYSTEM_COUNTER_POINTS,
+                );
+            }
+        } else if status == EventType::SyslogEvent.as_str() {
+            self.syslog_errors.push(SyslogError {
+                timestamp: event.timestamp,
+                error: string(&attributes["error_display_name"]),
+                line: event.message.clone(),
+            });
+        }
+    }
+
+    fn add_task_event(&mut self, attributes: &Value) {
+        let task_key = string(&attributes["work_dir"]).or_else(|| {
+            string(&attributes["task_id"])
+                .map(|task_id| format!("{}:{}", attributes["engine"], task_id))
+        });
+        let Some(task_key) = task_key else {
+            return;
+        };
+        if let Some(name) = string(&attributes["name"]) {
+            self.task_names.insert(task_key.clone(), name);
+        }
+        // Snakemake names the process of a job once it's running
+        if let Some(index) = string(&attributes["tool_pid"])
+            .and_then(|pid| self.running.get(&pid))
+            .copied()
+        {
+            let process = &mut self.processes[index];
+            process.task_key.get_or_insert_with(|| task_key.clone());
+            let indexes = self.tasks.entry(task_key.clone()).or_default();
+            if !indexes.contains(&index) {
+                indexes.push(index);
+            }
+        }
+
+        let failed = attributes["status"].as_str() == Some("failed");
+        let exit_code = attributes["exit_code"]
+            .as_i64()
+            .map(|exit_code| exit_code as i32);
+        if !failed && exit_code.is_none() {
+            return;
+        }
+        for index in self.tasks.get(&task_key).into_iter().flatten() {
+            let process = &mut self.processes[*index];
+            process.failed |= failed;
+            process.exit_code = exit_code.or(process.exit_code);
+        }
+    }
+
+    /// Usage of each process without that of the tracked processes it
+    /// started, which a cgroup's accounting covers too
+    fn get_exclusive_usage(&self) -> Vec<ProcessUsage> {
+        // Parents are always recorded before their children
+        let mut children_usage = vec![ProcessUsage::default(); self.processes.len()];
+        let mut exclusive_usage = vec![ProcessUsage::default(); self.processes.len()];
+        for (index, process) in self.processes.iter().enumerate().rev() {
+            let tree_usage = process.cgroup_usage.unwrap_or_else(|| {
+                let mut tree_usage = process.get_usage();
+                tree_usage.add(children_usage[index]);
+                tree_usage
+            });
+            exclusive_usage[index] = tree_usage.subtract(children_usage[index]);
+            if let Some(parent) = process.parent {
+                children_usage[parent].add(tree_usage);
+            }
+        }
+        exclusive_usage
+    }
+
+    pub fn get_tools(&self) -> Vec<ToolSummary> {
+        let mut tools: BTreeMap<&str, ToolSummary> = BTreeMap::new();
+        let exclusive_usage = self.get_exclusive_usage();
+        for (process, usage) in self.processes.iter().zip(exclusive_usage) {
+            let tool = tools
+                .entry(&process.tool_name)
+                .or_insert_with(|| ToolSummary {
+                    tool_name: process.tool_name.clone(),
+                    ..Default::default()
+                });
+            tool.count += 1;
+            tool.wall_time_seconds += process.wall_time_ms.unwrap_or_default() as f64 / 1000.0;
+            tool.cpu_time_seconds += usage.cpu_seconds;
+            tool.peak_memory_bytes = tool.peak_memory_bytes.max(process.peak_memory_bytes);
+            tool.read_bytes += usage.read_bytes;
+            tool.written_bytes += usage.written_bytes;
+            if process.wall_time_ms.is_none() {
+                tool.running += 1;
+            }
+            if process.failed {
+                tool.failed += 1;
+            }
+            if let Some(exit_code) = process.exit_code {
+                *tool.exit_codes.entry(exit_code).or_default() += 1;
+            }
+        }
+        tools.into_values().collect()
+    }
+
+    pub fn get_syslog_errors(&self) -> &[SyslogError] {
+        &self.syslog_errors
+    }
+
+    /// The tool executions of the run over time, up to `end_time`
+    pub fn get_timeline(
+        &self,
+        run_id: &str,
+        run_name: &str,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Timeline {
+        let processes = self
+            .processes
+            .iter()
+            .map(|process| TimelineProcess {
+                pid: process.pid.clone(),
+                tool_name: process.tool_name.clone(),
+                parent: process.parent,
+                task: process.task_key.as_ref().map(|task_key| {
+                    self.task_names
+                        .get(task_key)
+                        .cloned()
+                        .unwrap_or_else(|| task_key.clone())
+                }),
+                start_time: process.start_time,
+                end_time: process.end_time,
+                exit_code: process.exit_code,
+                samples: process.samples.clone(),
+            })
+            .collect();
+
+        Timeline {
+            run_id: run_id.to_string(),
+            run_name: run_name.to_string(),
+            start_time,
+            end_time,
+            processes,
+            system_samples: self.system_samples.clone(),
+        }
+    }
+}
+
+/// The processes of one tool in a run
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ToolSummary {
+    pub tool_name: String,
+    pub count: u32,
+    /// Processes that hadn't exited when the report was made
+    pub running: u32,
+    pub wall_time_seconds: f64,
+    pub cpu_time_seconds: f64,
+    pub peak_memory_bytes: u64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    /// Exit codes reported by the workflow engine, tools run outside of one have none
+    pub exit_codes: BTreeMap<i32, u32>,
+    pub failed: u32,
+}
+
+impl ToolSummary {
+    fn display_exit_status(&self) -> String {
+        let mut parts: Vec<String> = self
+            .exit_codes
+            .iter()
+            .map(|(exit_code, count)| format!("exit {} ({})", exit_code, count))
+            .collect();
+        if self.failed > 0 {
+            parts.push(format!("{} failed", self.failed));
+        }
+        if self.running > 0 {
+            parts.push(format!("{} running", self.running));
+        }
+        if parts.is_empty() {
+            return "unknown".to_string();
+        }
+        parts.join(", ")
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UploadedFile {
+    pub file_name: String,
+    pub source_path: String,
+    pub status: UploadStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HostInfo {
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    pub kernel_version: Option<String>,
+    pub arch: Option<String>,
+    pub cpus: usize,
+    pub total_memory_bytes: u64,
+    pub cloud: Option<InstanceMetadata>,
+    pub kubernetes: Option<PodMetadata>,
+}
+
+impl HostInfo {
+    pub fn gather(system: &System, cloud: Option<InstanceMetadata>) -> HostInfo {
+        HostInfo {
+            hostname: System::host_name(),
+            os: System::name(),
+            os_version: System::os_version(),
+            kernel_version: System::kernel_version(),
+            arch: System::cpu_arch(),
+            cpus: system.cpus().len(),
+            total_memory_bytes: system.total_memory(),
+            cloud,
+            kubernetes: detect_pod(),
+        }
+    }
+}
+
+/// Summary of a run, built by the daemon from the events it recorded
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RunReport {
+    pub run_id: String,
+    pub run_name: String,
+    pub service_name: String,
+    pub start_time: DateTime<Utc>,
+    /// `None` while the run is active
+    pub end_time: Option<DateTime<Utc>>,
+    pub pipeline: Option<PipelineMetadata>,
+    pub slurm_job: Option<SlurmJob>,
+    pub estimated_cost: Option<RunCost>,
+    pub tools: Vec<ToolSummary>,
+    pub syslog_errors: Vec<SyslogError>,
+    pub uploads: Vec<UploadedFile>,
+    pub host: HostInfo,
+    pub generated_at: DateTime<Utc>,
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_seconds(seconds: f64) -> String {
+    let whole_seconds = seconds.round() as u64;
+    match whole_seconds {
+        0..60 => format!("{:.1}s", seconds),
+        60..3600 => format!("{}m {:02}s", whole_seconds / 60, whole_seconds % 60),
+        _ => format!(
+            "{}h {:02}m {:02}s",
+            whole_seconds / 3600,
+            whole_seconds % 3600 / 60,
+            whole_seconds % 60
+        ),
+    }
+}
+
+fn escape_markdown(value: &str) -> String {
+    value.replace('|', "\\|").replace('\n', " ")
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const TOOL_COLUMNS: [&str; 8] = [
+    "Tool",
+    "Count",
+    "Wall time",
+    "CPU time",
+    "Peak memory",
+    "Read",
+    "Written",
+    "Exit status",
+];
+
+const HTML_STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 1.5em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }
+th { background: #f0f0f0; }
+code { font-size: 0.9em; }";
+
+impl RunReport {
+    fn duration_seconds(&self) -> f64 {
+        let end_time = self.end_time.unwrap_or(self.generated_at);
+        (end_time - self.start_time).num_milliseconds().max(0) as f64 / 1000.0
+    }
+
+    /// Rows of label and value describing the run
+    fn get_run_rows(&self) -> Vec<(&'static str, String)> {
+        let mut rows = vec![
+            ("Run name", self.run_name.clone()),
+            ("Run ID", self.run_id.clone()),
+            ("Service", self.service_name.clone()),
+            ("Started", self.start_time.to_rfc3339()),
+            (
+                "Ended",
+                self.end_time
+                    .map(|end_time| end_time.to_rfc3339())
+                    .unwrap_or_else(|| "still running".to_string()),
+            ),
+            ("Duration", format_seconds(self.duration_seconds())),
+        ];
+        if let Some(pipeline) = &self.pipeline {
+            rows.push((
+                "Pipeline",
+                format!(
+                    "{} ({}{})",
+                    pipeline.pipeline.as_deref().unwrap_or("unknown"),
+                    pipeline.engine.as_str(),
+                    pipeline
+                        .revision
+                        .as_ref()
+                        .map(|revision| format!(", revision {}", revision))
+                        .unwrap_or_default()
+                ),
+            ));
+        }
+        if let Some(slurm_job) = &self.slurm_job {
+            rows.push(("SLURM job", slurm_job.job_id.clone()));
+        }
+        if let Some(cost) = &self.estimated_cost {
+            rows.push((
+                "Estimated cost",
+                format!(
+                    "${:.2} (${:.2} by tools, ${:.3}/h)",
+                    cost.instance_cost, cost.tools_cost, cost.hourly_price
+                ),
+            ));
+        }
+        rows
+    }
+
+    fn get_host_rows(&self) -> Vec<(&'static str, String)> {
+        let host = &self.host;
+        let unknown = |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".into());
+        let mut rows = vec![
+            ("Hostname", unknown(&host.hostname)),
+            (
+                "OS",
+                format!(
+                    "{} {} (kernel {}, {})",
+                    unknown(&host.os),
+                    unknown(&host.os_version),
+                    unknown(&host.kernel_version),
+                    unknown(&host.arch)
+                ),
+            ),
+            ("CPUs", host.cpus.to_string()),
+            ("Memory", format_bytes(host.total_memory_bytes)),
+        ];
+        if let Some(cloud) = &host.cloud {
+            rows.push((
+                "Cloud instance",
+                format!(
+                    "{} in {}{}",
+                    unknown(&cloud.instance_type),
+                    unknown(&cloud.availability_zone),
+                    if cloud.spot == Some(true) {
+                        ", spot"
+                    } else {
+                        ""
+                    }
+                ),
+            ));
+        }
+        if let Some(pod) = &host.kubernetes {
+            rows.push((
+                "Kubernetes pod",
+                format!(
+                    "{}/{} on {}",
+                    unknown(&pod.namespace),
+                    unknown(&pod.pod_name),
+                    unknown(&pod.node_name)
+                ),
+            ));
+        }
+        rows
+    }
+
+    fn get_tool_rows(&self) -> Vec<[String; 8]> {
+        self.tools
+            .iter()
+            .map(|tool| {
+                [
+                    tool.tool_name.clone(),
+                    tool.count.to_string(),
+                    format_seconds(tool.wall_time_seconds),
+                    format_seconds(tool.cpu_time_seconds),
+                    format_bytes(tool.peak_memory_bytes),
+                    format_bytes(tool.read_bytes),
+                    format_bytes(tool.written_bytes),
+                    tool.display_exit_status(),
+                ]
+            })
+            .collect()
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = format!(
+            "# Tracer run report: {}\n\n",
+            escape_markdown(&self.run_name)
+        );
+
+        out.push_str("| | |\n|---|---|\n");
+        for (label, value) in self.get_run_rows() {
+            out.push_str(&format!("| {} | {} |\n", label, escape_markdown(&value)));
+        }
+
+        out.push_str("\n## Tools\n\n");
+        if self.tools.is_empty() {
+            out.push_str("No tools were seen.\n");
+        } else {
+            out.push_str(&format!("| {} |\n", TOOL_COLUMNS.join(" | ")));
+            out.push_str(&format!("|{}\n", "---|".repeat(TOOL_COLUMNS.len())));
+            for row in self.get_tool_rows() {
+                let cells: Vec<String> = row.iter().map(|cell| escape_markdown(cell)).collect();
+                out.push_str(&format!("| {} |\n", cells.join(" | ")));
+            }
+        }
+
+        out.push_str("\n## Syslog errors\n\n");
+        if self.syslog_errors.is_empty() {
+            out.push_str("No errors were seen.\n");
+        }
+        for error in &self.syslog_errors {
+            out.push_str(&format!(
+                "- {} **{}**: `{}`\n",
+                error.timestamp.to_rfc3339(),
+                escape_markdown(error.error.as_deref().unwrap_or("error")),
+                error.line.replace('`', "'")
+            ));
+        }
+
+        out.push_str("\n## Uploaded files\n\n");
+        if self.uploads.is_empty() {
+            out.push_str("No files were uploaded.\n");
+        }
+        for upload in &self.uploads {
+            out.push_str(&format!(
+                "- `{}` ({})\n",
+                upload.source_path.replace('`', "'"),
+                upload.status.as_str()
+            ));
+        }
+
+        out.push_str("\n## Host\n\n| | |\n|---|---|\n");
+        for (label, value) in self.get_host_rows() {
+            out.push_str(&format!("| {} | {} |\n", label, escape_markdown(&value)));
+        }
+
+        out.push_str(&format!(
+            "\nGenerated by tracer {} at {}\n",
+            env!("CARGO_PKG_VERSION"),
+            self.generated_at.to_rfc3339()
+        ));
+        out
+    }
+
+    pub fn to_html(&self) -> String {
+        let table = |rows: Vec<(&'static str, String)>| {
+            let rows: String = rows
+                .iter()
+                .map(|(label, value)| {
+                    format!(
+                        "<tr><th>{}</th><td>{}</td></tr>\n",
+                        label,
+                        escape_html(value)
+                    )
+                })
+                .collect();
+            format!("<table>\n{}</table>\n", rows)
+        };
+        let title = format!("Tracer run report: {}", escape_html(&self.run_name));
+
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+            title, HTML_STYLE, title
+        );
+        out.push_str(&table(self.get_run_rows()));
+
+        out.push_str("<h2>Tools</h2>\n");
+        if self.tools.is_empty() {
+            out.push_str("<p>No tools were seen.</p>\n");
+        } else {
+            out.push_str("<table>\n<tr>");
+            for column in TOOL_COLUMNS {
+                out.push_str(&format!("<th>{}</th>", column));
+            }
+            out.push_str("</tr>\n");
+            for row in self.get_tool_rows() {
+                out.push_str("<tr>");
+                for cell in row {
+                    out.push_str(&format!("<td>{}</td>", escape_html(&cell)));
+                }
+                out.push_str("</tr>\n");
+            }
+            out.push_str("</table>\n");
+        }
+
+        out.push_str("<h2>Syslog errors</h2>\n");
+        if self.syslog_errors.is_empty() {
+            out.push_str("<p>No errors were seen.</p>\n");
+        } else {
+            out.push_str("<ul>\n");
+            for error in &self.syslog_errors {
+                out.push_str(&format!(
+                    "<li>{} <strong>{}</strong>: <code>{}</code></li>\n",
+                    error.timestamp.to_rfc3339(),
+                    escape_html(error.error.as_deref().unwrap_or("error")),
+                    escape_html(&error.line)
+                ));
+            }
+            out.push_str("</ul>\n");
+        }
+
+        out.push_str("<h2>Uploaded files</h2>\n");
+        if self.uploads.is_empty() {
+            out.push_str("<p>No files were uploaded.</p>\n");
+        } else {
+            out.push_str("<ul>\n");
+            for upload in &self.uploads {
+                out.push_str(&format!(
+                    "<li><code>{}</code> ({})</li>\n",
+                    escape_html(&upload.source_path),
+                    upload.status.as_str()
+                ));
+            }
+            out.push_str("</ul>\n");
+        }
+
+        out.push_str("<h2>Host</h2>\n");
+        out.push_str(&table(self.get_host_rows()));
+        out.push_str(&format!(
+            "<p>Generated by tracer {} at {}</p>\n</body>\n</html>\n",
+            env!("CARGO_PKG_VERSION"),
+            self.generated_at.to_rfc3339()
+        ));
+        out
+    }
+
+    pub fn export(&self, format: &str) -> Result<String> {
+        match format {
+            "md" => Ok(self.to_markdown()),
+            "html" => Ok(self.to_html()),
+            "json" => Ok(serde_json::to_string_pretty(self)?),
+            _ => Err(anyhow::anyhow!("Unsupported report format: {}", format)),
+        }
+    }
+
+    /// Name of the report file, the extension being the format
+    pub fn get_file_name(&self, format: &str) -> String {
+        format!(
+            "tracer-report-{}.{}",
+            self.run_name.replace('/', "_"),
+            format
+        )
+    }
+}
+
+/// Writes a report in a directory of the run's owner. The file is created in
+/// the directory opened and checked beforehand, never through a symlink or
+/// over an existing file, and given to the owner, so a daemon running as root
+/// only writes what the owner could write themselves.
+pub fn write_report_file(
+    directory: &Path,
+    file_name: &str,
+    contents: &str,
+    owner_uid: Option<u32>,
+) -> Result<PathBuf> {
+    let directory_file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY)
+        .open(directory)
+        .with_context(|| format!("Failed to open {}", directory.display()))?;
+    let directory_metadata = directory_file.metadata()?;
+    let owner_uid = owner_uid.filter(|uid| *uid != current_uid());
+    if let Some(uid) = owner_uid {
+        if directory_metadata.uid() != uid {
+            bail!("{} doesn't belong to uid {}", directory.display(), uid);
+        }
+    }
+
+    let path = directory.join(file_name);
+    let name = CString::new(file_name)?;
+    // SAFETY: the directory fd is open for the whole call and `name` is NUL terminated
+    let fd = unsafe {
+        libc::openat(
+            directory_file.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            0o644 as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to create {}", path.display()));
+    }
+    // SAFETY: the fd was just opened and nothing else owns it
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    if let Some(uid) = owner_uid {
+        std::os::unix::fs::fchown(&file, Some(uid), Some(directory_metadata.gid()))?;
+    }
+    file.write_all(contents.as_bytes())?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_recorder::EventRecorder;
+    use chrono::TimeDelta;
+    use serde_json::json;
+
+    const RUN_ID: &str = "run-1";
+
+    fn record(
+        recorder: &mut EventRecorder,
+        event_type: EventType,
+        attributes: Value,
+        timestamp: DateTime<Utc>,
+    ) {
+        recorder.record_run_event(
+            Some(RUN_ID),
+            event_type,
+            format!("{:?}", event_type),
+            Some(attributes),
+            Some(timestamp),
+        );
+    }
+
+    fn recorded_run(start: DateTime<Utc>) -> EventRecorder {
+        let mut recorder = EventRecorder::new();
+        let seconds = |seconds: i64| start + TimeDelta::seconds(seconds);
+
+        // Two bwa processes of Nextflow tasks, the second fails
+        for (pid, work_dir) in [("100", "/work/ab/123"), ("200", "/work/cd/456")] {
+            record(
+                &mut recorder,
+                EventType::ToolExecution,
+                json!({
+                    "tool_name": "bwa",
+                    "tool_pid": pid,
+                    "process_cpu_utilization": 200.0,
+                    "process_memory_usage": 1024,
+                    "nextflow_task": { "work_dir": work_dir },
+                }),
+                seconds(0),
+            );
+        }
+        record(
+            &mut recorder,
+            EventType::ToolMetricEvent,
+            json!({
+                "tool_name": "bwa",
+                "tool_pid": "100",
+                "process_cpu_utilization": 100.0,
+                "process_memory_usage": 4096,
+                "process_disk_usage_read_total": 1000,
+                "process_disk_usage_write_total": 500,
+            }),
+            seconds(10),
+        );
+        record(
+            &mut recorder,
+            EventType::FinishedToolExecution,
+            json!({ "tool_name": "bwa", "tool_pid": "100", "duration": 20000 }),
+            seconds(20),
+        );
+        record(
+            &mut recorder,
+            EventType::FinishedToolExecution,
+            json!({
+                "tool_name": "bwa",
+                "tool_pid": "200",
+                "duration": 5000,
+                "cgroup": { "memory_peak": 8192, "cpu_usage_usec": 3_000_000 },
+            }),
+            seconds(5),
+        );
+        for (work_dir, status, exit_code) in [
+            ("/work/ab/123", "finished", 0),
+            ("/work/cd/456", "failed", 137),
+        ] {
+            record(
+                &mut recorder,
+                EventType::TaskEvent,
+                json!({ "engine": "nextflow", "status": status, "work_dir": work_dir, "exit_code": exit_code }),
+                seconds(21),
+            );
+        }
+
+        // A samtools process still running
+        record(
+            &mut recorder,
+            EventType::ToolExecution,
+            json!({ "tool_name": "samtools", "tool_pid": "300" }),
+            seconds(22),
+        );
+        recorder.record_run_event(
+            Some(RUN_ID),
+            EventType::SyslogEvent,
+            "Out of memory: Killed process 200 (bwa)".to_string(),
+            Some(json!({ "error_display_name": "Out of memory" })),
+            Some(seconds(6)),
+        );
+        recorder
+    }
+
+    fn report(start: DateTime<Utc>, recorder: &EventRecorder) -> RunReport {
+        let run_events = recorder.get_run_events(RUN_ID).unwrap();
+        RunReport {
+            run_id: RUN_ID.to_string(),
+            run_name: "brave-<ant>".to_string(),
+            service_name: "tracer".to_string(),
+            start_time: start,
+            end_time: Some(start + TimeDelta::seconds(90)),
+            pipeline: None,
+            slurm_job: None,
+            estimated_cost: None,
+            tools: run_events.get_tools(),
+            syslog_errors: run_events.get_syslog_errors().to_vec(),
+            uploads: vec![UploadedFile {
+                file_name: "multiqc_report.html".to_string(),
+                source_path: "/data/results/multiqc_report.html".to_string(),
+                status: UploadStatus::Done,
+            }],
+            host: HostInfo {
+                hostname: Some("node-3".to_string()),
+                os: Some("Ubuntu".to_string()),
+                os_version: Some("22.04".to_string()),
+                kernel_version: Some("6.5.0".to_string()),
+                arch: Some("x86_64".to_string()),
+                cpus: 8,
+                total_memory_bytes: 32 * 1024 * 1024 * 1024,
+                cloud: None,
+                kubernetes: None,
+            },
+            generated_at: start + TimeDelta::seconds(100),
+        }
+    }
+
+    #[test]
+    fn test_tools_are_summarized_from_events() {
+        let start = Utc::now();
+        let recorder = recorded_run(start);
+        let run_events = recorder.get_run_events(RUN_ID).unwrap();
+
+        assert_eq!(
+            run_events.get_tools(),
+            vec![
+                ToolSummary {
+                    tool_name: "bwa".to_string(),
+                    count: 2,
+                    running: 0,
+                    wall_time_seconds: 25.0,
+                    // 2 CPUs for 10s and 1 for 10s, and 3s from the cgroup of the other
+                    cpu_time_seconds: 33.0,
+                    peak_memory_bytes: 8192,
+                    read_bytes: 1000,
+                    written_bytes: 500,
+                    exit_codes: BTreeMap::from([(0, 1), (137, 1)]),
+                    failed: 1,
+                },
+                ToolSummary {
+                    tool_name: "samtools".to_string(),
+                    count: 1,
+                    running: 1,
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(run_events.get_syslog_errors().len(), 1);
+        assert_eq!(
+            run_events.get_syslog_errors()[0].error.as_deref(),
+            Some("Out of memory")
+        );
+    }
+
+    #[test]
+    fn test_cgroup_usage_is_not_counted_twice_for_tracked_children() {
+        let start = Utc::now();
+        let seconds = |seconds: i64| start + TimeDelta::seconds(seconds);
+        let mut recorder = EventRecorder::new();
+
+        // A script in a container runs bwa, which uses 2 CPUs for 4 seconds
+        record(
+            &mut recorder,
+            EventType::ToolExecution,
+            json!({ "tool_name": "bash", "tool_pid": "100", "process_cpu_utilization": 0.0 }),
+            seconds(0),
+        );
+        record(
+            &mut recorder,
+            EventType::ToolExecution,
+            json!({
+                "tool_name": "bwa",
+                "tool_pid": "101",
+                "tool_parent_pid": "100",
+                "process_cpu_utilization": 200.0,
+            }),
+            seconds(1),
+        );
+        record(
+            &mut recorder,
+            EventType::FinishedToolExecution,
+            json!({ "tool_name": "bwa", "tool_pid": "101", "duration": 2000 }),
+            seconds(3),
+        );
+        record(
+            &mut recorder,
+            EventType::FinishedToolExecution,
+            json!({
+                "tool_name": "bash",
+                "tool_pid": "100",
+                "duration": 5000,
+                "cgroup": { "cpu_usage_usec": 5_000_000, "io_read_bytes": 300 },
+            }),
+            seconds(5),
+        );
+
+        let tools = recorder.get_run_events(RUN_ID).unwrap().get_tools();
+        let cpu_time = |index: usize| tools[index].cpu_time_seconds;
+
+        assert_eq!((tools[0].tool_name.as_str(), cpu_time(0)), ("bash", 1.0));
+        assert_eq!((tools[1].tool_name.as_str(), cpu_time(1)), ("bwa", 4.0));
+        assert_eq!(tools[0].read_bytes, 300);
+    }
+
+    #[test]
+    fn test_export_report() -> Result<()> {
+        let start = Utc::now();
+        let report = report(start, &recorded_run(start));
+
+        let markdown = report.export("md")?;
+        assert!(markdown.starts_with("# Tracer run report: brave-<ant>\n"));
+        assert!(markdown.contains("| Duration | 1m 30s |"));
+        assert!(markdown.contains("| bwa | 2 | 25.0s | 33.0s | 8.0 KiB | 1000 B | 500 B | exit 0 (1), exit 137 (1), 1 failed |"));
+        assert!(markdown.contains("| samtools | 1 | 0.0s | 0.0s | 0 B | 0 B | 0 B | 1 running |"));
+        assert!(markdown.contains("**Out of memory**: `Out of memory: Killed process 200 (bwa)`"));
+        assert!(markdown.contains("- `/data/results/multiqc_report.html` (done)"));
+        assert!(markdown.contains("| Memory | 32.0 GiB |"));
+
+        let html = report.export("html")?;
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>Tracer run report: brave-&lt;ant&gt;</title>"));
+        assert!(html.contains("<td>bwa</td><td>2</td>"));
+        assert!(!html.contains("<ant>"));
+
+        let json: RunReport = serde_json::from_str(&report.export("json")?)?;
+        assert_eq!(json, report);
+
+        assert!(report.export("pdf").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_report_file() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = write_report_file(directory.path(), "report.md", "# Report\n", None)?;
+        assert_eq!(std::fs::read_to_string(&path)?, "# Report\n");
+
+        // An existing file isn't replaced, nor is a symlink followed
+        assert!(write_report_file(directory.path(), "report.md", "", None).is_err());
+        let target = directory.path().join("target");
+        std::fs::write(&target, "unchanged")?;
+        std::os::unix::fs::symlink(&target, directory.path().join("link.md"))?;
+        assert!(write_report_file(directory.path(), "link.md", "", None).is_err());
+        assert_eq!(std::fs::read_to_string(&target)?, "unchanged");
+
+        // Nor is a directory of another user written to for them
+        assert!(
+            write_report_file(directory.path(), "other.md", "", Some(current_uid() + 1)).is_err()
+        );
+
+        Ok(())
+    }
+}
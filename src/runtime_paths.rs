@@ -0,0 +1,237 @@
+// src/runtime_paths.rs
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+
+/// Where a daemon started with `tracer init --system` listens, for every user
+pub const SYSTEM_RUNTIME_DIR: &str = "/run/tracer";
+pub const SYSTEM_SOCKET_PATH: &str = "/run/tracer/tracerd.sock";
+/// File cache and upload queue of the system-wide daemon
+pub const SYSTEM_CACHE_DIR: &str = "/var/cache/tracer";
+
+lazy_static! {
+    /// Per-user directory holding the daemon's runtime files, so daemons of
+    /// different users on a shared node neither collide nor see each other
+    pub static ref RUNTIME_DIR: PathBuf =
+        get_runtime_dir(std::env::var("XDG_RUNTIME_DIR").ok(), current_uid());
+    pub static ref PID_FILE: String = runtime_path("tracerd.pid");
+    pub static ref STDOUT_FILE: String = runtime_path("tracerd.out");
+    pub static ref STDERR_FILE: String = runtime_path("tracerd.err");
+    pub static ref SOCKET_PATH: String = runtime_path("tracerd.sock");
+    pub static ref INTERCEPTOR_STDOUT_FILE: String = runtime_path("tracerd-stdout");
+    pub static ref INTERCEPTOR_STDERR_FILE: String = runtime_path("tracerd-stderr");
+    /// Per-user directory of the file cache and upload queue, which must
+    /// survive reboots and may grow larger than the runtime directory's tmpfs
+    pub static ref CACHE_DIR: PathBuf = get_user_cache_dir(
+        std::env::var("XDG_CACHE_HOME").ok(),
+        std::env::var("HOME").ok(),
+        current_uid()
+    );
+}
+
+pub fn current_uid() -> u32 {
+    // SAFETY: getuid has no preconditions and cannot fail
+    unsafe { libc::getuid() }
+}
+
+/// The user's own daemon if they run one, otherwise the system-wide daemon
+pub fn get_socket_path() -> &'static str {
+    select_socket_path(&SOCKET_PATH, SYSTEM_SOCKET_PATH)
+}
+
+fn select_socket_path<'a>(user_socket: &'a str, system_socket: &'a str) -> &'a str {
+    if !Path::new(user_socket).exists() && Path::new(system_socket).exists() {
+        system_socket
+    } else {
+        user_socket
+    }
+}
+
+fn runtime_path(name: &str) -> String {
+    RUNTIME_DIR.join(name).to_str().unwrap().to_string()
+}
+
+/// `$XDG_RUNTIME_DIR/tracer` when the session has one, `/tmp/tracer-<uid>` otherwise
+pub fn get_runtime_dir(xdg_runtime_dir: Option<String>, uid: u32) -> PathBuf {
+    match xdg_runtime_dir {
+        Some(directory) if Path::new(&directory).is_absolute() => {
+            PathBuf::from(directory).join("tracer")
+        }
+        _ => PathBuf::from(format!("/tmp/tracer-{}", uid)),
+    }
+}
+
+/// `$XDG_CACHE_HOME/tracer`, defaulting to `~/.cache/tracer` as the XDG spec
+/// says, or `/var/tmp/tracer-<uid>` for users without a home directory
+pub fn get_user_cache_dir(
+    xdg_cache_home: Option<String>,
+    home: Option<String>,
+    uid: u32,
+) -> PathBuf {
+    match (xdg_cache_home, home) {
+        (Some(directory), _) if Path::new(&directory).is_absolute() => {
+            PathBuf::from(directory).join("tracer")
+        }
+        (_, Some(home)) if Path::new(&home).is_absolute() => {
+            PathBuf::from(home).join(".cache").join("tracer")
+        }
+        _ => PathBuf::from(format!("/var/tmp/tracer-{}", uid)),
+    }
+}
+
+/// Where the daemon keeps its file cache and upload queue
+pub fn get_cache_dir(system_mode: bool) -> PathBuf {
+    if system_mode {
+        PathBuf::from(SYSTEM_CACHE_DIR)
+    } else {
+        CACHE_DIR.clone()
+    }
+}
+
+/// Creates a directory accessible only to its owner, for the runtime files
+/// and the file cache. An existing directory must belong to the current user,
+/// since anyone can create `/tmp/tracer-<uid>` ahead of time.
+pub fn ensure_private_dir(directory: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(directory)
+        .with_context(|| format!("Failed to create {}", directory.display()))?;
+
+    let metadata = fs::symlink_metadata(directory)?;
+    if !metadata.is_dir() {
+        bail!("{} is not a directory", directory.display());
+    }
+    if metadata.uid() != current_uid() {
+        bail!(
+            "{} belongs to uid {}, not to the current user",
+            directory.display(),
+            metadata.uid()
+        );
+    }
+    if metadata.permissions().mode() & 0o077 != 0 {
+        fs::set_permissions(directory, fs::Permissions::from_mode(0o700))?;
+    }
+
+    Ok(())
+}
+
+/// Creates the system runtime directory, which every user must be able to
+/// enter to reach the socket. Only root may write to it.
+pub fn ensure_system_runtime_dir() -> Result<()> {
+    let directory = Path::new(SYSTEM_RUNTIME_DIR);
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(directory)
+        .with_context(|| format!("Failed to create {}", directory.display()))?;
+
+    let metadata = fs::symlink_metadata(directory)?;
+    if !metadata.is_dir() || metadata.uid() != 0 {
+        bail!("{} must be a directory owned by root", directory.display());
+    }
+    fs::set_permissions(directory, fs::Permissions::from_mode(0o755))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_runtime_dir() {
+        assert_eq!(
+            get_runtime_dir(Some("/run/user/1000".to_string()), 1000),
+            PathBuf::from("/run/user/1000/tracer")
+        );
+        assert_eq!(
+            get_runtime_dir(None, 1000),
+            PathBuf::from("/tmp/tracer-1000")
+        );
+        // A relative or empty value is ignored, as the XDG spec requires
+        assert_eq!(
+            get_runtime_dir(Some("".to_string()), 1000),
+            PathBuf::from("/tmp/tracer-1000")
+        );
+    }
+
+    #[test]
+    fn test_get_user_cache_dir() {
+        assert_eq!(
+            get_user_cache_dir(
+                Some("/data/cache".to_string()),
+                Some("/home/ada".to_string()),
+                1000
+            ),
+            PathBuf::from("/data/cache/tracer")
+        );
+        assert_eq!(
+            get_user_cache_dir(Some("".to_string()), Some("/home/ada".to_string()), 1000),
+            PathBuf::from("/home/ada/.cache/tracer")
+        );
+        assert_eq!(
+            get_user_cache_dir(None, None, 1000),
+            PathBuf::from("/var/tmp/tracer-1000")
+        );
+    }
+
+    #[test]
+    fn test_select_socket_path_prefers_the_users_daemon() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let user_socket = directory.path().join("user.sock");
+        let system_socket = directory.path().join("system.sock");
+        let user_socket = user_socket.to_str().unwrap();
+        let system_socket = system_socket.to_str().unwrap();
+
+        assert_eq!(select_socket_path(user_socket, system_socket), user_socket);
+
+        fs::write(system_socket, "")?;
+        assert_eq!(
+            select_socket_path(user_socket, system_socket),
+            system_socket
+        );
+
+        fs::write(user_socket, "")?;
+        assert_eq!(select_socket_path(user_socket, system_socket), user_socket);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_private_dir_restricts_permissions() -> Result<()> {
+        let parent = tempfile::tempdir()?;
+        let directory = parent.path().join("tracer");
+
+        ensure_private_dir(&directory)?;
+        assert_eq!(
+            fs::metadata(&directory)?.permissions().mode() & 0o777,
+            0o700
+        );
+
+        fs::set_permissions(&directory, fs::Permissions::from_mode(0o755))?;
+        ensure_private_dir(&directory)?;
+        assert_eq!(
+            fs::metadata(&directory)?.permissions().mode() & 0o777,
+            0o700
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_private_dir_rejects_symlinks() -> Result<()> {
+        let parent = tempfile::tempdir()?;
+        let target = parent.path().join("target");
+        fs::create_dir(&target)?;
+        let link = parent.path().join("tracer");
+        std::os::unix::fs::symlink(&target, &link)?;
+
+        assert!(ensure_private_dir(&link).is_err());
+
+        Ok(())
+    }
+}
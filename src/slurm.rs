@@ -0,0 +1,552 @@
+// src/slurm.rs
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sysinfo::Pid;
+use tokio::process::Command;
+
+use crate::run_lifecycle::ProcessSnapshot;
+
+pub const SCONTROL: &str = "scontrol";
+pub const PROC_DIRECTORY: &str = "/proc";
+/// How long the controller gets to answer before the job is described from
+/// what's known on the node
+const SCONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// `/slurm/uid_1000/job_42/step_0` with cgroup v1,
+    /// `/system.slice/slurmstepd.scope/job_42/step_batch/user/task_0` with v2
+    static ref CGROUP_JOB: Regex = Regex::new(r"/slurm[^/]*/(?:[^/]+/)*job_(\d+)(?:/|$)").unwrap();
+}
+
+/// A SLURM job and the resources it requested
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SlurmJob {
+    pub job_id: String,
+    pub job_name: Option<String>,
+    pub array_job_id: Option<String>,
+    pub array_task_id: Option<String>,
+    pub partition: Option<String>,
+    pub cpus: Option<u32>,
+    pub memory_mb: Option<u64>,
+    pub node_list: Option<String>,
+}
+
+pub fn get_job_id_from_cgroup(cgroup: &str) -> Option<String> {
+    cgroup
+        .lines()
+        .find_map(|line| CGROUP_JOB.captures(line))
+        .map(|captures| captures[1].to_string())
+}
+
+/// Job a process runs in, from the cgroup SLURM puts it in
+pub fn read_process_job_id(proc_directory: &Path, pid: &str) -> Option<String> {
+    let cgroup = std::fs::read_to_string(proc_directory.join(pid).join("cgroup")).ok()?;
+    get_job_id_from_cgroup(&cgroup)
+}
+
+/// SLURM sizes are in megabytes unless suffixed, e.g. `16G` or `8000M`
+fn parse_memory_mb(value: &str) -> Option<u64> {
+    let (number, multiplier) = match value.chars().last()? {
+        'K' => (&value[..value.len() - 1], 1.0 / 1024.0),
+        'M' => (&value[..value.len() - 1], 1.0),
+        'G' => (&value[..value.len() - 1], 1024.0),
+        'T' => (&value[..value.len() - 1], 1024.0 * 1024.0),
+        _ => (value, 1.0),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .map(|number| (number * multiplier).round() as u64)
+}
+
+fn non_null(value: Option<&str>) -> Option<String> {
+    value
+        .filter(|value| !value.is_empty() && *value != "(null)" && *value != "N/A")
+        .map(String::from)
+}
+
+/// Parses `scontrol show job --oneliner`, a line of `Key=Value` fields
+pub fn parse_scontrol_job(output: &str) -> Option<SlurmJob> {
+    let fields: HashMap<&str, &str> = output
+        .split_whitespace()
+        .filter_map(|field| field.split_once('='))
+        .collect();
+    let field = |key: &str| non_null(fields.get(key).copied());
+
+    let cpus = field("NumCPUs").and_then(|cpus| cpus.parse().ok());
+    let tres_memory = fields.get("TRES").and_then(|tres| {
+        tres.split(',')
+            .find_map(|resource| resource.strip_prefix("mem="))
+            .and_then(parse_memory_mb)
+    });
+    let node_memory = field("MinMemoryNode").and_then(|memory| parse_memory_mb(&memory));
+    let cpu_memory = field("MinMemoryCPU")
+        .and_then(|memory| parse_memory_mb(&memory))
+        .zip(cpus)
+        .map(|(memory, cpus)| memory * cpus as u64);
+
+    Some(SlurmJob {
+        job_id: field("JobId")?,
+        job_name: field("JobName"),
+        array_job_id: field("ArrayJobId"),
+        array_task_id: field("ArrayTaskId"),
+        partition: field("Partition"),
+        cpus,
+        memory_mb: tres_memory.or(node_memory).or(cpu_memory),
+        node_list: field("NodeList"),
+    })
+}
+
+/// Asks the controller about a job, `None` when `scontrol` isn't available
+/// or doesn't answer in time
+pub async fn query_job(scontrol: &str, job_id: &str) -> Option<SlurmJob> {
+    query_job_within(scontrol, job_id, SCONTROL_TIMEOUT).await
+}
+
+async fn query_job_within(scontrol: &str, job_id: &str, limit: Duration) -> Option<SlurmJob> {
+    let output = Command::new(scontrol)
+        .args(["show", "job", "--oneliner", job_id])
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(limit, output).await.ok()?.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_scontrol_job(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The job SLURM describes in the environment of its job steps
+pub fn job_from_environment(get: impl Fn(&str) -> Option<String>) -> Option<SlurmJob> {
+    let cpus = get("SLURM_CPUS_ON_NODE")
+        .or_else(|| get("SLURM_JOB_CPUS_PER_NODE"))
+        .and_then(|cpus| {
+            // `SLURM_JOB_CPUS_PER_NODE` reads like `4(x2)` on several nodes
+            let count: String = cpus.chars().take_while(char::is_ascii_digit).collect();
+            count.parse().ok()
+        });
+    let memory_mb = get("SLURM_MEM_PER_NODE")
+        .and_then(|memory| parse_memory_mb(&memory))
+        .or_else(|| {
+            get("SLURM_MEM_PER_CPU")
+                .and_then(|memory| parse_memory_mb(&memory))
+                .zip(cpus)
+                .map(|(memory, cpus): (u64, u32)| memory * cpus as u64)
+        });
+
+    Some(SlurmJob {
+        job_id: get("SLURM_JOB_ID")?,
+        job_name: get("SLURM_JOB_NAME"),
+        array_job_id: get("SLURM_ARRAY_JOB_ID"),
+        array_task_id: get("SLURM_ARRAY_TASK_ID"),
+        partition: get("SLURM_JOB_PARTITION"),
+        cpus,
+        memory_mb,
+        node_list: get("SLURM_JOB_NODELIST"),
+    })
+}
+
+/// The job of the daemon itself, when it runs inside a SLURM allocation
+pub async fn detect_job(scontrol: &str) -> Option<SlurmJob> {
+    let environment_job = job_from_environment(|name| std::env::var(name).ok());
+    let job_id = environment_job
+        .as_ref()
+        .map(|job| job.job_id.clone())
+        .or_else(|| read_process_job_id(Path::new(PROC_DIRECTORY), "self"))?;
+
+    query_job(scontrol, &job_id)
+        .await
+        .or(environment_job)
+        .or(Some(SlurmJob {
+            job_id,
+            ..Default::default()
+        }))
+}
+
+/// Peak usage of a tool, in CPUs and megabytes
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ToolPeak {
+    pub peak_cpus: f32,
+    pub peak_memory_mb: u64,
+}
+
+/// Resources a job requested next to what its tools used, for the service to
+/// flag jobs that request far more than they need
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ResourceComparison {
+    pub job_id: String,
+    pub requested_cpus: Option<u32>,
+    pub requested_memory_mb: Option<u64>,
+    /// Peak of the usage of all of the job's tools together
+    pub peak_cpus: f32,
+    pub peak_memory_mb: u64,
+    /// Peak over requested, 0.25 when the job used a quarter of the request at most
+    pub cpu_utilization: Option<f32>,
+    pub memory_utilization: Option<f32>,
+    pub tools: BTreeMap<String, ToolPeak>,
+}
+
+#[derive(Default)]
+pub struct JobUsage {
+    tools: BTreeMap<String, ToolPeak>,
+    peak_cpus: f32,
+    peak_memory_mb: u64,
+}
+
+impl JobUsage {
+    /// Records what the job's tools used at the same moment, as tool name,
+    /// CPU usage in percent of a CPU as sysinfo reports it, and memory bytes
+    pub fn record_sample<'a>(&mut self, tools: impl IntoIterator<Item = (&'a str, f32, u64)>) {
+        let mut cpu_usage = 0.0;
+        let mut memory_bytes = 0;
+        for (tool_name, tool_cpu_usage, tool_memory_bytes) in tools {
+            let peak = self.tools.entry(tool_name.to_string()).or_default();
+            peak.peak_cpus = peak.peak_cpus.max(tool_cpu_usage / 100.0);
+            peak.peak_memory_mb = peak.peak_memory_mb.max(tool_memory_bytes / (1024 * 1024));
+            cpu_usage += tool_cpu_usage;
+            memory_bytes += tool_memory_bytes;
+        }
+        self.peak_cpus = self.peak_cpus.max(cpu_usage / 100.0);
+        self.peak_memory_mb = self.peak_memory_mb.max(memory_bytes / (1024 * 1024));
+    }
+
+    pub fn compare(&self, job: &SlurmJob) -> ResourceComparison {
+        let peak_cpus = self.peak_cpus;
+        let peak_memory_mb = self.peak_memory_mb;
+
+        ResourceComparison {
+            job_id: job.job_id.clone(),
+            requested_cpus: job.cpus,
+            requested_memory_mb: job.memory_mb,
+            peak_cpus,
+            peak_memory_mb,
+            cpu_utilization: job
+                .cpus
+                .filter(|cpus| *cpus > 0)
+                .map(|cpus| peak_cpus / cpus as f32),
+            memory_utilization: job
+                .memory_mb
+                .filter(|memory| *memory > 0)
+                .map(|memory| peak_memory_mb as f32 / memory as f32),
+            tools: self.tools.clone(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JobBoundary {
+    Start {
+        job: SlurmJob,
+        owner_uid: Option<u32>,
+    },
+    End {
+        run_id: String,
+        job_id: String,
+    },
+}
+
+/// Starts a run when processes of a new SLURM job appear on the node, and
+/// ends it when the last of them exits
+pub struct SlurmJobTracker {
+    proc_directory: PathBuf,
+    scontrol: String,
+    /// Job of each process by pid and start time, as pids get reused
+    process_jobs: HashMap<(Pid, u64), Option<String>>,
+    /// Jobs that got their run, which isn't started again if it's ended by hand
+    handled_jobs: HashSet<String>,
+    job_runs: HashMap<String, String>,
+    /// What the controller said about the running jobs, asked once per job
+    jobs: HashMap<String, SlurmJob>,
+}
+
+impl SlurmJobTracker {
+    pub fn new(proc_directory: PathBuf, scontrol: String) -> Self {
+        SlurmJobTracker {
+            proc_directory,
+            scontrol,
+            process_jobs: HashMap::new(),
+            handled_jobs: HashSet::new(),
+            job_runs: HashMap::new(),
+            jobs: HashMap::new(),
+        }
+    }
+
+    async fn get_job(&mut self, job_id: &str) -> SlurmJob {
+        if let Some(job) = self.jobs.get(job_id) {
+            return job.clone();
+        }
+        let job = query_job(&self.scontrol, job_id)
+            .await
+            .unwrap_or_else(|| SlurmJob {
+                job_id: job_id.to_string(),
+                ..Default::default()
+            });
+        self.jobs.insert(job_id.to_string(), job.clone());
+        job
+    }
+
+    pub fn register_run(&mut self, job_id: String, run_id: String) {
+        self.handled_jobs.insert(job_id.clone());
+        self.job_runs.insert(job_id, run_id);
+    }
+
+    pub fn forget_run(&mut self, run_id: &str) {
+        self.job_runs.retain(|_, job_run_id| job_run_id != run_id);
+    }
+
+    pub async fn detect(&mut self, snapshot: &ProcessSnapshot) -> Vec<JobBoundary> {
+        let keys: HashSet<(Pid, u64)> = snapshot
+            .iter()
+            .map(|(pid, process)| (*pid, process.start_time))
+            .collect();
+        self.process_jobs.retain(|key, _| keys.contains(key));
+
+        let mut running_jobs: HashMap<String, Option<u32>> = HashMap::new();
+        for (pid, process) in snapshot {
+            let job_id = self
+                .process_jobs
+                .entry((*pid, process.start_time))
+                .or_insert_with(|| read_process_job_id(&self.proc_directory, &pid.to_string()));
+            if let Some(job_id) = job_id {
+                running_jobs.entry(job_id.clone()).or_insert(process.uid);
+            }
+        }
+
+        let mut boundaries = vec![];
+        for (job_id, run_id) in self.job_runs.iter() {
+            if !running_jobs.contains_key(job_id) {
+                boundaries.push(JobBoundary::End {
+                    run_id: run_id.clone(),
+                    job_id: job_id.clone(),
+                });
+            }
+        }
+        self.handled_jobs
+            .retain(|job_id| running_jobs.contains_key(job_id));
+        self.jobs
+            .retain(|job_id, _| running_jobs.contains_key(job_id));
+
+        for (job_id, owner_uid) in running_jobs {
+            if self.handled_jobs.contains(&job_id) {
+                continue;
+            }
+            let job = self.get_job(&job_id).await;
+            boundaries.push(JobBoundary::Start { job, owner_uid });
+        }
+        boundaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_lifecycle::ObservedProcess;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    const SCONTROL_OUTPUT: &str = "JobId=4242 ArrayJobId=4240 ArrayTaskId=2 JobName=align UserId=alice(1000) GroupId=alice(1000) JobState=RUNNING Partition=compute NodeList=node[01-02] BatchHost=node01 NumNodes=2 NumCPUs=8 NumTasks=8 CPUs/Task=1 TRES=cpu=8,mem=16G,node=2,billing=8 MinCPUsNode=1 MinMemoryNode=8G MinTmpDiskNode=0 Command=/home/alice/align.sh WorkDir=/home/alice\n";
+
+    /// An executable that prints `output` for any arguments
+    fn fake_scontrol(directory: &Path, output: &str) -> String {
+        let path = directory.join("scontrol");
+        fs::write(&path, format!("#!/bin/sh\ncat <<'EOF'\n{}EOF\n", output)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn expected_job() -> SlurmJob {
+        SlurmJob {
+            job_id: "4242".to_string(),
+            job_name: Some("align".to_string()),
+            array_job_id: Some("4240".to_string()),
+            array_task_id: Some("2".to_string()),
+            partition: Some("compute".to_string()),
+            cpus: Some(8),
+            memory_mb: Some(16384),
+            node_list: Some("node[01-02]".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_get_job_id_from_cgroup() {
+        assert_eq!(
+            get_job_id_from_cgroup(
+                "12:memory:/slurm/uid_1000/job_4242/step_0/task_0\n11:cpuset:/slurm/uid_1000/job_4242/step_0\n"
+            ),
+            Some("4242".to_string())
+        );
+        assert_eq!(
+            get_job_id_from_cgroup(
+                "0::/system.slice/slurmstepd.scope/job_77/step_batch/user/task_0\n"
+            ),
+            Some("77".to_string())
+        );
+        assert_eq!(
+            get_job_id_from_cgroup("0::/user.slice/user-1000.slice/session-3.scope\n"),
+            None
+        );
+        assert_eq!(get_job_id_from_cgroup("0::/docker/job_12\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_query_job_with_scontrol() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let scontrol = fake_scontrol(directory.path(), SCONTROL_OUTPUT);
+
+        assert_eq!(query_job(&scontrol, "4242").await, Some(expected_job()));
+        assert_eq!(query_job("/nonexistent/scontrol", "4242").await, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_job_gives_up_on_a_slow_controller() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let scontrol = directory.path().join("scontrol");
+        fs::write(
+            &scontrol,
+            "#!/bin/sh
+sleep 10
+",
+        )?;
+        fs::set_permissions(&scontrol, fs::Permissions::from_mode(0o755))?;
+
+        let started = std::time::Instant::now();
+        let job = query_job_within(
+            scontrol.to_str().unwrap(),
+            "4242",
+            Duration::from_millis(100),
+        )
+        .await;
+
+        assert_eq!(job, None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_falls_back_to_per_cpu_requests() {
+        let job =
+            parse_scontrol_job("JobId=1 NumCPUs=4 MinMemoryCPU=2000M NodeList=(null)").unwrap();
+        assert_eq!(job.memory_mb, Some(8000));
+        assert_eq!(job.node_list, None);
+        assert_eq!(parse_scontrol_job("JobName=orphan"), None);
+    }
+
+    #[test]
+    fn test_job_from_environment() {
+        let environment = HashMap::from([
+            ("SLURM_JOB_ID", "4242"),
+            ("SLURM_JOB_NAME", "align"),
+            ("SLURM_ARRAY_JOB_ID", "4240"),
+            ("SLURM_ARRAY_TASK_ID", "2"),
+            ("SLURM_JOB_PARTITION", "compute"),
+            ("SLURM_JOB_CPUS_PER_NODE", "8(x2)"),
+            ("SLURM_MEM_PER_CPU", "2G"),
+            ("SLURM_JOB_NODELIST", "node[01-02]"),
+        ]);
+
+        let job = job_from_environment(|name| environment.get(name).map(|value| value.to_string()));
+
+        assert_eq!(job, Some(expected_job()));
+        assert_eq!(job_from_environment(|_| None), None);
+    }
+
+    #[test]
+    fn test_compare_requested_with_used_resources() {
+        let mut usage = JobUsage::default();
+        // bwa and samtools ran side by side, then bwa on its own
+        usage.record_sample([
+            ("bwa", 150.0, 2048 * 1024 * 1024),
+            ("samtools", 90.0, 4096 * 1024 * 1024),
+        ]);
+        usage.record_sample([("bwa", 200.0, 1024 * 1024 * 1024)]);
+
+        let comparison = usage.compare(&expected_job());
+
+        assert_eq!(comparison.peak_cpus, 2.4);
+        assert_eq!(comparison.peak_memory_mb, 6144);
+        assert_eq!(comparison.cpu_utilization, Some(0.3));
+        assert_eq!(comparison.memory_utilization, Some(0.375));
+        assert_eq!(
+            comparison.tools["bwa"],
+            ToolPeak {
+                peak_cpus: 2.0,
+                peak_memory_mb: 2048
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tracker_starts_and_ends_a_run_per_job() -> anyhow::Result<()> {
+        let proc_directory = tempfile::tempdir()?;
+        let write_cgroup = |pid: u32, cgroup: &str| {
+            let directory = proc_directory.path().join(pid.to_string());
+            fs::create_dir_all(&directory).unwrap();
+            fs::write(directory.join("cgroup"), cgroup).unwrap();
+        };
+        write_cgroup(
+            10,
+            "0::/system.slice/slurmstepd.scope/job_4242/step_batch/user/task_0\n",
+        );
+        write_cgroup(
+            11,
+            "0::/system.slice/slurmstepd.scope/job_4242/step_0/user/task_0\n",
+        );
+        write_cgroup(20, "0::/user.slice/user-1000.slice/session-3.scope\n");
+
+        let process = |start_time| ObservedProcess {
+            name: "bash".to_string(),
+            cmd: vec![],
+            parent_pid: None,
+            uid: Some(1000),
+            working_directory: None,
+            start_time,
+        };
+        let mut snapshot: ProcessSnapshot = HashMap::from([
+            (Pid::from(10), process(100)),
+            (Pid::from(11), process(100)),
+            (Pid::from(20), process(100)),
+        ]);
+
+        let mut tracker = SlurmJobTracker::new(
+            proc_directory.path().to_path_buf(),
+            "/nonexistent/scontrol".to_string(),
+        );
+        let boundaries = tracker.detect(&snapshot).await;
+        assert_eq!(
+            boundaries,
+            vec![JobBoundary::Start {
+                job: SlurmJob {
+                    job_id: "4242".to_string(),
+                    ..Default::default()
+                },
+                owner_uid: Some(1000),
+            }]
+        );
+        tracker.register_run("4242".to_string(), "run-1".to_string());
+        assert!(tracker.detect(&snapshot).await.is_empty());
+
+        // A run ended by hand isn't started again for the same job
+        tracker.forget_run("run-1");
+        assert!(tracker.detect(&snapshot).await.is_empty());
+        tracker.register_run("4242".to_string(), "run-1".to_string());
+
+        snapshot.remove(&Pid::from(10));
+        snapshot.remove(&Pid::from(11));
+        assert_eq!(
+            tracker.detect(&snapshot).await,
+            vec![JobBoundary::End {
+                run_id: "run-1".to_string(),
+                job_id: "4242".to_string(),
+            }]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,504 @@
+// src/timeline.rs
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+/// Track of the machine's counters, above the tracks of the tools
+const SYSTEM_TRACK: u32 = 1;
+const BYTES_PER_MIB: f64 = 1024.0 * 1024.0;
+/// Counter points kept per process and for the machine; longer series are
+/// downsampled so traces of long runs stay small enough to open
+pub const MAX_PROCESS_COUNTER_POINTS: usize = 100;
+pub const MAX_SYSTEM_COUNTER_POINTS: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CounterSample {
+    pub timestamp: DateTime<Utc>,
+    /// In percent of a CPU
+    pub cpu_usage: f64,
+    pub memory_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimelineProcess {
+    pub pid: String,
+    pub tool_name: String,
+    /// Index of the tracked process that started this one
+    pub parent: Option<usize>,
+    /// The workflow engine task the process ran for
+    pub task: Option<String>,
+    pub start_time: DateTime<Utc>,
+    /// `None` while the process runs
+    pub end_time: Option<DateTime<Utc>>,
+    pub exit_code: Option<i32>,
+    pub samples: Vec<CounterSample>,
+}
+
+impl TimelineProcess {
+    fn display_name(&self) -> String {
+        format!("{} ({})", self.tool_name, self.pid)
+    }
+}
+
+/// Tool executions of a run and the usage samples recorded along them
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeline {
+    pub run_id: String,
+    pub run_name: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub processes: Vec<TimelineProcess>,
+    pub system_samples: Vec<CounterSample>,
+}
+
+fn counter(name: &str, track: u32, ts: i64, series: &str, value: f64) -> Value {
+    json!({ "name": name, "ph": "C", "ts": ts, "pid": track, "args": { series: value } })
+}
+
+/// Merges consecutive samples into at most `max_points`, keeping the peak
+/// usage of each merged stretch at its first timestamp
+fn downsample(samples: &[CounterSample], max_points: usize) -> Vec<CounterSample> {
+    if samples.len() <= max_points {
+        return samples.to_vec();
+    }
+    samples
+        .chunks(samples.len().div_ceil(max_points))
+        .map(|chunk| CounterSample {
+            timestamp: chunk[0].timestamp,
+            cpu_usage: chunk
+                .iter()
+                .map(|sample| sample.cpu_usage)
+                .fold(0.0, f64::max),
+            memory_bytes: chunk
+                .iter()
+                .map(|sample| sample.memory_bytes)
+                .max()
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Adds a sample to those of a counter, merging them into their peaks once
+/// there are twice as many as get exported so a long run's stay bounded
+pub fn push_sample(samples: &mut Vec<CounterSample>, sample: CounterSample, max_points: usize) {
+    samples.push(sample);
+    if samples.len() >= 2 * max_points {
+        *samples = downsample(samples, max_points);
+    }
+}
+
+fn metadata(name: &str, track: u32, tid: Option<u32>, args: Value) -> Value {
+    let mut event = json!({ "name": name, "ph": "M", "pid": track, "args": args });
+    if let Some(tid) = tid {
+        event["tid"] = json!(tid);
+    }
+    event
+}
+
+impl Timeline {
+    /// Microseconds since the start of the run, the unit of trace timestamps
+    fn ts(&self, timestamp: DateTime<Utc>) -> i64 {
+        (timestamp - self.start_time)
+            .num_microseconds()
+            .unwrap_or_default()
+            .max(0)
+    }
+
+    /// The tracked process at the top of the tree the process is part of
+    fn get_root(&self, mut index: usize) -> &TimelineProcess {
+        while let Some(parent) = self.processes[index].parent {
+            index = parent;
+        }
+        &self.processes[index]
+    }
+
+    fn get_group_names(&self, group_by: &str) -> Result<Vec<String>> {
+        match group_by {
+            "process" => Ok((0..self.processes.len())
+                .map(|index| self.get_root(index).display_name())
+                .collect()),
+            "task" => Ok(self
+                .processes
+                .iter()
+                .map(|process| {
+                    process
+                        .task
+                        .clone()
+                        .unwrap_or_else(|| "Outside of tasks".to_string())
+                })
+                .collect()),
+            _ => Err(anyhow::anyhow!(
+                "Unsupported timeline grouping: {}",
+                group_by
+            )),
+        }
+    }
+
+    /// Chrome Trace Event JSON, which Perfetto and `chrome://tracing` open.
+    /// Each process tree or workflow engine task is a track, with a row for
+    /// each of its tools and their CPU and memory counters.
+    pub fn to_chrome_trace(&self, group_by: &str) -> Result<String> {
+        let group_names = self.get_group_names(group_by)?;
+        let mut tracks: HashMap<&str, u32> = HashMap::new();
+        let mut events = vec![
+            metadata(
+                "process_name",
+                SYSTEM_TRACK,
+                None,
+                json!({ "name": "System" }),
+            ),
+            metadata(
+                "process_sort_index",
+                SYSTEM_TRACK,
+                None,
+                json!({ "sort_index": SYSTEM_TRACK }),
+            ),
+        ];
+
+        for sample in &downsample(&self.system_samples, MAX_SYSTEM_COUNTER_POINTS) {
+            let ts = self.ts(sample.timestamp);
+            events.push(counter("CPU %", SYSTEM_TRACK, ts, "cpu", sample.cpu_usage));
+            events.push(counter(
+                "Memory (MiB)",
+                SYSTEM_TRACK,
+                ts,
+                "used",
+                sample.memory_bytes as f64 / BYTES_PER_MIB,
+            ));
+        }
+
+        for (index, (process, group_name)) in self.processes.iter().zip(&group_names).enumerate() {
+            let next_track = SYSTEM_TRACK + 1 + tracks.len() as u32;
+            let track = *tracks.entry(group_name).or_insert_with(|| {
+                events.push(metadata(
+                    "process_name",
+                    next_track,
+                    None,
+                    json!({ "name": group_name }),
+                ));
+                events.push(metadata(
+                    "process_sort_index",
+                    next_track,
+                    None,
+                    json!({ "sort_index": next_track }),
+                ));
+                next_track
+            });
+            let tid = process.pid.parse().unwrap_or(index as u32);
+            let name = process.display_name();
+            events.push(metadata(
+                "thread_name",
+                track,
+                Some(tid),
+                json!({ "name": name }),
+            ));
+
+            let start = self.ts(process.start_time);
+            let end = self.ts(process.end_time.unwrap_or(self.end_time));
+            let mut args = json!({ "pid": process.pid, "running": process.end_time.is_none() });
+            if let Some(task) = &process.task {
+                args["task"] = json!(task);
+            }
+            if let Some(exit_code) = process.exit_code {
+                args["exit_code"] = json!(exit_code);
+            }
+            events.push(json!({
+                "name": process.tool_name,
+                "cat": "tool",
+                "ph": "X",
+                "ts": start,
+                "dur": (end - start).max(1),
+                "pid": track,
+                "tid": tid,
+                "args": args,
+            }));
+
+            let cpu_name = format!("{} CPU %", name);
+            let memory_name = format!("{} memory (MiB)", name);
+            for sample in &downsample(&process.samples, MAX_PROCESS_COUNTER_POINTS) {
+                let ts = self.ts(sample.timestamp);
+                events.push(counter(&cpu_name, track, ts, "cpu", sample.cpu_usage));
+                events.push(counter(
+                    &memory_name,
+                    track,
+                    ts,
+                    "rss",
+                    sample.memory_bytes as f64 / BYTES_PER_MIB,
+                ));
+            }
+            // Counters hold their last value, which the process no longer uses once it exits
+            if process.end_time.is_some() && !process.samples.is_empty() {
+                events.push(counter(&cpu_name, track, end, "cpu", 0.0));
+                events.push(counter(&memory_name, track, end, "rss", 0.0));
+            }
+        }
+
+        Ok(serde_json::to_string(&json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+            "otherData": {
+                "run_id": self.run_id,
+                "run_name": self.run_name,
+                "start_time": self.start_time.to_rfc3339(),
+            },
+        }))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_recorder::{EventRecorder, EventType};
+    use chrono::TimeDelta;
+
+    const RUN_ID: &str = "run-1";
+
+    fn recorded_timeline(start: DateTime<Utc>) -> Timeline {
+        let mut recorder = EventRecorder::new();
+        let seconds = |seconds: i64| Some(start + TimeDelta::seconds(seconds));
+        let mut record = |event_type: EventType, attributes: Value, timestamp| {
+            recorder.record_run_event(
+                Some(RUN_ID),
+                event_type,
+                String::new(),
+                Some(attributes),
+                timestamp,
+            )
+        };
+
+        // A Nextflow task running a shell that runs bwa, and a Snakemake job
+        record(
+            EventType::ToolExecution,
+            json!({
+                "tool_name": "bash",
+                "tool_pid": "100",
+                "tool_parent_pid": "1",
+                "process_cpu_utilization": 10.0,
+                "process_memory_usage": 1048576,
+                "nextflow_task": { "name": "ALIGN (sample_1)", "work_dir": "/work/ab/123" },
+            }),
+            seconds(0),
+        );
+        record(
+            EventType::ToolExecution,
+            json!({ "tool_name": "bwa", "tool_pid": "101", "tool_parent_pid": "100" }),
+            seconds(1),
+        );
+        record(
+            EventType::ToolMetricEvent,
+            json!({
+                "tool_name": "bwa",
+                "tool_pid": "101",
+                "process_cpu_utilization": 400.0,
+                "process_memory_usage": 2097152,
+            }),
+            seconds(5),
+        );
+        record(
+            EventType::FinishedToolExecution,
+            json!({ "tool_name": "bwa", "tool_pid": "101", "duration": 9000 }),
+            seconds(10),
+        );
+        record(
+            EventType::FinishedToolExecution,
+            json!({ "tool_name": "bash", "tool_pid": "100", "duration": 11000 }),
+            seconds(11),
+        );
+        record(
+            EventType::ToolExecution,
+            json!({ "tool_name": "samtools", "tool_pid": "200", "tool_parent_pid": "1" }),
+            seconds(20),
+        );
+        record(
+            EventType::TaskEvent,
+            json!({
+                "engine": "snakemake",
+                "status": "running",
+                "name": "sort",
+                "task_id": "3",
+                "tool_pid": "200",
+            }),
+            seconds(20),
+        );
+        record(
+            EventType::MetricEvent,
+            json!({ "system_cpu_utilization": 55.5, "system_memory_used": 1073741824 }),
+            seconds(5),
+        );
+
+        recorder.get_run_events(RUN_ID).unwrap().get_timeline(
+            RUN_ID,
+            "brave-ant",
+            start,
+            start + TimeDelta::seconds(30),
+        )
+    }
+
+    fn get_events<'a>(trace: &'a Value, phase: &str) -> Vec<&'a Value> {
+        trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event["ph"] == phase)
+            .collect()
+    }
+
+    fn get_track_names(trace: &Value) -> Vec<&str> {
+        get_events(trace, "M")
+            .into_iter()
+            .filter(|event| event["name"] == "process_name")
+            .map(|event| event["args"]["name"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_timeline_from_events() {
+        let start = Utc::now();
+        let timeline = recorded_timeline(start);
+
+        assert_eq!(timeline.processes.len(), 3);
+        let bwa = &timeline.processes[1];
+        assert_eq!(bwa.parent, Some(0));
+        assert_eq!(bwa.task.as_deref(), Some("ALIGN (sample_1)"));
+        assert_eq!(bwa.start_time, start + TimeDelta::seconds(1));
+        assert_eq!(bwa.end_time, Some(start + TimeDelta::seconds(10)));
+        assert_eq!(
+            bwa.samples,
+            vec![CounterSample {
+                timestamp: start + TimeDelta::seconds(5),
+                cpu_usage: 400.0,
+                memory_bytes: 2097152,
+            }]
+        );
+        let samtools = &timeline.processes[2];
+        assert_eq!(samtools.parent, None);
+        assert_eq!(samtools.task.as_deref(), Some("sort"));
+        assert_eq!(samtools.end_time, None);
+        assert_eq!(timeline.system_samples.len(), 1);
+    }
+
+    #[test]
+    fn test_to_chrome_trace() -> Result<()> {
+        let timeline = recorded_timeline(Utc::now());
+
+        let trace: Value = serde_json::from_str(&timeline.to_chrome_trace("process")?)?;
+        assert_eq!(
+            get_track_names(&trace),
+            vec!["System", "bash (100)", "samtools (200)"]
+        );
+        let slices = get_events(&trace, "X");
+        assert_eq!(slices.len(), 3);
+        assert_eq!(
+            (&slices[1]["name"], &slices[1]["ts"], &slices[1]["dur"]),
+            (&json!("bwa"), &json!(1_000_000), &json!(9_000_000))
+        );
+        assert_eq!(
+            (&slices[1]["pid"], &slices[1]["tid"]),
+            (&json!(2), &json!(101))
+        );
+        // Still running when the timeline was exported
+        assert_eq!(slices[2]["dur"], 10_000_000);
+        assert_eq!(slices[2]["args"]["running"], true);
+
+        let counters = get_events(&trace, "C");
+        assert!(counters.contains(&&json!({
+            "name": "bwa (101) CPU %", "ph": "C", "ts": 5_000_000, "pid": 2, "args": { "cpu": 400.0 }
+        })));
+        assert!(counters.contains(&&json!({
+            "name": "Memory (MiB)", "ph": "C", "ts": 5_000_000, "pid": 1, "args": { "used": 1024.0 }
+        })));
+
+        let trace: Value = serde_json::from_str(&timeline.to_chrome_trace("task")?)?;
+        assert_eq!(
+            get_track_names(&trace),
+            vec!["System", "ALIGN (sample_1)", "sort"]
+        );
+
+        assert!(timeline.to_chrome_trace("tool").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recorded_samples_stay_bounded() {
+        let start = Utc::now();
+        let mut samples = Vec::new();
+        for index in 0..10_000 {
+            push_sample(
+                &mut samples,
+                CounterSample {
+                    timestamp: start + TimeDelta::seconds(index),
+                    cpu_usage: if index == 4321 { 800.0 } else { 10.0 },
+                    memory_bytes: 1024,
+                },
+                MAX_PROCESS_COUNTER_POINTS,
+            );
+        }
+
+        assert!(samples.len() < 2 * MAX_PROCESS_COUNTER_POINTS);
+        assert_eq!(samples[0].timestamp, start);
+        // Peaks survive the merging
+        assert!(samples.iter().any(|sample| sample.cpu_usage == 800.0));
+    }
+
+    #[test]
+    fn test_chrome_trace_of_a_long_run_stays_small() -> Result<()> {
+        let start = Utc::now();
+        let end = start + TimeDelta::hours(12);
+        // A sample every 5 seconds over 12 hours
+        let samples = |count: i64| -> Vec<CounterSample> {
+            (0..count)
+                .map(|index| CounterSample {
+                    timestamp: start + TimeDelta::seconds(index * 5),
+                    cpu_usage: (index % 100) as f64,
+                    memory_bytes: (index as u64 % 1000) * 1048576,
+                })
+                .collect()
+        };
+        // 500 tools, each running for an hour
+        let processes = (0..500)
+            .map(|index| TimelineProcess {
+                pid: (1000 + index).to_string(),
+                tool_name: "bwa".to_string(),
+                parent: None,
+                task: Some(format!("ALIGN (sample_{})", index)),
+                start_time: start,
+                end_time: Some(start + TimeDelta::hours(1)),
+                exit_code: Some(0),
+                samples: samples(720),
+            })
+            .collect();
+        let timeline = Timeline {
+            run_id: RUN_ID.to_string(),
+            run_name: "brave-ant".to_string(),
+            start_time: start,
+            end_time: end,
+            processes,
+            system_samples: samples(8640),
+        };
+
+        let trace = timeline.to_chrome_trace("task")?;
+        assert!(!trace.contains('\n'));
+        assert!(trace.len() < 16 * 1024 * 1024, "{} bytes", trace.len());
+
+        let trace: Value = serde_json::from_str(&trace)?;
+        let counters = get_events(&trace, "C");
+        let bwa_cpu: Vec<_> = counters
+            .iter()
+            .filter(|event| event["name"] == "bwa (1000) CPU %")
+            .collect();
+        // Downsampled, plus the zero once the process exited
+        assert!(bwa_cpu.len() <= MAX_PROCESS_COUNTER_POINTS + 1);
+        // Peaks survive downsampling
+        assert!(bwa_cpu.iter().any(|event| event["args"]["cpu"] == 99.0));
+        let system_cpu = counters
+            .iter()
+            .filter(|event| event["name"] == "CPU %")
+            .count();
+        assert!(system_cpu <= MAX_SYSTEM_COUNTER_POINTS);
+
+        Ok(())
+    }
+}
@@ -1,17 +1,35 @@
 // src/tracer_client.rs
+use crate::cloud_metadata::{get_instance_metadata, InstanceMetadata};
+use crate::cost::{MachineRate, PriceCatalog, RunCost};
+use crate::debug_log::Logger;
 use crate::event_recorder::{EventRecorder, EventType};
 use crate::events::{send_end_run_event, send_start_run_event};
+use crate::file_cache::FileCache;
 use crate::file_watcher::FileWatcher;
+use crate::lineage::{LineageGraph, RunLineages};
 use crate::metrics::SystemMetricsCollector;
 use crate::process_watcher::ProcessWatcher;
+use crate::qc_metrics::QcMetricsCollector;
+use crate::run_assignment::{assign_run, match_run, ProcessOrigin, RunScope};
+use crate::run_lifecycle::{snapshot_processes, ProcessSnapshot, RunBoundary, RunDetector};
+use crate::run_report::{write_report_file, HostInfo, RunReport, UploadedFile};
+use crate::slurm::{
+    detect_job, JobBoundary, JobUsage, SlurmJob, SlurmJobTracker, PROC_DIRECTORY, SCONTROL,
+};
 use crate::stdout::StdoutWatcher;
 use crate::submit_batched_data::submit_batched_data;
 use crate::syslog::SyslogWatcher;
-use crate::FILE_CACHE_DIR;
+use crate::timeline::Timeline;
+use crate::upload::upload_queue::{UploadEntry, UploadQueue};
+use crate::workflow_engines::nextflow::NextflowWatcher;
+use crate::workflow_engines::snakemake::SnakemakeWatcher;
+use crate::workflow_engines::{PipelineMetadata, WorkflowEngine};
 use crate::{config_manager::Config, process_watcher::ShortLivedProcessLog};
 use anyhow::Result;
 use chrono::{DateTime, TimeDelta, Utc};
-use std::ops::Sub;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use sysinfo::{Pid, System};
@@ -19,24 +37,128 @@ use tokio::sync::RwLock;
 
 #[derive(Clone)]
 pub struct RunMetadata {
-    pub last_interaction: Instant,
     pub name: String,
     pub id: String,
     pub service_name: String,
     pub parent_pid: Option<Pid>,
     pub start_time: DateTime<Utc>,
+    /// User whose processes the run tracks, `None` to track everyone's
+    pub owner_uid: Option<u32>,
+    /// Processes running below this directory belong to the run
+    pub working_directory: Option<String>,
+    /// The workflow engine launcher behind the run, once one is seen
+    pub pipeline: Option<PipelineMetadata>,
+    /// The SLURM job the run is part of
+    pub slurm_job: Option<SlurmJob>,
+    /// Set for runs of a whole SLURM job, which track all of the job's processes
+    pub slurm_job_id: Option<String>,
+    /// Login session the run was started from, whose processes it tracks too
+    pub session_id: Option<Pid>,
 }
 
-const RUN_COMPLICATED_PROCESS_IDENTIFICATION: bool = false;
-const WAIT_FOR_PROCESS_BEFORE_NEW_RUN: bool = false;
+impl RunMetadata {
+    fn get_scope(&self) -> RunScope {
+        RunScope {
+            run_id: self.id.clone(),
+            owner_uid: self.owner_uid,
+            parent_pid: self.parent_pid,
+            working_directory: self.working_directory.clone(),
+            slurm_job_id: self.slurm_job_id.clone(),
+            session_id: self.session_id,
+            start_time: self.start_time,
+        }
+    }
+}
+
+/// Where a run was started from, which decides the processes it tracks
+#[derive(Default)]
+pub struct RunOrigin {
+    pub owner_uid: Option<u32>,
+    pub parent_pid: Option<Pid>,
+    pub working_directory: Option<String>,
+    pub pipeline: Option<PipelineMetadata>,
+    /// The run tracks all processes of this SLURM job
+    pub slurm_job: Option<SlurmJob>,
+    pub session_id: Option<Pid>,
+}
+
+/// Ended runs kept for `tracer report`, `tracer timeline` and `tracer lineage`
+const MAX_ENDED_RUNS: usize = 16;
+
+/// What's left of a run once it ended
+pub struct EndedRun {
+    pub owner_uid: Option<u32>,
+    pub report: RunReport,
+    pub timeline: Timeline,
+    pub lineage: LineageGraph,
+}
 
 pub type LinesBufferArc = Arc<RwLock<Vec<String>>>;
 
+/// The detector to use with this config, keeping the state of the current one
+fn get_run_detector(current: Option<RunDetector>, config: &Config) -> Option<RunDetector> {
+    if !config.automatic_runs {
+        return None;
+    }
+    let inactivity_timeout = Duration::from_millis(config.new_run_pause_ms);
+    Some(match current {
+        Some(mut run_detector) => {
+            run_detector.reload_config(config.run_root_processes.clone(), inactivity_timeout);
+            run_detector
+        }
+        None => RunDetector::new(config.run_root_processes.clone(), inactivity_timeout),
+    })
+}
+
+fn get_slurm_job_tracker(
+    current: Option<SlurmJobTracker>,
+    config: &Config,
+) -> Option<SlurmJobTracker> {
+    if !config.slurm_job_runs {
+        return None;
+    }
+    Some(current.unwrap_or_else(|| {
+        SlurmJobTracker::new(PathBuf::from(PROC_DIRECTORY), SCONTROL.to_string())
+    }))
+}
+
+/// What the machine costs per hour, with the user's price catalog when it can be read
+fn get_cost_rate(
+    config: &Config,
+    instance_metadata: Option<&InstanceMetadata>,
+    system: &System,
+) -> Option<MachineRate> {
+    let catalog = PriceCatalog::load(config.price_catalog.as_deref().map(Path::new))
+        .unwrap_or_else(|error| {
+            eprintln!("{:#}, estimating costs with the bundled catalog", error);
+            PriceCatalog::bundled()
+        });
+    catalog.get_rate(
+        instance_metadata,
+        system.cpus().len(),
+        system.total_memory(),
+    )
+}
+
+fn display_requested(requested: Option<impl ToString>) -> String {
+    requested.map_or_else(|| "unknown".to_string(), |requested| requested.to_string())
+}
+
 pub struct TracerClient {
     system: System,
     last_sent: Option<Instant>,
     interval: Duration,
-    last_interaction_new_run_duration: Duration,
+    /// Set when runs start and end with the pipeline's root process
+    run_detector: Option<RunDetector>,
+    /// Set when each SLURM job on the node gets a run of its own
+    slurm_job_tracker: Option<SlurmJobTracker>,
+    /// The allocation the daemon runs in, if any
+    slurm_job: Option<SlurmJob>,
+    /// Tool usage of the runs of SLURM jobs, compared with the job's request when they end
+    slurm_usage: HashMap<String, JobUsage>,
+    instance_metadata: Option<InstanceMetadata>,
+    /// What the machine costs, `None` when the price catalog can't price it
+    cost_rate: Option<MachineRate>,
     process_metrics_send_interval: Duration,
     last_file_size_change_time_delta: TimeDelta,
     pub logs: EventRecorder,
@@ -45,32 +167,64 @@ pub struct TracerClient {
     stdout_watcher: StdoutWatcher,
     metrics_collector: SystemMetricsCollector,
     file_watcher: FileWatcher,
+    file_cache: FileCache,
+    upload_queue: UploadQueue,
+    qc_metrics_collector: QcMetricsCollector,
+    nextflow_watcher: NextflowWatcher,
+    snakemake_watcher: SnakemakeWatcher,
+    lineage: RunLineages,
+    /// The last runs that ended, newest last
+    ended_runs: VecDeque<EndedRun>,
     workflow_directory: String,
+    run_report_format: String,
     api_key: String,
     service_url: String,
-    current_run: Option<RunMetadata>,
+    upload_max_file_size: u64,
+    runs: HashMap<String, RunMetadata>,
     syslog_lines_buffer: LinesBufferArc,
     stdout_lines_buffer: LinesBufferArc,
     stderr_lines_buffer: LinesBufferArc,
 }
 
 impl TracerClient {
-    pub async fn new(config: Config, workflow_directory: String) -> Result<TracerClient> {
+    pub async fn new(
+        config: Config,
+        workflow_directory: String,
+        cache_dir: &Path,
+    ) -> Result<TracerClient> {
         let service_url = config.service_url.clone();
+        let run_detector = get_run_detector(None, &config);
+        let slurm_job_tracker = get_slurm_job_tracker(None, &config);
+        let mut process_watcher = ProcessWatcher::new(config.targets.clone());
+        process_watcher.reload_environment_variables(config.environment_variables.clone());
+        let system = System::new_all();
+        let instance_metadata = get_instance_metadata().await;
+        let cost_rate = get_cost_rate(&config, instance_metadata.as_ref(), &system);
+        process_watcher.set_cost_rate(cost_rate.clone());
 
         println!("Initializing TracerClient with API Key: {}", config.api_key);
         println!("Service URL: {}", service_url);
 
-        let file_watcher = FileWatcher::new();
+        let file_watcher = FileWatcher::new(config.compute_md5_checksums);
 
-        file_watcher.prepare_cache_directory(FILE_CACHE_DIR)?;
+        let cache_dir = cache_dir.to_str().unwrap();
+        file_watcher.prepare_cache_directory(cache_dir)?;
+        let file_cache = FileCache::new(cache_dir, config.file_cache_max_bytes)?;
+        let mut upload_queue = UploadQueue::load(&file_cache, config.upload_workers)?;
+        upload_queue.set_compute_md5_checksums(config.compute_md5_checksums);
 
         Ok(TracerClient {
             // fixed values
             api_key: config.api_key,
             service_url,
+            upload_max_file_size: config.upload_max_file_size_bytes,
             interval: Duration::from_millis(config.process_polling_interval_ms),
-            last_interaction_new_run_duration: Duration::from_millis(config.new_run_pause_ms),
+            run_detector,
+            slurm_job_tracker,
+            slurm_job: detect_job(SCONTROL).await,
+            slurm_usage: HashMap::new(),
+            instance_metadata,
+            cost_rate,
             process_metrics_send_interval: Duration::from_millis(
                 config.process_metrics_send_interval_ms,
             ),
@@ -78,19 +232,27 @@ impl TracerClient {
                 config.file_size_not_changing_period_ms as i64,
             ),
             // updated values
-            system: System::new_all(),
+            system,
             last_sent: None,
-            current_run: None,
+            runs: HashMap::new(),
             syslog_watcher: SyslogWatcher::new(),
             stdout_watcher: StdoutWatcher::new(),
             // Sub mannagers
             logs: EventRecorder::new(),
             file_watcher,
+            file_cache,
+            upload_queue,
+            qc_metrics_collector: QcMetricsCollector::new(),
+            nextflow_watcher: NextflowWatcher::new(),
+            snakemake_watcher: SnakemakeWatcher::new(),
+            lineage: RunLineages::new(),
+            ended_runs: VecDeque::new(),
             workflow_directory,
+            run_report_format: config.run_report_format,
             syslog_lines_buffer: Arc::new(RwLock::new(Vec::new())),
             stdout_lines_buffer: Arc::new(RwLock::new(Vec::new())),
             stderr_lines_buffer: Arc::new(RwLock::new(Vec::new())),
-            process_watcher: ProcessWatcher::new(config.targets),
+            process_watcher,
             metrics_collector: SystemMetricsCollector::new(),
         })
     }
@@ -98,16 +260,59 @@ impl TracerClient {
     pub fn reload_config_file(&mut self, config: &Config) {
         self.api_key.clone_from(&config.api_key);
         self.service_url.clone_from(&config.service_url);
+        self.upload_max_file_size = config.upload_max_file_size_bytes;
+        self.upload_queue.set_max_workers(config.upload_workers);
+        self.upload_queue
+            .set_compute_md5_checksums(config.compute_md5_checksums);
+        self.run_report_format.clone_from(&config.run_report_format);
         self.interval = Duration::from_millis(config.process_polling_interval_ms);
         self.process_watcher.reload_targets(config.targets.clone());
+        self.process_watcher
+            .reload_environment_variables(config.environment_variables.clone());
+        self.run_detector = get_run_detector(self.run_detector.take(), config);
+        self.slurm_job_tracker = get_slurm_job_tracker(self.slurm_job_tracker.take(), config);
+        self.cost_rate = get_cost_rate(config, self.instance_metadata.as_ref(), &self.system);
+        self.process_watcher.set_cost_rate(self.cost_rate.clone());
     }
 
     pub fn fill_logs_with_short_lived_process(
         &mut self,
         short_lived_process_log: ShortLivedProcessLog,
     ) -> Result<()> {
-        self.process_watcher
-            .fill_logs_with_short_lived_process(short_lived_process_log, &mut self.logs)?;
+        // The process has usually exited by now, so its parent is taken from the log
+        let properties = &short_lived_process_log.properties;
+        let pid = properties.tool_pid.parse::<usize>().ok().map(Pid::from);
+        let parent_pid = properties
+            .tool_parent_pid
+            .parse::<usize>()
+            .ok()
+            .map(Pid::from);
+        let origin = ProcessOrigin {
+            pid,
+            uid: properties.process_uid,
+            run_id: None,
+            working_directory: None,
+            slurm_job_id: None,
+            session_id: None,
+        };
+
+        let runs = self.get_run_scopes();
+        let run_id = assign_run(&runs, &origin, |process| {
+            if Some(process) == pid {
+                parent_pid
+            } else {
+                self.system
+                    .process(process)
+                    .and_then(|process| process.parent())
+            }
+        })
+        .map(|run| run.run_id.clone());
+
+        self.process_watcher.fill_logs_with_short_lived_process(
+            short_lived_process_log,
+            &mut self.logs,
+            run_id,
+        )?;
         Ok(())
     }
 
@@ -135,90 +340,542 @@ impl TracerClient {
         .await
     }
 
-    pub fn get_run_metadata(&self) -> Option<RunMetadata> {
-        self.current_run.clone()
+    pub fn get_run_metadata(&self, run_id: &str) -> Option<RunMetadata> {
+        self.runs.get(run_id).cloned()
     }
 
-    pub async fn run_cleanup(&mut self) -> Result<()> {
-        if let Some(run) = self.current_run.as_mut() {
-            if !RUN_COMPLICATED_PROCESS_IDENTIFICATION {
-                return Ok(());
+    /// Active runs, oldest first
+    pub fn get_runs(&self) -> Vec<RunMetadata> {
+        let mut runs: Vec<RunMetadata> = self.runs.values().cloned().collect();
+        runs.sort_by_key(|run| run.start_time);
+        runs
+    }
+
+    /// Estimated cost of the run so far
+    pub fn get_run_cost(&self, run_id: &str) -> Option<RunCost> {
+        let run = self.runs.get(run_id)?;
+        let duration = (Utc::now() - run.start_time).to_std().unwrap_or_default();
+        Some(
+            self.cost_rate
+                .as_ref()?
+                .run_cost(duration, self.process_watcher.get_tools_cost(run_id)),
+        )
+    }
+
+    fn get_finished_run_attributes(&self, run_id: &str) -> Result<Option<Value>> {
+        let Some(run_cost) = self.get_run_cost(run_id) else {
+            return Ok(None);
+        };
+        Ok(Some(
+            json!({ "estimated_cost": serde_json::to_value(run_cost)? }),
+        ))
+    }
+
+    /// Report of an active run, from the events recorded for it so far
+    pub fn build_run_report(
+        &self,
+        run_id: &str,
+        end_time: Option<DateTime<Utc>>,
+    ) -> Option<RunReport> {
+        let run = self.runs.get(run_id)?;
+        let run_events = self.logs.get_run_events(run_id);
+        let uploads = self
+            .upload_queue
+            .get_entries()
+            .into_iter()
+            .filter(|entry| {
+                entry.run_id.as_deref() == Some(run_id)
+                    && (entry.owner_uid.is_none() || entry.owner_uid == run.owner_uid)
+            })
+            .map(|entry| UploadedFile {
+                file_name: entry.file_name,
+                source_path: entry.source_path,
+                status: entry.status,
+            })
+            .collect();
+
+        Some(RunReport {
+            run_id: run.id.clone(),
+            run_name: run.name.clone(),
+            service_name: run.service_name.clone(),
+            start_time: run.start_time,
+            end_time,
+            pipeline: run.pipeline.clone(),
+            slurm_job: run.slurm_job.clone(),
+            estimated_cost: self.get_run_cost(run_id),
+            tools: run_events
+                .map(|run_events| run_events.get_tools())
+                .unwrap_or_default(),
+            syslog_errors: run_events
+                .map(|run_events| run_events.get_syslog_errors().to_vec())
+                .unwrap_or_default(),
+            uploads,
+            host: HostInfo::gather(&self.system, self.instance_metadata.clone()),
+            generated_at: Utc::now(),
+        })
+    }
+
+    /// Timeline of an active run, up to now
+    pub fn build_run_timeline(&self, run_id: &str, end_time: DateTime<Utc>) -> Option<Timeline> {
+        let run = self.runs.get(run_id)?;
+        let run_events = self.logs.get_run_events(run_id);
+        Some(match run_events {
+            Some(run_events) => {
+                run_events.get_timeline(&run.id, &run.name, run.start_time, end_time)
             }
-            if run.last_interaction.elapsed() > self.last_interaction_new_run_duration {
-                self.logs.record_event(
-                    EventType::FinishedRun,
-                    "Run ended due to inactivity".to_string(),
-                    None,
-                    None,
-                );
-                self.current_run = None;
-            } else if run.parent_pid.is_none() && !self.process_watcher.is_empty() {
-                run.parent_pid = self.process_watcher.get_parent_pid(Some(run.start_time));
-            } else if run.parent_pid.is_some() {
-                let parent_pid = run.parent_pid.unwrap();
-                if !self
-                    .process_watcher
-                    .is_process_alive(&self.system, parent_pid)
-                {
-                    self.logs.record_event(
-                        EventType::FinishedRun,
-                        "Run ended due to parent process termination".to_string(),
+            None => Timeline {
+                run_id: run.id.clone(),
+                run_name: run.name.clone(),
+                start_time: run.start_time,
+                end_time,
+                processes: vec![],
+                system_samples: vec![],
+            },
+        })
+    }
+
+    /// An ended run, the given one or the last one the user owned
+    pub fn get_ended_run(&self, run_id: Option<&str>, uid: Option<u32>) -> Option<&EndedRun> {
+        self.ended_runs
+            .iter()
+            .rev()
+            .filter(|ended_run| uid.is_none() || ended_run.owner_uid == uid)
+            .find(|ended_run| run_id.is_none_or(|run_id| ended_run.report.run_id == run_id))
+    }
+
+    /// Writes the report of an ending run next to its pipeline and keeps it
+    /// with its timeline and lineage for `tracer report`, `tracer timeline`
+    /// and `tracer lineage`
+    fn save_run_report(&mut self, run_id: &str) {
+        let end_time = Utc::now();
+        let (Some(report), Some(timeline)) = (
+            self.build_run_report(run_id, Some(end_time)),
+            self.build_run_timeline(run_id, end_time),
+        ) else {
+            return;
+        };
+        let run = &self.runs[run_id];
+        let directory = run
+            .working_directory
+            .as_deref()
+            .unwrap_or(&self.workflow_directory);
+        let written = report.export(&self.run_report_format).and_then(|contents| {
+            write_report_file(
+                Path::new(directory),
+                &report.get_file_name(&self.run_report_format),
+                &contents,
+                run.owner_uid,
+            )
+        });
+        if let Err(error) = written {
+            eprintln!("Failed to write the report of run {}: {:#}", run_id, error);
+        }
+
+        if self.ended_runs.len() == MAX_ENDED_RUNS {
+            self.ended_runs.pop_front();
+        }
+        self.ended_runs.push_back(EndedRun {
+            owner_uid: run.owner_uid,
+            report,
+            timeline,
+            lineage: self.lineage.remove(run_id).unwrap_or_default(),
+        });
+        self.logs.take_run_events(run_id);
+    }
+
+    fn get_run_scopes(&self) -> Vec<RunScope> {
+        self.runs.values().map(RunMetadata::get_scope).collect()
+    }
+
+    /// Events not tied to a process go to the most recently started run
+    fn update_default_run(&mut self) {
+        let latest = self
+            .runs
+            .values()
+            .max_by_key(|run| run.start_time)
+            .map(|run| run.id.clone());
+        self.logs.set_default_run_id(latest);
+    }
+
+    /// Starts and ends runs as pipeline root processes come and go, when
+    /// automatic runs are enabled
+    pub async fn run_cleanup(&mut self) -> Result<()> {
+        if self.run_detector.is_none() && self.slurm_job_tracker.is_none() {
+            return Ok(());
+        }
+        let snapshot = snapshot_processes(&self.system);
+        self.detect_pipeline_runs(&snapshot).await?;
+        self.detect_slurm_job_runs(&snapshot).await
+    }
+
+    async fn detect_pipeline_runs(&mut self, snapshot: &ProcessSnapshot) -> Result<()> {
+        let Some(run_detector) = self.run_detector.as_mut() else {
+            return Ok(());
+        };
+        let boundaries = run_detector.detect(
+            snapshot,
+            &self.process_watcher.get_active_run_ids(),
+            Instant::now(),
+        );
+
+        let logger = Logger::new();
+        for boundary in boundaries {
+            match &boundary {
+                RunBoundary::Start {
+                    root_pid,
+                    root_name,
+                    root_start_time,
+                    owner_uid,
+                    working_directory,
+                    pipeline,
+                } => {
+                    let origin = RunOrigin {
+                        owner_uid: *owner_uid,
+                        parent_pid: Some(*root_pid),
+                        working_directory: working_directory.clone(),
+                        pipeline: pipeline.clone(),
+                        slurm_job: None,
+                        session_id: None,
+                    };
+                    let start_time = DateTime::from_timestamp(*root_start_time as i64, 0);
+                    let run_id = self.start_new_run(start_time, origin).await?;
+                    if let Some(run_detector) = self.run_detector.as_mut() {
+                        run_detector.register_run(run_id.clone(), &boundary, Instant::now());
+                    }
+
+                    let reason =
+                        format!("Run started by root process {} ({})", root_name, root_pid);
+                    logger
+                        .log(
+                            "tracer_client.rs//run_cleanup",
+                            Some(&json!({ "run_id": run_id, "decision": reason })),
+                        )
+                        .await;
+                    self.logs.record_run_event(
+                        Some(&run_id),
+                        EventType::NewRun,
+                        reason,
                         None,
                         None,
                     );
-                    self.current_run = None;
+                }
+                RunBoundary::End { run_id, reason } => {
+                    logger
+                        .log(
+                            "tracer_client.rs//run_cleanup",
+                            Some(&json!({ "run_id": run_id, "decision": reason })),
+                        )
+                        .await;
+                    self.stop_run(run_id, reason).await?;
                 }
             }
-        } else if !WAIT_FOR_PROCESS_BEFORE_NEW_RUN || !self.process_watcher.is_empty() {
-            let earliest_process_time = self.process_watcher.get_earliest_process_time();
-            self.start_new_run(Some(earliest_process_time.sub(Duration::from_millis(1))))
-                .await?;
         }
         Ok(())
     }
 
-    pub async fn start_new_run(&mut self, timestamp: Option<DateTime<Utc>>) -> Result<()> {
-        if self.current_run.is_some() {
-            self.stop_run().await?;
+    async fn detect_slurm_job_runs(&mut self, snapshot: &ProcessSnapshot) -> Result<()> {
+        let Some(slurm_job_tracker) = self.slurm_job_tracker.as_mut() else {
+            return Ok(());
+        };
+        let boundaries = slurm_job_tracker.detect(snapshot).await;
+
+        let logger = Logger::new();
+        for boundary in boundaries {
+            let (run_id, reason) = match boundary {
+                JobBoundary::Start { job, owner_uid } => {
+                    let job_id = job.job_id.clone();
+                    let origin = RunOrigin {
+                        owner_uid,
+                        slurm_job: Some(job),
+                        ..Default::default()
+                    };
+                    let run_id = self.start_new_run(None, origin).await?;
+                    if let Some(slurm_job_tracker) = self.slurm_job_tracker.as_mut() {
+                        slurm_job_tracker.register_run(job_id.clone(), run_id.clone());
+                    }
+                    let reason = format!("Run started for SLURM job {}", job_id);
+                    self.logs.record_run_event(
+                        Some(&run_id),
+                        EventType::NewRun,
+                        reason.clone(),
+                        None,
+                        None,
+                    );
+                    (run_id, reason)
+                }
+                JobBoundary::End { run_id, job_id } => {
+                    let reason = format!("Run ended with SLURM job {}", job_id);
+                    self.stop_run(&run_id, &reason).await?;
+                    (run_id, reason)
+                }
+            };
+            logger
+                .log(
+                    "tracer_client.rs//run_cleanup",
+                    Some(&json!({ "run_id": run_id, "decision": reason })),
+                )
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Whether the user may end or tag the run; `None` stands for an
+    /// administrator of the daemon, who may control any run
+    pub fn can_control_run(&self, run_id: &str, uid: Option<u32>) -> bool {
+        match (self.runs.get(run_id), uid) {
+            (_, None) | (None, _) => true,
+            (Some(run), Some(uid)) => run.owner_uid == Some(uid),
+        }
+    }
+
+    /// Finds the run a CLI invocation refers to: the one given explicitly, the
+    /// one started by an ancestor of the caller or in a parent directory, or
+    /// the only run the caller may control.
+    pub fn resolve_run(
+        &self,
+        run_id: Option<&str>,
+        caller_pid: Option<Pid>,
+        working_directory: Option<&str>,
+        uid: Option<u32>,
+    ) -> Result<Option<String>> {
+        if let Some(run_id) = run_id {
+            if !self.runs.contains_key(run_id) {
+                return Err(anyhow::anyhow!("No active run with id {}", run_id));
+            }
+            return Ok(Some(run_id.to_string()));
+        }
+
+        let runs: Vec<RunScope> = self
+            .get_run_scopes()
+            .into_iter()
+            .filter(|run| uid.is_none() || run.owner_uid == uid)
+            .collect();
+
+        let origin = ProcessOrigin {
+            pid: caller_pid,
+            uid,
+            run_id: None,
+            working_directory: working_directory.map(Path::new),
+            slurm_job_id: None,
+            session_id: None,
+        };
+        if let Some(run) = match_run(&runs, &origin, |pid| {
+            self.system
+                .process(pid)
+                .and_then(|process| process.parent())
+        }) {
+            return Ok(Some(run.run_id.clone()));
         }
 
+        match runs.len() {
+            0 => Ok(None),
+            1 => Ok(Some(runs[0].run_id.clone())),
+            _ => Err(anyhow::anyhow!(
+                "Several runs are active, choose one with --run: {}",
+                runs.iter()
+                    .map(|run| run.run_id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+
+    /// Starts a run alongside the active ones, returning its id
+    pub async fn start_new_run(
+        &mut self,
+        timestamp: Option<DateTime<Utc>>,
+        origin: RunOrigin,
+    ) -> Result<String> {
         let result = send_start_run_event(&self.service_url, &self.api_key, &self.system).await?;
 
-        self.current_run = Some(RunMetadata {
-            last_interaction: Instant::now(),
-            parent_pid: None,
-            start_time: timestamp.unwrap_or_else(Utc::now),
-            name: result.run_name,
-            id: result.run_id,
-            service_name: result.service_name,
-        });
+        let run_id = result.run_id.clone();
+        let pipeline = origin.pipeline.clone();
+        let slurm_job_id = origin.slurm_job.as_ref().map(|job| job.job_id.clone());
+        let slurm_job = origin.slurm_job.or_else(|| self.slurm_job.clone());
+        self.runs.insert(
+            result.run_id.clone(),
+            RunMetadata {
+                parent_pid: origin.parent_pid,
+                start_time: timestamp.unwrap_or_else(Utc::now),
+                name: result.run_name,
+                id: result.run_id,
+                service_name: result.service_name,
+                owner_uid: origin.owner_uid,
+                working_directory: origin.working_directory,
+                pipeline: None,
+                slurm_job: slurm_job.clone(),
+                slurm_job_id,
+                session_id: origin.session_id,
+            },
+        );
+        self.update_default_run();
+        if let Some(slurm_job) = slurm_job {
+            self.logs.record_run_event(
+                Some(&run_id),
+                EventType::SlurmJob,
+                format!("[{}] SLURM job: {}", Utc::now(), slurm_job.job_id),
+                Some(serde_json::to_value(&slurm_job)?),
+                None,
+            );
+            self.slurm_usage.insert(run_id.clone(), JobUsage::default());
+        }
+        if let Some(pipeline) = pipeline {
+            self.attach_pipeline(&run_id, pipeline)?;
+        }
+
+        Ok(run_id)
+    }
+
+    /// Records what the run's workflow engine runs, the first time it's seen
+    fn attach_pipeline(&mut self, run_id: &str, pipeline: PipelineMetadata) -> Result<()> {
+        let Some(run) = self.runs.get_mut(run_id) else {
+            return Ok(());
+        };
+        if run.pipeline.is_some() {
+            return Ok(());
+        }
 
+        self.logs.record_run_event(
+            Some(run_id),
+            EventType::PipelineMetadata,
+            format!(
+                "[{}] {} pipeline: {}",
+                Utc::now(),
+                pipeline.engine.as_str(),
+                pipeline.pipeline.as_deref().unwrap_or("unknown")
+            ),
+            Some(serde_json::to_value(&pipeline)?),
+            None,
+        );
+        match (pipeline.engine, &pipeline.launch_directory) {
+            (WorkflowEngine::Nextflow, Some(launch_directory)) => self
+                .nextflow_watcher
+                .follow_log(Some(run_id.to_string()), Path::new(launch_directory)),
+            (WorkflowEngine::Snakemake, Some(launch_directory)) => {
+                self.snakemake_watcher.follow_logs(
+                    Some(run_id.to_string()),
+                    Path::new(launch_directory),
+                    run.start_time.into(),
+                )
+            }
+            _ => {}
+        }
+        run.pipeline = Some(pipeline);
         Ok(())
     }
 
-    pub async fn stop_run(&mut self) -> Result<()> {
-        if self.current_run.is_some() {
-            send_end_run_event(&self.service_url, &self.api_key).await?;
-            self.current_run = None;
+    /// Ends the run, recording why and its estimated cost
+    pub async fn stop_run(&mut self, run_id: &str, reason: &str) -> Result<()> {
+        if self.runs.contains_key(run_id) {
+            let attributes = self.get_finished_run_attributes(run_id)?;
+            self.logs.record_run_event(
+                Some(run_id),
+                EventType::FinishedRun,
+                reason.to_string(),
+                attributes,
+                None,
+            );
+            // Tasks that finished since the last poll still belong to the run
+            self.poll_workflow_engines()?;
+            self.nextflow_watcher.forget_run(run_id);
+            self.snakemake_watcher.forget_run(run_id);
+            self.record_slurm_resource_usage(run_id)?;
+            self.save_run_report(run_id);
+            send_end_run_event(&self.service_url, &self.api_key, run_id).await?;
+            self.runs.remove(run_id);
+            self.process_watcher.forget_run(run_id);
+            if let Some(run_detector) = self.run_detector.as_mut() {
+                run_detector.forget_run(run_id);
+            }
+            if let Some(slurm_job_tracker) = self.slurm_job_tracker.as_mut() {
+                slurm_job_tracker.forget_run(run_id);
+            }
+            self.update_default_run();
         }
         Ok(())
     }
 
+    /// Compares what the run's SLURM job requested with the peak usage of its tools
+    fn record_slurm_resource_usage(&mut self, run_id: &str) -> Result<()> {
+        let Some(usage) = self.slurm_usage.remove(run_id) else {
+            return Ok(());
+        };
+        let Some(slurm_job) = self.runs.get(run_id).and_then(|run| run.slurm_job.as_ref()) else {
+            return Ok(());
+        };
+
+        let comparison = usage.compare(slurm_job);
+        self.logs.record_run_event(
+            Some(run_id),
+            EventType::SlurmResourceUsage,
+            format!(
+                "[{}] SLURM job {} used {:.1} of {} CPUs and {} of {} MB",
+                Utc::now(),
+                comparison.job_id,
+                comparison.peak_cpus,
+                display_requested(comparison.requested_cpus),
+                comparison.peak_memory_mb,
+                display_requested(comparison.requested_memory_mb),
+            ),
+            Some(serde_json::to_value(&comparison)?),
+            None,
+        );
+        Ok(())
+    }
+
     /// These functions require logs and the system
     pub fn poll_processes(&mut self) -> Result<()> {
+        let runs = self.get_run_scopes();
         self.process_watcher.poll_processes(
             &mut self.system,
             &mut self.logs,
             &self.file_watcher,
+            &mut self.lineage,
+            &runs,
         )?;
 
-        if self.current_run.is_some() && !self.process_watcher.is_empty() {
-            self.current_run.as_mut().unwrap().last_interaction = Instant::now();
+        for (run_id, pipeline) in self.process_watcher.take_detected_pipelines() {
+            self.attach_pipeline(&run_id, pipeline)?;
+        }
+        for (run_id, task) in self.process_watcher.take_nextflow_tasks() {
+            self.nextflow_watcher.track_task(run_id, task);
+        }
+        for tool in self.process_watcher.take_new_tools() {
+            self.snakemake_watcher.track_tool(tool);
         }
         Ok(())
     }
 
+    /// Task transitions reported by the workflow engines of the active runs
+    pub fn poll_workflow_engines(&mut self) -> Result<()> {
+        self.nextflow_watcher.poll(&mut self.logs)?;
+        self.snakemake_watcher.poll(&mut self.logs)
+    }
+
     pub async fn poll_process_metrics(&mut self) -> Result<()> {
+        if !self.slurm_usage.is_empty() {
+            let mut run_samples: HashMap<String, Vec<(String, f32, u64)>> = HashMap::new();
+            for (run_id, tool_name, cpu_usage, memory) in
+                self.process_watcher.get_tool_usage(&self.system)
+            {
+                if let Some(run_id) = run_id {
+                    run_samples
+                        .entry(run_id)
+                        .or_default()
+                        .push((tool_name, cpu_usage, memory));
+                }
+            }
+            for (run_id, usage) in self.slurm_usage.iter_mut() {
+                let sample = run_samples
+                    .get(run_id)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                usage.record_sample(sample.iter().map(|(tool_name, cpu_usage, memory)| {
+                    (tool_name.as_str(), *cpu_usage, *memory)
+                }));
+            }
+        }
+
         self.process_watcher.poll_process_metrics(
             &self.system,
             &mut self.logs,
@@ -228,24 +885,73 @@ impl TracerClient {
     }
 
     pub async fn remove_completed_processes(&mut self) -> Result<()> {
-        self.process_watcher
-            .remove_completed_processes(&mut self.system, &mut self.logs)?;
+        self.process_watcher.remove_completed_processes(
+            &mut self.system,
+            &mut self.logs,
+            &mut self.lineage,
+        )?;
         Ok(())
     }
 
     pub async fn poll_files(&mut self) -> Result<()> {
+        // Files of the workflow directory belong to the run working there, if
+        // it can be told apart from the others
+        let run_id = self
+            .resolve_run(None, None, Some(&self.workflow_directory), None)
+            .unwrap_or_default();
         self.file_watcher
             .poll_files(
-                &self.service_url,
-                &self.api_key,
                 &self.workflow_directory,
-                FILE_CACHE_DIR,
+                &self.file_cache,
+                self.last_file_size_change_time_delta,
+                &self.upload_queue,
+                run_id.as_deref(),
+            )
+            .await?;
+        self.upload_queue
+            .process(&self.service_url, &self.api_key, self.upload_max_file_size)?;
+        self.lineage
+            .link_outputs(self.file_watcher.get_all_files(), &mut self.logs)?;
+        self.qc_metrics_collector
+            .poll_files(
+                self.file_watcher.get_all_files(),
                 self.last_file_size_change_time_delta,
+                &mut self.logs,
             )
             .await?;
+
+        let checksums = self.file_watcher.take_new_checksums();
+        self.process_watcher.record_input_checksums(
+            &checksums,
+            self.file_watcher.get_all_files(),
+            &mut self.logs,
+        );
+        for (key, checksum) in checksums {
+            self.logs.record_event(
+                EventType::FileChecksum,
+                format!("[{}] File checksum: {}", Utc::now(), key.path),
+                Some(json!({
+                    "file_path": key.path,
+                    "file_size": key.size,
+                    "file_updated_at_timestamp": key.last_update.to_rfc3339(),
+                    "file_sha256": checksum.sha256,
+                    "file_md5": checksum.md5,
+                })),
+                None,
+            );
+        }
+
         Ok(())
     }
 
+    /// Lineage of an active run, empty until one of its tools started
+    pub fn export_lineage(&self, run_id: &str, format: &str) -> Result<String> {
+        match self.lineage.get(run_id) {
+            Some(lineage) => lineage.export(format),
+            None => LineageGraph::for_run(run_id).export(format),
+        }
+    }
+
     pub async fn poll_syslog(&mut self) -> Result<()> {
         self.syslog_watcher
             .poll_syslog(
@@ -283,4 +989,24 @@ impl TracerClient {
     pub fn get_api_key(&self) -> &str {
         &self.api_key
     }
+
+    /// Starts the queued uploads that are due
+    pub fn process_uploads(&self) -> Result<()> {
+        self.upload_queue
+            .process(&self.service_url, &self.api_key, self.upload_max_file_size)?;
+        Ok(())
+    }
+
+    pub fn get_upload_queue(&self) -> UploadQueue {
+        self.upload_queue.clone()
+    }
+
+    /// Uploads of the given user, or all of them for `None`
+    pub fn get_uploads(&self, owner_uid: Option<u32>) -> Vec<UploadEntry> {
+        self.upload_queue
+            .get_entries()
+            .into_iter()
+            .filter(|entry| owner_uid.is_none() || entry.owner_uid == owner_uid)
+            .collect()
+    }
 }
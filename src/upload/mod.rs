@@ -1,4 +1,6 @@
+pub mod multipart_upload;
 pub mod presigned_url_put;
+pub mod upload_queue;
 pub mod upload_to_signed_url;
 
 use anyhow::{Context, Result};
@@ -6,7 +8,9 @@ use presigned_url_put::request_presigned_url;
 use std::fs;
 use std::path::Path;
 
+use crate::checksum::{compute_file_checksum, FileChecksum};
 use crate::debug_log::Logger;
+use crate::upload::multipart_upload::{upload_file_multipart, MULTIPART_UPLOAD_THRESHOLD};
 use crate::upload::upload_to_signed_url::upload_file_to_signed_url_s3;
 
 pub async fn upload_from_file_path(
@@ -14,9 +18,10 @@ pub async fn upload_from_file_path(
     api_key: &str,
     file_path: &str,
     custom_file_name: Option<&str>,
+    checksum: Option<&FileChecksum>,
+    compute_md5: bool,
+    max_file_size: u64,
 ) -> Result<()> {
-    const MAX_FILE_SIZE: u64 = 5 * 1024 * 1024; // 5MB in bytes
-
     let logger = Logger::new();
 
     // Step #1: Check if the file exists
@@ -46,29 +51,57 @@ pub async fn upload_from_file_path(
         .log(&format!("Uploading file '{}'", file_name), None)
         .await;
 
-    // Step #3: Check if the file is under 5MB
+    // Step #3: Check if the file is under the configured limit
     let metadata = fs::metadata(file_path)?;
     let file_size = metadata.len();
-    if file_size > MAX_FILE_SIZE {
+    if file_size > max_file_size {
         println!(
-            "Warning: File size ({} bytes) exceeds 5MB limit.",
-            file_size
+            "Warning: File size ({} bytes) exceeds {} bytes limit.",
+            file_size, max_file_size
         );
-        return Err(anyhow::anyhow!("File size exceeds 5MB limit"));
+        return Err(anyhow::anyhow!(
+            "File size exceeds {} bytes limit",
+            max_file_size
+        ));
     }
 
     logger
         .log(&format!("File size: {} bytes", file_size), None)
         .await;
 
-    // Step #4: Request the upload URL
-    let signed_url = request_presigned_url(service_url, api_key, file_name).await?;
+    // Step #4: Attach the file digest, computing it when the caller had none cached
+    let checksum = match checksum {
+        Some(checksum) => checksum.clone(),
+        None => {
+            let file_path = file_path.to_string();
+            tokio::task::spawn_blocking(move || compute_file_checksum(&file_path, compute_md5))
+                .await??
+        }
+    };
+
+    logger
+        .log(&format!("File sha256: {}", checksum.sha256), None)
+        .await;
+
+    // Large files go up in parts, streamed from disk
+    if file_size > MULTIPART_UPLOAD_THRESHOLD {
+        upload_file_multipart(service_url, api_key, file_path, file_name, Some(&checksum)).await?;
+
+        logger.log("File uploaded successfully", None).await;
+        println!("File '{}' has been uploaded successfully.", file_name);
+
+        return Ok(());
+    }
+
+    // Step #5: Request the upload URL
+    let signed_url =
+        request_presigned_url(service_url, api_key, file_name, Some(&checksum)).await?;
 
     logger
         .log(&format!("Presigned URL: {}", signed_url), None)
         .await;
 
-    // Step #5: Upload the file
+    // Step #6: Upload the file
     upload_file_to_signed_url_s3(&signed_url, file_path).await?;
 
     logger.log("File uploaded successfully", None).await;
@@ -96,8 +129,16 @@ mod tests {
         // Ensure the file exists before running the test
         assert!(Path::new(file_path).exists(), "Test file does not exist");
 
-        let result =
-            upload_from_file_path(&config.service_url, &config.api_key, file_path, None).await;
+        let result = upload_from_file_path(
+            &config.service_url,
+            &config.api_key,
+            file_path,
+            None,
+            None,
+            false,
+            config.upload_max_file_size_bytes,
+        )
+        .await;
         assert!(result.is_ok(), "Upload failed: {:?}", result.err());
 
         Ok(())
@@ -114,8 +155,16 @@ mod tests {
             "Test file unexpectedly exists"
         );
 
-        let result =
-            upload_from_file_path(&config.service_url, &config.api_key, file_path, None).await;
+        let result = upload_from_file_path(
+            &config.service_url,
+            &config.api_key,
+            file_path,
+            None,
+            None,
+            false,
+            config.upload_max_file_size_bytes,
+        )
+        .await;
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("does not exist"));
 
@@ -127,15 +176,23 @@ mod tests {
         let file_path = "large_test_file.txt";
         let config = ConfigManager::load_default_config();
 
-        // Create a file larger than 5MB
+        // Create a file larger than the configured limit
         {
             let mut file = File::create(file_path)?;
             let large_content = vec![0u8; 6 * 1024 * 1024]; // 6MB
             file.write_all(&large_content)?;
         }
 
-        let result =
-            upload_from_file_path(&config.service_url, &config.api_key, file_path, None).await;
+        let result = upload_from_file_path(
+            &config.service_url,
+            &config.api_key,
+            file_path,
+            None,
+            None,
+            false,
+            5 * 1024 * 1024,
+        )
+        .await;
         // Clean up the large file
         fs::remove_file(file_path)?;
 
@@ -143,7 +200,63 @@ mod tests {
         assert!(result
             .unwrap_err()
             .to_string()
-            .contains("exceeds 5MB limit"));
+            .contains("exceeds 5242880 bytes limit"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_from_file_path_uses_multipart_above_threshold() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(&vec![0u8; 6 * 1024 * 1024])?;
+
+        let create = server
+            .mock("POST", "/upload/multipart/create")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "fileName".into(),
+                "large.bam".into(),
+            ))
+            .with_body(
+                serde_json::json!({
+                    "uploadId": "upload-1",
+                    "partUrls": [format!("{}/parts/1", server.url())],
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let part = server
+            .mock("PUT", "/parts/1")
+            .with_header("ETag", "\"etag-1\"")
+            .create_async()
+            .await;
+        let complete = server
+            .mock("POST", "/upload/multipart/complete")
+            .create_async()
+            .await;
+        let presigned_put = server
+            .mock("POST", "/upload/presigned-put")
+            .match_query(mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        upload_from_file_path(
+            &server.url(),
+            "test-api-key",
+            file.path().to_str().unwrap(),
+            Some("large.bam"),
+            None,
+            false,
+            10 * 1024 * 1024,
+        )
+        .await?;
+
+        create.assert_async().await;
+        part.assert_async().await;
+        complete.assert_async().await;
+        presigned_put.assert_async().await;
 
         Ok(())
     }
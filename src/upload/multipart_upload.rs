@@ -0,0 +1,417 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use log::{debug, error, info};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use url::Url;
+
+use crate::{checksum::FileChecksum, debug_log::Logger, http_client::send_http_body};
+
+/// Files above this size are uploaded in parts; it is also the minimum part size S3 accepts
+pub const MULTIPART_UPLOAD_THRESHOLD: u64 = 5 * 1024 * 1024;
+const MULTIPART_PART_SIZE: u64 = 8 * 1024 * 1024;
+const MAX_PART_COUNT: u64 = 10_000;
+const MAX_PART_ATTEMPTS: u32 = 3;
+const PART_RETRY_DELAY_MS: u64 = 500;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MultipartUploadStart {
+    upload_id: String,
+    part_urls: Vec<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletedPart {
+    pub part_number: u64,
+    pub e_tag: String,
+}
+
+/// Parts are 8MiB unless that would need more than the 10,000 parts S3 allows
+pub fn get_part_size(file_size: u64) -> u64 {
+    MULTIPART_PART_SIZE.max(file_size.div_ceil(MAX_PART_COUNT))
+}
+
+pub fn get_part_count(file_size: u64) -> u64 {
+    file_size.div_ceil(get_part_size(file_size)).max(1)
+}
+
+async fn create_multipart_upload(
+    service_url: &str,
+    api_key: &str,
+    file_name: &str,
+    file_size: u64,
+    checksum: Option<&FileChecksum>,
+) -> Result<MultipartUploadStart> {
+    let mut url = Url::parse(&format!("{}/upload/multipart/create", service_url))
+        .context("Failed to parse service URL")?;
+    url.query_pairs_mut()
+        .append_pair("fileName", file_name)
+        .append_pair("fileSize", &file_size.to_string())
+        .append_pair("partCount", &get_part_count(file_size).to_string());
+
+    if let Some(checksum) = checksum {
+        url.query_pairs_mut()
+            .append_pair("sha256", &checksum.sha256);
+        if let Some(md5) = &checksum.md5 {
+            url.query_pairs_mut().append_pair("md5", md5);
+        }
+    }
+
+    let (status, response_text) = send_http_body(url.as_str(), api_key, &json!({})).await?;
+
+    if !(200..300).contains(&status) {
+        return Err(anyhow::anyhow!(
+            "Failed to start multipart upload. Status: {}, Response: {}",
+            status,
+            response_text
+        ));
+    }
+
+    let upload: MultipartUploadStart =
+        serde_json::from_str(&response_text).context("Failed to parse response JSON")?;
+
+    if upload.part_urls.len() as u64 != get_part_count(file_size) {
+        return Err(anyhow::anyhow!(
+            "Expected {} part URLs, received {}",
+            get_part_count(file_size),
+            upload.part_urls.len()
+        ));
+    }
+
+    Ok(upload)
+}
+
+async fn finish_multipart_upload(
+    service_url: &str,
+    api_key: &str,
+    action: &str,
+    body: &serde_json::Value,
+) -> Result<()> {
+    let url = format!("{}/upload/multipart/{}", service_url, action);
+    let (status, response_text) = send_http_body(&url, api_key, body).await?;
+
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Failed to {} multipart upload. Status: {}, Response: {}",
+            action,
+            status,
+            response_text
+        ))
+    }
+}
+
+async fn upload_part(client: &Client, part_url: &str, contents: Bytes) -> Result<String> {
+    let response = client
+        .put(part_url)
+        .body(contents)
+        .header("Content-Type", "application/octet-stream")
+        .send()
+        .await
+        .context("Failed to send part")?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "Part upload failed with status: {}",
+            status
+        ));
+    }
+
+    let e_tag = response
+        .headers()
+        .get("ETag")
+        .context("Part upload response has no ETag")?
+        .to_str()?
+        .to_string();
+
+    Ok(e_tag)
+}
+
+async fn upload_part_with_retries(
+    client: &Client,
+    part_url: &str,
+    part_number: u64,
+    contents: Bytes,
+) -> Result<CompletedPart> {
+    let mut attempt = 1;
+    loop {
+        // Cloning the part only takes another reference to its buffer
+        match upload_part(client, part_url, contents.clone()).await {
+            Ok(e_tag) => return Ok(CompletedPart { part_number, e_tag }),
+            Err(e) if attempt < MAX_PART_ATTEMPTS => {
+                error!(
+                    "Part {} failed (attempt {}/{}): {}",
+                    part_number, attempt, MAX_PART_ATTEMPTS, e
+                );
+                tokio::time::sleep(Duration::from_millis(PART_RETRY_DELAY_MS * attempt as u64))
+                    .await;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e.context(format!(
+                    "Part {} failed after {} attempts",
+                    part_number, MAX_PART_ATTEMPTS
+                )))
+            }
+        }
+    }
+}
+
+/// Streams the file from disk one part at a time, so memory use stays at a
+/// single part no matter how large the file is.
+async fn upload_parts(
+    file_path: &str,
+    part_urls: &[String],
+    file_size: u64,
+) -> Result<Vec<CompletedPart>> {
+    let client = Client::new();
+    let mut file = File::open(file_path).await?;
+    let part_size = get_part_size(file_size);
+    let mut parts = vec![];
+
+    for (index, part_url) in part_urls.iter().enumerate() {
+        let part_number = index as u64 + 1;
+        let offset = index as u64 * part_size;
+        let length = part_size.min(file_size - offset);
+
+        let mut contents = vec![0; length as usize];
+        file.read_exact(&mut contents).await?;
+
+        debug!("Uploading part {} ({} bytes)", part_number, length);
+        parts.push(
+            upload_part_with_retries(&client, part_url, part_number, Bytes::from(contents)).await?,
+        );
+    }
+
+    Ok(parts)
+}
+
+pub async fn upload_file_multipart(
+    service_url: &str,
+    api_key: &str,
+    file_path: &str,
+    file_name: &str,
+    checksum: Option<&FileChecksum>,
+) -> Result<()> {
+    let logger = Logger::new();
+    let file_size = tokio::fs::metadata(file_path).await?.len();
+
+    info!("Starting multipart upload of {} bytes", file_size);
+
+    let upload =
+        create_multipart_upload(service_url, api_key, file_name, file_size, checksum).await?;
+
+    logger
+        .log(
+            &format!(
+                "Multipart upload {} started with {} parts",
+                upload.upload_id,
+                upload.part_urls.len()
+            ),
+            None,
+        )
+        .await;
+
+    match upload_parts(file_path, &upload.part_urls, file_size).await {
+        Ok(parts) => {
+            finish_multipart_upload(
+                service_url,
+                api_key,
+                "complete",
+                &json!({
+                    "uploadId": upload.upload_id,
+                    "fileName": file_name,
+                    "parts": parts,
+                }),
+            )
+            .await?;
+
+            logger
+                .log(
+                    &format!("Multipart upload {} completed", upload.upload_id),
+                    None,
+                )
+                .await;
+
+            Ok(())
+        }
+        Err(e) => {
+            logger
+                .log(
+                    &format!("Multipart upload {} failed: {}", upload.upload_id, e),
+                    None,
+                )
+                .await;
+
+            // Don't leave the incomplete parts behind; the part error is the one worth reporting
+            let _ = finish_multipart_upload(
+                service_url,
+                api_key,
+                "abort",
+                &json!({
+                    "uploadId": upload.upload_id,
+                    "fileName": file_name,
+                }),
+            )
+            .await;
+
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+    use std::io::Write;
+
+    fn create_test_file(size: usize) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&vec![7u8; size]).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_get_part_count() {
+        assert_eq!(get_part_count(0), 1);
+        assert_eq!(get_part_count(MULTIPART_PART_SIZE), 1);
+        assert_eq!(get_part_count(MULTIPART_PART_SIZE + 1), 2);
+        assert_eq!(get_part_count(3 * MULTIPART_PART_SIZE), 3);
+
+        let huge_file = 200 * 1024 * 1024 * 1024;
+        assert!(get_part_size(huge_file) > MULTIPART_PART_SIZE);
+        assert!(get_part_count(huge_file) <= MAX_PART_COUNT);
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_multipart() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let file_size = MULTIPART_PART_SIZE as usize + 1024;
+        let file = create_test_file(file_size);
+
+        let create = server
+            .mock("POST", "/upload/multipart/create")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("fileName".into(), "big.bam".into()),
+                Matcher::UrlEncoded("partCount".into(), "2".into()),
+            ]))
+            .with_body(
+                json!({
+                    "uploadId": "upload-1",
+                    "partUrls": [
+                        format!("{}/parts/1", server.url()),
+                        format!("{}/parts/2", server.url()),
+                    ],
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        // The first attempt at part 1 fails and has to be retried
+        let failed_part = server
+            .mock("PUT", "/parts/1")
+            .with_status(500)
+            .expect(1)
+            .create_async()
+            .await;
+        let part_1 = server
+            .mock("PUT", "/parts/1")
+            .match_body(Matcher::Exact("\u{7}".repeat(MULTIPART_PART_SIZE as usize)))
+            .with_header("ETag", "\"etag-1\"")
+            .expect(1)
+            .create_async()
+            .await;
+        let part_2 = server
+            .mock("PUT", "/parts/2")
+            .match_body(Matcher::Exact("\u{7}".repeat(1024)))
+            .with_header("ETag", "\"etag-2\"")
+            .expect(1)
+            .create_async()
+            .await;
+
+        let complete = server
+            .mock("POST", "/upload/multipart/complete")
+            .match_body(Matcher::Json(json!({
+                "uploadId": "upload-1",
+                "fileName": "big.bam",
+                "parts": [
+                    { "partNumber": 1, "eTag": "\"etag-1\"" },
+                    { "partNumber": 2, "eTag": "\"etag-2\"" },
+                ],
+            })))
+            .create_async()
+            .await;
+
+        upload_file_multipart(
+            &server.url(),
+            "test-api-key",
+            file.path().to_str().unwrap(),
+            "big.bam",
+            None,
+        )
+        .await?;
+
+        create.assert_async().await;
+        failed_part.assert_async().await;
+        part_1.assert_async().await;
+        part_2.assert_async().await;
+        complete.assert_async().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_multipart_aborts_after_failed_part() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let file = create_test_file(1024);
+
+        server
+            .mock("POST", "/upload/multipart/create")
+            .match_query(Matcher::Any)
+            .with_body(
+                json!({
+                    "uploadId": "upload-2",
+                    "partUrls": [format!("{}/parts/1", server.url())],
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let part = server
+            .mock("PUT", "/parts/1")
+            .with_status(500)
+            .expect(MAX_PART_ATTEMPTS as usize)
+            .create_async()
+            .await;
+        let abort = server
+            .mock("POST", "/upload/multipart/abort")
+            .create_async()
+            .await;
+
+        let result = upload_file_multipart(
+            &server.url(),
+            "test-api-key",
+            file.path().to_str().unwrap(),
+            "small.log",
+            None,
+        )
+        .await;
+
+        assert!(result.is_err());
+        part.assert_async().await;
+        abort.assert_async().await;
+
+        Ok(())
+    }
+}
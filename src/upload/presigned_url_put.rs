@@ -2,12 +2,13 @@ use anyhow::{Context, Result};
 use serde_json::{json, Value};
 use url::Url;
 
-use crate::{debug_log::Logger, http_client::send_http_body};
+use crate::{checksum::FileChecksum, debug_log::Logger, http_client::send_http_body};
 
 pub async fn request_presigned_url(
     service_url: &str,
     api_key: &str,
     file_name: &str,
+    checksum: Option<&FileChecksum>,
 ) -> Result<String> {
     // Construct the full URL with the query parameter
     let presigned_url = format!("{}/upload/presigned-put", service_url);
@@ -15,6 +16,14 @@ pub async fn request_presigned_url(
     let mut url = Url::parse(&presigned_url).context("Failed to parse service URL")?;
     url.query_pairs_mut().append_pair("fileName", file_name);
 
+    if let Some(checksum) = checksum {
+        url.query_pairs_mut()
+            .append_pair("sha256", &checksum.sha256);
+        if let Some(md5) = &checksum.md5 {
+            url.query_pairs_mut().append_pair("md5", md5);
+        }
+    }
+
     // Prepare the request body (empty in this case)
     let request_body = json!({});
 
@@ -70,7 +79,8 @@ mod tests {
         let file_name = "log_outgoing_http_calls.txt";
 
         // Call the function
-        let presigned_url = request_presigned_url(&config.service_url, &api_key, file_name).await?;
+        let presigned_url =
+            request_presigned_url(&config.service_url, &api_key, file_name, None).await?;
 
         // Validate the returned presigned URL
         let url = Url::parse(&presigned_url)?;
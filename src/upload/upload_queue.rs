@@ -0,0 +1,907 @@
+// src/upload/upload_queue.rs
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::checksum::{compute_file_checksum, FileChecksum};
+use crate::debug_log::Logger;
+use crate::file_cache::FileCache;
+use crate::upload::upload_from_file_path;
+
+pub const UPLOAD_QUEUE_FILE: &str = "upload_queue.json";
+pub const UPLOAD_QUEUE_TEMP_FILE: &str = "upload_queue.json.tmp";
+pub const UPLOAD_SNAPSHOT_DIR: &str = "uploads";
+const MAX_UPLOAD_ATTEMPTS: u32 = 5;
+/// Done and failed uploads kept to be listed and to skip uploading the same content again
+const MAX_FINISHED_UPLOADS: usize = 1000;
+const UPLOAD_RETRY_DELAY_S: i64 = 30;
+const UPLOAD_WAIT_INTERVAL: Duration = Duration::from_millis(100);
+const UPLOAD_ID_CHARSET: &str = "abcdefghijklmnoprstuwxyz0123456789";
+const UPLOAD_ID_LENGTH: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl UploadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UploadStatus::Queued => "queued",
+            UploadStatus::Running => "running",
+            UploadStatus::Done => "done",
+            UploadStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Who an upload is made for
+#[derive(Clone, Debug, Default)]
+pub struct UploadOrigin {
+    /// User who asked for the upload, `None` for uploads of the daemon's own
+    pub owner_uid: Option<u32>,
+    /// Run the file was produced by
+    pub run_id: Option<String>,
+}
+
+impl UploadOrigin {
+    pub fn of_user(owner_uid: u32) -> Self {
+        UploadOrigin {
+            owner_uid: Some(owner_uid),
+            run_id: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadEntry {
+    pub id: String,
+    pub file_name: String,
+    pub source_path: String,
+    /// Copy of the file taken when it was queued, so later writes don't change what gets uploaded
+    pub snapshot_path: String,
+    /// The file watcher's cached copy the upload was made from, dropped once the upload is done
+    #[serde(default)]
+    pub cached_copy: Option<String>,
+    pub checksum: Option<FileChecksum>,
+    /// User who asked for the upload, `None` for uploads of the daemon's own
+    #[serde(default)]
+    pub owner_uid: Option<u32>,
+    /// Run the file was produced by, whose report lists the upload
+    #[serde(default)]
+    pub run_id: Option<String>,
+    pub status: UploadStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub duplicate_of: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Empties the cache directory, keeping the upload queue and the snapshots of
+/// pending uploads so they survive a daemon restart.
+pub fn prepare_cache_directory(file_cache_dir: &str) -> Result<()> {
+    let path = Path::new(file_cache_dir);
+    fs::create_dir_all(path)?;
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == UPLOAD_QUEUE_FILE || name == UPLOAD_SNAPSHOT_DIR {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Upload queue persisted in the cache directory. Uploads run on background
+/// tasks, at most `max_workers` at a time, and are retried with backoff.
+#[derive(Clone)]
+pub struct UploadQueue {
+    queue_file: PathBuf,
+    snapshot_dir: PathBuf,
+    max_workers: usize,
+    /// Whether the digests computed for uploads include an MD5
+    compute_md5_checksums: bool,
+    file_cache: FileCache,
+    entries: Arc<Mutex<Vec<UploadEntry>>>,
+    /// Held while writing the queue file
+    persist_lock: Arc<Mutex<()>>,
+    /// Whether the entries changed since they were last written
+    dirty: Arc<AtomicBool>,
+}
+
+/// Whether the same content is already queued, uploading or uploaded under the name
+fn has_duplicate(
+    entries: &[UploadEntry],
+    file_name: &str,
+    checksum: Option<&FileChecksum>,
+) -> bool {
+    checksum.is_some_and(|checksum| {
+        entries.iter().any(|entry| {
+            entry.status != UploadStatus::Failed
+                && entry.file_name == file_name
+                && entry.checksum.as_ref().map(|c| &c.sha256) == Some(&checksum.sha256)
+        })
+    })
+}
+
+/// Drops the oldest finished uploads past `max_finished`
+fn prune_finished(entries: &mut Vec<UploadEntry>, max_finished: usize) {
+    let finished = entries
+        .iter()
+        .filter(|entry| entry.status == UploadStatus::Done || entry.status == UploadStatus::Failed)
+        .count();
+    let mut to_prune = finished.saturating_sub(max_finished);
+    entries.retain(|entry| {
+        let prune = to_prune > 0
+            && (entry.status == UploadStatus::Done || entry.status == UploadStatus::Failed);
+        if prune {
+            to_prune -= 1;
+        }
+        !prune
+    });
+}
+
+impl UploadQueue {
+    /// Restores the queue left by a previous daemon; uploads that were running
+    /// when it stopped are queued again.
+    pub fn load(file_cache: &FileCache, max_workers: usize) -> Result<UploadQueue> {
+        let queue_file = file_cache.get_directory().join(UPLOAD_QUEUE_FILE);
+        let snapshot_dir = file_cache.get_directory().join(UPLOAD_SNAPSHOT_DIR);
+        fs::create_dir_all(&snapshot_dir)?;
+
+        let mut entries: Vec<UploadEntry> = match fs::read_to_string(&queue_file) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|error| {
+                eprintln!(
+                    "[{}] Failed to parse upload queue {:?}, starting empty: {}",
+                    Utc::now(),
+                    queue_file,
+                    error
+                );
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+
+        let mut queue = UploadQueue {
+            queue_file,
+            snapshot_dir,
+            max_workers: max_workers.max(1),
+            compute_md5_checksums: false,
+            file_cache: file_cache.clone(),
+            entries: Arc::new(Mutex::new(vec![])),
+            persist_lock: Arc::new(Mutex::new(())),
+            dirty: Arc::new(AtomicBool::new(false)),
+        };
+
+        for entry in entries.iter_mut() {
+            if entry.status == UploadStatus::Running {
+                entry.status = UploadStatus::Queued;
+            }
+            if entry.status != UploadStatus::Queued {
+                continue;
+            }
+            if queue.has_valid_snapshot(entry) {
+                file_cache.pin(&entry.snapshot_path);
+            } else {
+                entry.status = UploadStatus::Failed;
+                entry.last_error = Some("File snapshot is missing".to_string());
+            }
+        }
+
+        prune_finished(&mut entries, MAX_FINISHED_UPLOADS);
+        queue.entries = Arc::new(Mutex::new(entries));
+        queue.persist()?;
+
+        Ok(queue)
+    }
+
+    /// Snapshots live in the file cache, unless the file didn't fit in it
+    fn has_valid_snapshot(&self, entry: &UploadEntry) -> bool {
+        if Path::new(&entry.snapshot_path).starts_with(&self.snapshot_dir) {
+            self.file_cache.contains(&entry.snapshot_path)
+        } else {
+            Path::new(&entry.snapshot_path).exists()
+        }
+    }
+
+    /// Drops the copies kept for an upload that no longer needs them
+    fn release_files(&self, entry: &UploadEntry) {
+        self.file_cache.remove(&entry.snapshot_path);
+        if let Some(cached_copy) = &entry.cached_copy {
+            self.file_cache.remove(cached_copy);
+        }
+    }
+
+    pub fn set_max_workers(&mut self, max_workers: usize) {
+        self.max_workers = max_workers.max(1);
+    }
+
+    pub fn set_compute_md5_checksums(&mut self, compute_md5_checksums: bool) {
+        self.compute_md5_checksums = compute_md5_checksums;
+    }
+
+    pub fn get_entries(&self) -> Vec<UploadEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Waits until the first attempt at the upload finished, successful or not
+    pub async fn wait_for_attempt(&self, id: &str) -> Option<UploadEntry> {
+        loop {
+            let entry = self
+                .entries
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|entry| entry.id == id)
+                .cloned()?;
+
+            if entry.status == UploadStatus::Done
+                || entry.status == UploadStatus::Failed
+                || entry.attempts > 0
+            {
+                return Some(entry);
+            }
+            tokio::time::sleep(UPLOAD_WAIT_INTERVAL).await;
+        }
+    }
+
+    /// Writes the queue file without holding the lock of the entries. Changes
+    /// made while another write is in progress are written by the next one,
+    /// so concurrent updates share a write.
+    fn persist(&self) -> Result<()> {
+        self.dirty.store(true, Ordering::SeqCst);
+        let _writing = self.persist_lock.lock().unwrap();
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let content = serde_json::to_vec(&*self.entries.lock().unwrap())?;
+        let temp_file = self.queue_file.with_file_name(UPLOAD_QUEUE_TEMP_FILE);
+        fs::write(&temp_file, content)?;
+        fs::rename(&temp_file, &self.queue_file)?;
+        Ok(())
+    }
+
+    fn update_entry(&self, id: &str, update: impl FnOnce(&mut UploadEntry)) {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(entry) = entries.iter_mut().find(|entry| entry.id == id) {
+                update(entry);
+                entry.updated_at = Utc::now();
+            }
+            prune_finished(&mut entries, MAX_FINISHED_UPLOADS);
+        }
+        if let Err(error) = self.persist() {
+            eprintln!("[{}] Failed to persist upload queue: {}", Utc::now(), error);
+        }
+    }
+
+    /// Queues a copy of `upload_from` to be uploaded as `file_name`. Returns
+    /// `None` when the same content is already queued, uploading or uploaded.
+    /// Uploads a user asked for always get a snapshot, since `source_path`
+    /// may point elsewhere by the time the upload runs.
+    pub async fn enqueue(
+        &self,
+        source_path: &str,
+        upload_from: &str,
+        file_name: &str,
+        checksum: Option<FileChecksum>,
+        cached_copy: Option<&str>,
+        origin: UploadOrigin,
+    ) -> Result<Option<String>> {
+        if has_duplicate(&self.entries.lock().unwrap(), file_name, checksum.as_ref()) {
+            return Ok(None);
+        }
+
+        let id = random_string::generate(UPLOAD_ID_LENGTH, UPLOAD_ID_CHARSET);
+        let mut snapshot_path = self.snapshot_dir.join(&id).to_str().unwrap().to_string();
+        // Snapshots of large files take a while, so they're taken on a blocking
+        // thread without holding the lock of the entries
+        let stored = {
+            let file_cache = self.file_cache.clone();
+            let upload_from = upload_from.to_string();
+            let snapshot_path = snapshot_path.clone();
+            tokio::task::spawn_blocking(move || {
+                file_cache.store(&upload_from, &snapshot_path, true)
+            })
+            .await?
+        };
+        if let Err(error) = stored {
+            if origin.owner_uid.is_some() {
+                return Err(error.context(format!("Failed to snapshot {}", source_path)));
+            }
+            // Too large to keep a copy of, so upload whatever the file holds when its turn comes
+            eprintln!(
+                "[{}] Uploading {} without a snapshot: {}",
+                Utc::now(),
+                source_path,
+                error
+            );
+            snapshot_path = source_path.to_string();
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        // The same content may have been queued while the snapshot was taken
+        if has_duplicate(&entries, file_name, checksum.as_ref()) {
+            drop(entries);
+            self.file_cache.remove(&snapshot_path);
+            return Ok(None);
+        }
+        let now = Utc::now();
+        entries.push(UploadEntry {
+            id: id.clone(),
+            file_name: file_name.to_string(),
+            source_path: source_path.to_string(),
+            snapshot_path,
+            cached_copy: cached_copy.map(|cached_copy| cached_copy.to_string()),
+            checksum,
+            owner_uid: origin.owner_uid,
+            run_id: origin.run_id,
+            status: UploadStatus::Queued,
+            attempts: 0,
+            last_error: None,
+            duplicate_of: None,
+            next_attempt_at: now,
+            created_at: now,
+            updated_at: now,
+        });
+        drop(entries);
+        self.persist()?;
+
+        Ok(Some(id))
+    }
+
+    /// Starts queued uploads that are due, up to the worker limit
+    pub fn process(
+        &self,
+        service_url: &str,
+        api_key: &str,
+        max_file_size: u64,
+    ) -> Result<Vec<JoinHandle<()>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Utc::now();
+
+        let running = entries
+            .iter()
+            .filter(|entry| entry.status == UploadStatus::Running)
+            .count();
+
+        let mut started = vec![];
+        for entry in entries.iter_mut() {
+            if running + started.len() >= self.max_workers {
+                break;
+            }
+            if entry.status == UploadStatus::Queued && entry.next_attempt_at <= now {
+                entry.status = UploadStatus::Running;
+                entry.updated_at = now;
+                started.push(entry.id.clone());
+            }
+        }
+
+        if started.is_empty() {
+            return Ok(vec![]);
+        }
+        drop(entries);
+        self.persist()?;
+
+        Ok(started
+            .into_iter()
+            .map(|id| {
+                tokio::spawn(self.clone().run_upload(
+                    id,
+                    service_url.to_string(),
+                    api_key.to_string(),
+                    max_file_size,
+                ))
+            })
+            .collect())
+    }
+
+    async fn run_upload(
+        self,
+        id: String,
+        service_url: String,
+        api_key: String,
+        max_file_size: u64,
+    ) {
+        let logger = Logger::new();
+        let Some(entry) = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.id == id)
+            .cloned()
+        else {
+            return;
+        };
+
+        if !self.has_valid_snapshot(&entry) {
+            self.release_files(&entry);
+            self.update_entry(&id, |entry| {
+                entry.status = UploadStatus::Failed;
+                entry.last_error =
+                    Some("File changed or disappeared after being queued".to_string());
+            });
+            return;
+        }
+
+        let result = self
+            .upload_entry(&entry, &service_url, &api_key, max_file_size)
+            .await;
+
+        match result {
+            Ok(()) => {
+                logger
+                    .log(&format!("Upload of {} done", entry.source_path), None)
+                    .await;
+                self.release_files(&entry);
+                self.update_entry(&id, |entry| {
+                    entry.status = UploadStatus::Done;
+                    entry.attempts += 1;
+                    entry.last_error = None;
+                });
+            }
+            Err(error) => {
+                logger
+                    .log(
+                        &format!("Upload of {} failed: {:?}", entry.source_path, error),
+                        None,
+                    )
+                    .await;
+                let attempts = entry.attempts + 1;
+                if attempts >= MAX_UPLOAD_ATTEMPTS {
+                    self.release_files(&entry);
+                }
+                self.update_entry(&id, |entry| {
+                    entry.attempts = attempts;
+                    entry.last_error = Some(error.to_string());
+                    if attempts >= MAX_UPLOAD_ATTEMPTS {
+                        entry.status = UploadStatus::Failed;
+                    } else {
+                        entry.status = UploadStatus::Queued;
+                        entry.next_attempt_at =
+                            Utc::now() + TimeDelta::seconds(UPLOAD_RETRY_DELAY_S << (attempts - 1));
+                    }
+                });
+            }
+        }
+    }
+
+    async fn upload_entry(
+        &self,
+        entry: &UploadEntry,
+        service_url: &str,
+        api_key: &str,
+        max_file_size: u64,
+    ) -> Result<()> {
+        let checksum = match &entry.checksum {
+            Some(checksum) => checksum.clone(),
+            None => {
+                let snapshot_path = entry.snapshot_path.clone();
+                let compute_md5 = self.compute_md5_checksums;
+                let checksum = tokio::task::spawn_blocking(move || {
+                    compute_file_checksum(&snapshot_path, compute_md5)
+                })
+                .await??;
+                self.update_entry(&entry.id, |entry| {
+                    entry.checksum = Some(checksum.clone());
+                });
+                checksum
+            }
+        };
+
+        // The digest may only be known now, so check again for the same content
+        let duplicate_of = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|other| {
+                other.id != entry.id
+                    && other.status == UploadStatus::Done
+                    && other.file_name == entry.file_name
+                    && other.checksum.as_ref().map(|c| &c.sha256) == Some(&checksum.sha256)
+            })
+            .map(|other| other.id.clone());
+
+        if let Some(duplicate_of) = duplicate_of {
+            self.update_entry(&entry.id, |entry| {
+                entry.duplicate_of = Some(duplicate_of);
+            });
+            return Ok(());
+        }
+
+        upload_from_file_path(
+            service_url,
+            api_key,
+            &entry.snapshot_path,
+            Some(&entry.file_name),
+            Some(&checksum),
+            self.compute_md5_checksums,
+            max_file_size,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+    use std::io::Write;
+
+    fn create_file(directory: &Path, name: &str, content: &[u8]) -> String {
+        let path = directory.join(name);
+        fs::File::create(&path).unwrap().write_all(content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    async fn mock_upload(
+        server: &mut mockito::ServerGuard,
+        status: usize,
+        hits: usize,
+    ) -> mockito::Mock {
+        server
+            .mock("POST", "/upload/presigned-put")
+            .match_query(Matcher::Any)
+            .with_body(format!(r#"{{"signedUrl": "{}/s3/object"}}"#, server.url()))
+            .create_async()
+            .await;
+        server
+            .mock("PUT", "/s3/object")
+            .with_status(status)
+            .expect(hits)
+            .create_async()
+            .await
+    }
+
+    fn load_queue(cache_dir: &Path, max_workers: usize) -> Result<UploadQueue> {
+        let file_cache = FileCache::new(cache_dir.to_str().unwrap(), 1024 * 1024)?;
+        UploadQueue::load(&file_cache, max_workers)
+    }
+
+    #[tokio::test]
+    async fn test_user_uploads_require_a_snapshot() -> Result<()> {
+        let workflow_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let file_cache = FileCache::new(cache_dir.path().to_str().unwrap(), 4)?;
+        let queue = UploadQueue::load(&file_cache, 1)?;
+        let file = create_file(workflow_dir.path(), "results.tsv", b"larger than the cache");
+
+        assert!(queue
+            .enqueue(
+                &file,
+                &file,
+                "results.tsv",
+                None,
+                None,
+                UploadOrigin::of_user(1000)
+            )
+            .await
+            .is_err());
+        assert!(queue
+            .enqueue(
+                &file,
+                &file,
+                "results.tsv",
+                None,
+                None,
+                UploadOrigin::default()
+            )
+            .await?
+            .is_some());
+        assert_eq!(queue.get_entries()[0].snapshot_path, file);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_deduplicates_by_checksum() -> Result<()> {
+        let workflow_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let queue = load_queue(cache_dir.path(), 2)?;
+        let file = create_file(workflow_dir.path(), "Log.final.out", b"content");
+        let checksum = compute_file_checksum(&file, false)?;
+
+        let first = queue
+            .enqueue(
+                &file,
+                &file,
+                "Log.final.out",
+                Some(checksum.clone()),
+                None,
+                UploadOrigin::default(),
+            )
+            .await?;
+        let second = queue
+            .enqueue(
+                &file,
+                &file,
+                "Log.final.out",
+                Some(checksum),
+                None,
+                UploadOrigin::default(),
+            )
+            .await?;
+
+        assert!(first.is_some());
+        assert!(second.is_none());
+        assert_eq!(queue.get_entries().len(), 1);
+        assert!(Path::new(&queue.get_entries()[0].snapshot_path).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_deduplicates_content_queued_meanwhile() -> Result<()> {
+        let workflow_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let file_cache = FileCache::new(cache_dir.path().to_str().unwrap(), 1024 * 1024)?;
+        let queue = UploadQueue::load(&file_cache, 1)?;
+        let file = create_file(workflow_dir.path(), "Log.final.out", b"content");
+        let checksum = compute_file_checksum(&file, false)?;
+        let enqueue = || {
+            queue.enqueue(
+                &file,
+                &file,
+                "Log.final.out",
+                Some(checksum.clone()),
+                None,
+                UploadOrigin::default(),
+            )
+        };
+
+        let (first, second) = tokio::join!(enqueue(), enqueue());
+
+        assert!(first?.is_some() != second?.is_some());
+        assert_eq!(queue.get_entries().len(), 1);
+        // Only the snapshot of the queued upload is kept
+        assert_eq!(file_cache.get_total_bytes(), 7);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_uploads_with_worker_limit() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let put = mock_upload(&mut server, 200, 2).await;
+
+        let workflow_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let file_cache = FileCache::new(cache_dir.path().to_str().unwrap(), 1024 * 1024)?;
+        let queue = UploadQueue::load(&file_cache, 1)?;
+        let first = create_file(workflow_dir.path(), "a.summary", b"a");
+        let second = create_file(workflow_dir.path(), "b.summary", b"b");
+
+        // The file watcher's copy of the first file
+        let cached_copy = cache_dir
+            .path()
+            .join("cached")
+            .to_str()
+            .unwrap()
+            .to_string();
+        file_cache.store(&first, &cached_copy, false)?;
+
+        queue
+            .enqueue(
+                &first,
+                &cached_copy,
+                "a.summary",
+                None,
+                Some(&cached_copy),
+                UploadOrigin::default(),
+            )
+            .await?;
+        queue
+            .enqueue(
+                &second,
+                &second,
+                "b.summary",
+                None,
+                None,
+                UploadOrigin::default(),
+            )
+            .await?;
+
+        let handles = queue.process(&server.url(), "test-api-key", 1024)?;
+        assert_eq!(handles.len(), 1);
+        for handle in handles {
+            handle.await?;
+        }
+
+        let handles = queue.process(&server.url(), "test-api-key", 1024)?;
+        assert_eq!(handles.len(), 1);
+        for handle in handles {
+            handle.await?;
+        }
+
+        let entries = queue.get_entries();
+        assert!(entries
+            .iter()
+            .all(|entry| entry.status == UploadStatus::Done && entry.checksum.is_some()));
+        assert!(entries
+            .iter()
+            .all(|entry| !Path::new(&entry.snapshot_path).exists()));
+        // Confirmed uploads don't keep their copies around
+        assert!(!file_cache.contains(&cached_copy));
+        assert_eq!(file_cache.get_total_bytes(), 0);
+        put.assert_async().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_upload_is_retried_after_restart() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_upload(&mut server, 500, 1).await;
+
+        let workflow_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let queue = load_queue(cache_dir.path(), 2)?;
+        let file = create_file(workflow_dir.path(), "peaks.narrowPeak", b"chr1\t1\t2");
+        queue
+            .enqueue(
+                &file,
+                &file,
+                "peaks.narrowPeak",
+                None,
+                None,
+                UploadOrigin::default(),
+            )
+            .await?;
+
+        for handle in queue.process(&server.url(), "test-api-key", 1024)? {
+            handle.await?;
+        }
+
+        let entry = &queue.get_entries()[0];
+        assert_eq!(entry.status, UploadStatus::Queued);
+        assert_eq!(entry.attempts, 1);
+        assert!(entry.last_error.is_some());
+        assert!(entry.next_attempt_at > Utc::now());
+
+        // Not due yet
+        assert!(queue
+            .process(&server.url(), "test-api-key", 1024)?
+            .is_empty());
+
+        // The retry state is read back by the next daemon
+        prepare_cache_directory(cache_dir.path().to_str().unwrap())?;
+        let restored = load_queue(cache_dir.path(), 2)?;
+        let restored_entry = &restored.get_entries()[0];
+        assert_eq!(restored_entry.id, entry.id);
+        assert_eq!(restored_entry.attempts, 1);
+        assert_eq!(restored_entry.status, UploadStatus::Queued);
+        assert!(Path::new(&restored_entry.snapshot_path).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_uploads_compute_md5_checksums_if_configured() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_upload(&mut server, 200, 1).await;
+
+        let workflow_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let mut queue = load_queue(cache_dir.path(), 1)?;
+        queue.set_compute_md5_checksums(true);
+        let file = create_file(workflow_dir.path(), "counts.tsv", b"gene\t1");
+        queue
+            .enqueue(
+                &file,
+                &file,
+                "counts.tsv",
+                None,
+                None,
+                UploadOrigin::default(),
+            )
+            .await?;
+
+        for handle in queue.process(&server.url(), "test-api-key", 1024)? {
+            handle.await?;
+        }
+
+        let entry = &queue.get_entries()[0];
+        assert_eq!(entry.status, UploadStatus::Done);
+        assert_eq!(entry.checksum, Some(compute_file_checksum(&file, true)?));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_last_failed_attempt_releases_the_cached_copy() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_upload(&mut server, 500, 1).await;
+
+        let workflow_dir = tempfile::tempdir()?;
+        let cache_dir = tempfile::tempdir()?;
+        let file_cache = FileCache::new(cache_dir.path().to_str().unwrap(), 1024 * 1024)?;
+        let queue = UploadQueue::load(&file_cache, 1)?;
+        let file = create_file(workflow_dir.path(), "Log.final.out", b"content");
+        let cached_copy = cache_dir
+            .path()
+            .join("cached")
+            .to_str()
+            .unwrap()
+            .to_string();
+        file_cache.store(&file, &cached_copy, false)?;
+
+        let id = queue
+            .enqueue(
+                &file,
+                &cached_copy,
+                "Log.final.out",
+                None,
+                Some(&cached_copy),
+                UploadOrigin::default(),
+            )
+            .await?
+            .unwrap();
+        queue.update_entry(&id, |entry| entry.attempts = MAX_UPLOAD_ATTEMPTS - 1);
+
+        for handle in queue.process(&server.url(), "test-api-key", 1024)? {
+            handle.await?;
+        }
+
+        assert_eq!(queue.get_entries()[0].status, UploadStatus::Failed);
+        assert!(!file_cache.contains(&cached_copy));
+        assert_eq!(file_cache.get_total_bytes(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_finished_keeps_the_latest() {
+        let now = Utc::now();
+        let entry = |id: &str, status: UploadStatus| UploadEntry {
+            id: id.to_string(),
+            file_name: "a.summary".to_string(),
+            source_path: "/data/a.summary".to_string(),
+            snapshot_path: "/data/a.summary".to_string(),
+            cached_copy: None,
+            checksum: None,
+            owner_uid: None,
+            run_id: None,
+            status,
+            attempts: 0,
+            last_error: None,
+            duplicate_of: None,
+            next_attempt_at: now,
+            created_at: now,
+            updated_at: now,
+        };
+        let mut entries = vec![
+            entry("done-1", UploadStatus::Done),
+            entry("queued", UploadStatus::Queued),
+            entry("failed", UploadStatus::Failed),
+            entry("done-2", UploadStatus::Done),
+            entry("running", UploadStatus::Running),
+        ];
+
+        prune_finished(&mut entries, 2);
+
+        let ids: Vec<&str> = entries.iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(ids, vec!["queued", "failed", "done-2", "running"]);
+    }
+}
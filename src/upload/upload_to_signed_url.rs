@@ -101,9 +101,10 @@ mod tests {
 
         let config = ConfigManager::load_default_config();
 
-        let signed_url = request_presigned_url(&config.service_url, &config.api_key, &file_path)
-            .await
-            .unwrap();
+        let signed_url =
+            request_presigned_url(&config.service_url, &config.api_key, file_path, None)
+                .await
+                .unwrap();
 
         let result = upload_file_to_signed_url_s3(&signed_url, file_path).await;
 
@@ -0,0 +1,55 @@
+// src/users.rs
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref USERNAMES: Mutex<HashMap<u32, Option<String>>> = Mutex::new(HashMap::new());
+}
+
+fn lookup_username(uid: u32) -> Option<String> {
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    // SAFETY: every pointer refers to memory owned by this frame, sized as passed
+    let status = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+
+    // SAFETY: on success pw_name points to a NUL terminated string inside `buffer`
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().to_string())
+}
+
+/// Name of the user with this uid, cached since it's looked up for every process
+pub fn get_username(uid: u32) -> Option<String> {
+    USERNAMES
+        .lock()
+        .unwrap()
+        .entry(uid)
+        .or_insert_with(|| lookup_username(uid))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_username() {
+        assert_eq!(get_username(0), Some("root".to_string()));
+        assert_eq!(get_username(u32::MAX - 1), None);
+    }
+}
@@ -0,0 +1,99 @@
+// src/workflow_engines/log_follower.rs
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+/// Reads the lines appended to a log file since the last read. The file may
+/// not exist yet, and starts over when it's truncated or replaced by a
+/// smaller one, as engines rotate their log at the start of every run.
+pub struct LogFollower {
+    path: PathBuf,
+    offset: u64,
+    partial_line: String,
+}
+
+impl LogFollower {
+    pub fn new(path: PathBuf) -> Self {
+        LogFollower {
+            path,
+            offset: 0,
+            partial_line: String::new(),
+        }
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Complete lines written since the last call
+    pub fn read_new_lines(&mut self) -> Result<Vec<String>> {
+        let Ok(mut file) = File::open(&self.path) else {
+            return Ok(vec![]);
+        };
+
+        if file.metadata()?.len() < self.offset {
+            self.offset = 0;
+            self.partial_line.clear();
+        }
+
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut buffer = vec![];
+        self.offset += file.read_to_end(&mut buffer)? as u64;
+
+        self.partial_line
+            .push_str(&String::from_utf8_lossy(&buffer));
+        let Some(last_newline) = self.partial_line.rfind('\n') else {
+            return Ok(vec![]);
+        };
+
+        let rest = self.partial_line.split_off(last_newline + 1);
+        let lines = std::mem::replace(&mut self.partial_line, rest)
+            .lines()
+            .map(String::from)
+            .collect();
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn test_reads_complete_lines_once() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("engine.log");
+        let mut follower = LogFollower::new(path.clone());
+
+        assert!(follower.read_new_lines()?.is_empty());
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        write!(file, "first\nsecond\nthi")?;
+        assert_eq!(follower.read_new_lines()?, vec!["first", "second"]);
+        assert!(follower.read_new_lines()?.is_empty());
+
+        writeln!(file, "rd")?;
+        assert_eq!(follower.read_new_lines()?, vec!["third"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_starts_over_on_a_rotated_log() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("engine.log");
+        let mut follower = LogFollower::new(path.clone());
+
+        std::fs::write(&path, "an old run with a long log\n")?;
+        assert_eq!(follower.read_new_lines()?.len(), 1);
+
+        std::fs::write(&path, "new run\n")?;
+        assert_eq!(follower.read_new_lines()?, vec!["new run"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,624 @@
+// src/workflow_engines/mod.rs
+pub mod log_follower;
+pub mod nextflow;
+pub mod snakemake;
+
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sysinfo::{Pid, System};
+
+use crate::event_recorder::{EventRecorder, EventType};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkflowEngine {
+    Nextflow,
+    Snakemake,
+    Cromwell,
+    Miniwdl,
+    Cwltool,
+}
+
+impl WorkflowEngine {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkflowEngine::Nextflow => "nextflow",
+            WorkflowEngine::Snakemake => "snakemake",
+            WorkflowEngine::Cromwell => "cromwell",
+            WorkflowEngine::Miniwdl => "miniwdl",
+            WorkflowEngine::Cwltool => "cwltool",
+        }
+    }
+}
+
+/// What a workflow engine launcher was asked to run, parsed from its command line
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PipelineMetadata {
+    pub engine: WorkflowEngine,
+    pub pipeline: Option<String>,
+    pub revision: Option<String>,
+    pub profile: Option<String>,
+    pub params: BTreeMap<String, String>,
+    /// Working directory of the launcher, where engines keep their log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub launch_directory: Option<String>,
+}
+
+impl PipelineMetadata {
+    fn new(engine: WorkflowEngine) -> Self {
+        PipelineMetadata {
+            engine,
+            pipeline: None,
+            revision: None,
+            profile: None,
+            params: BTreeMap::new(),
+            launch_directory: None,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Submitted,
+    Cached,
+    /// A tool process of the task started
+    Running,
+    Failed,
+    Finished,
+}
+
+/// A state change of a task of the workflow engine, reported as a `task_event`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TaskEvent {
+    pub engine: WorkflowEngine,
+    pub status: TaskStatus,
+    pub name: String,
+    /// The pipeline step the task runs, a Nextflow process or a Snakemake rule
+    pub step: Option<String>,
+    pub tag: Option<String>,
+    /// Nextflow's short task hash or Snakemake's job id
+    pub task_id: Option<String>,
+    pub exit_code: Option<i32>,
+    pub work_dir: Option<String>,
+    /// Resource usage as measured by the engine
+    pub trace: Option<Map<String, Value>>,
+    /// The tool process a `running` event is about
+    pub tool_pid: Option<String>,
+    /// Timestamp of the engine's log line the event comes from
+    pub log_timestamp: Option<String>,
+}
+
+impl TaskEvent {
+    pub fn record(&self, logs: &mut EventRecorder, run_id: Option<&str>) -> Result<()> {
+        logs.record_run_event(
+            run_id,
+            EventType::TaskEvent,
+            format!(
+                "[{}] {} task {}: {}",
+                chrono::Utc::now(),
+                self.engine.as_str(),
+                serde_json::to_value(self.status)?
+                    .as_str()
+                    .unwrap_or_default(),
+                self.name
+            ),
+            Some(serde_json::to_value(self)?),
+            None,
+        );
+        Ok(())
+    }
+}
+
+/// The task of a workflow engine a tool process runs in: the engine's child
+/// process the tool descends from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EngineTask {
+    pub engine: WorkflowEngine,
+    pub launcher_pid: String,
+    pub task_pid: String,
+    pub task_directory: Option<String>,
+}
+
+/// Read access to the processes of the machine, or to a synthetic process tree
+pub trait ProcessTable {
+    fn parent(&self, pid: Pid) -> Option<Pid>;
+    fn command(&self, pid: Pid) -> Option<&[String]>;
+    fn working_directory(&self, pid: Pid) -> Option<String>;
+}
+
+impl ProcessTable for System {
+    fn parent(&self, pid: Pid) -> Option<Pid> {
+        self.process(pid)?.parent()
+    }
+
+    fn command(&self, pid: Pid) -> Option<&[String]> {
+        self.process(pid).map(|process| process.cmd())
+    }
+
+    fn working_directory(&self, pid: Pid) -> Option<String> {
+        self.process(pid)?
+            .cwd()
+            .map(|directory| directory.to_string_lossy().to_string())
+    }
+}
+
+const NEXTFLOW_MAIN_CLASS: &str = "nextflow.cli.Launcher";
+
+/// Nextflow options taking a value, besides the revision and profile
+const NEXTFLOW_VALUE_OPTIONS: [&str; 15] = [
+    "-bucket-dir",
+    "-c",
+    "-config",
+    "-entry",
+    "-hub",
+    "-lib",
+    "-main-script",
+    "-name",
+    "-params-file",
+    "-plugins",
+    "-qs",
+    "-queue-size",
+    "-user",
+    "-w",
+    "-work-dir",
+];
+
+const CWLTOOL_VALUE_OPTIONS: [&str; 7] = [
+    "--basedir",
+    "--cachedir",
+    "--default-container",
+    "--outdir",
+    "--provenance",
+    "--tmp-outdir-prefix",
+    "--tmpdir-prefix",
+];
+
+fn basename(arg: &str) -> &str {
+    arg.rsplit('/').next().unwrap_or(arg)
+}
+
+fn is_interpreter(name: &str) -> bool {
+    name == "bash" || name == "sh" || name.starts_with("python")
+}
+
+/// Index of the engine's executable in the command, which may be run by an
+/// interpreter: `python -m snakemake`, `java -jar cromwell.jar`
+fn find_executable(cmd: &[String], matches: impl Fn(&str) -> bool) -> Option<usize> {
+    let interpreter = basename(cmd.first()?);
+    if matches(interpreter) {
+        return Some(0);
+    }
+    if interpreter.starts_with("java") {
+        // The main class or the jar, after any number of JVM options
+        return cmd.iter().position(|arg| matches(basename(arg)));
+    }
+    if is_interpreter(interpreter) {
+        let index = cmd.iter().skip(1).position(|arg| !arg.starts_with('-'))? + 1;
+        return matches(basename(&cmd[index])).then_some(index);
+    }
+    None
+}
+
+/// The arguments after the engine's `run` subcommand, which only global
+/// options may precede
+fn run_arguments(cmd: &[String], executable: usize) -> Option<&[String]> {
+    let arguments = &cmd[executable + 1..];
+    let run = arguments.iter().position(|arg| arg == "run")?;
+    let is_option_or_value = |index: usize| {
+        arguments[index].starts_with('-') || (index > 0 && arguments[index - 1].starts_with('-'))
+    };
+    (0..run)
+        .all(is_option_or_value)
+        .then(|| &arguments[run + 1..])
+}
+
+/// A `--name value`, `--name=value` or bare `--flag` parameter at `index`,
+/// returning the number of arguments it spans
+fn parse_param(arguments: &[String], index: usize, params: &mut BTreeMap<String, String>) -> usize {
+    let name = arguments[index].trim_start_matches('-');
+    if let Some((name, value)) = name.split_once('=') {
+        params.insert(name.to_string(), value.to_string());
+        return 1;
+    }
+    match arguments.get(index + 1) {
+        Some(value) if !value.starts_with('-') => {
+            params.insert(name.to_string(), value.clone());
+            2
+        }
+        _ => {
+            params.insert(name.to_string(), "true".to_string());
+            1
+        }
+    }
+}
+
+fn parse_nextflow(arguments: &[String]) -> PipelineMetadata {
+    let mut metadata = PipelineMetadata::new(WorkflowEngine::Nextflow);
+    let mut index = 0;
+    while index < arguments.len() {
+        let arg = arguments[index].as_str();
+        let value = arguments.get(index + 1).cloned();
+        index += match arg {
+            _ if arg.starts_with("--") => parse_param(arguments, index, &mut metadata.params),
+            "-r" | "-revision" => {
+                metadata.revision = value;
+                2
+            }
+            "-profile" => {
+                metadata.profile = value;
+                2
+            }
+            _ if NEXTFLOW_VALUE_OPTIONS.contains(&arg) => 2,
+            _ if arg.starts_with('-') => 1,
+            _ => {
+                metadata.pipeline.get_or_insert_with(|| arg.to_string());
+                1
+            }
+        };
+    }
+    metadata
+}
+
+fn parse_snakemake(arguments: &[String]) -> PipelineMetadata {
+    let mut metadata = PipelineMetadata::new(WorkflowEngine::Snakemake);
+    let mut index = 0;
+    while index < arguments.len() {
+        let value = arguments.get(index + 1).cloned();
+        match arguments[index].as_str() {
+            "-s" | "--snakefile" => {
+                metadata.pipeline = value;
+                index += 1;
+            }
+            "--profile" => {
+                metadata.profile = value;
+                index += 1;
+            }
+            "-C" | "--config" => {
+                // Any number of key=value pairs
+                while let Some((key, value)) = arguments
+                    .get(index + 1)
+                    .filter(|arg| !arg.starts_with('-'))
+                    .and_then(|arg| arg.split_once('='))
+                {
+                    metadata.params.insert(key.to_string(), value.to_string());
+                    index += 1;
+                }
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    metadata
+}
+
+fn parse_cromwell(arguments: &[String]) -> PipelineMetadata {
+    let mut metadata = PipelineMetadata::new(WorkflowEngine::Cromwell);
+    let mut index = 0;
+    while index < arguments.len() {
+        let arg = arguments[index].as_str();
+        let param = match arg {
+            "-i" | "--inputs" => Some("inputs"),
+            "-o" | "--options" => Some("options"),
+            "-l" | "--labels" => Some("labels"),
+            "-t" | "--type" => Some("type"),
+            "-v" | "--type-version" => Some("type_version"),
+            "-p" | "--imports" => Some("imports"),
+            "-m" | "--metadata-output" => Some("metadata_output"),
+            _ => None,
+        };
+        if let (Some(param), Some(value)) = (param, arguments.get(index + 1)) {
+            metadata.params.insert(param.to_string(), value.clone());
+            index += 1;
+        } else if !arg.starts_with('-') {
+            metadata.pipeline.get_or_insert_with(|| arg.to_string());
+        }
+        index += 1;
+    }
+    metadata
+}
+
+fn parse_miniwdl(arguments: &[String]) -> PipelineMetadata {
+    let mut metadata = PipelineMetadata::new(WorkflowEngine::Miniwdl);
+    let mut index = 0;
+    while index < arguments.len() {
+        let arg = arguments[index].as_str();
+        match arg {
+            "-i" | "--input" => {
+                if let Some(value) = arguments.get(index + 1) {
+                    metadata.params.insert("input".to_string(), value.clone());
+                }
+                index += 1;
+            }
+            "-d" | "--dir" | "--cfg" | "--task" | "-o" => index += 1,
+            _ if arg.starts_with('-') => {}
+            _ if metadata.pipeline.is_none() => metadata.pipeline = Some(arg.to_string()),
+            _ => {
+                if let Some((key, value)) = arg.split_once('=') {
+                    metadata.params.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+        index += 1;
+    }
+    metadata
+}
+
+fn parse_cwltool(arguments: &[String]) -> PipelineMetadata {
+    let mut metadata = PipelineMetadata::new(WorkflowEngine::Cwltool);
+    let mut index = 0;
+    while index < arguments.len() {
+        let arg = arguments[index].as_str();
+        index += match arg {
+            // Options after the workflow are its inputs
+            _ if arg.starts_with("--") && metadata.pipeline.is_some() => {
+                parse_param(arguments, index, &mut metadata.params)
+            }
+            _ if CWLTOOL_VALUE_OPTIONS.contains(&arg) => 2,
+            _ if arg.starts_with('-') => 1,
+            _ if metadata.pipeline.is_none() => {
+                metadata.pipeline = Some(arg.to_string());
+                1
+            }
+            _ => {
+                metadata
+                    .params
+                    .insert("job_order".to_string(), arg.to_string());
+                1
+            }
+        };
+    }
+    metadata
+}
+
+/// Recognises `nextflow run`, `snakemake`, `cromwell run`, `miniwdl run` and
+/// `cwltool` launchers, directly or through their interpreter
+pub fn recognize_launcher(cmd: &[String]) -> Option<PipelineMetadata> {
+    if let Some(executable) = find_executable(cmd, |name| {
+        name == "nextflow" || name == NEXTFLOW_MAIN_CLASS
+    }) {
+        return run_arguments(cmd, executable).map(parse_nextflow);
+    }
+    if let Some(executable) = find_executable(cmd, |name| name == "snakemake") {
+        return Some(parse_snakemake(&cmd[executable + 1..]));
+    }
+    if let Some(executable) = find_executable(cmd, |name| {
+        name == "cromwell" || (name.starts_with("cromwell") && name.ends_with(".jar"))
+    }) {
+        return run_arguments(cmd, executable).map(parse_cromwell);
+    }
+    if let Some(executable) = find_executable(cmd, |name| name == "miniwdl") {
+        return run_arguments(cmd, executable).map(parse_miniwdl);
+    }
+    if let Some(executable) = find_executable(cmd, |name| name == "cwltool") {
+        return Some(parse_cwltool(&cmd[executable + 1..]));
+    }
+    None
+}
+
+/// The engine task a process runs in, found through its closest launcher
+/// ancestor, along with what that launcher runs
+pub fn find_engine_task(
+    processes: &impl ProcessTable,
+    pid: Pid,
+) -> Option<(EngineTask, PipelineMetadata)> {
+    let mut visited = HashSet::from([pid]);
+    let mut task = pid;
+    let mut ancestor = processes.parent(pid);
+    while let Some(current) = ancestor {
+        if !visited.insert(current) {
+            break;
+        }
+        if let Some(mut pipeline) = processes.command(current).and_then(recognize_launcher) {
+            pipeline.launch_directory = processes.working_directory(current);
+            let engine_task = EngineTask {
+                engine: pipeline.engine,
+                launcher_pid: current.to_string(),
+                task_pid: task.to_string(),
+                task_directory: processes.working_directory(task),
+            };
+            return Some((engine_task, pipeline));
+        }
+        task = current;
+        ancestor = processes.parent(current);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_lifecycle::{ObservedProcess, ProcessSnapshot};
+    use std::collections::HashMap;
+
+    fn cmd(command: &str) -> Vec<String> {
+        command.split_whitespace().map(String::from).collect()
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_recognize_nextflow() {
+        let expected = PipelineMetadata {
+            engine: WorkflowEngine::Nextflow,
+            pipeline: Some("nf-core/rnaseq".to_string()),
+            revision: Some("3.14.0".to_string()),
+            profile: Some("docker,test".to_string()),
+            launch_directory: None,
+            params: params(&[
+                ("outdir", "results"),
+                ("skip_qc", "true"),
+                ("genome", "GRCh38"),
+            ]),
+        };
+
+        let launcher = recognize_launcher(&cmd(
+            "/bin/bash /usr/local/bin/nextflow run nf-core/rnaseq -r 3.14.0 -profile docker,test -resume --outdir results --skip_qc --genome=GRCh38",
+        ));
+        assert_eq!(launcher.as_ref(), Some(&expected));
+
+        // The JVM the launcher script starts
+        let jvm = recognize_launcher(&cmd(
+            "java -Xmx1g -cp /root/.nextflow/framework/nextflow.jar nextflow.cli.Launcher run nf-core/rnaseq -c extra.config -profile docker,test -r 3.14.0 --outdir results --skip_qc --genome GRCh38",
+        ));
+        assert_eq!(jvm, Some(expected));
+
+        assert_eq!(
+            recognize_launcher(&cmd("nextflow pull nf-core/rnaseq")),
+            None
+        );
+        assert_eq!(
+            recognize_launcher(&cmd("nextflow -log run.log run main.nf"))
+                .unwrap()
+                .pipeline,
+            Some("main.nf".to_string())
+        );
+    }
+
+    #[test]
+    fn test_recognize_snakemake() {
+        let launcher = recognize_launcher(&cmd(
+            "/opt/conda/bin/python3.12 /opt/conda/bin/snakemake --cores 8 -s workflow/Snakefile --profile slurm --config samples=samples.tsv threads=4 --use-conda",
+        ));
+
+        assert_eq!(
+            launcher,
+            Some(PipelineMetadata {
+                engine: WorkflowEngine::Snakemake,
+                pipeline: Some("workflow/Snakefile".to_string()),
+                revision: None,
+                profile: Some("slurm".to_string()),
+                launch_directory: None,
+                params: params(&[("samples", "samples.tsv"), ("threads", "4")]),
+            })
+        );
+        assert_eq!(
+            recognize_launcher(&cmd("python -m snakemake -j 4")).map(|launcher| launcher.engine),
+            Some(WorkflowEngine::Snakemake)
+        );
+    }
+
+    #[test]
+    fn test_recognize_wdl_engines() {
+        let cromwell = recognize_launcher(&cmd(
+            "java -Xmx4g -jar /opt/cromwell-86.jar run germline.wdl --inputs inputs.json -o options.json",
+        ));
+        assert_eq!(
+            cromwell,
+            Some(PipelineMetadata {
+                engine: WorkflowEngine::Cromwell,
+                pipeline: Some("germline.wdl".to_string()),
+                revision: None,
+                profile: None,
+                launch_directory: None,
+                params: params(&[("inputs", "inputs.json"), ("options", "options.json")]),
+            })
+        );
+        assert_eq!(
+            recognize_launcher(&cmd("java -jar cromwell.jar server")),
+            None
+        );
+
+        let miniwdl = recognize_launcher(&cmd(
+            "/usr/bin/python3 /usr/local/bin/miniwdl run germline.wdl sample=NA12878 -i inputs.json --dir runs",
+        ));
+        assert_eq!(
+            miniwdl,
+            Some(PipelineMetadata {
+                engine: WorkflowEngine::Miniwdl,
+                pipeline: Some("germline.wdl".to_string()),
+                revision: None,
+                profile: None,
+                launch_directory: None,
+                params: params(&[("sample", "NA12878"), ("input", "inputs.json")]),
+            })
+        );
+    }
+
+    #[test]
+    fn test_recognize_cwltool() {
+        let launcher = recognize_launcher(&cmd(
+            "cwltool --outdir out --parallel align.cwl job.yml --threads 4",
+        ));
+
+        assert_eq!(
+            launcher,
+            Some(PipelineMetadata {
+                engine: WorkflowEngine::Cwltool,
+                pipeline: Some("align.cwl".to_string()),
+                revision: None,
+                profile: None,
+                launch_directory: None,
+                params: params(&[("job_order", "job.yml"), ("threads", "4")]),
+            })
+        );
+    }
+
+    #[test]
+    fn test_engine_names_in_arguments_are_not_launchers() {
+        assert_eq!(
+            recognize_launcher(&cmd("bwa mem /data/nextflow run.fa")),
+            None
+        );
+        assert_eq!(recognize_launcher(&cmd("cat /home/user/snakemake")), None);
+        assert_eq!(recognize_launcher(&[]), None);
+    }
+
+    fn process(parent_pid: usize, command: &str, working_directory: &str) -> ObservedProcess {
+        ObservedProcess {
+            name: cmd(command)[0].clone(),
+            cmd: cmd(command),
+            parent_pid: Some(Pid::from(parent_pid)),
+            uid: None,
+            working_directory: Some(working_directory.to_string()),
+            start_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_find_engine_task() {
+        // nextflow (10) -> bash .command.run (11) -> bash .command.sh (12) -> salmon (13)
+        let processes: ProcessSnapshot = HashMap::from([
+            (
+                Pid::from(10),
+                process(1, "java nextflow.cli.Launcher run main.nf", "/data"),
+            ),
+            (
+                Pid::from(11),
+                process(10, "bash .command.run", "/data/work/ab/12cd"),
+            ),
+            (
+                Pid::from(12),
+                process(11, "bash .command.sh", "/data/work/ab/12cd"),
+            ),
+            (
+                Pid::from(13),
+                process(12, "salmon quant -i index", "/data/work/ab/12cd"),
+            ),
+            (Pid::from(20), process(1, "salmon index", "/data")),
+        ]);
+
+        let (task, pipeline) = find_engine_task(&processes, Pid::from(13)).unwrap();
+
+        assert_eq!(
+            task,
+            EngineTask {
+                engine: WorkflowEngine::Nextflow,
+                launcher_pid: "10".to_string(),
+                task_pid: "11".to_string(),
+                task_directory: Some("/data/work/ab/12cd".to_string()),
+            }
+        );
+        assert_eq!(pipeline.pipeline, Some("main.nf".to_string()));
+        assert_eq!(pipeline.launch_directory, Some("/data".to_string()));
+        assert_eq!(find_engine_task(&processes, Pid::from(20)), None);
+    }
+}
@@ -0,0 +1,447 @@
+// src/workflow_engines/nextflow.rs
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use super::log_follower::LogFollower;
+use super::{TaskEvent, TaskStatus, WorkflowEngine};
+use crate::event_recorder::EventRecorder;
+
+const LOG_FILE_NAME: &str = ".nextflow.log";
+const TASK_NAME_HEADER: &str = "# NEXTFLOW TASK: ";
+
+lazy_static! {
+    static ref SUBMITTED_OR_CACHED: Regex = Regex::new(
+        r"\[(?P<hash>[0-9a-f]{2}/[0-9a-f]{6})\] (?P<status>Submitted|Cached) process > (?P<name>.+)$"
+    )
+    .unwrap();
+    static ref TASK_COMPLETED: Regex = Regex::new(
+        r"Task completed > TaskHandler\[.*name: (?P<name>.+?); status: (?P<status>\w+); exit: (?P<exit>[^;]+);.*workDir: (?P<work_dir>[^\s\]]+)"
+    )
+    .unwrap();
+    static ref LOG_TIMESTAMP: Regex = Regex::new(r"^(?P<timestamp>\w{3}-\d{2} [\d:.]+) ").unwrap();
+}
+
+/// A Nextflow task, read from its work directory `work/xx/<hash>`
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct NextflowTask {
+    /// Process name followed by the tag, e.g. `FASTQC (sample_1)`
+    pub name: String,
+    pub process: String,
+    pub tag: Option<String>,
+    /// Short hash as Nextflow prints it, e.g. `a1/b2c3d4`
+    pub hash: String,
+    pub work_dir: String,
+}
+
+fn is_hex(name: &str, length: usize) -> bool {
+    name.len() == length && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_task_directory(path: &Path) -> bool {
+    let name = path.file_name().and_then(|name| name.to_str());
+    let parent = path
+        .parent()
+        .and_then(|parent| parent.file_name())
+        .and_then(|name| name.to_str());
+    matches!((parent, name), (Some(parent), Some(name)) if is_hex(parent, 2) && is_hex(name, 30))
+        && (path.join(".command.run").exists() || path.join(".command.sh").exists())
+}
+
+/// The task work directory a process runs in, from its working directory
+pub fn find_task_directory(working_directory: &Path) -> Option<&Path> {
+    working_directory
+        .ancestors()
+        .find(|path| is_task_directory(path))
+}
+
+fn get_short_hash(work_dir: &Path) -> Option<String> {
+    let hash = work_dir.file_name()?.to_str()?;
+    let prefix = work_dir.parent()?.file_name()?.to_str()?;
+    Some(format!("{}/{}", prefix, hash.get(..6)?))
+}
+
+/// `FASTQC (sample_1)` is the process `FASTQC` with the tag `sample_1`
+fn split_task_name(name: &str) -> (String, Option<String>) {
+    match name
+        .strip_suffix(')')
+        .and_then(|name| name.split_once(" ("))
+    {
+        Some((process, tag)) => (process.to_string(), Some(tag.to_string())),
+        None => (name.to_string(), None),
+    }
+}
+
+pub fn read_task(task_directory: &Path) -> Option<NextflowTask> {
+    let command_run = fs::read_to_string(task_directory.join(".command.run")).ok()?;
+    let name = command_run
+        .lines()
+        .take(10)
+        .find_map(|line| line.strip_prefix(TASK_NAME_HEADER))?
+        .trim()
+        .to_string();
+    let (process, tag) = split_task_name(&name);
+
+    Some(NextflowTask {
+        name,
+        process,
+        tag,
+        hash: get_short_hash(task_directory)?,
+        work_dir: task_directory.to_string_lossy().to_string(),
+    })
+}
+
+/// Resource usage Nextflow's wrapper measured, from `.command.trace`
+pub fn read_trace(task_directory: &Path) -> Option<Map<String, Value>> {
+    let trace = fs::read_to_string(task_directory.join(".command.trace")).ok()?;
+    let metrics = trace
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = match (value.parse::<u64>(), value.parse::<f64>()) {
+                (Ok(number), _) => Value::from(number),
+                (_, Ok(number)) => Value::from(number),
+                _ => Value::from(value),
+            };
+            (key.to_string(), value)
+        })
+        .collect();
+    Some(metrics)
+}
+
+fn read_exit_code(task_directory: &Path) -> Option<i32> {
+    fs::read_to_string(task_directory.join(".exitcode"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn task_event(status: TaskStatus, name: &str) -> TaskEvent {
+    let (process, tag) = split_task_name(name);
+    TaskEvent {
+        engine: WorkflowEngine::Nextflow,
+        status,
+        name: name.to_string(),
+        step: Some(process),
+        tag,
+        task_id: None,
+        exit_code: None,
+        work_dir: None,
+        trace: None,
+        tool_pid: None,
+        log_timestamp: None,
+    }
+}
+
+/// The task transition a `.nextflow.log` line reports, if any
+pub fn parse_log_line(line: &str) -> Option<TaskEvent> {
+    let mut event = if let Some(captures) = SUBMITTED_OR_CACHED.captures(line) {
+        let status = match &captures["status"] {
+            "Cached" => TaskStatus::Cached,
+            _ => TaskStatus::Submitted,
+        };
+        TaskEvent {
+            task_id: Some(captures["hash"].to_string()),
+            ..task_event(status, &captures["name"])
+        }
+    } else if let Some(captures) = TASK_COMPLETED.captures(line) {
+        // Successful tasks are reported once their exit code is read
+        if &captures["status"] == "COMPLETED" && &captures["exit"] == "0" {
+            return None;
+        }
+        let work_dir = Path::new(&captures["work_dir"]);
+        TaskEvent {
+            task_id: get_short_hash(work_dir),
+            exit_code: captures["exit"].parse().ok(),
+            work_dir: Some(captures["work_dir"].to_string()),
+            ..task_event(TaskStatus::Failed, &captures["name"])
+        }
+    } else {
+        return None;
+    };
+
+    event.log_timestamp = LOG_TIMESTAMP
+        .captures(line)
+        .map(|captures| captures["timestamp"].to_string());
+    Some(event)
+}
+
+/// Follows the Nextflow runs of the active runs: the tasks their tools run
+/// in, and the task transitions in `.nextflow.log`
+pub struct NextflowWatcher {
+    tasks: HashMap<PathBuf, (Option<String>, NextflowTask)>,
+    logs: Vec<(Option<String>, LogFollower)>,
+}
+
+impl NextflowWatcher {
+    pub fn new() -> Self {
+        NextflowWatcher {
+            tasks: HashMap::new(),
+            logs: vec![],
+        }
+    }
+
+    /// Reports the task once it finishes
+    pub fn track_task(&mut self, run_id: Option<String>, task: NextflowTask) {
+        self.tasks
+            .entry(PathBuf::from(&task.work_dir))
+            .or_insert((run_id, task));
+    }
+
+    /// Follows `.nextflow.log` in the launch directory of a run, which is
+    /// where Nextflow writes it unless told otherwise with `-log`
+    pub fn follow_log(&mut self, run_id: Option<String>, launch_directory: &Path) {
+        let path = launch_directory.join(LOG_FILE_NAME);
+        if self
+            .logs
+            .iter()
+            .all(|(_, follower)| follower.get_path() != path)
+        {
+            self.logs.push((run_id, LogFollower::new(path)));
+        }
+    }
+
+    pub fn forget_run(&mut self, run_id: &str) {
+        self.tasks
+            .retain(|_, (task_run_id, _)| task_run_id.as_deref() != Some(run_id));
+        self.logs
+            .retain(|(log_run_id, _)| log_run_id.as_deref() != Some(run_id));
+    }
+
+    pub fn poll(&mut self, logs: &mut EventRecorder) -> Result<()> {
+        for (run_id, follower) in self.logs.iter_mut() {
+            // A log that can't be read doesn't stop the others from being followed
+            let lines = follower.read_new_lines().unwrap_or_else(|error| {
+                eprintln!(
+                    "[{}] Failed to read {:?}: {}",
+                    Utc::now(),
+                    follower.get_path(),
+                    error
+                );
+                vec![]
+            });
+            for line in lines {
+                if let Some(event) = parse_log_line(&line) {
+                    event.record(logs, run_id.as_deref())?;
+                }
+            }
+        }
+
+        let mut finished = vec![];
+        for (work_dir, (run_id, task)) in self.tasks.iter() {
+            if !work_dir.exists() {
+                finished.push(work_dir.clone());
+                continue;
+            }
+            let Some(exit_code) = read_exit_code(work_dir) else {
+                continue;
+            };
+
+            let event = TaskEvent {
+                engine: WorkflowEngine::Nextflow,
+                status: TaskStatus::Finished,
+                name: task.name.clone(),
+                step: Some(task.process.clone()),
+                tag: task.tag.clone(),
+                task_id: Some(task.hash.clone()),
+                exit_code: Some(exit_code),
+                work_dir: Some(task.work_dir.clone()),
+                trace: read_trace(work_dir),
+                tool_pid: None,
+                log_timestamp: None,
+            };
+            event.record(logs, run_id.as_deref())?;
+            finished.push(work_dir.clone());
+        }
+        for work_dir in finished {
+            self.tasks.remove(&work_dir);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for NextflowWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const HASH: &str = "3c9e2b7f0a1d4e5f6a7b8c9d0e1f2a";
+
+    /// A task directory as Nextflow lays it out, in `<root>/work/a1/<HASH>`
+    fn create_task_directory(root: &Path, name: &str) -> PathBuf {
+        let task_directory = root.join("work").join("a1").join(HASH);
+        fs::create_dir_all(&task_directory).unwrap();
+        fs::write(
+            task_directory.join(".command.run"),
+            format!("#!/bin/bash\n# NEXTFLOW TASK: {}\nset -e\n", name),
+        )
+        .unwrap();
+        fs::write(task_directory.join(".command.sh"), "salmon quant\n").unwrap();
+        task_directory
+    }
+
+    #[test]
+    fn test_read_task_from_working_directory() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let task_directory =
+            create_task_directory(root.path(), "NFCORE_RNASEQ:SALMON_QUANT (WT_REP1)");
+        let nested = task_directory.join("salmon_output");
+        fs::create_dir(&nested)?;
+
+        assert_eq!(find_task_directory(&nested), Some(task_directory.as_path()));
+        assert_eq!(find_task_directory(root.path()), None);
+
+        let task = read_task(&task_directory).unwrap();
+        assert_eq!(
+            task,
+            NextflowTask {
+                name: "NFCORE_RNASEQ:SALMON_QUANT (WT_REP1)".to_string(),
+                process: "NFCORE_RNASEQ:SALMON_QUANT".to_string(),
+                tag: Some("WT_REP1".to_string()),
+                hash: "a1/3c9e2b".to_string(),
+                work_dir: task_directory.to_string_lossy().to_string(),
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_log_line() {
+        let submitted = parse_log_line(
+            "Jun-10 12:00:01.123 [Task submitter] INFO  nextflow.Session - [a1/3c9e2b] Submitted process > FASTQC (sample_1)",
+        )
+        .unwrap();
+        assert_eq!(submitted.status, TaskStatus::Submitted);
+        assert_eq!(submitted.name, "FASTQC (sample_1)");
+        assert_eq!(submitted.step.as_deref(), Some("FASTQC"));
+        assert_eq!(submitted.tag.as_deref(), Some("sample_1"));
+        assert_eq!(submitted.task_id.as_deref(), Some("a1/3c9e2b"));
+        assert_eq!(
+            submitted.log_timestamp.as_deref(),
+            Some("Jun-10 12:00:01.123")
+        );
+
+        let cached = parse_log_line(
+            "Jun-10 12:00:01.200 [Actor Thread 4] INFO  nextflow.processor.TaskProcessor - [0b/77aa12] Cached process > MULTIQC",
+        )
+        .unwrap();
+        assert_eq!(cached.status, TaskStatus::Cached);
+        assert_eq!(cached.tag, None);
+
+        let failed = parse_log_line(
+            "Jun-10 12:03:00.000 [Task monitor] DEBUG n.processor.TaskPollingMonitor - Task completed > TaskHandler[id: 2; name: FASTQC (sample_2); status: COMPLETED; exit: 137; error: -; workDir: /data/work/c4/d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9]",
+        )
+        .unwrap();
+        assert_eq!(failed.status, TaskStatus::Failed);
+        assert_eq!(failed.exit_code, Some(137));
+        assert_eq!(failed.task_id.as_deref(), Some("c4/d5e6f7"));
+
+        assert_eq!(
+            parse_log_line(
+                "Jun-10 12:03:00.000 [Task monitor] DEBUG n.processor.TaskPollingMonitor - Task completed > TaskHandler[id: 1; name: FASTQC (sample_1); status: COMPLETED; exit: 0; error: -; workDir: /data/work/a1/3c9e2b7f0a1d4e5f6a7b8c9d0e1f2a]"
+            ),
+            None
+        );
+        assert_eq!(
+            parse_log_line(
+                "Jun-10 12:00:00.000 [main] DEBUG nextflow.cli.Launcher - Setting http proxy"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_watcher_reports_log_transitions_and_finished_tasks() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let task_directory = create_task_directory(root.path(), "FASTQC (sample_1)");
+        let mut watcher = NextflowWatcher::new();
+        let mut logs = EventRecorder::new();
+
+        watcher.follow_log(Some("run-1".to_string()), root.path());
+        watcher.track_task(
+            Some("run-1".to_string()),
+            read_task(&task_directory).unwrap(),
+        );
+        fs::write(
+            root.path().join(LOG_FILE_NAME),
+            "Jun-10 12:00:01.123 [Task submitter] INFO  nextflow.Session - [a1/3c9e2b] Submitted process > FASTQC (sample_1)\n",
+        )?;
+
+        watcher.poll(&mut logs)?;
+        assert_eq!(logs.len(), 1);
+        assert_eq!(
+            logs.get_events()[0].attributes.as_ref().unwrap()["status"],
+            "submitted"
+        );
+        assert_eq!(logs.get_events()[0].run_id.as_deref(), Some("run-1"));
+
+        // Finishing writes the trace, then the exit code
+        fs::write(
+            task_directory.join(".command.trace"),
+            "nextflow.trace/v2\nrealtime=1520\n%cpu=98.5\npeak_rss=1048576\ncpu_model=Intel Xeon\n",
+        )?;
+        fs::write(task_directory.join(".exitcode"), "0")?;
+
+        watcher.poll(&mut logs)?;
+        assert_eq!(logs.len(), 2);
+        let attributes = logs.get_events()[1].attributes.as_ref().unwrap();
+        assert_eq!(attributes["status"], "finished");
+        assert_eq!(attributes["step"], "FASTQC");
+        assert_eq!(attributes["exit_code"], 0);
+        assert_eq!(
+            attributes["trace"],
+            json!({
+                "realtime": 1520,
+                "%cpu": 98.5,
+                "peak_rss": 1048576,
+                "cpu_model": "Intel Xeon"
+            })
+        );
+
+        // Each task is reported once
+        watcher.poll(&mut logs)?;
+        assert_eq!(logs.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_watcher_keeps_following_logs_past_unreadable_ones() -> Result<()> {
+        let unreadable = tempfile::tempdir()?;
+        let readable = tempfile::tempdir()?;
+        // Opening a directory works, reading it doesn't
+        fs::create_dir(unreadable.path().join(LOG_FILE_NAME))?;
+        let mut watcher = NextflowWatcher::new();
+        let mut logs = EventRecorder::new();
+
+        watcher.follow_log(Some("run-1".to_string()), unreadable.path());
+        watcher.follow_log(Some("run-2".to_string()), readable.path());
+        fs::write(
+            readable.path().join(LOG_FILE_NAME),
+            "Jun-10 12:00:01.123 [Task submitter] INFO  nextflow.Session - [a1/3c9e2b] Submitted process > FASTQC (sample_1)\n",
+        )?;
+
+        watcher.poll(&mut logs)?;
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs.get_events()[0].run_id.as_deref(), Some("run-2"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,729 @@
+// src/workflow_engines/snakemake.rs
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDateTime, TimeDelta, TimeZone, Utc};
+
+use super::log_follower::LogFollower;
+use super::{TaskEvent, TaskStatus, WorkflowEngine};
+use crate::event_recorder::EventRecorder;
+
+const LOG_DIRECTORY: &str = ".snakemake/log";
+const LOG_TIMESTAMP_FORMAT: &str = "%a %b %e %H:%M:%S %Y";
+const JOB_RULE_PREFIXES: [&str; 3] = ["rule ", "localrule ", "checkpoint "];
+/// Log timestamps have a resolution of a second
+const START_TIME_TOLERANCE: TimeDelta = TimeDelta::seconds(1);
+/// Tools not matched to a job by then most likely don't belong to one
+const TOOL_MATCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A job of a rule, as Snakemake logs it when the job starts
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnakemakeJob {
+    pub job_id: String,
+    pub rule: String,
+    pub wildcards: BTreeMap<String, String>,
+    /// Only logged when Snakemake runs with `--printshellcmds`
+    pub shell_command: Option<String>,
+    pub log_timestamp: Option<String>,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+}
+
+impl SnakemakeJob {
+    /// `sample=A, unit=1`, as Snakemake prints wildcards
+    fn get_tag(&self) -> Option<String> {
+        if self.wildcards.is_empty() {
+            return None;
+        }
+        let wildcards: Vec<String> = self
+            .wildcards
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        Some(wildcards.join(", "))
+    }
+
+    fn to_task_event(&self, status: TaskStatus, log_timestamp: Option<String>) -> TaskEvent {
+        let tag = self.get_tag();
+        TaskEvent {
+            engine: WorkflowEngine::Snakemake,
+            status,
+            name: match &tag {
+                Some(tag) => format!("{} ({})", self.rule, tag),
+                None => self.rule.clone(),
+            },
+            step: Some(self.rule.clone()),
+            tag,
+            task_id: Some(self.job_id.clone()),
+            exit_code: None,
+            work_dir: None,
+            trace: None,
+            tool_pid: None,
+            log_timestamp,
+        }
+    }
+
+    fn was_running_at(&self, time: DateTime<Utc>) -> bool {
+        let started = self
+            .start_time
+            .is_none_or(|start_time| start_time <= time + START_TIME_TOLERANCE);
+        let not_ended = self
+            .end_time
+            .is_none_or(|end_time| end_time >= time - START_TIME_TOLERANCE);
+        started && not_ended
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LogRecord {
+    JobStarted(SnakemakeJob),
+    /// A line of the shell command of the last started job
+    ShellCommand {
+        job_id: String,
+        line: String,
+    },
+    JobFinished {
+        job_id: String,
+        log_timestamp: Option<String>,
+    },
+    JobFailed {
+        job_id: String,
+        rule: String,
+        log_timestamp: Option<String>,
+    },
+}
+
+enum BlockKind {
+    Start,
+    Error,
+}
+
+/// The indented `key: value` lines below `rule X:` or `Error in rule X:`
+struct Block {
+    kind: BlockKind,
+    rule: String,
+    job_id: Option<String>,
+    wildcards: BTreeMap<String, String>,
+}
+
+fn parse_log_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    let timestamp = NaiveDateTime::parse_from_str(timestamp, LOG_TIMESTAMP_FORMAT).ok()?;
+    Local
+        .from_local_datetime(&timestamp)
+        .earliest()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
+fn parse_wildcards(wildcards: &str) -> BTreeMap<String, String> {
+    wildcards
+        .split(", ")
+        .filter_map(|wildcard| wildcard.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Turns the lines of a Snakemake log into job records. Jobs are logged as
+/// blocks, so the parser keeps the block it's in between lines.
+#[derive(Default)]
+pub struct LogParser {
+    timestamp: Option<String>,
+    block: Option<Block>,
+    shell_command_job_id: Option<String>,
+}
+
+impl LogParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse_line(&mut self, line: &str) -> Vec<LogRecord> {
+        let trimmed = line.trim();
+        if let Some(block) = self.block.as_mut() {
+            if line.starts_with(char::is_whitespace) && !trimmed.is_empty() {
+                match trimmed.split_once(": ") {
+                    Some(("jobid", job_id)) => block.job_id = Some(job_id.to_string()),
+                    Some(("wildcards", wildcards)) => block.wildcards = parse_wildcards(wildcards),
+                    _ => {}
+                }
+                return vec![];
+            }
+        }
+
+        let mut records: Vec<LogRecord> = self.close_block().into_iter().collect();
+        records.extend(self.parse_record(trimmed));
+        records
+    }
+
+    fn close_block(&mut self) -> Option<LogRecord> {
+        let block = self.block.take()?;
+        let job_id = block.job_id?;
+        match block.kind {
+            BlockKind::Start => {
+                self.shell_command_job_id = Some(job_id.clone());
+                Some(LogRecord::JobStarted(SnakemakeJob {
+                    job_id,
+                    rule: block.rule,
+                    wildcards: block.wildcards,
+                    shell_command: None,
+                    start_time: self.timestamp.as_deref().and_then(parse_log_timestamp),
+                    end_time: None,
+                    log_timestamp: self.timestamp.clone(),
+                }))
+            }
+            BlockKind::Error => Some(LogRecord::JobFailed {
+                job_id,
+                rule: block.rule,
+                log_timestamp: self.timestamp.clone(),
+            }),
+        }
+    }
+
+    fn parse_record(&mut self, line: &str) -> Option<LogRecord> {
+        if line.is_empty() {
+            return None;
+        }
+
+        if let Some(timestamp) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            if parse_log_timestamp(timestamp).is_some() {
+                self.timestamp = Some(timestamp.to_string());
+                self.shell_command_job_id = None;
+                return None;
+            }
+        }
+
+        if let Some(rule) = line.strip_suffix(':').and_then(|line| {
+            JOB_RULE_PREFIXES
+                .iter()
+                .find_map(|prefix| line.strip_prefix(prefix))
+        }) {
+            self.start_block(BlockKind::Start, rule);
+            return None;
+        }
+
+        if let Some(rule) = line
+            .strip_prefix("Error in rule ")
+            .and_then(|line| line.strip_suffix(':'))
+        {
+            self.start_block(BlockKind::Error, rule);
+            return None;
+        }
+
+        // `Finished job 4.`, or `Finished jobid: 4 (Rule: bwa_map)` since Snakemake 8
+        if let Some(job_id) = line
+            .strip_prefix("Finished job ")
+            .and_then(|line| line.strip_suffix('.'))
+            .or_else(|| {
+                line.strip_prefix("Finished jobid: ")
+                    .and_then(|line| line.split(' ').next())
+            })
+        {
+            self.shell_command_job_id = None;
+            return Some(LogRecord::JobFinished {
+                job_id: job_id.to_string(),
+                log_timestamp: self.timestamp.clone(),
+            });
+        }
+
+        self.shell_command_job_id
+            .clone()
+            .map(|job_id| LogRecord::ShellCommand {
+                job_id,
+                line: line.to_string(),
+            })
+    }
+
+    fn start_block(&mut self, kind: BlockKind, rule: &str) {
+        self.shell_command_job_id = None;
+        self.block = Some(Block {
+            kind,
+            rule: rule.to_string(),
+            job_id: None,
+            wildcards: BTreeMap::new(),
+        });
+    }
+}
+
+/// A tool process waiting to be matched to the job that launched it
+#[derive(Clone, Debug)]
+pub struct ToolProcess {
+    pub run_id: Option<String>,
+    pub pid: String,
+    pub command: Vec<String>,
+    pub start_time: DateTime<Utc>,
+}
+
+impl ToolProcess {
+    /// The command as it'd appear in a shell command, `bwa mem ...` for `/usr/bin/bwa mem ...`
+    fn get_shell_form(&self) -> Option<String> {
+        let (executable, arguments) = self.command.split_first()?;
+        let executable = Path::new(executable).file_name()?.to_str()?;
+        Some(
+            std::iter::once(executable)
+                .chain(arguments.iter().map(String::as_str))
+                .collect::<Vec<&str>>()
+                .join(" "),
+        )
+    }
+}
+
+/// One invocation of Snakemake, which writes a log file of its own
+struct SnakemakeLog {
+    follower: LogFollower,
+    parser: LogParser,
+    jobs: HashMap<String, SnakemakeJob>,
+}
+
+struct LogDirectory {
+    run_id: Option<String>,
+    path: PathBuf,
+    /// Logs last written before this are from earlier runs
+    since: SystemTime,
+    logs: Vec<SnakemakeLog>,
+}
+
+impl LogDirectory {
+    fn follow_new_logs(&mut self) -> Result<()> {
+        let Ok(entries) = fs::read_dir(&self.path) else {
+            return Ok(());
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let is_recent = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified >= self.since);
+            let is_followed = self.logs.iter().any(|log| log.follower.get_path() == path);
+            if path.extension().is_some_and(|extension| extension == "log")
+                && is_recent
+                && !is_followed
+            {
+                self.logs.push(SnakemakeLog {
+                    follower: LogFollower::new(path),
+                    parser: LogParser::new(),
+                    jobs: HashMap::new(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The job of this directory's runs a tool most likely belongs to
+    fn find_job(&self, tool: &ToolProcess) -> Option<&SnakemakeJob> {
+        let candidates: Vec<&SnakemakeJob> = self
+            .logs
+            .iter()
+            .flat_map(|log| log.jobs.values())
+            .filter(|job| job.was_running_at(tool.start_time))
+            .collect();
+
+        let shell_form = tool.get_shell_form()?;
+        let mut matching = candidates.iter().filter(|job| {
+            job.shell_command
+                .as_ref()
+                .is_some_and(|shell_command| shell_command.contains(&shell_form))
+        });
+        if let (Some(job), None) = (matching.next(), matching.next()) {
+            return Some(job);
+        }
+
+        // Without shell commands in the log, only an unambiguous start time will do
+        match candidates.as_slice() {
+            [job] if job.shell_command.is_none() => Some(job),
+            _ => None,
+        }
+    }
+}
+
+/// Follows the Snakemake logs of the active runs, and tags their tools with
+/// the rule, wildcards and job id that launched them
+pub struct SnakemakeWatcher {
+    directories: Vec<LogDirectory>,
+    tools: Vec<(ToolProcess, SystemTime)>,
+}
+
+impl SnakemakeWatcher {
+    pub fn new() -> Self {
+        SnakemakeWatcher {
+            directories: vec![],
+            tools: vec![],
+        }
+    }
+
+    /// Follows the logs Snakemake writes to `.snakemake/log` in the workflow
+    /// directory from `since` on
+    pub fn follow_logs(
+        &mut self,
+        run_id: Option<String>,
+        workflow_directory: &Path,
+        since: SystemTime,
+    ) {
+        let path = workflow_directory.join(LOG_DIRECTORY);
+        if self
+            .directories
+            .iter()
+            .all(|directory| directory.path != path)
+        {
+            self.directories.push(LogDirectory {
+                run_id,
+                path,
+                since,
+                logs: vec![],
+            });
+        }
+    }
+
+    /// Matches the tool to its job on the next polls
+    pub fn track_tool(&mut self, tool: ToolProcess) {
+        if self
+            .directories
+            .iter()
+            .any(|directory| directory.run_id == tool.run_id)
+        {
+            self.tools.push((tool, SystemTime::now()));
+        }
+    }
+
+    pub fn forget_run(&mut self, run_id: &str) {
+        self.directories
+            .retain(|directory| directory.run_id.as_deref() != Some(run_id));
+        self.tools
+            .retain(|(tool, _)| tool.run_id.as_deref() != Some(run_id));
+    }
+
+    pub fn poll(&mut self, logs: &mut EventRecorder) -> Result<()> {
+        // A log that can't be read doesn't stop the others from being followed
+        for directory in self.directories.iter_mut() {
+            if let Err(error) = directory.follow_new_logs() {
+                eprintln!(
+                    "[{}] Failed to look for logs in {:?}: {}",
+                    Utc::now(),
+                    directory.path,
+                    error
+                );
+            }
+            let run_id = directory.run_id.as_deref();
+            for log in directory.logs.iter_mut() {
+                let lines = log.follower.read_new_lines().unwrap_or_else(|error| {
+                    eprintln!(
+                        "[{}] Failed to read {:?}: {}",
+                        Utc::now(),
+                        log.follower.get_path(),
+                        error
+                    );
+                    vec![]
+                });
+                for line in lines {
+                    for record in log.parser.parse_line(&line) {
+                        Self::apply_record(&mut log.jobs, record, logs, run_id)?;
+                    }
+                }
+            }
+        }
+
+        let mut tools = std::mem::take(&mut self.tools);
+        let mut result = Ok(());
+        tools.retain(|(tool, tracked_at)| {
+            let job = self
+                .directories
+                .iter()
+                .filter(|directory| directory.run_id == tool.run_id)
+                .find_map(|directory| directory.find_job(tool));
+            match job {
+                Some(job) => {
+                    let event = TaskEvent {
+                        tool_pid: Some(tool.pid.clone()),
+                        ..job.to_task_event(TaskStatus::Running, job.log_timestamp.clone())
+                    };
+                    if result.is_ok() {
+                        result = event.record(logs, tool.run_id.as_deref());
+                    }
+                    false
+                }
+                None => tracked_at
+                    .elapsed()
+                    .is_ok_and(|elapsed| elapsed < TOOL_MATCH_TIMEOUT),
+            }
+        });
+        self.tools = tools;
+
+        result
+    }
+
+    fn apply_record(
+        jobs: &mut HashMap<String, SnakemakeJob>,
+        record: LogRecord,
+        logs: &mut EventRecorder,
+        run_id: Option<&str>,
+    ) -> Result<()> {
+        match record {
+            LogRecord::JobStarted(job) => {
+                job.to_task_event(TaskStatus::Submitted, job.log_timestamp.clone())
+                    .record(logs, run_id)?;
+                jobs.insert(job.job_id.clone(), job);
+            }
+            LogRecord::ShellCommand { job_id, line } => {
+                if let Some(job) = jobs.get_mut(&job_id) {
+                    match &mut job.shell_command {
+                        Some(shell_command) => {
+                            shell_command.push('\n');
+                            shell_command.push_str(&line);
+                        }
+                        None => job.shell_command = Some(line),
+                    }
+                }
+            }
+            LogRecord::JobFinished {
+                job_id,
+                log_timestamp,
+            } => {
+                if let Some(job) = jobs.get_mut(&job_id) {
+                    job.end_time = log_timestamp.as_deref().and_then(parse_log_timestamp);
+                    job.to_task_event(TaskStatus::Finished, log_timestamp)
+                        .record(logs, run_id)?;
+                }
+            }
+            LogRecord::JobFailed {
+                job_id,
+                rule,
+                log_timestamp,
+            } => {
+                let job = jobs.entry(job_id.clone()).or_insert(SnakemakeJob {
+                    job_id,
+                    rule,
+                    wildcards: BTreeMap::new(),
+                    shell_command: None,
+                    log_timestamp: None,
+                    start_time: None,
+                    end_time: None,
+                });
+                job.end_time = log_timestamp.as_deref().and_then(parse_log_timestamp);
+                job.to_task_event(TaskStatus::Failed, log_timestamp)
+                    .record(logs, run_id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for SnakemakeWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "\
+Building DAG of jobs...
+Using shell: /usr/bin/bash
+Select jobs to execute...
+
+[Mon Jun 10 12:00:00 2024]
+rule bwa_map:
+    input: data/genome.fa, data/samples/A.fastq
+    output: mapped_reads/A.bam
+    jobid: 4
+    reason: Missing output files: mapped_reads/A.bam
+    wildcards: sample=A
+    resources: tmpdir=/tmp
+
+bwa mem data/genome.fa data/samples/A.fastq | samtools view -Sb - > mapped_reads/A.bam
+[Mon Jun 10 12:00:05 2024]
+Finished job 4.
+1 of 3 steps (33%) done
+Select jobs to execute...
+
+[Mon Jun 10 12:00:05 2024]
+rule samtools_sort:
+    input: mapped_reads/A.bam
+    output: sorted_reads/A.bam
+    jobid: 2
+    wildcards: sample=A
+
+samtools sort -T sorted_reads/A -O bam mapped_reads/A.bam > sorted_reads/A.bam
+[Mon Jun 10 12:00:09 2024]
+Error in rule samtools_sort:
+    jobid: 2
+    output: sorted_reads/A.bam
+    shell:
+        samtools sort -T sorted_reads/A -O bam mapped_reads/A.bam > sorted_reads/A.bam
+        (one of the commands exited with non-zero exit code)
+
+Shutting down, this might take some time.
+";
+
+    fn parse(log: &str) -> Vec<LogRecord> {
+        let mut parser = LogParser::new();
+        log.lines()
+            .flat_map(|line| parser.parse_line(line))
+            .collect()
+    }
+
+    fn get_time(timestamp: &str) -> DateTime<Utc> {
+        parse_log_timestamp(timestamp).unwrap()
+    }
+
+    #[test]
+    fn test_parse_log() {
+        let records = parse(LOG);
+        assert_eq!(records.len(), 6);
+
+        assert_eq!(
+            records[0],
+            LogRecord::JobStarted(SnakemakeJob {
+                job_id: "4".to_string(),
+                rule: "bwa_map".to_string(),
+                wildcards: BTreeMap::from([("sample".to_string(), "A".to_string())]),
+                shell_command: None,
+                log_timestamp: Some("Mon Jun 10 12:00:00 2024".to_string()),
+                start_time: Some(get_time("Mon Jun 10 12:00:00 2024")),
+                end_time: None,
+            })
+        );
+        assert_eq!(
+            records[1],
+            LogRecord::ShellCommand {
+                job_id: "4".to_string(),
+                line: "bwa mem data/genome.fa data/samples/A.fastq | samtools view -Sb - > mapped_reads/A.bam".to_string(),
+            }
+        );
+        assert_eq!(
+            records[2],
+            LogRecord::JobFinished {
+                job_id: "4".to_string(),
+                log_timestamp: Some("Mon Jun 10 12:00:05 2024".to_string()),
+            }
+        );
+        assert!(matches!(&records[3], LogRecord::JobStarted(job) if job.rule == "samtools_sort"));
+        assert!(matches!(&records[4], LogRecord::ShellCommand { job_id, .. } if job_id == "2"));
+        assert_eq!(
+            records[5],
+            LogRecord::JobFailed {
+                job_id: "2".to_string(),
+                rule: "samtools_sort".to_string(),
+                log_timestamp: Some("Mon Jun 10 12:00:09 2024".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_snakemake_8_log() {
+        let records = parse(
+            "[Mon Jun  3 09:15:00 2024]\nlocalrule all:\n    jobid: 0\n    reason: Forced execution\n\n[Mon Jun  3 09:15:01 2024]\nFinished jobid: 0 (Rule: all)\n",
+        );
+        assert!(matches!(
+            &records[0],
+            LogRecord::JobStarted(job) if job.rule == "all" && job.start_time == Some(get_time("Mon Jun  3 09:15:00 2024"))
+        ));
+        assert!(matches!(&records[1], LogRecord::JobFinished { job_id, .. } if job_id == "0"));
+    }
+
+    #[test]
+    fn test_watcher_tags_tools_with_their_job() -> Result<()> {
+        let workflow_directory = tempfile::tempdir()?;
+        let log_directory = workflow_directory.path().join(LOG_DIRECTORY);
+        fs::create_dir_all(&log_directory)?;
+        let run_id = Some("run-1".to_string());
+
+        let mut watcher = SnakemakeWatcher::new();
+        let mut logs = EventRecorder::new();
+        watcher.follow_logs(
+            run_id.clone(),
+            workflow_directory.path(),
+            SystemTime::now() - Duration::from_secs(5),
+        );
+        fs::write(
+            log_directory.join("2024-06-10T120000.000000.snakemake.log"),
+            LOG,
+        )?;
+
+        let tool = |pid: &str, command: &[&str], start_time: &str| ToolProcess {
+            run_id: run_id.clone(),
+            pid: pid.to_string(),
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            start_time: get_time(start_time),
+        };
+        watcher.track_tool(tool(
+            "100",
+            &[
+                "/usr/bin/bwa",
+                "mem",
+                "data/genome.fa",
+                "data/samples/A.fastq",
+            ],
+            "Mon Jun 10 12:00:01 2024",
+        ));
+        watcher.track_tool(tool(
+            "101",
+            &["samtools", "view", "-Sb", "-"],
+            "Mon Jun 10 12:00:01 2024",
+        ));
+        watcher.track_tool(tool(
+            "102",
+            &["fastqc", "A.fastq"],
+            "Mon Jun 10 12:00:01 2024",
+        ));
+        watcher.poll(&mut logs)?;
+
+        let events: Vec<&serde_json::Value> = logs
+            .get_events()
+            .iter()
+            .filter_map(|event| event.attributes.as_ref())
+            .collect();
+        let statuses: Vec<&str> = events
+            .iter()
+            .map(|event| event["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                "submitted",
+                "finished",
+                "submitted",
+                "failed",
+                "running",
+                "running"
+            ]
+        );
+        assert_eq!(events[4]["tool_pid"], "100");
+        assert_eq!(events[4]["name"], "bwa_map (sample=A)");
+        assert_eq!(events[4]["task_id"], "4");
+        assert_eq!(events[5]["tool_pid"], "101");
+
+        // Unmatched tools are retried until they time out
+        assert_eq!(watcher.tools.len(), 1);
+
+        watcher.forget_run("run-1");
+        assert!(watcher.directories.is_empty());
+        assert!(watcher.tools.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_logs_of_earlier_runs_are_ignored() -> Result<()> {
+        let workflow_directory = tempfile::tempdir()?;
+        let log_directory = workflow_directory.path().join(LOG_DIRECTORY);
+        fs::create_dir_all(&log_directory)?;
+        fs::write(log_directory.join("old.snakemake.log"), LOG)?;
+
+        let mut watcher = SnakemakeWatcher::new();
+        let mut logs = EventRecorder::new();
+        watcher.follow_logs(
+            None,
+            workflow_directory.path(),
+            SystemTime::now() + Duration::from_secs(60),
+        );
+        watcher.poll(&mut logs)?;
+        assert_eq!(logs.len(), 0);
+
+        Ok(())
+    }
+}